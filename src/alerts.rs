@@ -0,0 +1,259 @@
+//! Alerting on queue conditions.
+//!
+//! Rules from the `[alerts]` config section are evaluated periodically against
+//! every queue. When a rule starts firing for a queue, and again when it
+//! resolves, the operator is notified with a system message and, optionally,
+//! a webhook call:
+//!
+//! ```toml
+//! [alerts]
+//! interval_secs = 30
+//!
+//! [[alerts.rules]]
+//! name = "reviewer-backlog"
+//! metric = "queue_depth"
+//! threshold = 100
+//! project_id = "owner/repo"
+//! agent_id = "reviewer"
+//! webhook = "https://hooks.example.com/mailbox"
+//! ```
+
+use crate::config::ConfigError;
+use crate::db::{Database, QueueStatus};
+use crate::time;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// Timeout for a single webhook call.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Queue measurement an alert rule is evaluated against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    /// Number of pending messages.
+    QueueDepth,
+    /// Age of the oldest pending message, in seconds.
+    OldestMessageAgeSecs,
+    /// Number of the agent's messages in the project's dead-letter queue.
+    DeadLetters,
+}
+
+impl AlertMetric {
+    /// Returns the metric name as used in the config file.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::QueueDepth => "queue_depth",
+            Self::OldestMessageAgeSecs => "oldest_message_age_secs",
+            Self::DeadLetters => "dead_letters",
+        }
+    }
+
+    const fn value(self, queue: &QueueStatus) -> u64 {
+        match self {
+            Self::QueueDepth => queue.depth,
+            Self::OldestMessageAgeSecs => queue.oldest_message_age_secs,
+            Self::DeadLetters => queue.dead_letters,
+        }
+    }
+}
+
+/// A condition on queues that notifies the operator while it holds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    /// Unique rule name, included in notifications.
+    pub name: String,
+    pub metric: AlertMetric,
+    /// The rule fires when the metric is strictly above this value.
+    pub threshold: u64,
+    /// Only evaluate queues in this project (default: all projects).
+    #[serde(default)]
+    pub project_id: Option<String>,
+    /// Only evaluate this agent's queue (default: all agents).
+    #[serde(default)]
+    pub agent_id: Option<String>,
+    /// URL to POST a JSON notification to.
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// Whether to post a system message to the operator (default: true).
+    #[serde(default = "default_system_message")]
+    pub system_message: bool,
+}
+
+const fn default_system_message() -> bool {
+    true
+}
+
+impl AlertRule {
+    fn matches(&self, queue: &QueueStatus) -> bool {
+        self.project_id
+            .as_deref()
+            .is_none_or(|p| p == queue.project_id)
+            && self.agent_id.as_deref().is_none_or(|a| a == queue.agent_id)
+    }
+}
+
+/// Alerting settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertsConfig {
+    /// Seconds between rule evaluations.
+    pub interval_secs: u64,
+    pub rules: Vec<AlertRule>,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 30,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl AlertsConfig {
+    /// Checks that rule names are unique and webhooks are valid HTTP(S) URLs.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |rule: &AlertRule, reason: &str| ConfigError::InvalidAlert {
+            name: rule.name.clone(),
+            reason: reason.to_string(),
+        };
+        let mut names = HashSet::new();
+        for rule in &self.rules {
+            if !names.insert(rule.name.as_str()) {
+                return Err(invalid(rule, "duplicate rule name"));
+            }
+            if let Some(webhook) = &rule.webhook {
+                let url =
+                    reqwest::Url::parse(webhook).map_err(|e| invalid(rule, &e.to_string()))?;
+                if !matches!(url.scheme(), "http" | "https") {
+                    return Err(invalid(rule, "webhook must be an http(s) URL"));
+                }
+            }
+            if rule.webhook.is_none() && !rule.system_message {
+                return Err(invalid(
+                    rule,
+                    "rule has no webhook and system_message is off",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Identifies a rule firing for one queue: (rule index, project, agent).
+type FiringKey = (usize, String, String);
+
+/// Evaluates alert rules until the task is cancelled.
+pub async fn run(db: Database, config: AlertsConfig) {
+    if config.rules.is_empty() {
+        return;
+    }
+    let client = reqwest::Client::new();
+    let mut firing: HashMap<FiringKey, u64> = HashMap::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+
+    loop {
+        ticker.tick().await;
+
+        let overview_db = db.clone();
+        let queues = match tokio::task::spawn_blocking(move || overview_db.queue_overview()).await {
+            Ok(Ok(queues)) => queues,
+            Ok(Err(e)) => {
+                tracing::warn!("Alert evaluation failed: {e}");
+                continue;
+            }
+            Err(e) => {
+                tracing::warn!("Alert evaluation task panicked: {e}");
+                continue;
+            }
+        };
+
+        let mut now_firing = HashMap::new();
+        for (index, rule) in config.rules.iter().enumerate() {
+            for queue in queues.iter().filter(|q| rule.matches(q)) {
+                let value = rule.metric.value(queue);
+                if value > rule.threshold {
+                    now_firing.insert(
+                        (index, queue.project_id.clone(), queue.agent_id.clone()),
+                        value,
+                    );
+                }
+            }
+        }
+
+        for (key, value) in &now_firing {
+            if !firing.contains_key(key) {
+                notify(&db, &client, &config.rules[key.0], key, *value, true).await;
+            }
+        }
+        for key in firing.keys() {
+            if !now_firing.contains_key(key) {
+                // Queues that drained no longer appear in the overview
+                let value = queues
+                    .iter()
+                    .find(|q| q.project_id == key.1 && q.agent_id == key.2)
+                    .map_or(0, |q| config.rules[key.0].metric.value(q));
+                notify(&db, &client, &config.rules[key.0], key, value, false).await;
+            }
+        }
+        firing = now_firing;
+    }
+}
+
+async fn notify(
+    db: &Database,
+    client: &reqwest::Client,
+    rule: &AlertRule,
+    (_, project_id, agent_id): &FiringKey,
+    value: u64,
+    firing: bool,
+) {
+    let status = if firing { "firing" } else { "resolved" };
+    tracing::info!(
+        "Alert '{}' {status} for {project_id}/{agent_id} ({} = {value}, threshold {})",
+        rule.name,
+        rule.metric.name(),
+        rule.threshold
+    );
+
+    if rule.system_message {
+        let content = format!(
+            "Alert '{}' {status}: queue {project_id}/{agent_id} {} = {value} (threshold {})",
+            rule.name,
+            rule.metric.name(),
+            rule.threshold
+        );
+        let db = db.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || db.post_system_alert(&content)).await {
+            tracing::warn!("Failed to post alert message: {e}");
+        }
+    }
+
+    if let Some(webhook) = &rule.webhook {
+        let payload = json!({
+            "alert": rule.name,
+            "status": status,
+            "project_id": project_id,
+            "agent_id": agent_id,
+            "metric": rule.metric.name(),
+            "value": value,
+            "threshold": rule.threshold,
+            "timestamp": time::now_iso8601(),
+        });
+        let result = client
+            .post(webhook)
+            .timeout(WEBHOOK_TIMEOUT)
+            .json(&payload)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        if let Err(e) = result {
+            tracing::warn!("Alert webhook for '{}' failed: {e}", rule.name);
+        }
+    }
+}
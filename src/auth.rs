@@ -0,0 +1,449 @@
+//! Bearer-token authentication.
+//!
+//! When the `[auth]` config section is present, requests to `/mcp` and
+//! `/peek` must carry `Authorization: Bearer <token>`. Tokens are verified
+//! either by an external command, which gets the token on stdin and must exit
+//! successfully after printing `{"agent_id": "...", "roles": ["..."]}` (plus
+//! optionally `"tools": ["..."]`, limiting the tools the caller may call):
+//!
+//! ```toml
+//! [auth]
+//! command = ["/usr/local/bin/verify-token", "--audience", "mailbox"]
+//! ```
+//!
+//! or as JWTs signed by an OIDC provider, whose keys are fetched from its
+//! JWKS endpoint (discovered from the issuer unless `jwks_url` is given):
+//!
+//! ```toml
+//! [auth.oidc]
+//! issuer = "https://idp.example.com"
+//! audience = "mailbox"
+//! agent_claim = "sub"
+//! roles_claim = "roles"
+//! ```
+//!
+//! Tokens starting with `mbx_` are instead looked up among the API tokens
+//! managed with the `create_api_token`, `rotate_api_token` and
+//! `expire_api_token` tools; an `[auth]` section with neither method accepts
+//! only those. Rotating a token keeps the old one valid for an overlap window,
+//! so clients can switch without downtime.
+//!
+//! The verified [`Identity`] is attached to the request. Its agent ID is the
+//! default sender of the caller's messages, and the admin tools require the
+//! [`ADMIN_ROLE`].
+
+use crate::config::ConfigError;
+use crate::db::{Database, API_TOKEN_PREFIX};
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+/// Role required for the admin tools and overrides.
+pub const ADMIN_ROLE: &str = "admin";
+
+/// How long a verification command may run before the token is rejected.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Minimum time between JWKS fetches, so tokens with unknown key IDs can't
+/// make the server hammer the provider.
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Timeout for fetching the provider's discovery document and keys.
+const OIDC_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Authentication settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Verification command and its arguments.
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    /// Seconds a token accepted by the command is trusted without running it
+    /// again (default: 60; 0 verifies every request).
+    #[serde(default = "default_cache_secs")]
+    pub cache_secs: u64,
+    /// OIDC provider whose JWTs are accepted.
+    #[serde(default)]
+    pub oidc: Option<OidcConfig>,
+}
+
+const fn default_cache_secs() -> u64 {
+    60
+}
+
+/// OIDC provider settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OidcConfig {
+    /// Expected `iss` claim; also the base URL for discovery.
+    pub issuer: String,
+    /// Expected `aud` claim.
+    pub audience: String,
+    /// JWKS endpoint (default: the `jwks_uri` of the issuer's discovery document).
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    /// Claim holding the agent ID (default: `sub`).
+    #[serde(default = "default_agent_claim")]
+    pub agent_claim: String,
+    /// Claim holding the roles, as an array or a space-separated string
+    /// (default: `roles`).
+    #[serde(default = "default_roles_claim")]
+    pub roles_claim: String,
+}
+
+fn default_agent_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_roles_claim() -> String {
+    "roles".to_string()
+}
+
+impl AuthConfig {
+    /// Checks that at most one external verification method is configured.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| ConfigError::InvalidAuth {
+            reason: reason.to_string(),
+        };
+        match (&self.command, &self.oidc) {
+            (Some(command), None) if command.is_empty() => Err(invalid("command is empty")),
+            (Some(_), None) | (None, None) => Ok(()),
+            (None, Some(oidc)) => {
+                for url in std::iter::once(&oidc.issuer).chain(&oidc.jwks_url) {
+                    let url = reqwest::Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+                    if !matches!(url.scheme(), "http" | "https") {
+                        return Err(invalid("OIDC URLs must be http(s)"));
+                    }
+                }
+                Ok(())
+            }
+            (Some(_), Some(_)) => Err(invalid("set only one of command and [auth.oidc]")),
+        }
+    }
+}
+
+/// The verified caller of a request.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Identity {
+    pub agent_id: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Tools the caller may call; `None` allows all of them.
+    #[serde(default)]
+    pub tools: Option<Vec<String>>,
+    /// The API token used, if the caller presented one.
+    #[serde(skip)]
+    pub token_id: Option<String>,
+}
+
+impl Identity {
+    /// Returns `true` if the caller has the given role.
+    #[must_use]
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+
+    /// Returns `true` if the caller may call the tool with the given name.
+    #[must_use]
+    pub fn may_call(&self, tool: &str) -> bool {
+        self.tools
+            .as_ref()
+            .is_none_or(|tools| tools.iter().any(|t| t == tool))
+    }
+
+    /// Returns `true` if the caller may act as (or read the queue of) the
+    /// given agent: its own, or any with the [`ADMIN_ROLE`].
+    #[must_use]
+    pub fn may_act_as(&self, agent_id: &str) -> bool {
+        self.agent_id == agent_id.trim() || self.has_role(ADMIN_ROLE)
+    }
+}
+
+/// Reasons a request is not authenticated.
+#[derive(Error, Debug)]
+pub enum AuthError {
+    /// The request has no bearer token.
+    #[error("Missing bearer token")]
+    MissingToken,
+
+    /// The token was rejected.
+    #[error("Invalid token: {reason}")]
+    InvalidToken { reason: String },
+
+    /// The token could not be checked, e.g. because the provider is unreachable.
+    #[error("Token verification unavailable: {reason}")]
+    Unavailable { reason: String },
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::MissingToken | Self::InvalidToken { .. } => StatusCode::UNAUTHORIZED,
+            Self::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        (
+            status,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            self.to_string(),
+        )
+            .into_response()
+    }
+}
+
+fn invalid(reason: impl ToString) -> AuthError {
+    AuthError::InvalidToken {
+        reason: reason.to_string(),
+    }
+}
+
+fn unavailable(reason: impl ToString) -> AuthError {
+    AuthError::Unavailable {
+        reason: reason.to_string(),
+    }
+}
+
+/// Verifies bearer tokens; cheap to clone.
+#[derive(Clone)]
+pub struct Authenticator {
+    config: Arc<AuthConfig>,
+    client: reqwest::Client,
+    /// Tokens accepted by the command, with when they were verified.
+    verified: Arc<Mutex<HashMap<String, (Identity, Instant)>>>,
+    /// The provider's keys, with when they were fetched.
+    keys: Arc<tokio::sync::Mutex<Option<(JwkSet, Instant)>>>,
+    /// Database holding the managed API tokens.
+    tokens: Option<Database>,
+}
+
+impl Authenticator {
+    #[must_use]
+    pub fn new(config: AuthConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            client: reqwest::Client::new(),
+            verified: Arc::new(Mutex::new(HashMap::new())),
+            keys: Arc::new(tokio::sync::Mutex::new(None)),
+            tokens: None,
+        }
+    }
+
+    /// Accepts the API tokens stored in the database.
+    #[must_use]
+    pub fn with_tokens(mut self, db: Database) -> Self {
+        self.tokens = Some(db);
+        self
+    }
+
+    /// Verifies a bearer token, returning the caller's identity.
+    pub async fn verify(&self, token: &str) -> Result<Identity, AuthError> {
+        if let Some(db) = self
+            .tokens
+            .as_ref()
+            .filter(|_| token.starts_with(API_TOKEN_PREFIX))
+        {
+            let token = db
+                .verify_api_token(token)
+                .map_err(unavailable)?
+                .ok_or_else(|| invalid("unknown or expired API token"))?;
+            return Ok(Identity {
+                agent_id: token.agent_id,
+                roles: token.roles,
+                tools: token.tools,
+                token_id: Some(token.token_id),
+            });
+        }
+        match (&self.config.command, &self.config.oidc) {
+            (Some(command), _) => self.verify_with_command(command, token).await,
+            (None, Some(oidc)) => self.verify_jwt(oidc, token).await,
+            (None, None) => Err(invalid("not an API token")),
+        }
+    }
+
+    fn verified(&self) -> std::sync::MutexGuard<'_, HashMap<String, (Identity, Instant)>> {
+        self.verified
+            .lock()
+            .expect("Token cache mutex poisoned - this indicates a bug")
+    }
+
+    async fn verify_with_command(
+        &self,
+        command: &[String],
+        token: &str,
+    ) -> Result<Identity, AuthError> {
+        let ttl = Duration::from_secs(self.config.cache_secs);
+        {
+            let mut verified = self.verified();
+            verified.retain(|_, (_, at)| at.elapsed() < ttl);
+            if let Some((identity, _)) = verified.get(token) {
+                return Ok(identity.clone());
+            }
+        }
+
+        let mut child = tokio::process::Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| unavailable(format!("cannot run {}: {e}", command[0])))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A verifier that exits without reading its input is not an error
+            let _ = stdin.write_all(format!("{token}\n").as_bytes()).await;
+        }
+        let output = tokio::time::timeout(COMMAND_TIMEOUT, child.wait_with_output())
+            .await
+            .map_err(|_| unavailable("verification command timed out"))?
+            .map_err(unavailable)?;
+        if !output.status.success() {
+            return Err(invalid("rejected by verification command"));
+        }
+        let identity: Identity = serde_json::from_slice(&output.stdout)
+            .map_err(|e| unavailable(format!("unexpected verification command output: {e}")))?;
+        if identity.agent_id.trim().is_empty() {
+            return Err(invalid("no agent ID"));
+        }
+        if !ttl.is_zero() {
+            self.verified()
+                .insert(token.to_string(), (identity.clone(), Instant::now()));
+        }
+        Ok(identity)
+    }
+
+    async fn verify_jwt(&self, oidc: &OidcConfig, token: &str) -> Result<Identity, AuthError> {
+        let header = jsonwebtoken::decode_header(token).map_err(invalid)?;
+        let key = self.signing_key(oidc, header.kid.as_deref()).await?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&oidc.issuer]);
+        validation.set_audience(&[&oidc.audience]);
+        let claims =
+            jsonwebtoken::decode::<serde_json::Map<String, Value>>(token, &key, &validation)
+                .map_err(invalid)?
+                .claims;
+
+        let agent_id = claims
+            .get(&oidc.agent_claim)
+            .and_then(Value::as_str)
+            .filter(|agent_id| !agent_id.trim().is_empty())
+            .ok_or_else(|| invalid(format!("no '{}' claim", oidc.agent_claim)))?;
+        let roles = match claims.get(&oidc.roles_claim) {
+            Some(Value::Array(roles)) => roles
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect(),
+            Some(Value::String(roles)) => roles.split_whitespace().map(str::to_string).collect(),
+            _ => Vec::new(),
+        };
+        Ok(Identity {
+            agent_id: agent_id.to_string(),
+            roles,
+            tools: None,
+            token_id: None,
+        })
+    }
+
+    /// Returns the provider key with the given ID (or its only key, if the
+    /// token names none), refetching the key set if the key is unknown.
+    async fn signing_key(
+        &self,
+        oidc: &OidcConfig,
+        kid: Option<&str>,
+    ) -> Result<DecodingKey, AuthError> {
+        let mut keys = self.keys.lock().await;
+        let find = |set: &JwkSet| match kid {
+            Some(kid) => set.find(kid).cloned(),
+            None if set.keys.len() == 1 => set.keys.first().cloned(),
+            None => None,
+        };
+        if let Some(jwk) = keys.as_ref().and_then(|(set, _)| find(set)) {
+            return DecodingKey::from_jwk(&jwk).map_err(invalid);
+        }
+        if keys
+            .as_ref()
+            .is_some_and(|(_, fetched)| fetched.elapsed() < JWKS_REFRESH_INTERVAL)
+        {
+            return Err(invalid("unknown signing key"));
+        }
+
+        let set = self.fetch_keys(oidc).await?;
+        let jwk = find(&set);
+        *keys = Some((set, Instant::now()));
+        let jwk = jwk.ok_or_else(|| invalid("unknown signing key"))?;
+        DecodingKey::from_jwk(&jwk).map_err(invalid)
+    }
+
+    async fn fetch_keys(&self, oidc: &OidcConfig) -> Result<JwkSet, AuthError> {
+        let jwks_url = match &oidc.jwks_url {
+            Some(url) => url.clone(),
+            None => {
+                let discovery = format!(
+                    "{}/.well-known/openid-configuration",
+                    oidc.issuer.trim_end_matches('/')
+                );
+                let document: Value = self.fetch_json(&discovery).await?;
+                document
+                    .get("jwks_uri")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| unavailable("discovery document has no jwks_uri"))?
+                    .to_string()
+            }
+        };
+        self.fetch_json(&jwks_url).await
+    }
+
+    async fn fetch_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, AuthError> {
+        let response = self
+            .client
+            .get(url)
+            .timeout(OIDC_FETCH_TIMEOUT)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| unavailable(format!("cannot fetch {url}: {e}")))?;
+        response
+            .json()
+            .await
+            .map_err(|e| unavailable(format!("invalid response from {url}: {e}")))
+    }
+}
+
+/// Middleware rejecting requests without a valid bearer token, and attaching
+/// the caller's [`Identity`] to the others.
+pub async fn require_bearer(
+    State(auth): State<Authenticator>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty());
+    let Some(token) = token else {
+        return AuthError::MissingToken.into_response();
+    };
+    match auth.verify(token).await {
+        Ok(identity) => {
+            request.extensions_mut().insert(identity);
+            next.run(request).await
+        }
+        Err(e) => {
+            tracing::debug!("Rejected request: {e}");
+            e.into_response()
+        }
+    }
+}
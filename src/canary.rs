@@ -0,0 +1,117 @@
+//! Synthetic canary messages.
+//!
+//! When the `[canary]` config section is present, a background task
+//! periodically sends a self-addressed message through the configured project,
+//! receives it back, and records the enqueue→receive latency in [`Metrics`]:
+//!
+//! ```toml
+//! [canary]
+//! project_id = "_canary"
+//! interval_secs = 60
+//! ```
+
+use crate::db::{Database, DbError};
+use crate::metrics::Metrics;
+use crate::time;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// Agent that sends and receives canary messages.
+pub const CANARY_AGENT: &str = "canary";
+
+/// Canary settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CanaryConfig {
+    /// Project the canary messages travel through.
+    pub project_id: String,
+    /// Seconds between canary round trips.
+    pub interval_secs: u64,
+}
+
+impl Default for CanaryConfig {
+    fn default() -> Self {
+        Self {
+            project_id: "_canary".to_string(),
+            interval_secs: 60,
+        }
+    }
+}
+
+/// Outcome of a single canary round trip.
+enum Probe {
+    /// The message came back after the given latency.
+    Delivered(Duration),
+    /// The message was sent but not received back.
+    Lost,
+}
+
+/// Sends a canary message and receives it back.
+fn probe(db: &Database, project_id: &str) -> Result<Probe, DbError> {
+    let token = format!("canary {}", time::unix_millis());
+    let started = Instant::now();
+    db.send_message(project_id, CANARY_AGENT, CANARY_AGENT, &token, None)?;
+    let received = db.receive_messages(project_id, CANARY_AGENT, None)?;
+    let latency = started.elapsed();
+    Ok(if received.iter().any(|m| m.content == token) {
+        Probe::Delivered(latency)
+    } else {
+        Probe::Lost
+    })
+}
+
+/// Runs canary round trips until the task is cancelled.
+pub async fn run(db: Database, metrics: Metrics, config: CanaryConfig) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+
+    loop {
+        ticker.tick().await;
+
+        let db = db.clone();
+        let metrics = metrics.clone();
+        let project_id = config.project_id.clone();
+        let outcome =
+            tokio::task::spawn_blocking(move || round_trip(&db, &metrics, &project_id)).await;
+        if let Err(e) = outcome {
+            tracing::warn!("Canary task panicked: {e}");
+        }
+    }
+}
+
+/// Sends a canary message through the project and receives it back,
+/// recording the outcome in the metrics.
+pub fn round_trip(db: &Database, metrics: &Metrics, project_id: &str) {
+    match probe(db, project_id) {
+        Ok(Probe::Delivered(latency)) => {
+            metrics.increment(
+                "mailbox_canary_success_total",
+                "Canary round trips that delivered the message",
+            );
+            metrics.set_gauge(
+                "mailbox_canary_latency_seconds",
+                "Enqueue to receive latency of the last successful canary",
+                latency.as_secs_f64(),
+            );
+            #[allow(clippy::cast_precision_loss)] // seconds fit comfortably in f64
+            metrics.set_gauge(
+                "mailbox_canary_last_success_timestamp_seconds",
+                "Unix time of the last successful canary",
+                time::unix_seconds() as f64,
+            );
+        }
+        Ok(Probe::Lost) => {
+            tracing::warn!("Canary message was not received back");
+            metrics.increment(
+                "mailbox_canary_failure_total",
+                "Canary round trips that failed",
+            );
+        }
+        Err(e) => {
+            tracing::warn!("Canary round trip failed: {e}");
+            metrics.increment(
+                "mailbox_canary_failure_total",
+                "Canary round trips that failed",
+            );
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! HTTP response compression.
+//!
+//! When the `[compression]` config section is present, responses are
+//! compressed for clients that send a matching `Accept-Encoding`:
+//!
+//! ```toml
+//! [compression]
+//! algorithms = ["gzip", "br"]
+//! min_size = 1024
+//! ```
+//!
+//! MCP responses are streamed as server-sent events, which generic compression
+//! skips; they are compressed too. The long-lived `GET /mcp` event stream is
+//! not, since its notifications would be held back in the compressor's buffer.
+
+use axum::extract::Request;
+use axum::http::{header, Method};
+use serde::Deserialize;
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
+
+/// A content coding offered to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Gzip,
+    /// Brotli.
+    Br,
+}
+
+/// Response compression settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// Codings offered; the client's preference decides among them.
+    pub algorithms: Vec<Algorithm>,
+    /// Responses of known size smaller than this many bytes are sent as-is.
+    pub min_size: u16,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            algorithms: vec![Algorithm::Gzip, Algorithm::Br],
+            min_size: 1024,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Builds the layer compressing responses, including event streams.
+    #[must_use]
+    pub fn layer(&self) -> CompressionLayer<impl Predicate> {
+        let predicate = SizeAbove::new(self.min_size)
+            .and(NotForContentType::GRPC)
+            .and(NotForContentType::IMAGES);
+        CompressionLayer::new()
+            .gzip(self.algorithms.contains(&Algorithm::Gzip))
+            .br(self.algorithms.contains(&Algorithm::Br))
+            .compress_when(predicate)
+    }
+}
+
+/// Request middleware that keeps `GET` responses (the standalone MCP event
+/// stream) uncompressed, by dropping their `Accept-Encoding`.
+pub async fn uncompressed_streams(mut request: Request) -> Request {
+    if request.method() == Method::GET {
+        request.headers_mut().remove(header::ACCEPT_ENCODING);
+    }
+    request
+}
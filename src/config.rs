@@ -0,0 +1,146 @@
+//! Configuration file support for mailbox-mcp.
+//!
+//! Settings that don't fit comfortably on the command line live in an optional
+//! TOML file passed via `--config`:
+//!
+//! ```toml
+//! [tools]
+//! # Only expose these tools (omit to expose all)
+//! enabled = ["context_get", "context_set", "context_list", "send_message"]
+//! # Expose context_get under an additional name
+//! aliases = { mailbox_context_get = "context_get" }
+//! # Hide tools entirely, under every name
+//! hidden = ["storage_analyze"]
+//! ```
+//!
+//! Alert rules live under `[alerts]` (see [`crate::alerts`]), the synthetic
+//! canary under `[canary]` (see [`crate::canary`]), StatsD export under
+//! `[statsd]` (see [`crate::statsd`]), response compression under
+//! `[compression]` (see [`crate::compression`]), and authentication under
+//! `[auth]` (see [`crate::auth`]), periodic jobs under `[[jobs]]` (see
+//! [`crate::jobs`]), and content transformations under `[[transforms]]` (see
+//! [`crate::transform`]). Any string may reference environment
+//! variables or encrypted secrets instead of holding credentials in plain
+//! text (see [`crate::secrets`]).
+
+use crate::alerts::AlertsConfig;
+use crate::auth::AuthConfig;
+use crate::canary::CanaryConfig;
+use crate::compression::CompressionConfig;
+use crate::jobs::{self, JobConfig};
+use crate::secrets::{self, SecretsConfig};
+use crate::statsd::StatsdConfig;
+use crate::transform::TransformConfig;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use thiserror::Error;
+
+/// Errors that can occur while loading or applying configuration.
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    #[error("Failed to read config file: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The configuration file is not valid TOML or has unknown fields.
+    #[error("Invalid config file: {0}")]
+    Parse(#[from] toml::de::Error),
+
+    /// A tool referenced in the configuration does not exist.
+    #[error("Unknown tool '{name}' in {section}")]
+    UnknownTool { name: String, section: &'static str },
+
+    /// An alias collides with an existing tool name.
+    #[error("Tool alias '{alias}' collides with an existing tool")]
+    AliasCollision { alias: String },
+
+    /// An alias points to a tool that is hidden or not enabled.
+    #[error("Tool alias '{alias}' points to '{target}', which {section} disables")]
+    AliasOfDisabledTool {
+        alias: String,
+        target: String,
+        section: &'static str,
+    },
+
+    /// An alert rule is malformed.
+    #[error("Invalid alert rule '{name}': {reason}")]
+    InvalidAlert { name: String, reason: String },
+
+    /// A periodic job is malformed.
+    #[error("Invalid job '{name}': {reason}")]
+    InvalidJob { name: String, reason: String },
+
+    /// The authentication settings are malformed.
+    #[error("Invalid auth config: {reason}")]
+    InvalidAuth { reason: String },
+
+    /// A content transformation stage is malformed.
+    #[error("Invalid transform: {reason}")]
+    InvalidTransform { reason: String },
+
+    /// A secret reference cannot be resolved.
+    #[error("Cannot resolve config secrets: {reason}")]
+    InvalidSecret { reason: String },
+}
+
+/// Top-level configuration file contents.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Tool exposure settings.
+    pub tools: ToolsConfig,
+    /// Alert rules on queue conditions.
+    pub alerts: AlertsConfig,
+    /// Synthetic canary settings; the canary is disabled if absent.
+    pub canary: Option<CanaryConfig>,
+    /// StatsD export settings; export is disabled if absent.
+    pub statsd: Option<StatsdConfig>,
+    /// HTTP response compression; responses are uncompressed if absent.
+    pub compression: Option<CompressionConfig>,
+    /// Bearer-token authentication; requests are unauthenticated if absent.
+    pub auth: Option<AuthConfig>,
+    /// Built-in actions run on cron schedules.
+    pub jobs: Vec<JobConfig>,
+    /// Stages applied, in order, to the content of sent messages.
+    pub transforms: Vec<TransformConfig>,
+    /// Encrypted secrets file referenced by `${secret:NAME}`.
+    pub secrets: Option<SecretsConfig>,
+}
+
+impl Config {
+    /// Loads configuration from a TOML file.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut value = toml::Value::Table(toml::from_str(&contents)?);
+        // The secrets section itself may only reference the environment
+        let secrets = match value.get("secrets") {
+            Some(section) => {
+                let mut section = section.clone();
+                secrets::resolve(&mut section, None)?;
+                Some(SecretsConfig::deserialize(section)?.load()?)
+            }
+            None => None,
+        };
+        secrets::resolve(&mut value, secrets.as_ref())?;
+        let config: Self = value.try_into()?;
+        config.alerts.validate()?;
+        jobs::validate(&config.jobs)?;
+        if let Some(auth) = &config.auth {
+            auth.validate()?;
+        }
+        Ok(config)
+    }
+}
+
+/// Controls which tools are exposed and under which names.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolsConfig {
+    /// Additional names for tools, mapping alias to canonical tool name.
+    pub aliases: BTreeMap<String, String>,
+    /// Canonical tool names to expose. If set, all other tools are disabled.
+    pub enabled: Option<Vec<String>>,
+    /// Canonical tool names to hide. They can't be aliased either.
+    pub hidden: Vec<String>,
+}
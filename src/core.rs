@@ -0,0 +1,135 @@
+//! Stable API for embedding the mailbox engine in other Rust programs.
+//!
+//! Everything reachable from this module is covered by semantic versioning:
+//! while the crate is at 0.x, breaking changes to it only ship in releases
+//! that bump the minor version. The other modules are the server's own
+//! plumbing and may change in any release, so embedders should import from
+//! here rather than from [`crate::db`] and friends directly.
+//!
+//! The engine doesn't depend on MCP: with `default-features = false` (which
+//! drops the `server` feature), the crate builds without rmcp, leaving the
+//! storage and policies to be exposed through an MCP server of your own or
+//! any other interface.
+//!
+//! ```no_run
+//! use mailbox_mcp::core::{ContextStore, Database, MessageFilter, MessageStore, NewMessage};
+//!
+//! # fn example() -> Result<(), mailbox_mcp::core::DbError> {
+//! let db = Database::open(std::path::Path::new("mailbox.db"))?;
+//! db.set(Some("owner/repo"), "build", "green")?;
+//! db.send(&NewMessage {
+//!     project_id: "owner/repo",
+//!     to_agent: "reviewer",
+//!     from_agent: "author",
+//!     content: "PR ready",
+//!     ..NewMessage::default()
+//! })?;
+//! let messages = db.receive("owner/repo", "reviewer", &MessageFilter::default(), None)?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::db::{
+    Database, DbError, DbHealth, DbResult, DeliveryMode, Message, MessageFilter, NewMessage,
+    Priority, QueueSettings,
+};
+pub use crate::rate_limit::{RateLimit, SendRateLimiter};
+pub use crate::time::TimestampFormat;
+pub use crate::transform::{Pipeline, Transform, TransformConfig};
+
+/// Per-agent message queues.
+pub trait MessageStore {
+    /// Validates and queues a message, returning its ID.
+    fn send(&self, message: &NewMessage<'_>) -> DbResult<String>;
+
+    /// Consumes up to `limit` messages matching the filter (default 100, max
+    /// 500), in delivery order, following the queue's delivery mode.
+    fn receive(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        filter: &MessageFilter<'_>,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<Message>>;
+
+    /// Returns up to `limit` messages matching the filter without consuming them.
+    fn peek(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        filter: &MessageFilter<'_>,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<Message>>;
+
+    /// Acknowledges a message received with at-least-once delivery, returning
+    /// whether it was awaiting acknowledgment.
+    fn ack(&self, message_id: &str) -> DbResult<bool>;
+}
+
+/// Global (`project_id` of `None`) and project-scoped key-value context.
+pub trait ContextStore {
+    /// Returns the value stored under a key.
+    fn get(&self, project_id: Option<&str>, key: &str) -> DbResult<Option<String>>;
+
+    /// Stores a value under a key, replacing any previous value.
+    fn set(&self, project_id: Option<&str>, key: &str, value: &str) -> DbResult<()>;
+
+    /// Deletes a key, returning whether it existed.
+    fn delete(&self, project_id: Option<&str>, key: &str) -> DbResult<bool>;
+
+    /// Lists the keys in a scope, in order.
+    fn keys(&self, project_id: Option<&str>) -> DbResult<Vec<String>>;
+}
+
+/// A complete mailbox backend: queues and context.
+pub trait Storage: MessageStore + ContextStore {}
+
+impl<T: MessageStore + ContextStore> Storage for T {}
+
+impl MessageStore for Database {
+    fn send(&self, message: &NewMessage<'_>) -> DbResult<String> {
+        Database::send(self, message)
+    }
+
+    fn receive(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        filter: &MessageFilter<'_>,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<Message>> {
+        self.receive_filtered(project_id, agent_id, filter, limit, None)
+    }
+
+    fn peek(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        filter: &MessageFilter<'_>,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<Message>> {
+        self.peek_filtered(project_id, agent_id, filter, limit)
+    }
+
+    fn ack(&self, message_id: &str) -> DbResult<bool> {
+        self.ack_message(message_id, None)
+    }
+}
+
+impl ContextStore for Database {
+    fn get(&self, project_id: Option<&str>, key: &str) -> DbResult<Option<String>> {
+        self.context_get(project_id, key)
+    }
+
+    fn set(&self, project_id: Option<&str>, key: &str, value: &str) -> DbResult<()> {
+        self.context_set(project_id, key, value, false, None, None, false)
+    }
+
+    fn delete(&self, project_id: Option<&str>, key: &str) -> DbResult<bool> {
+        self.context_delete(project_id, key, None)
+    }
+
+    fn keys(&self, project_id: Option<&str>) -> DbResult<Vec<String>> {
+        self.context_list(project_id)
+    }
+}
@@ -0,0 +1,154 @@
+//! Cron expressions for recurring messages.
+//!
+//! Supports the standard five fields (`minute hour day-of-month month
+//! day-of-week`, evaluated in UTC) with `*`, lists (`1,15`), ranges (`9-17`)
+//! and steps (`*/15`, `0-30/10`), plus the `@hourly`, `@daily`, `@weekly` and
+//! `@monthly` shorthands. Day-of-week 0 and 7 are both Sunday. As in classic
+//! cron, when both day fields are restricted a day matching either one fires.
+
+use crate::time::UtcDateTime;
+use std::str::FromStr;
+
+/// How far ahead [`CronSchedule::next_after`] looks before giving up (e.g. on
+/// `0 0 31 2 *`, which never fires).
+const MAX_LOOKAHEAD_SECS: i64 = 5 * 366 * 86_400;
+
+/// A parsed cron expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CronSchedule {
+    /// One bit per allowed value of each field.
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    /// Whether the day fields were given as `*`.
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl CronSchedule {
+    /// Returns the first time strictly after `after` (Unix seconds) at which
+    /// the schedule fires, or `None` if it doesn't fire in the next five years.
+    #[must_use]
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        // Start at the beginning of the next minute
+        let mut t = after.div_euclid(60) * 60 + 60;
+        let limit = after + MAX_LOOKAHEAD_SECS;
+
+        while t <= limit {
+            let now = UtcDateTime::from_unix_seconds(t);
+            if !has(self.months, now.month) {
+                let (year, month) = if now.month == 12 {
+                    (now.year + 1, 1)
+                } else {
+                    (now.year, now.month + 1)
+                };
+                t = UtcDateTime {
+                    year,
+                    month,
+                    day: 1,
+                    hour: 0,
+                    minute: 0,
+                    second: 0,
+                    weekday: 0,
+                }
+                .to_unix_seconds();
+            } else if !self.matches_day(&now) {
+                t = (t.div_euclid(86_400) + 1) * 86_400;
+            } else if !has(self.hours, now.hour) {
+                t = (t.div_euclid(3600) + 1) * 3600;
+            } else if !has(self.minutes, now.minute) {
+                t += 60;
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, date: &UtcDateTime) -> bool {
+        let day_of_month = has(self.days_of_month, date.day);
+        let day_of_week = has(self.days_of_week, date.weekday);
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        }
+    }
+}
+
+const fn has(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+/// Parses one field into a bitmask of allowed values within `min..=max`.
+fn parse_field(field: &str, name: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("Invalid {name} field '{field}' (allowed values: {min}-{max})");
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| invalid())?;
+                if step == 0 {
+                    return Err(invalid());
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                start.parse().map_err(|_| invalid())?,
+                end.parse().map_err(|_| invalid())?,
+            )
+        } else {
+            let value: u32 = range.parse().map_err(|_| invalid())?;
+            // "5/15" means "from 5 to the end, every 15"
+            (value, if step > 1 { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expr = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            expr => expr,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day_of_month, month, day_of_week] = fields[..] else {
+            return Err(format!(
+                "Invalid cron expression '{s}' (expected 5 fields: minute hour day-of-month month day-of-week)"
+            ));
+        };
+
+        let mut days_of_week = parse_field(day_of_week, "day-of-week", 0, 7)?;
+        // 7 is an alias for Sunday
+        if has(days_of_week, 7) {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minute, "minute", 0, 59)?,
+            hours: parse_field(hour, "hour", 0, 23)?,
+            days_of_month: parse_field(day_of_month, "day-of-month", 1, 31)?,
+            months: parse_field(month, "month", 1, 12)?,
+            days_of_week,
+            any_day_of_month: day_of_month == "*",
+            any_day_of_week: day_of_week == "*",
+        })
+    }
+}
@@ -2,9 +2,22 @@
 //!
 //! Provides SQLite-backed storage for context key-value pairs and message queues.
 
-use rusqlite::{params, Connection, Result as SqliteResult};
+use crate::cron::CronSchedule;
+use crate::schema;
+use crate::template;
+use crate::time;
+use rusqlite::types::Value;
+use rusqlite::{
+    params, params_from_iter, Connection, ErrorCode, OpenFlags, Result as SqliteResult,
+    Transaction, TransactionBehavior,
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 /// Maximum allowed size for message content (1MB = 1,048,576 bytes).
@@ -16,6 +29,124 @@ pub const MAX_CONTEXT_VALUE_SIZE: usize = 64 * 1024;
 /// Maximum number of messages to retrieve in a single query.
 pub const MAX_MESSAGE_LIMIT: u32 = 500;
 
+/// Default visibility timeout for at-least-once queues: 5 minutes.
+pub const DEFAULT_VISIBILITY_TIMEOUT_SECS: u32 = 300;
+
+/// Maximum visibility timeout: 12 hours.
+pub const MAX_VISIBILITY_TIMEOUT_SECS: u32 = 12 * 60 * 60;
+
+/// Redeliveries after which the visibility timeout stops doubling (it has
+/// reached [`MAX_VISIBILITY_TIMEOUT_SECS`] long before).
+const MAX_BACKOFF_DOUBLINGS: u32 = 16;
+
+/// Default number of deliveries of an unacknowledged message before it is
+/// moved to the dead-letter queue.
+pub const DEFAULT_MAX_DELIVERY_ATTEMPTS: u32 = 5;
+
+/// Maximum lifetime of an unread message: 90 days.
+pub const MAX_MESSAGE_TTL_SECS: u64 = 90 * 24 * 60 * 60;
+
+/// Maximum lifetime of an ephemeral project: 90 days.
+pub const MAX_PROJECT_TTL_SECS: u64 = 90 * 24 * 60 * 60;
+
+/// Maximum window (in minutes) for queue history queries: 7 days.
+pub const MAX_HISTORY_WINDOW_MINUTES: u32 = 7 * 24 * 60;
+
+/// Maximum watchdog interval: 7 days.
+pub const MAX_WATCHDOG_INTERVAL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Maximum size of a message's serialized metadata (4KB = 4,096 bytes).
+pub const MAX_METADATA_SIZE: usize = 4 * 1024;
+
+/// Maximum size of a registered message schema (64KB = 65,536 bytes).
+pub const MAX_SCHEMA_SIZE: usize = 64 * 1024;
+
+/// Maximum size of a registered message template (64KB = 65,536 bytes).
+pub const MAX_TEMPLATE_SIZE: usize = 64 * 1024;
+
+/// Maximum size of an attachment (10MB = 10,485,760 bytes).
+pub const MAX_ATTACHMENT_SIZE: usize = 10 * 1024 * 1024;
+
+/// Maximum number of attachments referenced by a single message.
+pub const MAX_MESSAGE_ATTACHMENTS: usize = 16;
+
+/// Maximum number of tags on a single message.
+pub const MAX_MESSAGE_TAGS: usize = 8;
+
+/// Maximum size of a message tag in bytes.
+pub const MAX_TAG_SIZE: usize = 64;
+
+/// Maximum number of recipients of a single multi-recipient send.
+pub const MAX_MESSAGE_RECIPIENTS: usize = 100;
+
+/// How long attachments no stored message references are kept, in hours, so
+/// senders can upload before sending.
+pub const UNREFERENCED_ATTACHMENT_RETENTION_HOURS: u32 = 24;
+
+/// Maximum total size of a chunked message upload in bytes (10 MB).
+pub const MAX_UPLOAD_SIZE: usize = MAX_ATTACHMENT_SIZE;
+
+/// How long uncommitted chunked uploads are kept, in hours.
+pub const UPLOAD_RETENTION_HOURS: u32 = 24;
+
+/// Versions of each context key kept in its history by maintenance.
+pub const CONTEXT_HISTORY_VERSIONS: u32 = 50;
+
+/// Maximum size of a message annotation in bytes.
+pub const MAX_ANNOTATION_SIZE: usize = 64;
+
+/// How long message annotations are kept, in days.
+pub const ANNOTATION_RETENTION_DAYS: u32 = 7;
+
+/// How long the delivery status of a message is kept after it was sent, in
+/// days, once the message has left its queue.
+pub const DELIVERY_STATUS_RETENTION_DAYS: u32 = 7;
+
+/// How long entries of the project event feed are kept, in days.
+pub const EVENT_RETENTION_DAYS: u32 = 7;
+
+/// Default time a rotated API token stays valid alongside its replacement, in seconds.
+pub const DEFAULT_TOKEN_OVERLAP_SECS: u64 = 3600;
+
+/// Maximum lifetime of an API token, and of a rotation overlap, in seconds (1 year).
+pub const MAX_TOKEN_TTL_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// Prefix of API token secrets, telling them apart from externally issued tokens.
+pub const API_TOKEN_PREFIX: &str = "mbx_";
+
+/// Agent name used for messages generated by the server itself.
+pub const SYSTEM_AGENT: &str = "system";
+
+/// Project used for server-generated operational alerts.
+pub const SYSTEM_PROJECT: &str = "_system";
+
+/// Agent whose queue receives server-generated operational alerts.
+pub const OPERATOR_AGENT: &str = "operator";
+
+/// Number of consecutive `SQLITE_BUSY`/`SQLITE_LOCKED` failures after which the
+/// database is considered persistently locked.
+const MAX_CONSECUTIVE_BUSY: u32 = 3;
+
+/// Longest lease a writer can take on a context key (1 day).
+pub const MAX_CONTEXT_LEASE_SECS: u64 = 86_400;
+
+/// Maximum number of entries kept in an appended context list.
+pub const MAX_CONTEXT_LIST_ENTRIES: u32 = 1000;
+
+/// Maximum keys read or written by a single [`Database::context_get_many`] or
+/// [`Database::context_set_many`].
+pub const MAX_CONTEXT_BATCH_KEYS: usize = 100;
+
+/// Maximum lifetime of a context value set with a TTL: 90 days.
+pub const MAX_CONTEXT_TTL_SECS: u64 = 90 * 24 * 60 * 60;
+
+/// SQL condition matching context values that haven't expired.
+const LIVE_CONTEXT: &str =
+    "(expires_at IS NULL OR expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))";
+
+/// How long SQLite retries internally before reporting `SQLITE_BUSY`.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Errors that can occur during database operations.
 #[derive(Error, Debug)]
 pub enum DbError {
@@ -27,6 +158,25 @@ pub enum DbError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
+    /// The database was upgraded by a newer server, whose schema this one doesn't know.
+    #[error(
+        "Database schema version {found} is newer than this server supports ({supported}); \
+         it requires mailbox-mcp {required} or later. Upgrade mailbox-mcp, or restore \
+         the backup taken before the upgrade (mailbox.db.v{supported}.bak, if present)"
+    )]
+    SchemaTooNew {
+        found: u32,
+        supported: u32,
+        required: String,
+    },
+
+    /// The database hasn't been migrated to this server's schema yet.
+    #[error(
+        "Database schema version {found} is older than this server's ({expected}); \
+         start the server once to migrate it"
+    )]
+    SchemaOutdated { found: u32, expected: u32 },
+
     /// Content exceeds maximum allowed size.
     #[error("Content too large: {size} bytes exceeds limit of {limit} bytes")]
     ContentTooLarge { size: usize, limit: usize },
@@ -38,6 +188,135 @@ pub enum DbError {
     /// Invalid message ID format.
     #[error("Invalid message ID: '{id}' (must be a numeric ID)")]
     InvalidMessageId { id: String },
+
+    /// A recurring schedule's cron expression is invalid or never fires.
+    #[error("{reason}")]
+    InvalidSchedule { reason: String },
+
+    /// A message link would point from a message to itself.
+    #[error("Message {id} cannot be linked to itself")]
+    SelfLink { id: String },
+
+    /// A message schema is not a valid JSON Schema.
+    #[error("Invalid JSON Schema: {reason}")]
+    InvalidSchema { reason: String },
+
+    /// A message carries more tags than allowed.
+    #[error("Too many tags: {count} exceeds limit of {limit}")]
+    TooManyTags { count: usize, limit: usize },
+
+    /// A message references more attachments than allowed.
+    #[error("Too many attachments: {count} exceeds limit of {limit}")]
+    TooManyAttachments { count: usize, limit: usize },
+
+    /// A bulk context read asks for more keys than allowed.
+    #[error("Too many keys: {count} exceeds limit of {limit}")]
+    TooManyKeys { count: usize, limit: usize },
+
+    /// A message is addressed to more recipients than allowed.
+    #[error("Too many recipients: {count} exceeds limit of {limit}")]
+    TooManyRecipients { count: usize, limit: usize },
+
+    /// The recipient's queue is at its configured quota.
+    #[error(
+        "Queue of '{agent_id}' in project '{project_id}' is full ({depth} messages, {bytes} bytes pending); retry once the recipient drains it"
+    )]
+    QueueFull {
+        project_id: String,
+        agent_id: String,
+        depth: u64,
+        bytes: u64,
+    },
+
+    /// A message references an attachment that isn't stored in its project.
+    #[error("Attachment '{hash}' does not exist in project '{project_id}'")]
+    UnknownAttachment { project_id: String, hash: String },
+
+    /// A message names a schema that isn't registered in its project.
+    #[error("Schema '{name}' is not registered in project '{project_id}'")]
+    UnknownSchema { project_id: String, name: String },
+
+    /// A send names a template that isn't registered in its project.
+    #[error("Template '{name}' is not registered in project '{project_id}'")]
+    UnknownTemplate { project_id: String, name: String },
+
+    /// A template placeholder was given no value.
+    #[error("Template variable '{name}' has no value")]
+    MissingTemplateVariable { name: String },
+
+    /// Message content doesn't match the schema named by the sender.
+    #[error("Content does not match schema '{name}': {reason}")]
+    SchemaViolation { name: String, reason: String },
+
+    /// A full-text search query is malformed.
+    #[error("Invalid search query: {reason}")]
+    InvalidSearchQuery { reason: String },
+
+    /// An operation needs consumed messages, but archiving is disabled.
+    #[error("Message archive is disabled (start the server with --archive-days)")]
+    ArchiveDisabled,
+
+    /// No chunked upload has the given ID (it was committed, or expired).
+    #[error("Upload '{upload_id}' does not exist")]
+    UnknownUpload { upload_id: String },
+
+    /// No API token has the given ID.
+    #[error("API token '{token_id}' does not exist")]
+    UnknownToken { token_id: String },
+
+    /// A context key appended to holds something other than a list.
+    #[error("Context key '{key}' does not hold a list (a JSON array of strings)")]
+    NotAList { key: String },
+
+    /// A context key is leased to another writer.
+    #[error("Context key '{key}' is leased by '{owner}' until {expires_at}")]
+    Conflict {
+        key: String,
+        owner: String,
+        expires_at: String,
+    },
+
+    /// No agent group has the given ID in the project.
+    #[error("Agent group '{group_id}' does not exist in project '{project_id}'")]
+    UnknownGroup {
+        project_id: String,
+        group_id: String,
+    },
+
+    /// A message was sent to an agent group without members.
+    #[error("Agent group '{group_id}' has no members")]
+    EmptyGroup { group_id: String },
+
+    /// The target project already holds data.
+    #[error("Project '{project_id}' is not empty")]
+    ProjectNotEmpty { project_id: String },
+
+    /// The database is in degraded mode and cannot accept writes.
+    #[error("Database degraded: {reason}. Writes are unavailable; reads are served from the last good snapshot")]
+    Degraded { reason: String },
+}
+
+impl DbError {
+    /// Returns `true` if the error means the database file can't be opened
+    /// or written at all (no permission, a read-only filesystem, a missing
+    /// directory), rather than that the database is busy, corrupted or
+    /// failed to migrate.
+    #[must_use]
+    pub fn is_inaccessible(&self) -> bool {
+        match self {
+            Self::Io(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::PermissionDenied
+                    | std::io::ErrorKind::ReadOnlyFilesystem
+                    | std::io::ErrorKind::NotFound
+            ),
+            Self::Sqlite(e) => matches!(
+                e.sqlite_error_code(),
+                Some(ErrorCode::CannotOpen | ErrorCode::ReadOnly | ErrorCode::PermissionDenied)
+            ),
+            _ => false,
+        }
+    }
 }
 
 /// Result type for database operations.
@@ -52,335 +331,7823 @@ pub struct Message {
     pub from_agent: String,
     /// Optional reference to a previous message (for request/response linking).
     pub reference_id: Option<String>,
+    /// Queue replies should go to, if not the sender's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
     /// Message content.
     pub content: String,
     /// Timestamp when the message was created (ISO 8601 format: `2025-01-08T12:00:00Z`).
     pub created_at: String,
+    /// Correlation ID shared by all messages resulting from a single user request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// Delivery priority; omitted when normal.
+    #[serde(default, skip_serializing_if = "Priority::is_normal")]
+    pub priority: Priority,
+    /// Arbitrary JSON object attached by the sender (routing hints, model names, ...).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Hashes of attachments stored with [`Database::store_attachment`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<String>,
+    /// Whether the message is classified confidential, excluding it from exports.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub confidential: bool,
+    /// Which delivery of the message this is (1 for the first); set by receives.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempt: Option<u32>,
+    /// When the message will be delivered again unless acknowledged; set by
+    /// receives that keep it in flight. Backs off exponentially with each attempt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_retry_at: Option<String>,
+    /// Position in the recipient's queue: one more than the previous message
+    /// sent to the queue, never reused. Absent on messages consumed before
+    /// sequence numbers were introduced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// Routing tags attached by the sender.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Whether the message is pinned, keeping it out of receives.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
 }
 
-/// Thread-safe database handle.
-///
-/// All operations are serialized through an internal mutex. This is appropriate
-/// for local-only use with low concurrency.
-#[derive(Clone)]
-pub struct Database {
-    conn: Arc<Mutex<Connection>>,
+/// Columns selected by message queries, in the order expected by [`Message::from_row`].
+const MESSAGE_COLUMNS: &str =
+    "id, from_agent, reference_id, content, created_at, trace_id, priority, metadata, attachments, \
+     confidential, seq, tags, reply_to, pinned";
+
+/// SQL expression rendering the value of a `json_each` row as JSON text, the
+/// form in which metadata values are indexed. `json_each` yields booleans as
+/// 1/0, so they are spelled out.
+const JSON_EACH_VALUE: &str =
+    "CASE type WHEN 'true' THEN 'true' WHEN 'false' THEN 'false' ELSE json_quote(value) END";
+
+/// Order in which queued messages are delivered: by priority, then FIFO.
+const DELIVERY_ORDER: &str = "priority DESC, id ASC";
+
+/// SQL condition matching messages that can be delivered: not awaiting
+/// acknowledgment (or whose visibility timeout has expired), not snoozed, and
+/// not expired.
+const VISIBLE: &str =
+    "(visible_after IS NULL OR visible_after <= strftime('%Y-%m-%dT%H:%M:%SZ', 'now')) \
+     AND (snoozed_until IS NULL OR snoozed_until <= strftime('%Y-%m-%dT%H:%M:%SZ', 'now')) \
+     AND (expires_at IS NULL OR expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))";
+
+impl Message {
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        Ok(Self {
+            id: row.get::<_, i64>(0)?.to_string(),
+            from_agent: row.get(1)?,
+            reference_id: row.get(2)?,
+            content: row.get(3)?,
+            created_at: row.get(4)?,
+            trace_id: row.get(5)?,
+            priority: Priority::from_level(row.get(6)?),
+            // Only JSON objects are stored
+            metadata: row
+                .get::<_, Option<String>>(7)?
+                .and_then(|metadata| serde_json::from_str(&metadata).ok()),
+            attachments: row
+                .get::<_, Option<String>>(8)?
+                .and_then(|attachments| serde_json::from_str(&attachments).ok())
+                .unwrap_or_default(),
+            confidential: row.get(9)?,
+            attempt: None,
+            next_retry_at: None,
+            seq: row.get(10)?,
+            tags: row
+                .get::<_, Option<String>>(11)?
+                .and_then(|tags| serde_json::from_str(&tags).ok())
+                .unwrap_or_default(),
+            reply_to: row.get(12)?,
+            pinned: row.get(13)?,
+        })
+    }
 }
 
-#[allow(clippy::missing_errors_doc)]
-impl Database {
-    /// Creates a new database connection using the platform-specific default path.
-    ///
-    /// The database file is stored in the user's application data directory:
-    /// - Linux: `~/.local/share/mailbox-mcp/mailbox.db`
-    /// - macOS: `~/Library/Application Support/mailbox-mcp/mailbox.db`
-    /// - Windows: `%APPDATA%\mailbox-mcp\mailbox.db`
-    pub fn new() -> DbResult<Self> {
-        let path = Self::default_path()?;
-        Self::open(&path)
+/// Delivery priority of a message. Higher priorities are received first.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    /// Returns the level stored in the database.
+    #[must_use]
+    pub const fn level(self) -> i64 {
+        match self {
+            Self::Low => 0,
+            Self::Normal => 1,
+            Self::High => 2,
+            Self::Urgent => 3,
+        }
     }
 
-    /// Opens a database at the specified path.
-    ///
-    /// Creates the parent directory if it doesn't exist.
-    /// Runs migrations to ensure the schema is up to date.
-    pub fn open(path: &Path) -> DbResult<Self> {
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
+    /// Maps a stored level back to a priority, clamping unknown levels.
+    #[must_use]
+    pub const fn from_level(level: i64) -> Self {
+        match level {
+            i64::MIN..=0 => Self::Low,
+            1 => Self::Normal,
+            2 => Self::High,
+            _ => Self::Urgent,
         }
+    }
 
-        let conn = Connection::open(path)?;
-        let db = Self {
-            conn: Arc::new(Mutex::new(conn)),
-        };
-        db.migrate()?;
-        Ok(db)
+    /// Returns `true` for the default priority.
+    #[must_use]
+    pub fn is_normal(&self) -> bool {
+        *self == Self::Normal
     }
+}
 
-    fn default_path() -> DbResult<PathBuf> {
-        let home = std::env::var("HOME")
-            .or_else(|_| std::env::var("USERPROFILE"))
-            .map_err(|_| {
-                std::io::Error::new(std::io::ErrorKind::NotFound, "No home directory found")
-            })?;
+impl std::str::FromStr for Priority {
+    type Err = String;
 
-        #[cfg(target_os = "macos")]
-        let path = PathBuf::from(home).join("Library/Application Support/mailbox-mcp/mailbox.db");
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            "urgent" => Ok(Self::Urgent),
+            _ => Err(format!(
+                "Invalid priority '{s}' (expected low, normal, high, or urgent)"
+            )),
+        }
+    }
+}
 
-        #[cfg(target_os = "windows")]
-        let path = std::env::var("APPDATA")
-            .map(PathBuf::from)
-            .unwrap_or_else(|_| PathBuf::from(&home))
-            .join("mailbox-mcp/mailbox.db");
+/// Delivery semantics of a queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMode {
+    /// Messages are deleted as they are received (the default).
+    #[default]
+    AtMostOnce,
+    /// Received messages stay in the queue, hidden for the visibility timeout,
+    /// until acknowledged with [`Database::ack_message`]. Unacknowledged
+    /// messages are delivered again once the timeout expires.
+    AtLeastOnce,
+}
 
-        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
-        let path = PathBuf::from(home).join(".local/share/mailbox-mcp/mailbox.db");
+impl DeliveryMode {
+    /// Returns the mode name as stored in the database.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::AtMostOnce => "at_most_once",
+            Self::AtLeastOnce => "at_least_once",
+        }
+    }
+}
 
-        Ok(path)
+impl std::str::FromStr for DeliveryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "at_most_once" => Ok(Self::AtMostOnce),
+            "at_least_once" => Ok(Self::AtLeastOnce),
+            _ => Err(format!(
+                "Invalid delivery mode '{s}' (expected at_most_once or at_least_once)"
+            )),
+        }
     }
+}
 
-    fn migrate(&self) -> DbResult<()> {
-        self.with_conn(|conn| {
-            conn.execute_batch(
-                r"
-                -- Unified context table (project_id NULL = global)
-                CREATE TABLE IF NOT EXISTS context (
-                    project_id TEXT,
-                    key TEXT NOT NULL,
-                    value TEXT NOT NULL,
-                    PRIMARY KEY (project_id, key)
-                );
+/// Type of a link between two messages, read as "source *kind* target".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    /// The source message repeats the target.
+    Duplicates,
+    /// The target can't proceed until the source is done.
+    Blocks,
+    /// The source message continues work started by the target.
+    FollowsFrom,
+}
 
-                -- Message queue
-                CREATE TABLE IF NOT EXISTS messages (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    project_id TEXT NOT NULL,
-                    to_agent TEXT NOT NULL,
-                    from_agent TEXT NOT NULL,
-                    reference_id TEXT,
-                    content TEXT NOT NULL,
-                    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
-                );
+impl LinkKind {
+    /// Returns the kind name as stored in the database.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Duplicates => "duplicates",
+            Self::Blocks => "blocks",
+            Self::FollowsFrom => "follows_from",
+        }
+    }
 
-                CREATE INDEX IF NOT EXISTS idx_messages_queue
-                    ON messages(project_id, to_agent, created_at);
-                ",
-            )?;
-            Ok(())
-        })
+    /// Returns the name of the relation as seen from the target message.
+    #[must_use]
+    pub const fn inverse_str(self) -> &'static str {
+        match self {
+            Self::Duplicates => "duplicated_by",
+            Self::Blocks => "blocked_by",
+            Self::FollowsFrom => "followed_by",
+        }
     }
+}
 
-    fn with_conn<F, T>(&self, f: F) -> DbResult<T>
-    where
-        F: FnOnce(&Connection) -> SqliteResult<T>,
-    {
-        let conn = self
-            .conn
-            .lock()
-            .expect("Database mutex poisoned - this indicates a bug");
-        f(&conn).map_err(DbError::from)
+impl std::str::FromStr for LinkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "duplicates" => Ok(Self::Duplicates),
+            "blocks" => Ok(Self::Blocks),
+            "follows_from" => Ok(Self::FollowsFrom),
+            _ => Err(format!(
+                "Invalid link kind '{s}' (expected duplicates, blocks, or follows_from)"
+            )),
+        }
     }
+}
 
-    // -------------------------------------------------------------------------
-    // Context operations
-    // -------------------------------------------------------------------------
+/// How messages sent to an agent group are spread across its members.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupStrategy {
+    /// Each member in turn, in agent ID order.
+    #[default]
+    RoundRobin,
+    /// The member with the fewest queued messages (pending or in flight).
+    LeastLoaded,
+}
 
-    /// Sets a context value.
-    ///
-    /// If `project_id` is `None`, sets a global context value.
-    /// If `project_id` is `Some`, sets a project-scoped context value.
-    ///
-    /// # Errors
-    /// - `EmptyField` if key is empty
-    /// - `ContentTooLarge` if value exceeds 65,536 bytes
-    pub fn context_set(&self, project_id: Option<&str>, key: &str, value: &str) -> DbResult<()> {
-        let key = key.trim();
-        if key.is_empty() {
-            return Err(DbError::EmptyField { field: "key" });
+impl GroupStrategy {
+    /// Returns the strategy name as stored in the database.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::RoundRobin => "round_robin",
+            Self::LeastLoaded => "least_loaded",
         }
-        if value.len() > MAX_CONTEXT_VALUE_SIZE {
-            return Err(DbError::ContentTooLarge {
-                size: value.len(),
-                limit: MAX_CONTEXT_VALUE_SIZE,
-            });
+    }
+}
+
+impl std::str::FromStr for GroupStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(Self::RoundRobin),
+            "least_loaded" => Ok(Self::LeastLoaded),
+            _ => Err(format!(
+                "Invalid group strategy '{s}' (expected round_robin or least_loaded)"
+            )),
         }
+    }
+}
 
-        self.with_conn(|conn| {
-            conn.execute(
-                r"INSERT INTO context (project_id, key, value)
-                  VALUES (?1, ?2, ?3)
-                  ON CONFLICT(project_id, key) DO UPDATE SET value = ?3",
-                params![project_id, key, value],
-            )?;
-            Ok(())
-        })
+/// Per-queue delivery settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct QueueSettings {
+    pub delivery_mode: DeliveryMode,
+    /// How long received messages stay hidden before redelivery (at-least-once only).
+    pub visibility_timeout_secs: u32,
+    /// Deliveries of an unacknowledged message before it is dead-lettered
+    /// (at-least-once only).
+    pub max_delivery_attempts: u32,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            delivery_mode: DeliveryMode::AtMostOnce,
+            visibility_timeout_secs: DEFAULT_VISIBILITY_TIMEOUT_SECS,
+            max_delivery_attempts: DEFAULT_MAX_DELIVERY_ATTEMPTS,
+        }
     }
+}
 
-    /// Gets a context value.
+/// A message to be sent, as accepted by [`Database::send`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NewMessage<'a> {
+    pub project_id: &'a str,
+    pub to_agent: &'a str,
+    pub from_agent: &'a str,
+    pub content: &'a str,
+    /// Reference to a previous message (for request/response linking).
+    pub reference_id: Option<&'a str>,
+    /// Queue replies should go to instead of the sender's.
+    pub reply_to: Option<&'a str>,
+    /// Correlation ID. If omitted on a reply, it is inherited from the referenced message.
+    pub trace_id: Option<&'a str>,
+    pub priority: Priority,
+    /// Seconds after which the message is dead-lettered if still unread.
+    pub ttl_secs: Option<u64>,
+    /// Arbitrary JSON object attached to the message.
+    pub metadata: Option<&'a serde_json::Map<String, serde_json::Value>>,
+    /// Registered schema the content must be a JSON document valid against.
+    pub schema: Option<&'a str>,
+    /// Hashes of attachments stored in the project.
+    pub attachments: Option<&'a [String]>,
+    /// Classify the message confidential, excluding it from exports.
+    pub confidential: bool,
+    /// Routing tags, for receivers to filter on.
+    pub tags: Option<&'a [String]>,
+    /// Have a receipt sent back to the sender when the message is first received.
+    pub request_receipt: bool,
+}
+
+/// Storage usage report produced by [`Database::storage_analyze`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct StorageReport {
+    /// On-disk size of each table and index, largest first.
+    pub tables: Vec<TableSize>,
+    /// Largest pending messages by content size.
+    pub largest_messages: Vec<LargeItem>,
+    /// Largest context values by size.
+    pub largest_context_values: Vec<LargeItem>,
+    /// Total bytes of message content (the logical payload).
+    pub message_payload_bytes: u64,
+    /// Ratio of on-disk bytes of the `messages` table to its logical payload.
     ///
-    /// Returns `Ok(Some(value))` if the key exists, `Ok(None)` if it doesn't.
-    pub fn context_get(&self, project_id: Option<&str>, key: &str) -> DbResult<Option<String>> {
-        self.with_conn(|conn| {
-            let mut stmt =
-                conn.prepare("SELECT value FROM context WHERE project_id IS ?1 AND key = ?2")?;
-            let result = stmt.query_row(params![project_id, key], |row| row.get(0));
-            match result {
-                Ok(value) => Ok(Some(value)),
-                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-                Err(e) => Err(e),
-            }
+    /// Values well above 1.0 indicate per-row overhead dominates; compression
+    /// only helps when payloads are large relative to this overhead.
+    pub message_storage_ratio: Option<f64>,
+    /// Page-level fragmentation statistics.
+    pub fragmentation: Fragmentation,
+}
+
+/// On-disk size of a single table or index.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct TableSize {
+    pub name: String,
+    pub pages: u64,
+    pub bytes: u64,
+    /// Bytes allocated to pages but not holding data.
+    pub unused_bytes: u64,
+}
+
+/// A single large message or context value.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LargeItem {
+    /// Message ID or context key.
+    pub id: String,
+    pub project_id: Option<String>,
+    pub bytes: u64,
+}
+
+/// Page-level fragmentation of the database file.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Fragmentation {
+    pub page_size: u64,
+    pub page_count: u64,
+    /// Pages on the freelist, reclaimable by `VACUUM`.
+    pub freelist_count: u64,
+    /// Fraction of the file made up of free pages (0.0 - 1.0).
+    pub free_ratio: f64,
+}
+
+/// Number of entries reported in the "largest items" lists.
+const LARGEST_ITEMS_LIMIT: u32 = 10;
+
+/// Lifetime of an ephemeral project, produced by [`Database::set_project_expiry`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ProjectExpiry {
+    pub project_id: String,
+    pub created_at: String,
+    /// Time after which all of the project's data is purged.
+    pub expires_at: String,
+}
+
+/// What [`Database::clone_project`] copies from the source project.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CloneOptions {
+    /// Copy project-scoped context keys.
+    pub context: bool,
+    /// Copy per-agent queue settings (delivery modes).
+    pub agents: bool,
+    /// Also copy context keys classified confidential (admin override).
+    pub confidential: bool,
+}
+
+/// An immutable copy of a project's context, taken by [`Database::context_snapshot`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ContextSnapshot {
+    pub snapshot_id: i64,
+    pub project_id: String,
+    /// Optional label, e.g. the phase the snapshot precedes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Number of context keys in the snapshot.
+    pub keys: u64,
+    pub created_at: String,
+}
+
+impl ContextSnapshot {
+    /// Columns selected by snapshot queries, in the order expected by [`ContextSnapshot::from_row`].
+    const COLUMNS: &'static str = "id, project_id, name, key_count, created_at";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        Ok(Self {
+            snapshot_id: row.get(0)?,
+            project_id: row.get(1)?,
+            name: row.get(2)?,
+            keys: row.get(3)?,
+            created_at: row.get(4)?,
         })
     }
+}
 
-    /// Deletes a context value.
-    ///
-    /// Returns `true` if a value was deleted, `false` if the key didn't exist.
-    pub fn context_delete(&self, project_id: Option<&str>, key: &str) -> DbResult<bool> {
-        self.with_conn(|conn| {
-            let rows = conn.execute(
-                "DELETE FROM context WHERE project_id IS ?1 AND key = ?2",
-                params![project_id, key],
-            )?;
-            Ok(rows > 0)
+/// A binary attachment stored by [`Database::store_attachment`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Attachment {
+    /// Hex-encoded SHA-256 of the data, used to reference it from messages.
+    pub hash: String,
+    pub project_id: String,
+    /// MIME type given by the uploader.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Size of the data in bytes.
+    pub size: u64,
+    pub created_at: String,
+}
+
+impl Attachment {
+    /// Columns selected by attachment queries, in the order expected by [`Attachment::from_row`].
+    const COLUMNS: &'static str = "hash, project_id, content_type, size, created_at";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        Ok(Self {
+            hash: row.get(0)?,
+            project_id: row.get(1)?,
+            content_type: row.get(2)?,
+            size: row.get(3)?,
+            created_at: row.get(4)?,
         })
     }
+}
 
-    /// Lists all context keys.
-    ///
-    /// If `project_id` is `None`, lists global context keys.
-    /// If `project_id` is `Some`, lists project-scoped context keys.
-    pub fn context_list(&self, project_id: Option<&str>) -> DbResult<Vec<String>> {
-        self.with_conn(|conn| {
-            let mut stmt =
-                conn.prepare("SELECT key FROM context WHERE project_id IS ?1 ORDER BY key")?;
-            let keys = stmt
-                .query_map(params![project_id], |row| row.get(0))?
-                .collect::<Result<Vec<String>, _>>()?;
-            Ok(keys)
+/// A JSON Schema registered by [`Database::register_message_schema`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MessageSchema {
+    pub project_id: String,
+    pub name: String,
+    pub schema: serde_json::Value,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl MessageSchema {
+    /// Columns selected by schema queries, in the order expected by [`MessageSchema::from_row`].
+    const COLUMNS: &'static str = "project_id, name, schema, created_at, updated_at";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        let schema: String = row.get(2)?;
+        Ok(Self {
+            project_id: row.get(0)?,
+            name: row.get(1)?,
+            // Only valid schemas are stored
+            schema: serde_json::from_str(&schema).unwrap_or_default(),
+            created_at: row.get(3)?,
+            updated_at: row.get(4)?,
         })
     }
+}
 
-    // -------------------------------------------------------------------------
-    // Message operations
-    // -------------------------------------------------------------------------
+/// A past or current value of a context key, listed by [`Database::context_history`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ContextVersion {
+    /// The value written, or `None` where the key was deleted.
+    pub value: Option<String>,
+    /// Who wrote (or deleted) it, if known: the writer identified to
+    /// [`Database::context_set`] and friends.
+    pub author: Option<String>,
+    pub changed_at: String,
+}
 
-    /// Sends a message to an agent's queue.
+/// Interest in changes to a context key, or to every key starting with a
+/// prefix, registered by [`Database::context_watch`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ContextWatch {
+    pub project_id: String,
+    /// Agent whose queue receives the change notices.
+    pub agent_id: String,
+    /// Watched key, or key prefix.
+    pub key: String,
+    /// Whether `key` is a prefix.
+    pub prefix: bool,
+    pub created_at: String,
+}
+
+impl ContextWatch {
+    /// Columns selected by watch queries, in the order expected by [`ContextWatch::from_row`].
+    const COLUMNS: &'static str = "project_id, agent_id, key, prefix, created_at";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        Ok(Self {
+            project_id: row.get(0)?,
+            agent_id: row.get(1)?,
+            key: row.get(2)?,
+            prefix: row.get(3)?,
+            created_at: row.get(4)?,
+        })
+    }
+}
+
+/// A message template registered by [`Database::register_message_template`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MessageTemplate {
+    pub project_id: String,
+    pub name: String,
+    pub template: String,
+    /// Names of the template's placeholders, in order of first use.
+    pub placeholders: Vec<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl MessageTemplate {
+    /// Columns selected by template queries, in the order expected by [`MessageTemplate::from_row`].
+    const COLUMNS: &'static str = "project_id, name, template, created_at, updated_at";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        let template: String = row.get(2)?;
+        Ok(Self {
+            project_id: row.get(0)?,
+            name: row.get(1)?,
+            placeholders: template::placeholders(&template),
+            template,
+            created_at: row.get(3)?,
+            updated_at: row.get(4)?,
+        })
+    }
+}
+
+/// An API token issued by [`Database::create_api_token`]. The secret itself
+/// is only returned on creation; only its hash is stored.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ApiToken {
+    pub token_id: String,
+    /// Optional label, e.g. the deployment using the token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Agent identity of callers using the token.
+    pub agent_id: String,
+    pub roles: Vec<String>,
+    /// Tools callers using the token may call; `None` allows all of them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<String>>,
+    pub created_at: String,
+    /// Time after which the token is rejected; `None` if it never expires.
+    pub expires_at: Option<String>,
+    /// Last time the token authenticated a request (updated at most once a minute).
+    pub last_used_at: Option<String>,
+    /// Token that replaced this one when it was rotated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaced_by: Option<String>,
+}
+
+impl ApiToken {
+    /// Columns selected by token queries, in the order expected by [`ApiToken::from_row`].
+    const COLUMNS: &'static str =
+        "id, name, agent_id, roles, created_at, expires_at, last_used_at, replaced_by, tools";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        let roles: String = row.get(3)?;
+        let tools: Option<String> = row.get(8)?;
+        Ok(Self {
+            token_id: row.get(0)?,
+            name: row.get(1)?,
+            agent_id: row.get(2)?,
+            roles: serde_json::from_str(&roles).unwrap_or_default(),
+            // An unreadable list allows nothing rather than everything
+            tools: tools.map(|tools| serde_json::from_str(&tools).unwrap_or_default()),
+            created_at: row.get(4)?,
+            expires_at: row.get(5)?,
+            last_used_at: row.get(6)?,
+            replaced_by: row.get(7)?,
+        })
+    }
+}
+
+/// Rows copied by [`Database::clone_project`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CloneSummary {
+    pub context_keys: usize,
+    pub agents: usize,
+}
+
+/// Summary of a purged ephemeral project, kept after its data is gone.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ExpiredProject {
+    pub project_id: String,
+    pub created_at: String,
+    pub expires_at: String,
+    pub purged_at: String,
+    /// Distinct agents that sent or received messages (within queue history retention).
+    pub agents: u64,
+    /// Messages sent in the project (within queue history retention).
+    pub messages_sent: u64,
+    /// Messages still queued when the project was purged.
+    pub pending_messages: u64,
+    pub context_keys: u64,
+    pub milestones: u64,
+}
+
+/// Where a message in a thread is currently stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageState {
+    /// Queued, or awaiting acknowledgment.
+    Pending,
+    /// Consumed and kept in the archive.
+    Archived,
+    /// In the project's dead-letter queue.
+    DeadLetter,
+}
+
+impl MessageState {
+    fn from_sql(value: &str) -> Self {
+        match value {
+            "archived" => Self::Archived,
+            "dead_letter" => Self::DeadLetter,
+            _ => Self::Pending,
+        }
+    }
+}
+
+/// How far a message has progressed towards its recipient.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Queued and not yet seen by the recipient.
+    Pending,
+    /// Queued, and seen by the recipient with a peek.
+    Peeked,
+    /// Delivered by a receive; with at-least-once delivery, possibly not yet
+    /// acknowledged.
+    Received,
+    /// Delivered and acknowledged.
+    Acknowledged,
+    /// Moved to the dead-letter queue.
+    DeadLettered,
+    /// Deleted before being received.
+    Deleted,
+    /// Not sent in the project, or sent more than
+    /// [`DELIVERY_STATUS_RETENTION_DAYS`] ago.
+    Unknown,
+}
+
+impl DeliveryStatus {
+    fn from_sql(value: Option<&str>) -> Self {
+        match value {
+            Some("pending") => Self::Pending,
+            Some("peeked") => Self::Peeked,
+            Some("received") => Self::Received,
+            Some("acknowledged") => Self::Acknowledged,
+            Some("dead_lettered") => Self::DeadLettered,
+            Some("deleted") => Self::Deleted,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Delivery status of a message, produced by [`Database::message_statuses`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct MessageStatus {
+    pub message_id: String,
+    pub status: DeliveryStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<String>,
+    /// When the recipient first saw the message with a peek.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peeked_at: Option<String>,
+    /// When the message was first delivered by a receive.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<String>,
+    /// Group queue member the message was last delivered to, if it named itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub received_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acknowledged_at: Option<String>,
+}
+
+/// A stored message with its recipient and state, produced by
+/// [`Database::thread`] and [`Database::search_messages`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ThreadMessage {
+    #[serde(flatten)]
+    pub message: Message,
+    /// Agent the message was addressed to.
+    pub to_agent: String,
+    pub state: MessageState,
+}
+
+/// A message moved to its project's dead-letter queue.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DeadLetter {
+    #[serde(flatten)]
+    pub message: Message,
+    /// Agent the message was addressed to.
+    pub to_agent: String,
+    /// Number of times the message was delivered without being acknowledged.
+    pub delivery_count: u32,
+    /// Why the message was dead-lettered: `max_delivery_attempts` or `expired`.
+    pub reason: String,
+    /// Timestamp when the message was dead-lettered (ISO 8601 format: `2025-01-08T12:00:00Z`).
+    pub dead_lettered_at: String,
+}
+
+impl DeadLetter {
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        Ok(Self {
+            message: Message::from_row(row)?,
+            to_agent: row.get(14)?,
+            delivery_count: row.get(15)?,
+            reason: row.get(16)?,
+            dead_lettered_at: row.get(17)?,
+        })
+    }
+}
+
+/// A message related to another one, produced by [`Database::related_messages`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RelatedMessage {
+    pub message_id: String,
+    /// Relation of the queried message to this one: a [`LinkKind`] name for
+    /// links from the queried message, or its inverse (e.g. `blocked_by`) for
+    /// links to it.
+    pub relation: String,
+    /// Timestamp when the link was created (ISO 8601 format: `2025-01-08T12:00:00Z`).
+    pub linked_at: String,
+}
+
+/// A recurring message, sent whenever its cron expression fires.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Schedule {
+    pub schedule_id: i64,
+    pub project_id: String,
+    pub to_agent: String,
+    pub from_agent: String,
+    pub content: String,
+    /// Cron expression, evaluated in UTC.
+    pub cron_expr: String,
+    /// Timestamp of the next send (ISO 8601 format: `2025-01-08T12:00:00Z`).
+    pub next_run_at: String,
+    pub created_at: String,
+}
+
+impl Schedule {
+    /// Columns selected by schedule queries, in the order expected by [`Schedule::from_row`].
+    const COLUMNS: &'static str =
+        "id, project_id, to_agent, from_agent, content, cron_expr, next_run_at, created_at";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        Ok(Self {
+            schedule_id: row.get(0)?,
+            project_id: row.get(1)?,
+            to_agent: row.get(2)?,
+            from_agent: row.get(3)?,
+            content: row.get(4)?,
+            cron_expr: row.get(5)?,
+            next_run_at: row.get(6)?,
+            created_at: row.get(7)?,
+        })
+    }
+}
+
+/// A watchdog that alerts a supervisor when an agent stops heartbeating and
+/// receiving messages.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Watchdog {
+    pub project_id: String,
+    /// Watched agent.
+    pub agent_id: String,
+    /// Agent alerted when the watched agent goes quiet.
+    pub supervisor: String,
+    pub interval_secs: u64,
+    /// Timestamp of the agent's last heartbeat or receive (ISO 8601 format: `2025-01-08T12:00:00Z`).
+    pub last_activity_at: String,
+    /// When the supervisor was alerted; cleared once the agent is active again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alerted_at: Option<String>,
+    pub created_at: String,
+}
+
+impl Watchdog {
+    /// Columns selected by watchdog queries, in the order expected by [`Watchdog::from_row`].
+    const COLUMNS: &'static str =
+        "project_id, agent_id, supervisor, interval_secs, last_activity_at, alerted_at, created_at";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        Ok(Self {
+            project_id: row.get(0)?,
+            agent_id: row.get(1)?,
+            supervisor: row.get(2)?,
+            interval_secs: row.get(3)?,
+            last_activity_at: row.get(4)?,
+            alerted_at: row.get(5)?,
+            created_at: row.get(6)?,
+        })
+    }
+}
+
+/// Supervisor alerts sent by [`Database::check_watchdogs`].
+#[derive(Debug, Clone, Default)]
+pub struct WatchdogCheck {
+    /// Watchdogs whose agent went quiet.
+    pub alerted: Vec<Watchdog>,
+    /// Watchdogs whose agent became active again after an alert.
+    pub recovered: Vec<Watchdog>,
+}
+
+/// A marker attached to a message by an agent (e.g. `ack`, `rejected`).
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Annotation {
+    pub message_id: String,
+    /// Agent that attached the annotation.
+    pub agent_id: String,
+    pub annotation: String,
+    /// Timestamp when the annotation was attached (ISO 8601 format: `2025-01-08T12:00:00Z`).
+    pub created_at: String,
+}
+
+impl Annotation {
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        Ok(Self {
+            message_id: row.get::<_, i64>(0)?.to_string(),
+            agent_id: row.get(1)?,
+            annotation: row.get(2)?,
+            created_at: row.get(3)?,
+        })
+    }
+}
+
+/// A named progress point reached in a project's workflow.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Milestone {
+    pub name: String,
+    /// Optional data attached when the milestone was set.
+    pub payload: Option<String>,
+    /// Timestamp when the milestone was (last) set (ISO 8601 format: `2025-01-08T12:00:00Z`).
+    pub reached_at: String,
+}
+
+impl ExpiredProject {
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        Ok(Self {
+            project_id: row.get(0)?,
+            created_at: row.get(1)?,
+            expires_at: row.get(2)?,
+            purged_at: row.get(3)?,
+            agents: row.get(4)?,
+            messages_sent: row.get(5)?,
+            pending_messages: row.get(6)?,
+            context_keys: row.get(7)?,
+            milestones: row.get(8)?,
+        })
+    }
+}
+
+impl Milestone {
+    fn from_row(row: &rusqlite::Row<'_>) -> SqliteResult<Self> {
+        Ok(Self {
+            name: row.get(0)?,
+            payload: row.get(1)?,
+            reached_at: row.get(2)?,
+        })
+    }
+}
+
+/// Optional criteria narrowing which queued messages are returned.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessageFilter<'a> {
+    /// Only messages replying to one of these message IDs.
+    pub reference_ids: Option<&'a [String]>,
+    /// Only messages with this trace ID.
+    pub trace_id: Option<&'a str>,
+    /// Only messages from this agent.
+    pub from_agent: Option<&'a str>,
+    /// Only messages created at or after this time (Unix seconds).
+    pub since: Option<i64>,
+    /// Only messages created before this time (Unix seconds).
+    pub before: Option<i64>,
+    /// Only messages whose content contains this substring (case-sensitive).
+    pub content_contains: Option<&'a str>,
+    /// Only messages whose metadata has all of these key/value pairs.
+    pub metadata: Option<&'a serde_json::Map<String, serde_json::Value>>,
+    /// Only messages after this position in delivery order.
+    pub after: Option<QueueCursor>,
+    /// Only messages with a greater sequence number.
+    pub after_seq: Option<u64>,
+    /// Only messages carrying all of these tags.
+    pub tags: Option<&'a [String]>,
+}
+
+/// A position in a queue's delivery order (priority, then FIFO), for paging
+/// through a queue. Formatted as an opaque string for clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueCursor {
+    priority: i64,
+    id: i64,
+}
+
+impl QueueCursor {
+    /// Returns the position just after a message, or `None` if its ID is not numeric.
+    #[must_use]
+    pub fn after(message: &Message) -> Option<Self> {
+        Some(Self {
+            priority: message.priority.level(),
+            id: message.id.parse().ok()?,
+        })
+    }
+}
+
+impl std::fmt::Display for QueueCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.priority, self.id)
+    }
+}
+
+impl std::str::FromStr for QueueCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid cursor '{s}' (use next_cursor from a previous page)");
+        let (priority, id) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            priority: priority.parse().map_err(|_| invalid())?,
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl MessageFilter<'_> {
+    /// Returns SQL conditions (each prefixed with `AND`) for this filter,
+    /// appending their bound values to `values`.
+    fn conditions(&self, values: &mut Vec<Value>) -> String {
+        let mut sql = String::new();
+        if let Some(reference_ids) = self.reference_ids {
+            let placeholders = reference_ids
+                .iter()
+                .map(|id| {
+                    values.push(Value::Text(id.clone()));
+                    format!("?{}", values.len())
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+            sql.push_str(&format!(" AND reference_id IN ({placeholders})"));
+        }
+        if let Some(trace_id) = self.trace_id {
+            values.push(Value::Text(trace_id.to_string()));
+            sql.push_str(&format!(" AND trace_id = ?{}", values.len()));
+        }
+        if let Some(from_agent) = self.from_agent {
+            values.push(Value::Text(from_agent.to_string()));
+            sql.push_str(&format!(" AND from_agent = ?{}", values.len()));
+        }
+        if let Some(since) = self.since {
+            values.push(Value::Text(
+                time::UtcDateTime::from_unix_seconds(since).to_iso8601(),
+            ));
+            sql.push_str(&format!(" AND created_at >= ?{}", values.len()));
+        }
+        if let Some(before) = self.before {
+            values.push(Value::Text(
+                time::UtcDateTime::from_unix_seconds(before).to_iso8601(),
+            ));
+            sql.push_str(&format!(" AND created_at < ?{}", values.len()));
+        }
+        if let Some(needle) = self.content_contains {
+            values.push(Value::Text(needle.to_string()));
+            sql.push_str(&format!(" AND instr(content, ?{}) > 0", values.len()));
+        }
+        // Looked up in the metadata index, with the value rendered like indexed values
+        for (key, value) in self.metadata.into_iter().flatten() {
+            values.push(Value::Text(key.clone()));
+            values.push(Value::Text(value.to_string()));
+            sql.push_str(&format!(
+                r" AND id IN (SELECT message_id FROM message_metadata
+                              WHERE key = ?{} AND value = (SELECT {JSON_EACH_VALUE} FROM json_each(?{})))",
+                values.len() - 1,
+                values.len()
+            ));
+        }
+        if let Some(after_seq) = self.after_seq {
+            values.push(Value::Integer(i64::try_from(after_seq).unwrap_or(i64::MAX)));
+            sql.push_str(&format!(" AND seq > ?{}", values.len()));
+        }
+        for tag in self.tags.into_iter().flatten() {
+            values.push(Value::Text(tag.trim().to_string()));
+            sql.push_str(&format!(
+                " AND id IN (SELECT message_id FROM message_tags WHERE tag = ?{})",
+                values.len()
+            ));
+        }
+        // Matches DELIVERY_ORDER
+        if let Some(after) = self.after {
+            values.push(Value::Integer(after.priority));
+            values.push(Value::Integer(after.id));
+            sql.push_str(&format!(
+                " AND (priority < ?{0} OR (priority = ?{0} AND id > ?{1}))",
+                values.len() - 1,
+                values.len()
+            ));
+        }
+        sql
+    }
+}
+
+/// A copy of a broadcast or multi-recipient message delivered to one agent.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Delivery {
+    pub agent_id: String,
+    pub message_id: String,
+}
+
+/// A copy of a cross-posted message delivered to one project.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ProjectDelivery {
+    pub project_id: String,
+    pub message_id: String,
+}
+
+/// Current state of one non-empty queue, produced by [`Database::queue_overview`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct QueueStatus {
+    pub project_id: String,
+    pub agent_id: String,
+    /// Number of pending messages.
+    pub depth: u64,
+    /// Age of the oldest pending message, in seconds.
+    pub oldest_message_age_secs: u64,
+    /// Number of messages in the project's dead-letter queue addressed to this agent.
+    pub dead_letters: u64,
+}
+
+/// Statistics of one queue in a project, produced by [`Database::queue_stats`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct QueueStats {
+    pub agent_id: String,
+    /// Number of pending messages, including those awaiting acknowledgment.
+    pub pending: u64,
+    /// Pending messages received but not yet acknowledged.
+    pub in_flight: u64,
+    /// Age of the oldest pending message, in seconds.
+    pub oldest_message_age_secs: u64,
+    /// Total content size of the pending messages, in bytes.
+    pub total_bytes: u64,
+    /// Pending messages per sender, most messages first.
+    pub senders: Vec<SenderStats>,
+}
+
+/// A sender's share of a queue, part of [`QueueStats`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SenderStats {
+    pub from_agent: String,
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Outcome of [`Database::context_append`].
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct ContextAppend {
+    /// Entries in the list after the append.
+    pub entries: usize,
+    /// Oldest entries dropped to stay within the cap.
+    pub evicted: usize,
+}
+
+/// Exclusive write access to a context key, granted by [`Database::context_lease`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ContextLease {
+    pub key: String,
+    pub owner: String,
+    /// When the lease lapses (ISO 8601 format: `2025-01-08T12:00:00Z`).
+    pub expires_at: String,
+}
+
+/// Summary of an agent's queue, produced by [`Database::inbox_overview`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InboxOverview {
+    /// Number of pending messages, including those awaiting acknowledgment.
+    pub pending: u64,
+    /// Pending messages received but not yet acknowledged.
+    pub in_flight: u64,
+    /// Messages of the agent in the project's dead-letter queue.
+    pub dead_letters: u64,
+    /// Age of the oldest pending message, in seconds.
+    pub oldest_message_age_secs: u64,
+    /// Pending messages per sender, most messages first.
+    pub by_sender: Vec<InboxCount>,
+    /// Pending messages per annotation, most messages first.
+    pub by_annotation: Vec<InboxCount>,
+    /// Pending messages by age.
+    pub by_age: InboxAges,
+}
+
+/// Number of pending messages sharing a sender or annotation, part of [`InboxOverview`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InboxCount {
+    pub key: String,
+    pub count: u64,
+}
+
+/// Pending messages by age, part of [`InboxOverview`].
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct InboxAges {
+    pub under_1m: u64,
+    pub under_1h: u64,
+    pub under_1d: u64,
+    pub older: u64,
+}
+
+/// Messages still pending in a queue, produced by [`Database::queue_remainder`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct QueueRemainder {
+    /// Number of pending messages.
+    pub count: u64,
+    /// Timestamp of the oldest pending message.
+    pub oldest_created_at: String,
+    /// Timestamp of the newest pending message.
+    pub newest_created_at: String,
+}
+
+/// A queue's change counter, depth and first messages, read together by
+/// [`Database::peek_snapshot`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct QueueSnapshot {
+    /// The queue's change counter (see [`Database::queue_version`]).
+    pub version: u64,
+    /// Number of deliverable messages.
+    pub visible: u64,
+    /// The first deliverable messages, in delivery order.
+    pub messages: Vec<Message>,
+}
+
+/// Send/receive counts for one queue during one minute.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct QueueBucket {
+    /// Start of the minute (ISO 8601 format: `2025-01-08T12:00:00Z`).
+    pub bucket: String,
+    pub sent: u64,
+    pub received: u64,
+}
+
+/// Throughput history of a queue, produced by [`Database::queue_history`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct QueueHistory {
+    pub window_minutes: u32,
+    /// Minutes with activity, oldest first. Minutes without activity are omitted.
+    pub buckets: Vec<QueueBucket>,
+    pub total_sent: u64,
+    pub total_received: u64,
+    /// Average messages sent per minute over the window.
+    pub send_rate_per_minute: f64,
+    /// Average messages received per minute over the window.
+    pub receive_rate_per_minute: f64,
+}
+
+/// An entry of a project's event feed, produced by [`Database::events_since`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Event {
+    /// Position in the feed; increases across all projects and is never reused.
+    pub seq: u64,
+    /// `message_enqueued`, `message_consumed`, `message_removed`,
+    /// `context_set`, `context_deleted`, `queue_settings_changed`,
+    /// `queue_paused`, `queue_resumed`, `schema_registered`,
+    /// `project_expiry_set` or `project_purged`.
+    pub kind: String,
+    /// Queue the event concerns, for message and queue events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent_id: Option<String>,
+    /// Message ID, context key or schema name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    pub created_at: String,
+}
+
+/// A page of a project's event feed, produced by [`Database::events_since`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct EventPage {
+    /// Events after the requested position, oldest first.
+    pub events: Vec<Event>,
+    /// Position to resume from: the last event's `seq`, or the requested
+    /// position if there were none.
+    pub next_seq: u64,
+    /// Whether events after the requested position were already pruned, so
+    /// a mirror has missed changes and should resynchronize.
+    pub pruned: bool,
+}
+
+/// State of a chunked message upload, produced by [`Database::append_chunk`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct UploadProgress {
+    pub upload_id: String,
+    /// Chunks appended so far.
+    pub chunks: u32,
+    /// Total size of the appended chunks in bytes.
+    pub size: u64,
+}
+
+/// The reassembled content of a chunked message upload, produced by
+/// [`Database::upload_content`].
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub project_id: String,
+    pub content: String,
+}
+
+/// Health of the underlying database, as reported by `/readyz`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DbHealth {
+    /// All operations are served from the primary database.
+    Healthy,
+    /// The primary database is persistently locked or corrupted.
+    ///
+    /// Reads are served from the last good snapshot (if any). Writes are rejected
+    /// while `corrupted` is `true`; otherwise they keep being attempted so that
+    /// the server recovers as soon as the lock clears.
+    Degraded { reason: String, corrupted: bool },
+}
+
+/// Tracks persistent failures of the primary database.
+#[derive(Default)]
+struct HealthTracker {
+    consecutive_busy: AtomicU32,
+    state: Mutex<Option<(String, bool)>>,
+}
+
+/// Wakes tasks waiting for messages in a queue when messages are sent to it.
+///
+/// Only sends made through this process are seen; waiters should re-check
+/// periodically to catch other processes' sends and expired visibility timeouts.
+#[derive(Default)]
+struct QueueWaiters {
+    queues: Mutex<HashMap<(String, String), Weak<tokio::sync::Notify>>>,
+}
+
+impl QueueWaiters {
+    fn queues(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<(String, String), Weak<tokio::sync::Notify>>> {
+        self.queues
+            .lock()
+            .expect("Queue waiters mutex poisoned - this indicates a bug")
+    }
+
+    fn subscribe(&self, project_id: &str, agent_id: &str) -> Arc<tokio::sync::Notify> {
+        let mut queues = self.queues();
+        let key = (project_id.to_string(), agent_id.to_string());
+        if let Some(notify) = queues.get(&key).and_then(Weak::upgrade) {
+            return notify;
+        }
+        // Forget queues nobody waits for anymore
+        queues.retain(|_, notify| notify.strong_count() > 0);
+        let notify = Arc::new(tokio::sync::Notify::new());
+        queues.insert(key, Arc::downgrade(&notify));
+        notify
+    }
+
+    fn notify(&self, project_id: &str, agent_id: &str) {
+        let queues = self.queues();
+        let key = (project_id.to_string(), agent_id.to_string());
+        if let Some(notify) = queues.get(&key).and_then(Weak::upgrade) {
+            notify.notify_waiters();
+        }
+    }
+}
+
+/// A schema migration, identified by the `user_version` it upgrades the database to.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    sql: &'static str,
+}
+
+/// Schema changes that opening a database would apply, produced by
+/// [`Database::migration_plan`].
+#[derive(Debug, Clone)]
+pub struct MigrationPlan {
+    /// Schema version of the database (0 if it doesn't exist yet).
+    pub current_version: u32,
+    /// Migrations not yet applied, as (version, description), in order.
+    pub pending: Vec<(u32, &'static str)>,
+    /// Whether tables of the pre-unified schema would be imported and dropped.
+    pub imports_legacy_tables: bool,
+    /// Where the database would be backed up before migrating, if it holds data.
+    pub backup_path: Option<PathBuf>,
+}
+
+/// Schema migrations, applied in order on startup.
+///
+/// Never edit an existing entry; append a new one with the next version instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "unified context and message queue tables",
+        sql: r"
+            -- Unified context table (project_id NULL = global)
+            CREATE TABLE IF NOT EXISTS context (
+                project_id TEXT,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (project_id, key)
+            );
+
+            -- Message queue
+            CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                project_id TEXT NOT NULL,
+                to_agent TEXT NOT NULL,
+                from_agent TEXT NOT NULL,
+                reference_id TEXT,
+                content TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_messages_queue
+                ON messages(project_id, to_agent, created_at);
+        ",
+    },
+    Migration {
+        version: 2,
+        description: "trace IDs for cross-agent correlation",
+        sql: r"
+            ALTER TABLE messages ADD COLUMN trace_id TEXT;
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "per-minute queue throughput rollups",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS queue_stats (
+                project_id TEXT NOT NULL,
+                agent_id TEXT NOT NULL,
+                bucket TEXT NOT NULL,
+                sent INTEGER NOT NULL DEFAULT 0,
+                received INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (project_id, agent_id, bucket)
+            );
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "per-queue delivery modes and in-flight tracking",
+        sql: r"
+            -- Set while a received message awaits acknowledgment (at-least-once queues)
+            ALTER TABLE messages ADD COLUMN visible_after TEXT;
+            ALTER TABLE messages ADD COLUMN delivery_count INTEGER NOT NULL DEFAULT 0;
+
+            CREATE TABLE IF NOT EXISTS queue_settings (
+                project_id TEXT NOT NULL,
+                agent_id TEXT NOT NULL,
+                delivery_mode TEXT NOT NULL,
+                visibility_timeout_secs INTEGER NOT NULL,
+                PRIMARY KEY (project_id, agent_id)
+            );
+        ",
+    },
+    Migration {
+        version: 5,
+        description: "named workflow milestones",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS milestones (
+                project_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                payload TEXT,
+                reached_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                PRIMARY KEY (project_id, name)
+            );
+        ",
+    },
+    Migration {
+        version: 6,
+        description: "message priorities",
+        sql: r"
+            ALTER TABLE messages ADD COLUMN priority INTEGER NOT NULL DEFAULT 1;
+
+            CREATE INDEX IF NOT EXISTS idx_messages_delivery
+                ON messages(project_id, to_agent, priority DESC, id);
+        ",
+    },
+    Migration {
+        version: 7,
+        description: "time-boxed ephemeral projects",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS project_expiry (
+                project_id TEXT PRIMARY KEY,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                expires_at TEXT NOT NULL
+            );
+
+            -- Summaries of purged ephemeral projects; kept after the project's data is gone
+            CREATE TABLE IF NOT EXISTS expired_projects (
+                project_id TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT NOT NULL,
+                purged_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                agents INTEGER NOT NULL,
+                messages_sent INTEGER NOT NULL,
+                pending_messages INTEGER NOT NULL,
+                context_keys INTEGER NOT NULL,
+                milestones INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 8,
+        description: "message annotations",
+        sql: r"
+            -- Not tied to the messages table: annotations outlive consumed messages
+            CREATE TABLE IF NOT EXISTS annotations (
+                project_id TEXT NOT NULL,
+                message_id INTEGER NOT NULL,
+                agent_id TEXT NOT NULL,
+                annotation TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                PRIMARY KEY (project_id, message_id, agent_id, annotation)
+            );
+        ",
+    },
+    Migration {
+        version: 9,
+        description: "dead-letter queues and message expiry",
+        sql: r"
+            ALTER TABLE messages ADD COLUMN expires_at TEXT;
+            ALTER TABLE queue_settings ADD COLUMN max_delivery_attempts INTEGER NOT NULL DEFAULT 5;
+
+            -- Speed up the dead-letter sweep, which only looks at these messages
+            CREATE INDEX IF NOT EXISTS idx_messages_expires
+                ON messages(expires_at) WHERE expires_at IS NOT NULL;
+            CREATE INDEX IF NOT EXISTS idx_messages_in_flight
+                ON messages(visible_after) WHERE visible_after IS NOT NULL;
+
+            -- Keeps the original message ID so replies and annotations still refer to it
+            CREATE TABLE IF NOT EXISTS dead_letters (
+                id INTEGER PRIMARY KEY,
+                project_id TEXT NOT NULL,
+                to_agent TEXT NOT NULL,
+                from_agent TEXT NOT NULL,
+                reference_id TEXT,
+                content TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                trace_id TEXT,
+                priority INTEGER NOT NULL,
+                delivery_count INTEGER NOT NULL,
+                reason TEXT NOT NULL,
+                dead_lettered_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+            );
+            CREATE INDEX IF NOT EXISTS idx_dead_letters_project ON dead_letters(project_id, to_agent);
+        ",
+    },
+    Migration {
+        version: 10,
+        description: "typed message links",
+        sql: r"
+            -- Like annotations, links outlive consumed messages
+            CREATE TABLE IF NOT EXISTS message_links (
+                project_id TEXT NOT NULL,
+                source_id INTEGER NOT NULL,
+                target_id INTEGER NOT NULL,
+                kind TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                PRIMARY KEY (project_id, source_id, target_id, kind)
+            );
+            CREATE INDEX IF NOT EXISTS idx_message_links_target
+                ON message_links(project_id, target_id);
+        ",
+    },
+    Migration {
+        version: 11,
+        description: "archive of consumed messages",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS archived_messages (
+                id INTEGER PRIMARY KEY,
+                project_id TEXT NOT NULL,
+                to_agent TEXT NOT NULL,
+                from_agent TEXT NOT NULL,
+                reference_id TEXT,
+                content TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                trace_id TEXT,
+                priority INTEGER NOT NULL,
+                consumed_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+            );
+            CREATE INDEX IF NOT EXISTS idx_archived_messages_reference
+                ON archived_messages(reference_id) WHERE reference_id IS NOT NULL;
+            CREATE INDEX IF NOT EXISTS idx_archived_messages_consumed
+                ON archived_messages(consumed_at);
+
+            -- Following replies when reconstructing threads
+            CREATE INDEX IF NOT EXISTS idx_messages_reference
+                ON messages(reference_id) WHERE reference_id IS NOT NULL;
+        ",
+    },
+    Migration {
+        version: 12,
+        description: "recurring message schedules",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS schedules (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                project_id TEXT NOT NULL,
+                to_agent TEXT NOT NULL,
+                from_agent TEXT NOT NULL,
+                content TEXT NOT NULL,
+                cron_expr TEXT NOT NULL,
+                next_run_at TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+            );
+            CREATE INDEX IF NOT EXISTS idx_schedules_next_run ON schedules(next_run_at);
+        ",
+    },
+    Migration {
+        version: 13,
+        description: "agent watchdogs",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS watchdogs (
+                project_id TEXT NOT NULL,
+                agent_id TEXT NOT NULL,
+                supervisor TEXT NOT NULL,
+                interval_secs INTEGER NOT NULL,
+                last_activity_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                alerted_at TEXT,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                PRIMARY KEY (project_id, agent_id)
+            );
+        ",
+    },
+    Migration {
+        version: 14,
+        description: "context snapshots",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS context_snapshots (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                project_id TEXT NOT NULL,
+                name TEXT,
+                key_count INTEGER NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+            );
+            CREATE INDEX IF NOT EXISTS idx_context_snapshots_project
+                ON context_snapshots(project_id);
+
+            CREATE TABLE IF NOT EXISTS context_snapshot_entries (
+                snapshot_id INTEGER NOT NULL,
+                project_id TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (snapshot_id, key)
+            );
+            CREATE INDEX IF NOT EXISTS idx_context_snapshot_entries_project
+                ON context_snapshot_entries(project_id);
+        ",
+    },
+    Migration {
+        version: 15,
+        description: "full-text search over message content",
+        sql: r"
+            CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts
+                USING fts5(content, content='messages', content_rowid='id');
+            CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+                INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+            END;
+            CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, content)
+                VALUES ('delete', old.id, old.content);
+            END;
+            CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, content)
+                VALUES ('delete', old.id, old.content);
+                INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+            END;
+            INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');
+
+            CREATE VIRTUAL TABLE IF NOT EXISTS archived_messages_fts
+                USING fts5(content, content='archived_messages', content_rowid='id');
+            CREATE TRIGGER IF NOT EXISTS archived_messages_fts_insert
+            AFTER INSERT ON archived_messages BEGIN
+                INSERT INTO archived_messages_fts (rowid, content) VALUES (new.id, new.content);
+            END;
+            CREATE TRIGGER IF NOT EXISTS archived_messages_fts_delete
+            AFTER DELETE ON archived_messages BEGIN
+                INSERT INTO archived_messages_fts (archived_messages_fts, rowid, content)
+                VALUES ('delete', old.id, old.content);
+            END;
+            CREATE TRIGGER IF NOT EXISTS archived_messages_fts_update
+            AFTER UPDATE OF content ON archived_messages BEGIN
+                INSERT INTO archived_messages_fts (archived_messages_fts, rowid, content)
+                VALUES ('delete', old.id, old.content);
+                INSERT INTO archived_messages_fts (rowid, content) VALUES (new.id, new.content);
+            END;
+            INSERT INTO archived_messages_fts (archived_messages_fts) VALUES ('rebuild');
+        ",
+    },
+    Migration {
+        version: 16,
+        description: "message metadata",
+        sql: r"
+            ALTER TABLE messages ADD COLUMN metadata TEXT;
+            ALTER TABLE archived_messages ADD COLUMN metadata TEXT;
+            ALTER TABLE dead_letters ADD COLUMN metadata TEXT;
+        ",
+    },
+    Migration {
+        version: 17,
+        description: "message metadata index",
+        sql: r"
+            -- Top-level metadata entries, values as JSON text (see JSON_EACH_VALUE).
+            -- Rows live as long as the message is pending, archived or dead-lettered.
+            CREATE TABLE IF NOT EXISTS message_metadata (
+                message_id INTEGER NOT NULL,
+                project_id TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (message_id, key)
+            );
+            CREATE INDEX IF NOT EXISTS idx_message_metadata_lookup
+                ON message_metadata(key, value);
+            CREATE INDEX IF NOT EXISTS idx_message_metadata_project
+                ON message_metadata(project_id);
+
+            CREATE TRIGGER IF NOT EXISTS message_metadata_insert
+            AFTER INSERT ON messages WHEN new.metadata IS NOT NULL BEGIN
+                INSERT OR IGNORE INTO message_metadata (message_id, project_id, key, value)
+                SELECT new.id, new.project_id, key,
+                       CASE type WHEN 'true' THEN 'true' WHEN 'false' THEN 'false'
+                                 ELSE json_quote(value) END
+                FROM json_each(new.metadata);
+            END;
+            CREATE TRIGGER IF NOT EXISTS message_metadata_delete_pending
+            AFTER DELETE ON messages WHEN old.metadata IS NOT NULL BEGIN
+                DELETE FROM message_metadata WHERE message_id = old.id
+                  AND NOT EXISTS (SELECT 1 FROM archived_messages WHERE id = old.id)
+                  AND NOT EXISTS (SELECT 1 FROM dead_letters WHERE id = old.id);
+            END;
+            CREATE TRIGGER IF NOT EXISTS message_metadata_delete_archived
+            AFTER DELETE ON archived_messages WHEN old.metadata IS NOT NULL BEGIN
+                DELETE FROM message_metadata WHERE message_id = old.id
+                  AND NOT EXISTS (SELECT 1 FROM messages WHERE id = old.id)
+                  AND NOT EXISTS (SELECT 1 FROM dead_letters WHERE id = old.id);
+            END;
+            CREATE TRIGGER IF NOT EXISTS message_metadata_delete_dead_letter
+            AFTER DELETE ON dead_letters WHEN old.metadata IS NOT NULL BEGIN
+                DELETE FROM message_metadata WHERE message_id = old.id
+                  AND NOT EXISTS (SELECT 1 FROM messages WHERE id = old.id)
+                  AND NOT EXISTS (SELECT 1 FROM archived_messages WHERE id = old.id);
+            END;
+
+            INSERT OR IGNORE INTO message_metadata (message_id, project_id, key, value)
+            SELECT m.id, m.project_id, j.key,
+                   CASE j.type WHEN 'true' THEN 'true' WHEN 'false' THEN 'false'
+                               ELSE json_quote(j.value) END
+            FROM (SELECT id, project_id, metadata FROM messages
+                  UNION ALL SELECT id, project_id, metadata FROM archived_messages
+                  UNION ALL SELECT id, project_id, metadata FROM dead_letters) m,
+                 json_each(m.metadata) j
+            WHERE m.metadata IS NOT NULL;
+        ",
+    },
+    Migration {
+        version: 18,
+        description: "queue change counters",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS queue_versions (
+                project_id TEXT NOT NULL,
+                agent_id TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                PRIMARY KEY (project_id, agent_id)
+            );
+
+            CREATE TRIGGER IF NOT EXISTS queue_version_insert AFTER INSERT ON messages BEGIN
+                INSERT INTO queue_versions (project_id, agent_id, version)
+                VALUES (new.project_id, new.to_agent, 1)
+                ON CONFLICT (project_id, agent_id) DO UPDATE SET version = version + 1;
+            END;
+            CREATE TRIGGER IF NOT EXISTS queue_version_update AFTER UPDATE ON messages BEGIN
+                INSERT INTO queue_versions (project_id, agent_id, version)
+                VALUES (new.project_id, new.to_agent, 1)
+                ON CONFLICT (project_id, agent_id) DO UPDATE SET version = version + 1;
+            END;
+            CREATE TRIGGER IF NOT EXISTS queue_version_delete AFTER DELETE ON messages BEGIN
+                INSERT INTO queue_versions (project_id, agent_id, version)
+                VALUES (old.project_id, old.to_agent, 1)
+                ON CONFLICT (project_id, agent_id) DO UPDATE SET version = version + 1;
+            END;
+        ",
+    },
+    Migration {
+        version: 19,
+        description: "message schemas",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS message_schemas (
+                project_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                schema TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                PRIMARY KEY (project_id, name)
+            );
+        ",
+    },
+    Migration {
+        version: 20,
+        description: "message attachments",
+        sql: r"
+            -- Content-addressed per project, so identical uploads are stored once.
+            CREATE TABLE IF NOT EXISTS attachments (
+                project_id TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                content_type TEXT,
+                size INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                PRIMARY KEY (project_id, hash)
+            );
+
+            ALTER TABLE messages ADD COLUMN attachments TEXT;
+            ALTER TABLE archived_messages ADD COLUMN attachments TEXT;
+            ALTER TABLE dead_letters ADD COLUMN attachments TEXT;
+        ",
+    },
+    Migration {
+        version: 21,
+        description: "message delivery status",
+        sql: r"
+            -- Outlives the message itself, so senders can tell what became of it.
+            CREATE TABLE IF NOT EXISTS delivery_status (
+                message_id INTEGER PRIMARY KEY,
+                project_id TEXT NOT NULL,
+                to_agent TEXT NOT NULL,
+                sent_at TEXT NOT NULL,
+                peeked_at TEXT,
+                received_at TEXT,
+                acknowledged_at TEXT
+            );
+            CREATE INDEX IF NOT EXISTS idx_delivery_status_project
+                ON delivery_status(project_id);
+            CREATE INDEX IF NOT EXISTS idx_delivery_status_sent
+                ON delivery_status(sent_at);
+
+            INSERT OR IGNORE INTO delivery_status (message_id, project_id, to_agent, sent_at, received_at)
+            SELECT id, project_id, to_agent, created_at,
+                   CASE WHEN delivery_count > 0 THEN created_at END
+            FROM messages;
+        ",
+    },
+    Migration {
+        version: 22,
+        description: "confidential classification of messages and context",
+        sql: r"
+            ALTER TABLE messages ADD COLUMN confidential INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE archived_messages ADD COLUMN confidential INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE dead_letters ADD COLUMN confidential INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE context ADD COLUMN confidential INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE context_snapshot_entries ADD COLUMN confidential INTEGER NOT NULL DEFAULT 0;
+        ",
+    },
+    Migration {
+        version: 23,
+        description: "API tokens",
+        sql: r"
+            -- Secrets are stored as SHA-256 hashes
+            CREATE TABLE IF NOT EXISTS api_tokens (
+                id TEXT PRIMARY KEY,
+                name TEXT,
+                agent_id TEXT NOT NULL,
+                roles TEXT NOT NULL,
+                secret_hash TEXT NOT NULL UNIQUE,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                expires_at TEXT,
+                last_used_at TEXT,
+                replaced_by TEXT
+            );
+        ",
+    },
+    Migration {
+        version: 24,
+        description: "schema compatibility metadata",
+        sql: r"
+            -- min_server_version: the release that migrated the schema to its
+            -- current version, which older releases refuse to open
+            CREATE TABLE IF NOT EXISTS schema_info (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 25,
+        description: "per-queue message sequence numbers",
+        sql: r"
+            -- Last sequence number handed out per queue; never reset, so
+            -- numbers aren't reused even after a queue drains or is purged
+            CREATE TABLE IF NOT EXISTS queue_sequences (
+                project_id TEXT NOT NULL,
+                agent_id TEXT NOT NULL,
+                seq INTEGER NOT NULL,
+                PRIMARY KEY (project_id, agent_id)
+            );
+
+            ALTER TABLE messages ADD COLUMN seq INTEGER;
+            ALTER TABLE archived_messages ADD COLUMN seq INTEGER;
+            ALTER TABLE dead_letters ADD COLUMN seq INTEGER;
+
+            -- Number queued messages in send order; consumed ones stay unnumbered
+            UPDATE messages SET seq = numbered.seq
+            FROM (SELECT id, ROW_NUMBER() OVER (PARTITION BY project_id, to_agent ORDER BY id) AS seq
+                  FROM messages) AS numbered
+            WHERE messages.id = numbered.id;
+            INSERT INTO queue_sequences (project_id, agent_id, seq)
+            SELECT project_id, to_agent, MAX(seq) FROM messages GROUP BY project_id, to_agent;
+
+            CREATE INDEX IF NOT EXISTS idx_messages_seq ON messages(project_id, to_agent, seq);
+        ",
+    },
+    Migration {
+        version: 26,
+        description: "context key leases",
+        sql: r"
+            -- project_id is '' for global keys, so the primary key stays unique
+            CREATE TABLE IF NOT EXISTS context_leases (
+                project_id TEXT NOT NULL,
+                key TEXT NOT NULL,
+                owner TEXT NOT NULL,
+                expires_at TEXT NOT NULL,
+                PRIMARY KEY (project_id, key)
+            );
+        ",
+    },
+    Migration {
+        version: 27,
+        description: "message tags",
+        sql: r"
+            ALTER TABLE messages ADD COLUMN tags TEXT;
+            ALTER TABLE archived_messages ADD COLUMN tags TEXT;
+            ALTER TABLE dead_letters ADD COLUMN tags TEXT;
+
+            -- Like message_metadata: rows live as long as the message is
+            -- pending, archived or dead-lettered
+            CREATE TABLE IF NOT EXISTS message_tags (
+                message_id INTEGER NOT NULL,
+                project_id TEXT NOT NULL,
+                tag TEXT NOT NULL,
+                PRIMARY KEY (message_id, tag)
+            );
+            CREATE INDEX IF NOT EXISTS idx_message_tags_lookup ON message_tags(tag);
+            CREATE INDEX IF NOT EXISTS idx_message_tags_project ON message_tags(project_id);
+
+            CREATE TRIGGER IF NOT EXISTS message_tags_insert
+            AFTER INSERT ON messages WHEN new.tags IS NOT NULL BEGIN
+                INSERT OR IGNORE INTO message_tags (message_id, project_id, tag)
+                SELECT new.id, new.project_id, value FROM json_each(new.tags);
+            END;
+            CREATE TRIGGER IF NOT EXISTS message_tags_delete_pending
+            AFTER DELETE ON messages WHEN old.tags IS NOT NULL BEGIN
+                DELETE FROM message_tags WHERE message_id = old.id
+                  AND NOT EXISTS (SELECT 1 FROM archived_messages WHERE id = old.id)
+                  AND NOT EXISTS (SELECT 1 FROM dead_letters WHERE id = old.id);
+            END;
+            CREATE TRIGGER IF NOT EXISTS message_tags_delete_archived
+            AFTER DELETE ON archived_messages WHEN old.tags IS NOT NULL BEGIN
+                DELETE FROM message_tags WHERE message_id = old.id
+                  AND NOT EXISTS (SELECT 1 FROM messages WHERE id = old.id)
+                  AND NOT EXISTS (SELECT 1 FROM dead_letters WHERE id = old.id);
+            END;
+            CREATE TRIGGER IF NOT EXISTS message_tags_delete_dead_letter
+            AFTER DELETE ON dead_letters WHEN old.tags IS NOT NULL BEGIN
+                DELETE FROM message_tags WHERE message_id = old.id
+                  AND NOT EXISTS (SELECT 1 FROM messages WHERE id = old.id)
+                  AND NOT EXISTS (SELECT 1 FROM archived_messages WHERE id = old.id);
+            END;
+        ",
+    },
+    Migration {
+        version: 28,
+        description: "reply_to addressing",
+        sql: r"
+            ALTER TABLE messages ADD COLUMN reply_to TEXT;
+            ALTER TABLE archived_messages ADD COLUMN reply_to TEXT;
+            ALTER TABLE dead_letters ADD COLUMN reply_to TEXT;
+        ",
+    },
+    Migration {
+        version: 29,
+        description: "project event feed",
+        sql: r"
+            -- AUTOINCREMENT keeps seq from being reused once old events are pruned
+            CREATE TABLE IF NOT EXISTS events (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                project_id TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                agent_id TEXT,
+                subject TEXT,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+            );
+            CREATE INDEX IF NOT EXISTS idx_events_project ON events(project_id, seq);
+            CREATE INDEX IF NOT EXISTS idx_events_created ON events(created_at);
+
+            CREATE TRIGGER IF NOT EXISTS events_message_enqueued AFTER INSERT ON messages BEGIN
+                INSERT INTO events (project_id, kind, agent_id, subject)
+                VALUES (new.project_id, 'message_enqueued', new.to_agent, new.id);
+            END;
+            CREATE TRIGGER IF NOT EXISTS events_message_removed AFTER DELETE ON messages BEGIN
+                INSERT INTO events (project_id, kind, agent_id, subject)
+                VALUES (old.project_id, 'message_removed', old.to_agent, old.id);
+            END;
+            CREATE TRIGGER IF NOT EXISTS events_message_consumed
+            AFTER UPDATE OF received_at ON delivery_status
+            WHEN old.received_at IS NULL AND new.received_at IS NOT NULL BEGIN
+                INSERT INTO events (project_id, kind, agent_id, subject)
+                VALUES (new.project_id, 'message_consumed', new.to_agent, new.message_id);
+            END;
+
+            -- Global context has no project feed to go to
+            CREATE TRIGGER IF NOT EXISTS events_context_insert
+            AFTER INSERT ON context WHEN new.project_id IS NOT NULL BEGIN
+                INSERT INTO events (project_id, kind, subject)
+                VALUES (new.project_id, 'context_set', new.key);
+            END;
+            CREATE TRIGGER IF NOT EXISTS events_context_update
+            AFTER UPDATE ON context WHEN new.project_id IS NOT NULL BEGIN
+                INSERT INTO events (project_id, kind, subject)
+                VALUES (new.project_id, 'context_set', new.key);
+            END;
+            CREATE TRIGGER IF NOT EXISTS events_context_delete
+            AFTER DELETE ON context WHEN old.project_id IS NOT NULL BEGIN
+                INSERT INTO events (project_id, kind, subject)
+                VALUES (old.project_id, 'context_deleted', old.key);
+            END;
+
+            CREATE TRIGGER IF NOT EXISTS events_queue_settings_insert
+            AFTER INSERT ON queue_settings BEGIN
+                INSERT INTO events (project_id, kind, agent_id)
+                VALUES (new.project_id, 'queue_settings_changed', new.agent_id);
+            END;
+            CREATE TRIGGER IF NOT EXISTS events_queue_settings_update
+            AFTER UPDATE ON queue_settings BEGIN
+                INSERT INTO events (project_id, kind, agent_id)
+                VALUES (new.project_id, 'queue_settings_changed', new.agent_id);
+            END;
+            CREATE TRIGGER IF NOT EXISTS events_schema_insert
+            AFTER INSERT ON message_schemas BEGIN
+                INSERT INTO events (project_id, kind, subject)
+                VALUES (new.project_id, 'schema_registered', new.name);
+            END;
+            CREATE TRIGGER IF NOT EXISTS events_schema_update
+            AFTER UPDATE ON message_schemas BEGIN
+                INSERT INTO events (project_id, kind, subject)
+                VALUES (new.project_id, 'schema_registered', new.name);
+            END;
+            CREATE TRIGGER IF NOT EXISTS events_project_expiry_insert
+            AFTER INSERT ON project_expiry BEGIN
+                INSERT INTO events (project_id, kind) VALUES (new.project_id, 'project_expiry_set');
+            END;
+            CREATE TRIGGER IF NOT EXISTS events_project_expiry_update
+            AFTER UPDATE ON project_expiry BEGIN
+                INSERT INTO events (project_id, kind) VALUES (new.project_id, 'project_expiry_set');
+            END;
+        ",
+    },
+    Migration {
+        version: 30,
+        description: "group queue consumers",
+        sql: r"
+            -- Member of a group queue holding an in-flight message
+            ALTER TABLE messages ADD COLUMN claimed_by TEXT;
+            ALTER TABLE delivery_status ADD COLUMN received_by TEXT;
+        ",
+    },
+    Migration {
+        version: 31,
+        description: "agent groups",
+        sql: r"
+            -- next_member: position of the member the next round-robin send goes to
+            CREATE TABLE IF NOT EXISTS agent_groups (
+                project_id TEXT NOT NULL,
+                group_id TEXT NOT NULL,
+                strategy TEXT NOT NULL,
+                next_member INTEGER NOT NULL DEFAULT 0,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                PRIMARY KEY (project_id, group_id)
+            );
+            CREATE TABLE IF NOT EXISTS agent_group_members (
+                project_id TEXT NOT NULL,
+                group_id TEXT NOT NULL,
+                agent_id TEXT NOT NULL,
+                PRIMARY KEY (project_id, group_id, agent_id)
+            );
+        ",
+    },
+    Migration {
+        version: 32,
+        description: "chunked message uploads",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS message_uploads (
+                id TEXT PRIMARY KEY,
+                project_id TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+            );
+            CREATE TABLE IF NOT EXISTS message_upload_chunks (
+                upload_id TEXT NOT NULL,
+                project_id TEXT NOT NULL,
+                chunk INTEGER NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY (upload_id, chunk)
+            );
+        ",
+    },
+    Migration {
+        version: 33,
+        description: "message pinning",
+        sql: r"
+            ALTER TABLE messages ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE archived_messages ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+            ALTER TABLE dead_letters ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+        ",
+    },
+    Migration {
+        version: 34,
+        description: "message snoozing",
+        sql: r"
+            ALTER TABLE messages ADD COLUMN snoozed_until TEXT;
+        ",
+    },
+    Migration {
+        version: 35,
+        description: "paused queues",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS paused_queues (
+                project_id TEXT NOT NULL,
+                agent_id TEXT NOT NULL,
+                paused_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                PRIMARY KEY (project_id, agent_id)
+            );
+
+            CREATE TRIGGER IF NOT EXISTS events_queue_paused
+            AFTER INSERT ON paused_queues BEGIN
+                INSERT INTO events (project_id, kind, agent_id)
+                VALUES (new.project_id, 'queue_paused', new.agent_id);
+            END;
+            CREATE TRIGGER IF NOT EXISTS events_queue_resumed
+            AFTER DELETE ON paused_queues BEGIN
+                INSERT INTO events (project_id, kind, agent_id)
+                VALUES (old.project_id, 'queue_resumed', old.agent_id);
+            END;
+        ",
+    },
+    Migration {
+        version: 36,
+        description: "return receipts",
+        sql: r"
+            ALTER TABLE messages ADD COLUMN request_receipt INTEGER NOT NULL DEFAULT 0;
+        ",
+    },
+    Migration {
+        version: 37,
+        description: "message templates",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS message_templates (
+                project_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                template TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                PRIMARY KEY (project_id, name)
+            );
+        ",
+    },
+    Migration {
+        version: 38,
+        description: "context history",
+        sql: r"
+            -- Writer of the current value, recorded as the author of its version
+            ALTER TABLE context ADD COLUMN updated_by TEXT;
+
+            -- Every value a key has held; deletions are recorded with a NULL value
+            CREATE TABLE IF NOT EXISTS context_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                project_id TEXT,
+                key TEXT NOT NULL,
+                value TEXT,
+                author TEXT,
+                changed_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+            );
+            CREATE INDEX IF NOT EXISTS idx_context_history_key
+                ON context_history(project_id, key, id);
+
+            CREATE TRIGGER IF NOT EXISTS context_history_insert AFTER INSERT ON context BEGIN
+                INSERT INTO context_history (project_id, key, value, author)
+                VALUES (new.project_id, new.key, new.value, new.updated_by);
+            END;
+            CREATE TRIGGER IF NOT EXISTS context_history_update
+            AFTER UPDATE OF value ON context WHEN new.value IS NOT old.value BEGIN
+                INSERT INTO context_history (project_id, key, value, author)
+                VALUES (new.project_id, new.key, new.value, new.updated_by);
+            END;
+            CREATE TRIGGER IF NOT EXISTS context_history_delete AFTER DELETE ON context BEGIN
+                INSERT INTO context_history (project_id, key, value)
+                VALUES (old.project_id, old.key, NULL);
+            END;
+        ",
+    },
+    Migration {
+        version: 39,
+        description: "context TTL",
+        sql: r"
+            -- When the value is treated as absent; maintenance deletes it later
+            ALTER TABLE context ADD COLUMN expires_at TEXT;
+            CREATE INDEX IF NOT EXISTS idx_context_expires
+                ON context(expires_at) WHERE expires_at IS NOT NULL;
+        ",
+    },
+    Migration {
+        version: 40,
+        description: "context watches",
+        sql: r"
+            CREATE TABLE IF NOT EXISTS context_watches (
+                project_id TEXT NOT NULL,
+                agent_id TEXT NOT NULL,
+                key TEXT NOT NULL,
+                prefix INTEGER NOT NULL DEFAULT 0,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                PRIMARY KEY (project_id, agent_id, key, prefix)
+            );
+        ",
+    },
+    Migration {
+        version: 41,
+        description: "trace IDs in delivery status",
+        sql: r"
+            -- Lets replies inherit the trace of a message already consumed
+            ALTER TABLE delivery_status ADD COLUMN trace_id TEXT;
+            UPDATE delivery_status SET trace_id = (
+                SELECT trace_id FROM messages WHERE messages.id = delivery_status.message_id);
+        ",
+    },
+    Migration {
+        version: 42,
+        description: "unique global context keys",
+        sql: r"
+            -- The primary key doesn't stop duplicate global keys (NULL project_id),
+            -- which upserts used to create. Keep each key's latest write, without
+            -- recording the duplicates' removal as deletions.
+            DROP TRIGGER IF EXISTS context_history_delete;
+            DELETE FROM context WHERE project_id IS NULL AND rowid NOT IN (
+                SELECT MAX(rowid) FROM context WHERE project_id IS NULL GROUP BY key);
+            CREATE TRIGGER IF NOT EXISTS context_history_delete AFTER DELETE ON context BEGIN
+                INSERT INTO context_history (project_id, key, value)
+                VALUES (old.project_id, old.key, NULL);
+            END;
+            CREATE UNIQUE INDEX IF NOT EXISTS idx_context_global_key
+                ON context(key) WHERE project_id IS NULL;
+        ",
+    },
+    Migration {
+        version: 43,
+        description: "per-token tool lists",
+        sql: r"
+            -- JSON array of the tools a token may call; NULL allows all
+            ALTER TABLE api_tokens ADD COLUMN tools TEXT;
+        ",
+    },
+];
+
+/// Schema version this server migrates databases to.
+const SCHEMA_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+/// Tables holding project-scoped data, purged together by [`Database::purge_project`].
+///
+/// `queue_versions` and `queue_sequences` are deliberately absent: a purge
+/// bumps the counters of the purged queues instead of resetting them, so
+/// clients never see an old version or sequence number reused. So is
+/// `events`, whose feed reports the purge itself.
+const PROJECT_TABLES: &[&str] = &[
+    "messages",
+    "context",
+    "queue_stats",
+    "queue_settings",
+    "milestones",
+    "project_expiry",
+    "annotations",
+    "dead_letters",
+    "message_links",
+    "archived_messages",
+    "schedules",
+    "watchdogs",
+    "context_snapshots",
+    "context_snapshot_entries",
+    "message_metadata",
+    "message_tags",
+    "message_schemas",
+    "message_templates",
+    "attachments",
+    "delivery_status",
+    "context_leases",
+    "agent_groups",
+    "agent_group_members",
+    "message_uploads",
+    "message_upload_chunks",
+    "paused_queues",
+    "context_watches",
+    // After context, whose deletion records tombstones here
+    "context_history",
+];
+
+/// A cross-table invariant, checked by [`Database::check_invariants`].
+struct Invariant {
+    name: &'static str,
+    description: &'static str,
+    /// Counts the rows breaking the invariant.
+    violations_sql: &'static str,
+}
+
+/// Invariants the schema can't enforce by itself; add one for every feature
+/// that keeps related rows in several tables.
+const INVARIANTS: &[Invariant] = &[
+    Invariant {
+        name: "attachment_references",
+        description: "attachments listed by messages are stored in the message's project",
+        violations_sql: r"SELECT COUNT(*)
+                          FROM (SELECT project_id, attachments FROM messages
+                                UNION ALL SELECT project_id, attachments FROM archived_messages
+                                UNION ALL SELECT project_id, attachments FROM dead_letters) m,
+                               json_each(m.attachments) j
+                          WHERE m.attachments IS NOT NULL
+                            AND NOT EXISTS (SELECT 1 FROM attachments a
+                                            WHERE a.project_id = m.project_id AND a.hash = j.value)",
+    },
+    Invariant {
+        name: "attachment_sizes",
+        description: "stored attachment sizes match their data",
+        violations_sql: "SELECT COUNT(*) FROM attachments WHERE size != length(data)",
+    },
+    Invariant {
+        name: "in_flight_deliveries",
+        description: "messages in flight have been delivered at least once",
+        violations_sql: r"SELECT COUNT(*) FROM messages
+                          WHERE visible_after IS NOT NULL AND delivery_count < 1",
+    },
+    Invariant {
+        name: "metadata_index",
+        description: "indexed metadata belongs to a pending, archived or dead-lettered message",
+        violations_sql: r"SELECT COUNT(*) FROM message_metadata
+                          WHERE message_id NOT IN (SELECT id FROM messages)
+                            AND message_id NOT IN (SELECT id FROM archived_messages)
+                            AND message_id NOT IN (SELECT id FROM dead_letters)",
+    },
+    Invariant {
+        name: "tag_index",
+        description: "indexed tags belong to a pending, archived or dead-lettered message",
+        violations_sql: r"SELECT COUNT(*) FROM message_tags
+                          WHERE message_id NOT IN (SELECT id FROM messages)
+                            AND message_id NOT IN (SELECT id FROM archived_messages)
+                            AND message_id NOT IN (SELECT id FROM dead_letters)",
+    },
+    Invariant {
+        name: "sequence_counters",
+        description: "message sequence numbers don't exceed their queue's counter",
+        violations_sql: r"SELECT COUNT(*)
+                          FROM (SELECT project_id, to_agent, seq FROM messages
+                                UNION ALL SELECT project_id, to_agent, seq FROM archived_messages
+                                UNION ALL SELECT project_id, to_agent, seq FROM dead_letters) m
+                          LEFT JOIN queue_sequences q
+                            ON q.project_id = m.project_id AND q.agent_id = m.to_agent
+                          WHERE m.seq IS NOT NULL AND (q.seq IS NULL OR m.seq > q.seq)",
+    },
+    Invariant {
+        name: "unique_sequences",
+        description: "no two pending messages of a queue share a sequence number",
+        violations_sql: r"SELECT COUNT(*) FROM (
+                              SELECT 1 FROM messages WHERE seq IS NOT NULL
+                              GROUP BY project_id, to_agent, seq HAVING COUNT(*) > 1)",
+    },
+    Invariant {
+        name: "context_snapshots",
+        description:
+            "snapshot entries belong to a snapshot of the same project, and key counts match",
+        violations_sql: r"SELECT
+                              (SELECT COUNT(*) FROM context_snapshot_entries e
+                               WHERE NOT EXISTS (SELECT 1 FROM context_snapshots s
+                                                 WHERE s.id = e.snapshot_id
+                                                   AND s.project_id = e.project_id))
+                            + (SELECT COUNT(*) FROM context_snapshots s
+                               WHERE key_count != (SELECT COUNT(*) FROM context_snapshot_entries e
+                                                   WHERE e.snapshot_id = s.id))",
+    },
+    Invariant {
+        name: "queue_settings",
+        description: "queue settings have a positive visibility timeout and delivery attempt limit",
+        violations_sql: r"SELECT COUNT(*) FROM queue_settings
+                          WHERE visibility_timeout_secs < 1 OR max_delivery_attempts < 1",
+    },
+];
+
+/// Full-text indexes checked by [`Database::check_invariants`].
+const FTS_INDEXES: &[&str] = &["messages_fts", "archived_messages_fts"];
+
+/// An invariant found broken by [`Database::check_invariants`].
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InvariantViolation {
+    /// Short name of the invariant, e.g. `attachment_references`.
+    pub invariant: &'static str,
+    /// What the invariant requires.
+    pub description: &'static str,
+    /// Number of rows breaking it.
+    pub violations: u64,
+}
+
+/// Tables left behind by older versions that used UUID message IDs and
+/// separate `global_context`/`project_context` tables.
+struct LegacySchema {
+    global_context: bool,
+    project_context: bool,
+    messages: bool,
+}
+
+impl LegacySchema {
+    fn detect(conn: &Connection) -> SqliteResult<Self> {
+        let table_exists = |name: &str| -> SqliteResult<bool> {
+            conn.query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+                params![name],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|count| count > 0)
+        };
+        // Legacy message IDs were UUIDs stored as TEXT
+        let id_type: Option<String> = conn
+            .query_row(
+                "SELECT type FROM pragma_table_info('messages') WHERE name = 'id'",
+                [],
+                |row| row.get(0),
+            )
+            .or_else(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                e => Err(e),
+            })?;
+
+        Ok(Self {
+            global_context: table_exists("global_context")?,
+            project_context: table_exists("project_context")?,
+            messages: id_type.is_some_and(|t| !t.eq_ignore_ascii_case("INTEGER")),
+        })
+    }
+
+    /// Copies legacy data into the unified schema and drops the legacy tables.
+    ///
+    /// Existing unified context values take precedence over legacy ones.
+    fn import(&self, conn: &Connection) -> SqliteResult<()> {
+        if self.global_context {
+            let rows = conn.execute(
+                r"INSERT OR IGNORE INTO context (project_id, key, value)
+                  SELECT NULL, key, value FROM global_context",
+                [],
+            )?;
+            conn.execute_batch("DROP TABLE global_context")?;
+            tracing::info!("Migrated {rows} legacy global context entries");
+        }
+        if self.project_context {
+            let rows = conn.execute(
+                r"INSERT OR IGNORE INTO context (project_id, key, value)
+                  SELECT project_id, key, value FROM project_context",
+                [],
+            )?;
+            conn.execute_batch("DROP TABLE project_context")?;
+            tracing::info!("Migrated {rows} legacy project context entries");
+        }
+        if self.messages {
+            let rows = Self::import_messages(conn)?;
+            conn.execute_batch("DROP TABLE legacy_messages")?;
+            tracing::info!("Migrated {rows} legacy messages");
+        }
+        Ok(())
+    }
+
+    /// Re-inserts legacy messages in chronological order, assigning numeric IDs
+    /// and rewriting reference IDs that pointed at migrated UUIDs.
+    fn import_messages(conn: &Connection) -> SqliteResult<usize> {
+        let has_column = |name: &str| -> SqliteResult<bool> {
+            conn.query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('legacy_messages') WHERE name = ?1",
+                params![name],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|count| count > 0)
+        };
+        let reference_id = if has_column("reference_id")? {
+            "reference_id"
+        } else {
+            "NULL"
+        };
+        let created_at = if has_column("created_at")? {
+            "COALESCE(strftime('%Y-%m-%dT%H:%M:%SZ', created_at), created_at)"
+        } else {
+            "NULL"
+        };
+
+        let mut select = conn.prepare(&format!(
+            r"SELECT id, project_id, to_agent, from_agent, {reference_id}, content,
+                     COALESCE({created_at}, strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+              FROM legacy_messages
+              ORDER BY 7 ASC, rowid ASC"
+        ))?;
+        let mut insert = conn.prepare(
+            r"INSERT INTO messages (project_id, to_agent, from_agent, reference_id, content, created_at)
+              VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+
+        let mut new_ids = std::collections::HashMap::new();
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let old_id: String = row.get(0)?;
+            insert.execute(params![
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, String>(5)?,
+                row.get::<_, String>(6)?,
+            ])?;
+            new_ids.insert(old_id, conn.last_insert_rowid().to_string());
+        }
+
+        let mut update =
+            conn.prepare("UPDATE messages SET reference_id = ?2 WHERE reference_id = ?1")?;
+        for (old_id, new_id) in &new_ids {
+            update.execute(params![old_id, new_id])?;
+        }
+        Ok(new_ids.len())
+    }
+}
+
+/// Thread-safe database handle.
+///
+/// All operations are serialized through an internal mutex. This is appropriate
+/// for local-only use with low concurrency.
+#[derive(Clone)]
+pub struct Database {
+    conn: Arc<Mutex<Connection>>,
+    /// Time (in microseconds) the most recent operation spent waiting for the lock.
+    last_lock_wait_us: Arc<AtomicU64>,
+    health: Arc<HealthTracker>,
+    /// Location of the last good snapshot, written during maintenance.
+    snapshot_path: Option<PathBuf>,
+    /// Days consumed messages are kept in the archive; 0 disables archiving.
+    archive_days: Arc<AtomicU32>,
+    message_retention_days: Arc<AtomicU32>,
+    /// Most messages a recipient queue may hold for new sends; 0 is unlimited.
+    max_queue_messages: Arc<AtomicU64>,
+    /// Most content bytes a recipient queue may hold for new sends; 0 is unlimited.
+    max_queue_bytes: Arc<AtomicU64>,
+    waiters: Arc<QueueWaiters>,
+}
+
+#[allow(clippy::missing_errors_doc)]
+impl Database {
+    /// Creates a new database connection using the platform-specific default path.
+    ///
+    /// The database file is stored in the user's application data directory:
+    /// - Linux: `~/.local/share/mailbox-mcp/mailbox.db`
+    /// - macOS: `~/Library/Application Support/mailbox-mcp/mailbox.db`
+    /// - Windows: `%APPDATA%\mailbox-mcp\mailbox.db`
+    pub fn new() -> DbResult<Self> {
+        let path = Self::default_path()?;
+        Self::open(&path)
+    }
+
+    /// Opens a database at the specified path.
+    ///
+    /// Creates the parent directory if it doesn't exist.
+    /// Runs migrations to ensure the schema is up to date.
+    pub fn open(path: &Path) -> DbResult<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let conn = Connection::open(path)?;
+        if conn.is_readonly(rusqlite::DatabaseName::Main)? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("Database at {} is not writable", path.display()),
+            )
+            .into());
+        }
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        let mut snapshot_path = path.as_os_str().to_owned();
+        snapshot_path.push(".snapshot");
+        let db = Self {
+            conn: Arc::new(Mutex::new(conn)),
+            last_lock_wait_us: Arc::new(AtomicU64::new(0)),
+            health: Arc::new(HealthTracker::default()),
+            snapshot_path: Some(PathBuf::from(snapshot_path)),
+            archive_days: Arc::new(AtomicU32::new(0)),
+            message_retention_days: Arc::new(AtomicU32::new(0)),
+            max_queue_messages: Arc::new(AtomicU64::new(0)),
+            max_queue_bytes: Arc::new(AtomicU64::new(0)),
+            waiters: Arc::new(QueueWaiters::default()),
+        };
+        db.migrate()?;
+        Ok(db)
+    }
+
+    /// Logs every SQL statement executed on the primary connection at debug
+    /// level, with its duration and row counts. Bound parameter values are not logged.
+    pub fn enable_sql_trace(&self) {
+        let conn = self
+            .conn
+            .lock()
+            .expect("Database mutex poisoned - this indicates a bug");
+        crate::sql_trace::install(&conn);
+    }
+
+    /// Keeps consumed messages (received in at-most-once mode, or acknowledged)
+    /// in an archive for `days` days, so threads can still be reconstructed
+    /// after their messages were consumed. Archived messages past their
+    /// retention are deleted during maintenance.
+    pub fn enable_archive(&self, days: u32) {
+        self.archive_days.store(days, Ordering::Relaxed);
+    }
+
+    /// Makes [`purge_stale_messages`](Self::purge_stale_messages) remove
+    /// pending messages older than `days` days (0 keeps them forever).
+    pub fn set_message_retention(&self, days: u32) {
+        self.message_retention_days.store(days, Ordering::Relaxed);
+    }
+
+    /// Limits how many messages, and how many bytes of content, a recipient
+    /// queue may hold before sends to it fail with `QueueFull` (0 leaves a
+    /// limit off). Scheduled and system messages are never refused, but count
+    /// toward the quota.
+    pub fn set_queue_quota(&self, max_messages: u64, max_bytes: u64) {
+        self.max_queue_messages
+            .store(max_messages, Ordering::Relaxed);
+        self.max_queue_bytes.store(max_bytes, Ordering::Relaxed);
+    }
+
+    /// Returns the default fallback location, used when the primary database
+    /// path is unwritable: `mailbox-mcp/mailbox.db` inside the system temp directory.
+    #[must_use]
+    pub fn default_fallback_path() -> PathBuf {
+        std::env::temp_dir().join("mailbox-mcp/mailbox.db")
+    }
+
+    /// Returns the platform-specific default database path used by [`new`](Self::new).
+    pub fn default_path() -> DbResult<PathBuf> {
+        let home = std::env::var("HOME")
+            .or_else(|_| std::env::var("USERPROFILE"))
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "No home directory found")
+            })?;
+
+        #[cfg(target_os = "macos")]
+        let path = PathBuf::from(home).join("Library/Application Support/mailbox-mcp/mailbox.db");
+
+        #[cfg(target_os = "windows")]
+        let path = std::env::var("APPDATA")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(&home))
+            .join("mailbox-mcp/mailbox.db");
+
+        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+        let path = PathBuf::from(home).join(".local/share/mailbox-mcp/mailbox.db");
+
+        Ok(path)
+    }
+
+    /// Returns the schema changes opening the database at `path` would
+    /// apply, without changing (or creating) it.
+    pub fn migration_plan(path: &Path) -> DbResult<MigrationPlan> {
+        if !path.exists() {
+            return Ok(MigrationPlan {
+                current_version: 0,
+                pending: MIGRATIONS
+                    .iter()
+                    .map(|m| (m.version, m.description))
+                    .collect(),
+                imports_legacy_tables: false,
+                backup_path: None,
+            });
+        }
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        if let Some(e) = Self::schema_too_new(&conn)? {
+            return Err(e);
+        }
+        let current: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        let legacy = LegacySchema::detect(&conn)?;
+        Ok(MigrationPlan {
+            current_version: current,
+            pending: MIGRATIONS
+                .iter()
+                .filter(|m| m.version > current)
+                .map(|m| (m.version, m.description))
+                .collect(),
+            imports_legacy_tables: legacy.global_context
+                || legacy.project_context
+                || legacy.messages,
+            backup_path: Self::migration_backup_path(&conn)?,
+        })
+    }
+
+    /// Returns a `SchemaTooNew` error if a newer server has migrated the
+    /// database past [`SCHEMA_VERSION`], naming the release it requires.
+    fn schema_too_new(conn: &Connection) -> SqliteResult<Option<DbError>> {
+        let current: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if current <= SCHEMA_VERSION {
+            return Ok(None);
+        }
+        let required = match conn.query_row(
+            "SELECT value FROM schema_info WHERE key = 'min_server_version'",
+            [],
+            |row| row.get(0),
+        ) {
+            Ok(version) => version,
+            Err(rusqlite::Error::QueryReturnedNoRows) => "a newer version".to_string(),
+            Err(e) => return Err(e),
+        };
+        Ok(Some(DbError::SchemaTooNew {
+            found: current,
+            supported: SCHEMA_VERSION,
+            required,
+        }))
+    }
+
+    fn migrate(&self) -> DbResult<()> {
+        if let Some(e) = self.with_conn(Self::schema_too_new)? {
+            return Err(e);
+        }
+        self.backup_before_migration()?;
+        self.with_conn(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            let legacy = LegacySchema::detect(&tx)?;
+            if legacy.messages {
+                // Move the UUID-keyed table aside so the unified one can be created
+                tx.execute_batch("ALTER TABLE messages RENAME TO legacy_messages")?;
+            }
+
+            let current: u32 = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+            for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+                tracing::info!(
+                    "Applying schema migration {}: {}",
+                    migration.version,
+                    migration.description
+                );
+                tx.execute_batch(migration.sql)?;
+                tx.pragma_update(None, "user_version", migration.version)?;
+            }
+            if current < SCHEMA_VERSION {
+                tx.execute(
+                    r"INSERT INTO schema_info (key, value) VALUES ('min_server_version', ?1)
+                      ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+                    params![env!("CARGO_PKG_VERSION")],
+                )?;
+            }
+
+            legacy.import(&tx)?;
+            tx.commit()
+        })?;
+        self.check_consistency();
+        Ok(())
+    }
+
+    /// Copies an existing database aside before migrations change it, to
+    /// `<db>.v<schema version>.bak`, so a failed or unwanted upgrade can be undone
+    /// by restoring the copy.
+    fn backup_before_migration(&self) -> DbResult<()> {
+        let backup = self.with_conn(|conn| {
+            let Some(backup) = Self::migration_backup_path(conn)? else {
+                return Ok(None);
+            };
+            tracing::info!(
+                "Backing up database to {} before migrating",
+                backup.display()
+            );
+            let mut partial = backup.as_os_str().to_owned();
+            partial.push(".partial");
+            let partial = PathBuf::from(partial);
+            conn.backup(rusqlite::DatabaseName::Main, &partial, None)?;
+            Ok(Some((partial, backup)))
+        })?;
+        if let Some((partial, backup)) = backup {
+            std::fs::rename(partial, backup)?;
+        }
+        Ok(())
+    }
+
+    /// Returns where to back up the database before migrating it, or `None`
+    /// if it is up to date or holds nothing to protect (a fresh file).
+    fn migration_backup_path(conn: &Connection) -> SqliteResult<Option<PathBuf>> {
+        let current: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        let Some(path) = conn.path().filter(|path| !path.is_empty()) else {
+            return Ok(None);
+        };
+        let has_tables = conn
+            .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table'")?
+            .exists([])?;
+        Ok((current < SCHEMA_VERSION && has_tables)
+            .then(|| PathBuf::from(format!("{path}.v{current}.bak"))))
+    }
+
+    /// Runs SQLite's quick integrity check and enters degraded mode if it fails.
+    fn check_consistency(&self) {
+        let result = self.with_conn(|conn| {
+            conn.query_row("PRAGMA quick_check", [], |row| row.get::<_, String>(0))
+        });
+        match result {
+            Ok(status) if status == "ok" => {}
+            Ok(status) => self.enter_degraded(format!("consistency check failed: {status}"), true),
+            Err(e) => tracing::warn!("Startup consistency check could not run: {e}"),
+        }
+    }
+
+    /// Checks the invariants that hold between tables, returning those that
+    /// are broken (none on a healthy database).
+    ///
+    /// Unlike SQLite's own integrity checks, these catch rows left
+    /// inconsistent by a bug, e.g. a message listing an attachment that was
+    /// never stored or a metadata index entry outliving its message. Meant for
+    /// diagnostics and tests; every check scans whole tables.
+    pub fn check_invariants(&self) -> DbResult<Vec<InvariantViolation>> {
+        self.with_conn(Self::invariant_violations)
+    }
+
+    /// Like [`check_invariants`](Self::check_invariants), but on the database
+    /// at `path`, opened read-only: it is neither migrated nor backed up, and
+    /// the full-text index check (which SQLite runs as a write) is skipped.
+    ///
+    /// # Errors
+    /// - `SchemaOutdated` if the database needs migrations this server would apply
+    /// - `SchemaTooNew` if a newer server has migrated it
+    pub fn check_invariants_at(path: &Path) -> DbResult<Vec<InvariantViolation>> {
+        if !path.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No database at {}", path.display()),
+            )
+            .into());
+        }
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        if let Some(e) = Self::schema_too_new(&conn)? {
+            return Err(e);
+        }
+        let current: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if current < SCHEMA_VERSION {
+            return Err(DbError::SchemaOutdated {
+                found: current,
+                expected: SCHEMA_VERSION,
+            });
+        }
+        Ok(Self::invariant_violations(&conn)?)
+    }
+
+    fn invariant_violations(conn: &Connection) -> SqliteResult<Vec<InvariantViolation>> {
+        let mut broken = Vec::new();
+        let status: String = conn.query_row("PRAGMA quick_check", [], |row| row.get(0))?;
+        if status != "ok" {
+            broken.push(InvariantViolation {
+                invariant: "sqlite_integrity",
+                description: "SQLite's quick integrity check passes",
+                violations: 1,
+            });
+        }
+        for invariant in INVARIANTS {
+            let violations: u64 = conn.query_row(invariant.violations_sql, [], |row| row.get(0))?;
+            if violations > 0 {
+                broken.push(InvariantViolation {
+                    invariant: invariant.name,
+                    description: invariant.description,
+                    violations,
+                });
+            }
+        }
+        for index in FTS_INDEXES {
+            // Reports a mismatch as a corruption error, which would
+            // otherwise degrade the database
+            let result = conn.execute(
+                &format!("INSERT INTO {index} ({index}, rank) VALUES ('integrity-check', 1)"),
+                [],
+            );
+            match result {
+                Ok(_) => {}
+                Err(e) if e.sqlite_error_code() == Some(ErrorCode::DatabaseCorrupt) => {
+                    tracing::debug!("Full-text index {index} is inconsistent: {e}");
+                    broken.push(InvariantViolation {
+                        invariant: "fts_index",
+                        description: "full-text indexes match the messages they index",
+                        violations: 1,
+                    });
+                }
+                Err(e) if e.sqlite_error_code() == Some(ErrorCode::ReadOnly) => {
+                    tracing::debug!("Skipped checking full-text index {index}: read-only");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(broken)
+    }
+
+    fn with_conn<F, T>(&self, f: F) -> DbResult<T>
+    where
+        F: FnOnce(&Connection) -> SqliteResult<T>,
+    {
+        if let Some((reason, true)) = self.degradation() {
+            return Err(DbError::Degraded { reason });
+        }
+
+        let result = {
+            let started = Instant::now();
+            let conn = self
+                .conn
+                .lock()
+                .expect("Database mutex poisoned - this indicates a bug");
+            let waited = u64::try_from(started.elapsed().as_micros()).unwrap_or(u64::MAX);
+            self.last_lock_wait_us.store(waited, Ordering::Relaxed);
+            f(&conn)
+        };
+        self.record_outcome(result.as_ref().err());
+        result.map_err(DbError::from)
+    }
+
+    /// Runs a read-only operation.
+    ///
+    /// While the database is degraded, reads are served from the last good
+    /// snapshot instead of the primary database.
+    fn with_read_conn<F, T>(&self, f: F) -> DbResult<T>
+    where
+        F: FnOnce(&Connection) -> SqliteResult<T>,
+    {
+        let Some((reason, _)) = self.degradation() else {
+            return self.with_conn(f);
+        };
+        let snapshot = self
+            .snapshot_path
+            .as_deref()
+            .filter(|path| path.exists())
+            .ok_or(DbError::Degraded { reason })?;
+        let conn = Connection::open_with_flags(snapshot, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        f(&conn).map_err(DbError::from)
+    }
+
+    /// Runs a read-only operation of several queries in one read transaction,
+    /// so they all see the same state even while other processes write.
+    fn with_read_tx<F, T>(&self, f: F) -> DbResult<T>
+    where
+        F: FnOnce(&Connection) -> SqliteResult<T>,
+    {
+        self.with_read_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Deferred)?;
+            let result = f(&tx)?;
+            tx.commit()?;
+            Ok(result)
+        })
+    }
+
+    fn degradation(&self) -> Option<(String, bool)> {
+        self.health
+            .state
+            .lock()
+            .expect("Health mutex poisoned - this indicates a bug")
+            .clone()
+    }
+
+    /// Updates the health state after an operation on the primary database.
+    ///
+    /// Must be called without holding the connection lock, since state
+    /// transitions post alerts through the database.
+    fn record_outcome(&self, error: Option<&rusqlite::Error>) {
+        let code = error.and_then(rusqlite::Error::sqlite_error_code);
+        let transition = match code {
+            Some(ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase) => {
+                Some(Some(("database file is corrupted".to_string(), true)))
+            }
+            Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) => {
+                let failures = self.health.consecutive_busy.fetch_add(1, Ordering::Relaxed) + 1;
+                (failures >= MAX_CONSECUTIVE_BUSY)
+                    .then(|| Some(("database is persistently locked".to_string(), false)))
+            }
+            _ if error.is_none() => {
+                self.health.consecutive_busy.store(0, Ordering::Relaxed);
+                Some(None)
+            }
+            _ => None,
+        };
+        if let Some(next) = transition {
+            self.transition_health(next);
+        }
+    }
+
+    fn enter_degraded(&self, reason: String, corrupted: bool) {
+        self.transition_health(Some((reason, corrupted)));
+    }
+
+    fn transition_health(&self, next: Option<(String, bool)>) {
+        let previous = {
+            let mut state = self
+                .health
+                .state
+                .lock()
+                .expect("Health mutex poisoned - this indicates a bug");
+            if *state == next || state.as_ref().is_some_and(|(_, corrupted)| *corrupted) {
+                return;
+            }
+            std::mem::replace(&mut *state, next.clone())
+        };
+
+        match (previous, next) {
+            (_, Some((reason, _))) => {
+                tracing::error!("Database entered degraded mode: {reason}");
+                self.post_system_alert(&format!("Database entered degraded mode: {reason}"));
+            }
+            (Some((reason, _)), None) => {
+                tracing::info!("Database recovered from degraded mode ({reason})");
+                self.post_system_alert(&format!(
+                    "Database recovered from degraded mode ({reason})"
+                ));
+            }
+            (None, None) => {}
+        }
+    }
+
+    /// Returns the current health of the database.
+    #[must_use]
+    pub fn health(&self) -> DbHealth {
+        match self.degradation() {
+            None => DbHealth::Healthy,
+            Some((reason, corrupted)) => DbHealth::Degraded { reason, corrupted },
+        }
+    }
+
+    /// Posts an operational alert from the system agent to the operator queue.
+    ///
+    /// Alerts are written to the primary database even while it is degraded,
+    /// since they are typically raised exactly when it is misbehaving, and
+    /// bypass the queue quota. Failures are logged, with the alert, rather
+    /// than returned.
+    pub fn post_system_alert(&self, content: &str) {
+        let alert = NewMessage {
+            project_id: SYSTEM_PROJECT,
+            to_agent: OPERATOR_AGENT,
+            from_agent: SYSTEM_AGENT,
+            content,
+            ..NewMessage::default()
+        };
+        // Not through with_conn, which refuses writes once the database is
+        // marked corrupted, and whose outcome could trigger another transition
+        let result = {
+            let conn = self
+                .conn
+                .lock()
+                .expect("Database mutex poisoned - this indicates a bug");
+            Self::insert_message(&conn, &alert)
+        };
+        match result {
+            Ok(_) => self.waiters.notify(SYSTEM_PROJECT, OPERATOR_AGENT),
+            Err(e) => tracing::error!("Failed to post system alert ({content}): {e}"),
+        }
+    }
+
+    /// Returns how long the most recent operation waited to acquire the database lock.
+    ///
+    /// Used as a cheap indicator of lock contention for backpressure hints.
+    #[must_use]
+    pub fn last_lock_wait(&self) -> Duration {
+        Duration::from_micros(self.last_lock_wait_us.load(Ordering::Relaxed))
+    }
+
+    // -------------------------------------------------------------------------
+    // Context operations
+    // -------------------------------------------------------------------------
+
+    /// Sets a context value.
+    ///
+    /// If `project_id` is `None`, sets a global context value.
+    /// If `project_id` is `Some`, sets a project-scoped context value.
+    /// `confidential` classifies the key (replacing any earlier classification),
+    /// excluding it from project clones. With `ttl_secs` (capped at
+    /// [`MAX_CONTEXT_TTL_SECS`]), the value is treated as absent once that many
+    /// seconds have passed and deleted by maintenance; without, it never
+    /// expires, even if an earlier value did. `writer` identifies the caller
+    /// for leased keys (see [`context_lease`](Self::context_lease)). If the
+    /// value changes, agents watching the key are told (see
+    /// [`context_watch`](Self::context_watch)). With `dry_run`, every check
+    /// runs but the value isn't stored.
+    ///
+    /// # Errors
+    /// - `EmptyField` if key is empty
+    /// - `ContentTooLarge` if value exceeds 65,536 bytes
+    /// - `Conflict` if another writer holds a lease on the key
+    #[allow(clippy::too_many_arguments)] // one per context_set tool parameter
+    pub fn context_set(
+        &self,
+        project_id: Option<&str>,
+        key: &str,
+        value: &str,
+        confidential: bool,
+        ttl_secs: Option<u64>,
+        writer: Option<&str>,
+        dry_run: bool,
+    ) -> DbResult<()> {
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(DbError::EmptyField { field: "key" });
+        }
+        if value.len() > MAX_CONTEXT_VALUE_SIZE {
+            return Err(DbError::ContentTooLarge {
+                size: value.len(),
+                limit: MAX_CONTEXT_VALUE_SIZE,
+            });
+        }
+        let ttl = ttl_secs.map(|ttl| format!("+{} seconds", ttl.clamp(1, MAX_CONTEXT_TTL_SECS)));
+
+        let watchers = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            if let Some(e) = Self::lease_conflict(&tx, project_id, key, writer)? {
+                return Ok(Err(e));
+            }
+            let changed = Self::live_context_value(&tx, project_id, key)?.as_deref() != Some(value);
+            Self::write_context(
+                &tx,
+                project_id,
+                key,
+                value,
+                confidential,
+                writer,
+                ttl.as_deref(),
+            )?;
+            if dry_run {
+                return Ok(Ok(Vec::new()));
+            }
+            let watchers = if changed {
+                Self::notify_context_watchers(&tx, project_id, key, "set", writer)?
+            } else {
+                Vec::new()
+            };
+            tx.commit()?;
+            Ok(Ok(watchers))
+        })??;
+        self.wake_context_watchers(project_id, &watchers);
+        Ok(())
+    }
+
+    /// Sets several context values in one transaction: either all of them are
+    /// stored or, if any fails its checks, none is. Works like
+    /// [`context_set`](Self::context_set) otherwise, with `confidential` and
+    /// `ttl_secs` applying to every key; a key given twice gets its last value.
+    ///
+    /// # Errors
+    /// - `EmptyField` if no entries are given or a key is empty
+    /// - `TooManyKeys` if more than [`MAX_CONTEXT_BATCH_KEYS`] (100) entries are given
+    /// - `ContentTooLarge` if a value exceeds 65,536 bytes
+    /// - `Conflict` if another writer holds a lease on one of the keys
+    pub fn context_set_many(
+        &self,
+        project_id: Option<&str>,
+        entries: &[(&str, &str)],
+        confidential: bool,
+        ttl_secs: Option<u64>,
+        writer: Option<&str>,
+        dry_run: bool,
+    ) -> DbResult<()> {
+        if entries.is_empty() {
+            return Err(DbError::EmptyField { field: "entries" });
+        }
+        if entries.len() > MAX_CONTEXT_BATCH_KEYS {
+            return Err(DbError::TooManyKeys {
+                count: entries.len(),
+                limit: MAX_CONTEXT_BATCH_KEYS,
+            });
+        }
+        for (key, value) in entries {
+            if key.trim().is_empty() {
+                return Err(DbError::EmptyField { field: "key" });
+            }
+            if value.len() > MAX_CONTEXT_VALUE_SIZE {
+                return Err(DbError::ContentTooLarge {
+                    size: value.len(),
+                    limit: MAX_CONTEXT_VALUE_SIZE,
+                });
+            }
+        }
+        let ttl = ttl_secs.map(|ttl| format!("+{} seconds", ttl.clamp(1, MAX_CONTEXT_TTL_SECS)));
+
+        let watchers = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let mut watchers = Vec::new();
+            for (key, value) in entries {
+                let key = key.trim();
+                // Dropping the transaction undoes the keys already written
+                if let Some(e) = Self::lease_conflict(&tx, project_id, key, writer)? {
+                    return Ok(Err(e));
+                }
+                if Self::live_context_value(&tx, project_id, key)?.as_deref() != Some(*value) {
+                    watchers.extend(Self::notify_context_watchers(
+                        &tx, project_id, key, "set", writer,
+                    )?);
+                }
+                Self::write_context(
+                    &tx,
+                    project_id,
+                    key,
+                    value,
+                    confidential,
+                    writer,
+                    ttl.as_deref(),
+                )?;
+            }
+            if dry_run {
+                return Ok(Ok(Vec::new()));
+            }
+            tx.commit()?;
+            watchers.sort();
+            watchers.dedup();
+            Ok(Ok(watchers))
+        })??;
+        self.wake_context_watchers(project_id, &watchers);
+        Ok(())
+    }
+
+    /// Appends an entry to the list stored under a context key (a JSON array
+    /// of strings, created if the key doesn't exist), keeping at most
+    /// `max_entries` (capped at [`MAX_CONTEXT_LIST_ENTRIES`]): the oldest
+    /// entries are evicted first, also when the list would outgrow
+    /// [`MAX_CONTEXT_VALUE_SIZE`]. `writer` identifies the caller for leased
+    /// keys. The key's confidentiality and expiry are left as they are; an
+    /// expired value is replaced by a new list that doesn't expire. Agents
+    /// watching the key are told.
+    ///
+    /// Returns the list's length and the number of entries evicted.
+    ///
+    /// # Errors
+    /// - `EmptyField` if key is empty
+    /// - `ContentTooLarge` if the entry alone exceeds 65,536 bytes as a list
+    /// - `NotAList` if the key holds something other than a list of strings
+    /// - `Conflict` if another writer holds a lease on the key
+    pub fn context_append(
+        &self,
+        project_id: Option<&str>,
+        key: &str,
+        entry: &str,
+        max_entries: u32,
+        writer: Option<&str>,
+    ) -> DbResult<ContextAppend> {
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(DbError::EmptyField { field: "key" });
+        }
+        let max_entries = max_entries.clamp(1, MAX_CONTEXT_LIST_ENTRIES) as usize;
+
+        let (appended, watchers) = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            if let Some(e) = Self::lease_conflict(&tx, project_id, key, writer)? {
+                return Ok(Err(e));
+            }
+            let current = Self::live_context_value(&tx, project_id, key)?;
+            let mut entries: Vec<String> = match current {
+                Some(value) => match serde_json::from_str(&value) {
+                    Ok(entries) => entries,
+                    Err(_) => {
+                        return Ok(Err(DbError::NotAList {
+                            key: key.to_string(),
+                        }))
+                    }
+                },
+                None => Vec::new(),
+            };
+            entries.push(entry.to_string());
+
+            let mut evicted = entries.len().saturating_sub(max_entries);
+            entries.drain(..evicted);
+            let mut value = serde_json::to_string(&entries).unwrap_or_default();
+            while value.len() > MAX_CONTEXT_VALUE_SIZE {
+                if entries.len() == 1 {
+                    return Ok(Err(DbError::ContentTooLarge {
+                        size: value.len(),
+                        limit: MAX_CONTEXT_VALUE_SIZE,
+                    }));
+                }
+                entries.remove(0);
+                evicted += 1;
+                value = serde_json::to_string(&entries).unwrap_or_default();
+            }
+
+            // Update in place: global keys have a NULL project_id, which the
+            // primary key doesn't treat as a conflict.
+            let updated = tx.execute(
+                &format!(
+                    r"UPDATE context SET value = ?3, updated_by = ?4,
+                          expires_at = CASE WHEN {LIVE_CONTEXT} THEN expires_at END
+                      WHERE project_id IS ?1 AND key = ?2"
+                ),
+                params![project_id, key, value, writer],
+            )?;
+            if updated == 0 {
+                tx.execute(
+                    "INSERT INTO context (project_id, key, value, updated_by) VALUES (?1, ?2, ?3, ?4)",
+                    params![project_id, key, value, writer],
+                )?;
+            }
+            let watchers = Self::notify_context_watchers(&tx, project_id, key, "set", writer)?;
+            tx.commit()?;
+            Ok(Ok((
+                ContextAppend {
+                    entries: entries.len(),
+                    evicted,
+                },
+                watchers,
+            )))
+        })??;
+        self.wake_context_watchers(project_id, &watchers);
+        Ok(appended)
+    }
+
+    /// Gets a context value.
+    ///
+    /// Returns `Ok(Some(value))` if the key exists, `Ok(None)` if it doesn't
+    /// or its value has expired.
+    pub fn context_get(&self, project_id: Option<&str>, key: &str) -> DbResult<Option<String>> {
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT value FROM context WHERE project_id IS ?1 AND key = ?2 AND {LIVE_CONTEXT}"
+            ))?;
+            let result = stmt.query_row(params![project_id, key], |row| row.get(0));
+            match result {
+                Ok(value) => Ok(Some(value)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Gets several context values in one read transaction, so they are
+    /// consistent with each other. Returns the value of each key, in order,
+    /// or `None` where it doesn't exist or has expired.
+    ///
+    /// # Errors
+    /// - `TooManyKeys` if more than [`MAX_CONTEXT_BATCH_KEYS`] (100) keys are given
+    pub fn context_get_many(
+        &self,
+        project_id: Option<&str>,
+        keys: &[String],
+    ) -> DbResult<Vec<Option<String>>> {
+        if keys.len() > MAX_CONTEXT_BATCH_KEYS {
+            return Err(DbError::TooManyKeys {
+                count: keys.len(),
+                limit: MAX_CONTEXT_BATCH_KEYS,
+            });
+        }
+        self.with_read_tx(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT value FROM context WHERE project_id IS ?1 AND key = ?2 AND {LIVE_CONTEXT}"
+            ))?;
+            keys.iter()
+                .map(
+                    |key| match stmt.query_row(params![project_id, key], |row| row.get(0)) {
+                        Ok(value) => Ok(Some(value)),
+                        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                        Err(e) => Err(e),
+                    },
+                )
+                .collect()
+        })
+    }
+
+    /// Deletes a context value. `writer` identifies the caller for leased keys.
+    /// Agents watching the key are told.
+    ///
+    /// Returns `true` if a value was deleted, `false` if the key didn't exist
+    /// or its value had expired.
+    ///
+    /// # Errors
+    /// - `Conflict` if another writer holds a lease on the key
+    pub fn context_delete(
+        &self,
+        project_id: Option<&str>,
+        key: &str,
+        writer: Option<&str>,
+    ) -> DbResult<bool> {
+        let watchers = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            if let Some(e) = Self::lease_conflict(&tx, project_id, key, writer)? {
+                return Ok(Err(e));
+            }
+            // An expired value goes without counting as deleted by the writer
+            tx.execute(
+                &format!(
+                    "DELETE FROM context WHERE project_id IS ?1 AND key = ?2 AND NOT {LIVE_CONTEXT}"
+                ),
+                params![project_id, key],
+            )?;
+            let rows = tx.execute(
+                "DELETE FROM context WHERE project_id IS ?1 AND key = ?2",
+                params![project_id, key],
+            )?;
+            if rows == 0 {
+                tx.commit()?;
+                return Ok(Ok(None));
+            }
+            // The trigger recording the deletion doesn't know who deleted
+            tx.execute(
+                r"UPDATE context_history SET author = ?3
+                  WHERE id = (SELECT MAX(id) FROM context_history
+                              WHERE project_id IS ?1 AND key = ?2)",
+                params![project_id, key, writer],
+            )?;
+            let watchers = Self::notify_context_watchers(&tx, project_id, key, "deleted", writer)?;
+            tx.commit()?;
+            Ok(Ok(Some(watchers)))
+        })??;
+        let Some(watchers) = watchers else {
+            return Ok(false);
+        };
+        self.wake_context_watchers(project_id, &watchers);
+        Ok(true)
+    }
+
+    /// Stores a context value, replacing the key's value, classification,
+    /// writer and expiry (`ttl` is a SQLite time modifier such as
+    /// `+60 seconds`, or `None` for no expiry).
+    fn write_context(
+        conn: &Connection,
+        project_id: Option<&str>,
+        key: &str,
+        value: &str,
+        confidential: bool,
+        writer: Option<&str>,
+        ttl: Option<&str>,
+    ) -> SqliteResult<()> {
+        // Update in place: global keys have a NULL project_id, which the
+        // primary key doesn't treat as a conflict.
+        let updated = conn.execute(
+            r"UPDATE context SET value = ?3, confidential = ?4, updated_by = ?5,
+                  expires_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?6)
+              WHERE project_id IS ?1 AND key = ?2",
+            params![project_id, key, value, confidential, writer, ttl],
+        )?;
+        if updated == 0 {
+            conn.execute(
+                r"INSERT INTO context
+                    (project_id, key, value, confidential, updated_by, expires_at)
+                  VALUES (?1, ?2, ?3, ?4, ?5, strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?6))",
+                params![project_id, key, value, confidential, writer, ttl],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Returns the unexpired value stored under a key, if any.
+    fn live_context_value(
+        conn: &Connection,
+        project_id: Option<&str>,
+        key: &str,
+    ) -> SqliteResult<Option<String>> {
+        let result = conn.query_row(
+            &format!(
+                "SELECT value FROM context WHERE project_id IS ?1 AND key = ?2 AND {LIVE_CONTEXT}"
+            ),
+            params![project_id, key],
+            |row| row.get(0),
+        );
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns up to `limit` (default 20, capped at [`CONTEXT_HISTORY_VERSIONS`])
+    /// versions of a context key, newest first, including deletions. Each
+    /// write that changed the value is a version; maintenance keeps the last
+    /// [`CONTEXT_HISTORY_VERSIONS`] of each key.
+    pub fn context_history(
+        &self,
+        project_id: Option<&str>,
+        key: &str,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<ContextVersion>> {
+        let limit = limit.unwrap_or(20).min(CONTEXT_HISTORY_VERSIONS);
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(
+                r"SELECT value, author, changed_at FROM context_history
+                  WHERE project_id IS ?1 AND key = ?2
+                  ORDER BY id DESC
+                  LIMIT ?3",
+            )?;
+            let versions = stmt
+                .query_map(params![project_id, key.trim(), limit], |row| {
+                    Ok(ContextVersion {
+                        value: row.get(0)?,
+                        author: row.get(1)?,
+                        changed_at: row.get(2)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(versions)
+        })
+    }
+
+    /// Grants `owner` exclusive write access to a context key for `ttl_secs`
+    /// (capped at [`MAX_CONTEXT_LEASE_SECS`]): until the lease expires or is
+    /// released, [`context_set`](Self::context_set) and
+    /// [`context_delete`](Self::context_delete) fail with `Conflict` for other
+    /// writers. Reads are unaffected. The owner can renew its lease by taking
+    /// it again.
+    ///
+    /// # Errors
+    /// - `EmptyField` if key or owner is empty
+    /// - `Conflict` if another owner holds an unexpired lease on the key
+    pub fn context_lease(
+        &self,
+        project_id: Option<&str>,
+        key: &str,
+        owner: &str,
+        ttl_secs: u64,
+    ) -> DbResult<ContextLease> {
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(DbError::EmptyField { field: "key" });
+        }
+        if owner.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "owner" });
+        }
+        let ttl = format!("+{} seconds", ttl_secs.clamp(1, MAX_CONTEXT_LEASE_SECS));
+
+        self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            if let Some(e) = Self::lease_conflict(&tx, project_id, key, Some(owner))? {
+                return Ok(Err(e));
+            }
+            let expires_at = tx.query_row(
+                r"INSERT INTO context_leases (project_id, key, owner, expires_at)
+                  VALUES (COALESCE(?1, ''), ?2, ?3, strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?4))
+                  ON CONFLICT (project_id, key) DO UPDATE
+                  SET owner = excluded.owner, expires_at = excluded.expires_at
+                  RETURNING expires_at",
+                params![project_id, key, owner, ttl],
+                |row| row.get(0),
+            )?;
+            tx.commit()?;
+            Ok(Ok(ContextLease {
+                key: key.to_string(),
+                owner: owner.to_string(),
+                expires_at,
+            }))
+        })?
+    }
+
+    /// Releases `owner`'s lease on a context key, if it holds one.
+    ///
+    /// Returns `true` if a lease was released.
+    pub fn context_release(
+        &self,
+        project_id: Option<&str>,
+        key: &str,
+        owner: &str,
+    ) -> DbResult<bool> {
+        self.with_conn(|conn| {
+            let rows = conn.execute(
+                r"DELETE FROM context_leases
+                  WHERE project_id = COALESCE(?1, '') AND key = ?2 AND owner = ?3
+                    AND expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')",
+                params![project_id, key.trim(), owner],
+            )?;
+            Ok(rows > 0)
+        })
+    }
+
+    /// Returns a `Conflict` if someone other than `writer` holds an unexpired
+    /// lease on the key.
+    fn lease_conflict(
+        conn: &Connection,
+        project_id: Option<&str>,
+        key: &str,
+        writer: Option<&str>,
+    ) -> SqliteResult<Option<DbError>> {
+        let result = conn.query_row(
+            r"SELECT owner, expires_at FROM context_leases
+              WHERE project_id = COALESCE(?1, '') AND key = ?2 AND owner IS NOT ?3
+                AND expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')",
+            params![project_id, key, writer],
+            |row| {
+                Ok(DbError::Conflict {
+                    key: key.to_string(),
+                    owner: row.get(0)?,
+                    expires_at: row.get(1)?,
+                })
+            },
+        );
+        match result {
+            Ok(conflict) => Ok(Some(conflict)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Lists all context keys.
+    ///
+    /// If `project_id` is `None`, lists global context keys.
+    /// If `project_id` is `Some`, lists project-scoped context keys.
+    /// Keys whose values have expired are left out.
+    pub fn context_list(&self, project_id: Option<&str>) -> DbResult<Vec<String>> {
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT key FROM context WHERE project_id IS ?1 AND {LIVE_CONTEXT} ORDER BY key"
+            ))?;
+            let keys = stmt
+                .query_map(params![project_id], |row| row.get(0))?
+                .collect::<Result<Vec<String>, _>>()?;
+            Ok(keys)
+        })
+    }
+
+    /// Copies a project's current context into a new immutable snapshot.
+    /// Expired values are left out; restored values don't expire.
+    ///
+    /// # Errors
+    /// - `EmptyField` if project_id is empty
+    pub fn context_snapshot(
+        &self,
+        project_id: &str,
+        name: Option<&str>,
+    ) -> DbResult<ContextSnapshot> {
+        if project_id.trim().is_empty() {
+            return Err(DbError::EmptyField {
+                field: "project_id",
+            });
+        }
+        let name = name.map(str::trim).filter(|name| !name.is_empty());
+
+        self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let snapshot = tx.query_row(
+                &format!(
+                    r"INSERT INTO context_snapshots (project_id, name, key_count)
+                      SELECT ?1, ?2, COUNT(*) FROM context WHERE project_id = ?1 AND {LIVE_CONTEXT}
+                      RETURNING {}",
+                    ContextSnapshot::COLUMNS
+                ),
+                params![project_id, name],
+                ContextSnapshot::from_row,
+            )?;
+            tx.execute(
+                &format!(
+                    r"INSERT INTO context_snapshot_entries
+                        (snapshot_id, project_id, key, value, confidential)
+                      SELECT ?1, project_id, key, value, confidential FROM context
+                      WHERE project_id = ?2 AND {LIVE_CONTEXT}"
+                ),
+                params![snapshot.snapshot_id, project_id],
+            )?;
+            tx.commit()?;
+            Ok(snapshot)
+        })
+    }
+
+    /// Replaces a project's context with the contents of a snapshot: keys set
+    /// since the snapshot are removed, and changed or deleted keys get their
+    /// snapshotted values back. The snapshot itself is kept.
+    ///
+    /// Returns the snapshot, or `None` if it doesn't exist.
+    pub fn context_restore(&self, snapshot_id: i64) -> DbResult<Option<ContextSnapshot>> {
+        self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let snapshot = match tx.query_row(
+                &format!(
+                    "SELECT {} FROM context_snapshots WHERE id = ?1",
+                    ContextSnapshot::COLUMNS
+                ),
+                params![snapshot_id],
+                ContextSnapshot::from_row,
+            ) {
+                Ok(snapshot) => snapshot,
+                Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            tx.execute(
+                "DELETE FROM context WHERE project_id = ?1",
+                params![snapshot.project_id],
+            )?;
+            tx.execute(
+                r"INSERT INTO context (project_id, key, value, confidential)
+                  SELECT project_id, key, value, confidential FROM context_snapshot_entries
+                  WHERE snapshot_id = ?1",
+                params![snapshot_id],
+            )?;
+            tx.commit()?;
+            Ok(Some(snapshot))
+        })
+    }
+
+    /// Returns a project's context snapshots, oldest first.
+    pub fn context_snapshots(&self, project_id: &str) -> DbResult<Vec<ContextSnapshot>> {
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM context_snapshots WHERE project_id = ?1 ORDER BY id",
+                ContextSnapshot::COLUMNS
+            ))?;
+            let snapshots = stmt
+                .query_map(params![project_id], ContextSnapshot::from_row)?
+                .collect::<Result<_, _>>()?;
+            Ok(snapshots)
+        })
+    }
+
+    /// Registers interest in changes to a project context key, or with
+    /// `prefix` to every key starting with `key` (an empty prefix matches all
+    /// keys): whenever [`context_set`](Self::context_set) and friends change
+    /// a matching value or [`context_delete`](Self::context_delete) deletes
+    /// one, a notice from [`SYSTEM_AGENT`] is queued for `agent_id`. Changes
+    /// made by the agent itself aren't reported, nor are snapshot restores
+    /// and expiry. Watching again returns the existing watch.
+    ///
+    /// # Errors
+    /// - `EmptyField` if project_id or agent_id is empty, or key is empty without `prefix`
+    pub fn context_watch(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        key: &str,
+        prefix: bool,
+    ) -> DbResult<ContextWatch> {
+        for (field, value) in [("project_id", project_id), ("agent_id", agent_id)] {
+            if value.trim().is_empty() {
+                return Err(DbError::EmptyField { field });
+            }
+        }
+        let key = key.trim();
+        if key.is_empty() && !prefix {
+            return Err(DbError::EmptyField { field: "key" });
+        }
+
+        self.with_conn(|conn| {
+            conn.query_row(
+                &format!(
+                    r"INSERT INTO context_watches (project_id, agent_id, key, prefix)
+                      VALUES (?1, ?2, ?3, ?4)
+                      ON CONFLICT (project_id, agent_id, key, prefix)
+                      DO UPDATE SET created_at = created_at
+                      RETURNING {}",
+                    ContextWatch::COLUMNS
+                ),
+                params![project_id, agent_id, key, prefix],
+                ContextWatch::from_row,
+            )
+        })
+    }
+
+    /// Removes a watch registered by [`context_watch`](Self::context_watch).
+    ///
+    /// Returns `true` if the watch was removed, `false` if there was none.
+    pub fn context_unwatch(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        key: &str,
+        prefix: bool,
+    ) -> DbResult<bool> {
+        self.with_conn(|conn| {
+            let rows = conn.execute(
+                r"DELETE FROM context_watches
+                  WHERE project_id = ?1 AND agent_id = ?2 AND key = ?3 AND prefix = ?4",
+                params![project_id, agent_id, key.trim(), prefix],
+            )?;
+            Ok(rows > 0)
+        })
+    }
+
+    /// Lists a project's context watches, optionally only those of one agent,
+    /// ordered by agent and key.
+    pub fn context_watches(
+        &self,
+        project_id: &str,
+        agent_id: Option<&str>,
+    ) -> DbResult<Vec<ContextWatch>> {
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                r"SELECT {} FROM context_watches
+                  WHERE project_id = ?1 AND (?2 IS NULL OR agent_id = ?2)
+                  ORDER BY agent_id, key, prefix",
+                ContextWatch::COLUMNS
+            ))?;
+            let watches = stmt
+                .query_map(params![project_id, agent_id], ContextWatch::from_row)?
+                .collect::<Result<_, _>>()?;
+            Ok(watches)
+        })
+    }
+
+    /// Queues a notice of a change (`set` or `deleted`) to a project context
+    /// key for every agent watching it, other than the author, and returns
+    /// those agents. Global keys can't be watched.
+    fn notify_context_watchers(
+        conn: &Connection,
+        project_id: Option<&str>,
+        key: &str,
+        change: &str,
+        author: Option<&str>,
+    ) -> SqliteResult<Vec<String>> {
+        let Some(project_id) = project_id else {
+            return Ok(Vec::new());
+        };
+        let watchers = conn
+            .prepare(
+                r"SELECT DISTINCT agent_id FROM context_watches
+                  WHERE project_id = ?1
+                    AND (key = ?2 OR (prefix AND substr(?2, 1, length(key)) = key))
+                    AND agent_id IS NOT ?3
+                  ORDER BY agent_id",
+            )?
+            .query_map(params![project_id, key, author], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        if watchers.is_empty() {
+            return Ok(watchers);
+        }
+
+        let mut metadata = serde_json::Map::new();
+        metadata.insert("context_watch".to_string(), serde_json::Value::Bool(true));
+        metadata.insert("key".to_string(), key.into());
+        metadata.insert("change".to_string(), change.into());
+        if let Some(author) = author {
+            metadata.insert("author".to_string(), author.into());
+        }
+        let content = match author {
+            Some(author) => format!("Context key '{key}' was {change} by {author}"),
+            None => format!("Context key '{key}' was {change}"),
+        };
+        for agent_id in &watchers {
+            Self::insert_message(
+                conn,
+                &NewMessage {
+                    project_id,
+                    to_agent: agent_id,
+                    from_agent: SYSTEM_AGENT,
+                    content: &content,
+                    metadata: Some(&metadata),
+                    ..NewMessage::default()
+                },
+            )?;
+        }
+        Ok(watchers)
+    }
+
+    /// Wakes the agents told about a context change by
+    /// [`notify_context_watchers`](Self::notify_context_watchers).
+    fn wake_context_watchers(&self, project_id: Option<&str>, watchers: &[String]) {
+        if let Some(project_id) = project_id {
+            for agent_id in watchers {
+                self.waiters.notify(project_id, agent_id);
+            }
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // Message schemas
+    // -------------------------------------------------------------------------
+
+    /// Registers a JSON Schema under a name within a project, replacing any
+    /// schema already registered under that name. Senders can then have their
+    /// content validated against it (see [`NewMessage::schema`]).
+    ///
+    /// # Errors
+    /// - `EmptyField` if project_id or name is empty
+    /// - `ContentTooLarge` if the serialized schema exceeds 65,536 bytes
+    /// - `InvalidSchema` if the schema isn't a valid JSON Schema
+    pub fn register_message_schema(
+        &self,
+        project_id: &str,
+        name: &str,
+        schema: &serde_json::Value,
+    ) -> DbResult<MessageSchema> {
+        if project_id.trim().is_empty() {
+            return Err(DbError::EmptyField {
+                field: "project_id",
+            });
+        }
+        if name.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "name" });
+        }
+        let text = schema.to_string();
+        if text.len() > MAX_SCHEMA_SIZE {
+            return Err(DbError::ContentTooLarge {
+                size: text.len(),
+                limit: MAX_SCHEMA_SIZE,
+            });
+        }
+        schema::compile(schema).map_err(|reason| DbError::InvalidSchema { reason })?;
+
+        self.with_conn(|conn| {
+            conn.query_row(
+                &format!(
+                    r"INSERT INTO message_schemas (project_id, name, schema) VALUES (?1, ?2, ?3)
+                      ON CONFLICT (project_id, name) DO UPDATE SET
+                          schema = excluded.schema,
+                          updated_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+                      RETURNING {}",
+                    MessageSchema::COLUMNS
+                ),
+                params![project_id, name, text],
+                MessageSchema::from_row,
+            )
+        })
+    }
+
+    /// Returns a registered schema, or `None` if there is none by that name.
+    pub fn message_schema(&self, project_id: &str, name: &str) -> DbResult<Option<MessageSchema>> {
+        self.with_read_conn(|conn| {
+            let result = conn.query_row(
+                &format!(
+                    "SELECT {} FROM message_schemas WHERE project_id = ?1 AND name = ?2",
+                    MessageSchema::COLUMNS
+                ),
+                params![project_id, name],
+                MessageSchema::from_row,
+            );
+            match result {
+                Ok(schema) => Ok(Some(schema)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Lists a project's registered schemas, ordered by name.
+    pub fn message_schemas(&self, project_id: &str) -> DbResult<Vec<MessageSchema>> {
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM message_schemas WHERE project_id = ?1 ORDER BY name",
+                MessageSchema::COLUMNS
+            ))?;
+            let schemas = stmt
+                .query_map(params![project_id], MessageSchema::from_row)?
+                .collect::<Result<_, _>>()?;
+            Ok(schemas)
+        })
+    }
+
+    // -------------------------------------------------------------------------
+    // Message templates
+    // -------------------------------------------------------------------------
+
+    /// Registers a message template under a name within a project, replacing
+    /// any template already registered under that name. See [`template`] for
+    /// the placeholder syntax.
+    ///
+    /// # Errors
+    /// - `EmptyField` if project_id, name or template is empty
+    /// - `ContentTooLarge` if the template exceeds 65,536 bytes
+    pub fn register_message_template(
+        &self,
+        project_id: &str,
+        name: &str,
+        template: &str,
+    ) -> DbResult<MessageTemplate> {
+        if project_id.trim().is_empty() {
+            return Err(DbError::EmptyField {
+                field: "project_id",
+            });
+        }
+        if name.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "name" });
+        }
+        if template.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "template" });
+        }
+        if template.len() > MAX_TEMPLATE_SIZE {
+            return Err(DbError::ContentTooLarge {
+                size: template.len(),
+                limit: MAX_TEMPLATE_SIZE,
+            });
+        }
+
+        self.with_conn(|conn| {
+            conn.query_row(
+                &format!(
+                    r"INSERT INTO message_templates (project_id, name, template) VALUES (?1, ?2, ?3)
+                      ON CONFLICT (project_id, name) DO UPDATE SET
+                          template = excluded.template,
+                          updated_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+                      RETURNING {}",
+                    MessageTemplate::COLUMNS
+                ),
+                params![project_id, name, template],
+                MessageTemplate::from_row,
+            )
+        })
+    }
+
+    /// Returns a registered template, or `None` if there is none by that name.
+    pub fn message_template(
+        &self,
+        project_id: &str,
+        name: &str,
+    ) -> DbResult<Option<MessageTemplate>> {
+        self.with_read_conn(|conn| {
+            let result = conn.query_row(
+                &format!(
+                    "SELECT {} FROM message_templates WHERE project_id = ?1 AND name = ?2",
+                    MessageTemplate::COLUMNS
+                ),
+                params![project_id, name],
+                MessageTemplate::from_row,
+            );
+            match result {
+                Ok(template) => Ok(Some(template)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Lists a project's registered templates, ordered by name.
+    pub fn message_templates(&self, project_id: &str) -> DbResult<Vec<MessageTemplate>> {
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM message_templates WHERE project_id = ?1 ORDER BY name",
+                MessageTemplate::COLUMNS
+            ))?;
+            let templates = stmt
+                .query_map(params![project_id], MessageTemplate::from_row)?
+                .collect::<Result<_, _>>()?;
+            Ok(templates)
+        })
+    }
+
+    /// Renders a registered template with the given variables, returning the
+    /// message content. Variables the template doesn't use are ignored.
+    ///
+    /// # Errors
+    /// - `UnknownTemplate` if no template is registered under the name
+    /// - `MissingTemplateVariable` if a placeholder has no value
+    pub fn render_template(
+        &self,
+        project_id: &str,
+        name: &str,
+        variables: &serde_json::Map<String, serde_json::Value>,
+    ) -> DbResult<String> {
+        let registered =
+            self.message_template(project_id, name)?
+                .ok_or_else(|| DbError::UnknownTemplate {
+                    project_id: project_id.to_string(),
+                    name: name.to_string(),
+                })?;
+        template::render(&registered.template, variables)
+            .map_err(|name| DbError::MissingTemplateVariable { name })
+    }
+
+    // -------------------------------------------------------------------------
+    // Attachments
+    // -------------------------------------------------------------------------
+
+    /// Stores binary data in a project, to be referenced by hash from messages
+    /// (see [`NewMessage::attachments`]). Storing the same data again returns
+    /// the existing attachment.
+    ///
+    /// Attachments no stored message references are removed by maintenance
+    /// once they are older than 24 hours.
+    ///
+    /// # Errors
+    /// - `EmptyField` if project_id or the data is empty
+    /// - `ContentTooLarge` if the data exceeds 10,485,760 bytes
+    pub fn store_attachment(
+        &self,
+        project_id: &str,
+        data: &[u8],
+        content_type: Option<&str>,
+    ) -> DbResult<Attachment> {
+        if project_id.trim().is_empty() {
+            return Err(DbError::EmptyField {
+                field: "project_id",
+            });
+        }
+        if data.is_empty() {
+            return Err(DbError::EmptyField { field: "data" });
+        }
+        if data.len() > MAX_ATTACHMENT_SIZE {
+            return Err(DbError::ContentTooLarge {
+                size: data.len(),
+                limit: MAX_ATTACHMENT_SIZE,
+            });
+        }
+        let hash = hex(&Sha256::digest(data));
+        let content_type = content_type.map(str::trim).filter(|ct| !ct.is_empty());
+
+        self.with_conn(|conn| {
+            // The no-op update makes RETURNING yield the existing row on conflict
+            conn.query_row(
+                &format!(
+                    r"INSERT INTO attachments (project_id, hash, content_type, size, data)
+                      VALUES (?1, ?2, ?3, ?4, ?5)
+                      ON CONFLICT (project_id, hash) DO UPDATE SET hash = excluded.hash
+                      RETURNING {}",
+                    Attachment::COLUMNS
+                ),
+                params![project_id, hash, content_type, data.len(), data],
+                Attachment::from_row,
+            )
+        })
+    }
+
+    /// Returns an attachment and its data, or `None` if the project has no
+    /// attachment with that hash.
+    pub fn attachment(
+        &self,
+        project_id: &str,
+        hash: &str,
+    ) -> DbResult<Option<(Attachment, Vec<u8>)>> {
+        self.with_read_conn(|conn| {
+            let result = conn.query_row(
+                &format!(
+                    "SELECT {}, data FROM attachments WHERE project_id = ?1 AND hash = ?2",
+                    Attachment::COLUMNS
+                ),
+                params![project_id, hash],
+                |row| Ok((Attachment::from_row(row)?, row.get(5)?)),
+            );
+            match result {
+                Ok(attachment) => Ok(Some(attachment)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Starts a chunked upload of message content too large to send in one
+    /// call, returning its ID. Append the content with
+    /// [`append_chunk`](Self::append_chunk) and read it back with
+    /// [`upload_content`](Self::upload_content). Uploads never finished are
+    /// removed by maintenance after [`UPLOAD_RETENTION_HOURS`].
+    ///
+    /// # Errors
+    /// - `EmptyField` if project_id is empty
+    pub fn begin_upload(&self, project_id: &str) -> DbResult<String> {
+        if project_id.trim().is_empty() {
+            return Err(DbError::EmptyField {
+                field: "project_id",
+            });
+        }
+        let upload_id = format!("upl_{}", hex(&random_bytes::<16>()));
+        self.with_conn(|conn| {
+            conn.execute(
+                "INSERT INTO message_uploads (id, project_id) VALUES (?1, ?2)",
+                params![upload_id, project_id],
+            )
+        })?;
+        Ok(upload_id)
+    }
+
+    /// Appends the next chunk of content to an upload.
+    ///
+    /// # Errors
+    /// - `EmptyField` if the chunk is empty
+    /// - `UnknownUpload` if the upload doesn't exist
+    /// - `ContentTooLarge` if the chunk exceeds 1,048,576 bytes, or the upload
+    ///   would exceed [`MAX_UPLOAD_SIZE`]
+    pub fn append_chunk(&self, upload_id: &str, data: &str) -> DbResult<UploadProgress> {
+        if data.is_empty() {
+            return Err(DbError::EmptyField { field: "data" });
+        }
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(DbError::ContentTooLarge {
+                size: data.len(),
+                limit: MAX_MESSAGE_SIZE,
+            });
+        }
+        self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let upload = tx.query_row(
+                r"SELECT u.project_id, COUNT(c.chunk), COALESCE(SUM(length(CAST(c.data AS BLOB))), 0)
+                  FROM message_uploads u
+                  LEFT JOIN message_upload_chunks c ON c.upload_id = u.id
+                  WHERE u.id = ?1
+                  GROUP BY u.id",
+                params![upload_id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?, row.get::<_, u64>(2)?)),
+            );
+            let (project_id, chunks, size) = match upload {
+                Ok(upload) => upload,
+                Err(rusqlite::Error::QueryReturnedNoRows) => {
+                    return Ok(Err(DbError::UnknownUpload {
+                        upload_id: upload_id.to_string(),
+                    }))
+                }
+                Err(e) => return Err(e),
+            };
+            let size = size + data.len() as u64;
+            if size > MAX_UPLOAD_SIZE as u64 {
+                return Ok(Err(DbError::ContentTooLarge {
+                    size: usize::try_from(size).unwrap_or(usize::MAX),
+                    limit: MAX_UPLOAD_SIZE,
+                }));
+            }
+            tx.execute(
+                r"INSERT INTO message_upload_chunks (upload_id, project_id, chunk, data)
+                  VALUES (?1, ?2, ?3, ?4)",
+                params![upload_id, project_id, chunks, data],
+            )?;
+            tx.commit()?;
+            Ok(Ok(UploadProgress {
+                upload_id: upload_id.to_string(),
+                chunks: chunks + 1,
+                size,
+            }))
+        })?
+    }
+
+    /// Returns an upload's project and its chunks joined in order, or `None`
+    /// if the upload doesn't exist. The upload is kept until
+    /// [`discard_upload`](Self::discard_upload) is called.
+    pub fn upload_content(&self, upload_id: &str) -> DbResult<Option<Upload>> {
+        self.with_read_conn(|conn| {
+            let project_id = conn.query_row(
+                "SELECT project_id FROM message_uploads WHERE id = ?1",
+                params![upload_id],
+                |row| row.get(0),
+            );
+            let project_id = match project_id {
+                Ok(project_id) => project_id,
+                Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let mut stmt = conn.prepare(
+                "SELECT data FROM message_upload_chunks WHERE upload_id = ?1 ORDER BY chunk",
+            )?;
+            let content = stmt
+                .query_map(params![upload_id], |row| row.get::<_, String>(0))?
+                .collect::<Result<String, _>>()?;
+            Ok(Some(Upload {
+                project_id,
+                content,
+            }))
+        })
+    }
+
+    /// Deletes an upload and its chunks, returning whether it existed.
+    pub fn discard_upload(&self, upload_id: &str) -> DbResult<bool> {
+        self.with_conn(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute(
+                "DELETE FROM message_upload_chunks WHERE upload_id = ?1",
+                params![upload_id],
+            )?;
+            let deleted = tx.execute(
+                "DELETE FROM message_uploads WHERE id = ?1",
+                params![upload_id],
+            )?;
+            tx.commit()?;
+            Ok(deleted > 0)
+        })
+    }
+
+    // -------------------------------------------------------------------------
+    // Message operations
+    // -------------------------------------------------------------------------
+
+    /// Sends a message to an agent's queue.
+    ///
+    /// Returns the message ID. Shorthand for [`send`](Self::send) without a trace ID.
+    ///
+    /// # Errors
+    /// - `EmptyField` if `project_id` or `to_agent` is empty (Note: `from_agent` is validated
+    ///   at the API layer, which defaults empty values to "anonymous")
+    /// - `ContentTooLarge` if content exceeds 1,048,576 bytes
+    /// - `UnknownSchema` if the message names a schema that isn't registered
+    /// - `SchemaViolation` if the content isn't valid against the named schema
+    /// - `TooManyAttachments` if the message references more than 16 attachments
+    /// - `TooManyTags` if the message carries more than 8 tags
+    /// - `UnknownAttachment` if a referenced attachment isn't stored in the project
+    /// - `QueueFull` if the recipient's queue is at its quota (see
+    ///   [`set_queue_quota`](Self::set_queue_quota))
+    pub fn send_message(
+        &self,
+        project_id: &str,
+        to_agent: &str,
+        from_agent: &str,
+        content: &str,
+        reference_id: Option<&str>,
+    ) -> DbResult<String> {
+        self.send(&NewMessage {
+            project_id,
+            to_agent,
+            from_agent,
+            content,
+            reference_id,
+            ..NewMessage::default()
+        })
+    }
+
+    /// Sends a message to an agent's queue.
+    ///
+    /// Returns the message ID. If the message has no trace ID but references
+    /// another message that has one, the trace ID is inherited from it.
+    ///
+    /// # Errors
+    /// Same as [`send_message`](Self::send_message).
+    pub fn send(&self, message: &NewMessage<'_>) -> DbResult<String> {
+        if message.to_agent.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "to_agent" });
+        }
+        Self::validate(message)?;
+        self.check_schema(message)?;
+        self.check_attachments(message)?;
+        let id = self.with_conn(|conn| {
+            if let Some(e) = self.queue_full(conn, message)? {
+                return Ok(Err(e));
+            }
+            Self::insert_message(conn, message).map(Ok)
+        })??;
+        self.waiters.notify(message.project_id, message.to_agent);
+        Ok(id)
+    }
+
+    /// Checks that a message could be sent, without sending it: runs the
+    /// validation, schema, attachment and queue quota checks of
+    /// [`send`](Self::send).
+    ///
+    /// # Errors
+    /// Same as [`send_message`](Self::send_message).
+    pub fn check_send(&self, message: &NewMessage<'_>) -> DbResult<()> {
+        if message.to_agent.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "to_agent" });
+        }
+        Self::validate(message)?;
+        self.check_schema(message)?;
+        self.check_attachments(message)?;
+        match self.with_read_conn(|conn| self.queue_full(conn, message))? {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the distinct IDs of a multi-recipient send, in the given order.
+    ///
+    /// # Errors
+    /// - `EmptyField` (naming `field`) if `ids` is empty or contains an empty ID
+    /// - `TooManyRecipients` if there are more than 100 distinct IDs
+    pub fn distinct_recipients<'a>(
+        ids: &'a [String],
+        field: &'static str,
+    ) -> DbResult<Vec<&'a str>> {
+        let mut recipients: Vec<&str> = Vec::with_capacity(ids.len());
+        for id in ids {
+            if id.trim().is_empty() {
+                return Err(DbError::EmptyField { field });
+            }
+            if !recipients.contains(&id.as_str()) {
+                recipients.push(id);
+            }
+        }
+        if recipients.is_empty() {
+            return Err(DbError::EmptyField { field });
+        }
+        if recipients.len() > MAX_MESSAGE_RECIPIENTS {
+            return Err(DbError::TooManyRecipients {
+                count: recipients.len(),
+                limit: MAX_MESSAGE_RECIPIENTS,
+            });
+        }
+        Ok(recipients)
+    }
+
+    /// Sends a copy of a message to each of the given agents. The message's
+    /// `to_agent` is ignored, and repeated recipients get a single copy. All
+    /// copies are inserted atomically.
+    ///
+    /// Returns the recipients and their copies' message IDs, in the given order.
+    ///
+    /// # Errors
+    /// Same as [`send_message`](Self::send_message), plus
+    /// - `EmptyField` if `to_agents` is empty or contains an empty agent ID
+    /// - `TooManyRecipients` if there are more than 100 distinct recipients
+    pub fn send_to_agents(
+        &self,
+        message: &NewMessage<'_>,
+        to_agents: &[String],
+    ) -> DbResult<Vec<Delivery>> {
+        let recipients = Self::distinct_recipients(to_agents, "to_agents")?;
+        Self::validate(message)?;
+        self.check_schema(message)?;
+        self.check_attachments(message)?;
+        let deliveries = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let mut deliveries = Vec::with_capacity(recipients.len());
+            for &agent_id in &recipients {
+                let copy = NewMessage {
+                    to_agent: agent_id,
+                    ..*message
+                };
+                if let Some(e) = self.queue_full(&tx, &copy)? {
+                    return Ok(Err(e));
+                }
+                deliveries.push(Delivery {
+                    agent_id: agent_id.to_string(),
+                    message_id: Self::insert_message(&tx, &copy)?,
+                });
+            }
+            tx.commit()?;
+            Ok(Ok(deliveries))
+        })??;
+        for agent_id in recipients {
+            self.waiters.notify(message.project_id, agent_id);
+        }
+        Ok(deliveries)
+    }
+
+    /// Sends a copy of a message to its recipient's queue in each of the given
+    /// projects (cross-posting). The message's `project_id` is ignored, and
+    /// repeated projects get a single copy. Schemas and attachments are looked
+    /// up in each project. All copies are inserted atomically.
+    ///
+    /// Returns the projects and their copies' message IDs, in the given order.
+    ///
+    /// # Errors
+    /// Same as [`send_message`](Self::send_message), plus
+    /// - `EmptyField` if `project_ids` is empty or contains an empty project ID
+    /// - `TooManyRecipients` if there are more than 100 distinct projects
+    pub fn send_to_projects(
+        &self,
+        message: &NewMessage<'_>,
+        project_ids: &[String],
+    ) -> DbResult<Vec<ProjectDelivery>> {
+        let projects = Self::distinct_recipients(project_ids, "project_ids")?;
+        if message.to_agent.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "to_agent" });
+        }
+        let copies: Vec<NewMessage<'_>> = projects
+            .iter()
+            .map(|&project_id| NewMessage {
+                project_id,
+                ..*message
+            })
+            .collect();
+        for copy in &copies {
+            Self::validate(copy)?;
+            self.check_schema(copy)?;
+            self.check_attachments(copy)?;
+        }
+        let deliveries = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let mut deliveries = Vec::with_capacity(copies.len());
+            for copy in &copies {
+                if let Some(e) = self.queue_full(&tx, copy)? {
+                    return Ok(Err(e));
+                }
+                deliveries.push(ProjectDelivery {
+                    project_id: copy.project_id.to_string(),
+                    message_id: Self::insert_message(&tx, copy)?,
+                });
+            }
+            tx.commit()?;
+            Ok(Ok(deliveries))
+        })??;
+        for project_id in projects {
+            self.waiters.notify(project_id, message.to_agent);
+        }
+        Ok(deliveries)
+    }
+
+    /// Returns a handle that is notified whenever this process sends a message
+    /// to the queue. Create the [`Notified`](tokio::sync::Notified) future
+    /// before checking the queue, so no send is missed in between.
+    pub fn subscribe_queue(&self, project_id: &str, agent_id: &str) -> Arc<tokio::sync::Notify> {
+        self.waiters.subscribe(project_id, agent_id)
+    }
+
+    /// Delivers a copy of a message to every agent known in its project,
+    /// except the sender.
+    ///
+    /// Agents are known if they currently have queued messages, were sent
+    /// messages within the queue history retention period, or have queue
+    /// settings. The message's `to_agent` is ignored. All copies are inserted
+    /// atomically.
+    ///
+    /// Returns the recipients and their copies' message IDs.
+    ///
+    /// # Errors
+    /// Same as [`send_message`](Self::send_message), except that `to_agent` isn't required.
+    pub fn broadcast_message(&self, message: &NewMessage<'_>) -> DbResult<Vec<Delivery>> {
+        self.send_to_matching(message, "*")
+    }
+
+    /// Like [`broadcast_message`](Self::broadcast_message), but only delivers
+    /// to the known agents whose IDs match a glob `pattern`: `*` matches any
+    /// run of characters, `?` any single one, and `[...]` one of a set, case
+    /// sensitively (e.g. `reviewer-*`). Nothing is sent if no agent matches.
+    ///
+    /// # Errors
+    /// Same as [`broadcast_message`](Self::broadcast_message).
+    pub fn send_to_matching(
+        &self,
+        message: &NewMessage<'_>,
+        pattern: &str,
+    ) -> DbResult<Vec<Delivery>> {
+        Self::validate(message)?;
+        self.check_schema(message)?;
+        self.check_attachments(message)?;
+        let deliveries = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let recipients =
+                Self::known_agents(&tx, message.project_id, pattern, message.from_agent)?;
+
+            let mut deliveries = Vec::with_capacity(recipients.len());
+            for agent_id in recipients {
+                let copy = NewMessage {
+                    to_agent: &agent_id,
+                    ..*message
+                };
+                if let Some(e) = self.queue_full(&tx, &copy)? {
+                    return Ok(Err(e));
+                }
+                let message_id = Self::insert_message(&tx, &copy)?;
+                deliveries.push(Delivery {
+                    agent_id,
+                    message_id,
+                });
+            }
+            tx.commit()?;
+            Ok(Ok(deliveries))
+        })??;
+        for delivery in &deliveries {
+            self.waiters.notify(message.project_id, &delivery.agent_id);
+        }
+        Ok(deliveries)
+    }
+
+    /// Returns the known agents of a project whose IDs match a glob pattern
+    /// (see [`send_to_matching`](Self::send_to_matching)), except `exclude`,
+    /// in order.
+    pub fn matching_agents(
+        &self,
+        project_id: &str,
+        pattern: &str,
+        exclude: &str,
+    ) -> DbResult<Vec<String>> {
+        self.with_read_conn(|conn| Self::known_agents(conn, project_id, pattern, exclude))
+    }
+
+    fn known_agents(
+        conn: &Connection,
+        project_id: &str,
+        pattern: &str,
+        exclude: &str,
+    ) -> SqliteResult<Vec<String>> {
+        let mut stmt = conn.prepare(
+            r"SELECT agent FROM (
+                  SELECT to_agent AS agent FROM messages WHERE project_id = ?1
+                  UNION SELECT agent_id FROM queue_stats WHERE project_id = ?1
+                  UNION SELECT agent_id FROM queue_settings WHERE project_id = ?1
+              )
+              WHERE agent != ?2 AND agent GLOB ?3
+              ORDER BY agent",
+        )?;
+        let agents = stmt
+            .query_map(params![project_id, exclude, pattern], |row| row.get(0))?
+            .collect();
+        agents
+    }
+
+    /// Returns whether an agent ID is a glob pattern addressing several
+    /// agents (see [`send_to_matching`](Self::send_to_matching)).
+    #[must_use]
+    pub fn is_agent_pattern(agent_id: &str) -> bool {
+        agent_id.contains(['*', '?', '['])
+    }
+
+    /// Sends a sequence of messages as one thread: each message after the
+    /// first references its predecessor, unless it already has a reference.
+    /// All messages are validated first and inserted atomically.
+    ///
+    /// Returns the message IDs in order.
+    ///
+    /// # Errors
+    /// Same as [`send_message`](Self::send_message), for any of the messages.
+    pub fn send_thread(&self, messages: &[NewMessage<'_>]) -> DbResult<Vec<String>> {
+        for message in messages {
+            if message.to_agent.trim().is_empty() {
+                return Err(DbError::EmptyField { field: "to_agent" });
+            }
+            Self::validate(message)?;
+            self.check_schema(message)?;
+            self.check_attachments(message)?;
+        }
+        let ids = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let mut ids: Vec<String> = Vec::with_capacity(messages.len());
+            for message in messages {
+                let reference_id = message.reference_id.or(ids.last().map(String::as_str));
+                if let Some(e) = self.queue_full(&tx, message)? {
+                    return Ok(Err(e));
+                }
+                let id = Self::insert_message(
+                    &tx,
+                    &NewMessage {
+                        reference_id,
+                        ..*message
+                    },
+                )?;
+                ids.push(id);
+            }
+            tx.commit()?;
+            Ok(Ok(ids))
+        })??;
+        for message in messages {
+            self.waiters.notify(message.project_id, message.to_agent);
+        }
+        Ok(ids)
+    }
+
+    /// Checks the fields shared by all outgoing messages.
+    fn validate(message: &NewMessage<'_>) -> DbResult<()> {
+        if message.project_id.trim().is_empty() {
+            return Err(DbError::EmptyField {
+                field: "project_id",
+            });
+        }
+        if message.from_agent.trim().is_empty() {
+            return Err(DbError::EmptyField {
+                field: "from_agent",
+            });
+        }
+        if message.content.len() > MAX_MESSAGE_SIZE {
+            return Err(DbError::ContentTooLarge {
+                size: message.content.len(),
+                limit: MAX_MESSAGE_SIZE,
+            });
+        }
+        if let Some(metadata) = message.metadata {
+            let size = serde_json::Value::from(metadata.clone()).to_string().len();
+            if size > MAX_METADATA_SIZE {
+                return Err(DbError::ContentTooLarge {
+                    size,
+                    limit: MAX_METADATA_SIZE,
+                });
+            }
+        }
+        if let Some(attachments) = message.attachments {
+            if attachments.len() > MAX_MESSAGE_ATTACHMENTS {
+                return Err(DbError::TooManyAttachments {
+                    count: attachments.len(),
+                    limit: MAX_MESSAGE_ATTACHMENTS,
+                });
+            }
+        }
+        if let Some(tags) = message.tags {
+            if tags.len() > MAX_MESSAGE_TAGS {
+                return Err(DbError::TooManyTags {
+                    count: tags.len(),
+                    limit: MAX_MESSAGE_TAGS,
+                });
+            }
+            for tag in tags {
+                let tag = tag.trim();
+                if tag.is_empty() {
+                    return Err(DbError::EmptyField { field: "tag" });
+                }
+                if tag.len() > MAX_TAG_SIZE {
+                    return Err(DbError::ContentTooLarge {
+                        size: tag.len(),
+                        limit: MAX_TAG_SIZE,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the content against the schema named by the message, if any.
+    fn check_schema(&self, message: &NewMessage<'_>) -> DbResult<()> {
+        let Some(name) = message.schema else {
+            return Ok(());
+        };
+        let schema = self
+            .message_schema(message.project_id, name)?
+            .ok_or_else(|| DbError::UnknownSchema {
+                project_id: message.project_id.to_string(),
+                name: name.to_string(),
+            })?;
+        let validator =
+            schema::compile(&schema.schema).map_err(|reason| DbError::InvalidSchema { reason })?;
+        schema::check(&validator, message.content).map_err(|reason| DbError::SchemaViolation {
+            name: name.to_string(),
+            reason,
+        })
+    }
+
+    /// Checks that every attachment referenced by the message is stored in its project.
+    fn check_attachments(&self, message: &NewMessage<'_>) -> DbResult<()> {
+        let Some(attachments) = message.attachments else {
+            return Ok(());
+        };
+        let missing = self.with_read_conn(|conn| {
+            let mut stmt = conn
+                .prepare_cached("SELECT 1 FROM attachments WHERE project_id = ?1 AND hash = ?2")?;
+            for hash in attachments {
+                if !stmt.exists(params![message.project_id, hash])? {
+                    return Ok(Some(hash));
+                }
+            }
+            Ok(None)
+        })?;
+        match missing {
+            Some(hash) => Err(DbError::UnknownAttachment {
+                project_id: message.project_id.to_string(),
+                hash: hash.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a `QueueFull` error if adding the message would take its
+    /// recipient's queue past the quota.
+    fn queue_full(
+        &self,
+        conn: &Connection,
+        message: &NewMessage<'_>,
+    ) -> SqliteResult<Option<DbError>> {
+        let max_messages = self.max_queue_messages.load(Ordering::Relaxed);
+        let max_bytes = self.max_queue_bytes.load(Ordering::Relaxed);
+        if max_messages == 0 && max_bytes == 0 {
+            return Ok(None);
+        }
+        let (depth, bytes): (u64, u64) = conn
+            .prepare_cached(
+                r"SELECT COUNT(*), COALESCE(SUM(length(CAST(content AS BLOB))), 0)
+                  FROM messages WHERE project_id = ?1 AND to_agent = ?2",
+            )?
+            .query_row(params![message.project_id, message.to_agent], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+        let full = (max_messages > 0 && depth >= max_messages)
+            || (max_bytes > 0 && bytes + message.content.len() as u64 > max_bytes);
+        Ok(full.then(|| DbError::QueueFull {
+            project_id: message.project_id.to_string(),
+            agent_id: message.to_agent.to_string(),
+            depth,
+            bytes,
+        }))
+    }
+
+    /// Inserts a validated message, returning its ID.
+    fn insert_message(conn: &Connection, message: &NewMessage<'_>) -> SqliteResult<String> {
+        let trace_id = match (message.trace_id, message.reference_id) {
+            (Some(trace_id), _) => Some(trace_id.to_string()),
+            (None, Some(reference_id)) => {
+                Self::inherited_trace_id(conn, message.project_id, reference_id)?
+            }
+            (None, None) => None,
+        };
+        let expires_in = message
+            .ttl_secs
+            .map(|ttl| format!("+{} seconds", ttl.clamp(1, MAX_MESSAGE_TTL_SECS)));
+        let metadata = message
+            .metadata
+            .filter(|metadata| !metadata.is_empty())
+            .map(|metadata| serde_json::Value::from(metadata.clone()).to_string());
+        let attachments = message
+            .attachments
+            .filter(|attachments| !attachments.is_empty())
+            .map(|attachments| serde_json::Value::from(attachments).to_string());
+        let tags = message
+            .tags
+            .map(|tags| {
+                let mut trimmed = Vec::with_capacity(tags.len());
+                for tag in tags.iter().map(|tag| tag.trim()) {
+                    if !trimmed.contains(&tag) {
+                        trimmed.push(tag);
+                    }
+                }
+                trimmed
+            })
+            .filter(|tags| !tags.is_empty())
+            .map(|tags| serde_json::Value::from(tags).to_string());
+        let seq: i64 = conn
+            .prepare_cached(
+                r"INSERT INTO queue_sequences (project_id, agent_id, seq) VALUES (?1, ?2, 1)
+                  ON CONFLICT (project_id, agent_id) DO UPDATE SET seq = seq + 1
+                  RETURNING seq",
+            )?
+            .query_row(params![message.project_id, message.to_agent], |row| {
+                row.get(0)
+            })?;
+        conn.execute(
+            r"INSERT INTO messages
+                (project_id, to_agent, from_agent, reference_id, content, trace_id, priority,
+                 expires_at, metadata, attachments, confidential, seq, tags, reply_to,
+                 request_receipt)
+              VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7,
+                      strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?8), ?9, ?10, ?11, ?12, ?13, ?14,
+                      ?15)",
+            params![
+                message.project_id,
+                message.to_agent,
+                message.from_agent,
+                message.reference_id,
+                message.content,
+                trace_id,
+                message.priority.level(),
+                expires_in,
+                metadata,
+                attachments,
+                message.confidential,
+                seq,
+                tags,
+                message.reply_to.map(str::trim).filter(|s| !s.is_empty()),
+                message.request_receipt
+            ],
+        )?;
+        let id = conn.last_insert_rowid();
+        conn.execute(
+            r"INSERT OR REPLACE INTO delivery_status
+                (message_id, project_id, to_agent, sent_at, trace_id)
+              SELECT id, project_id, to_agent, created_at, trace_id FROM messages WHERE id = ?1",
+            params![id],
+        )?;
+        Self::record_queue_stats(conn, message.project_id, message.to_agent, 1, 0)?;
+        Ok(id.to_string())
+    }
+
+    /// Adds send/receive counts to the current minute's rollup for a queue.
+    fn record_queue_stats(
+        conn: &Connection,
+        project_id: &str,
+        agent_id: &str,
+        sent: usize,
+        received: usize,
+    ) -> SqliteResult<()> {
+        conn.execute(
+            r"INSERT INTO queue_stats (project_id, agent_id, bucket, sent, received)
+              VALUES (?1, ?2, strftime('%Y-%m-%dT%H:%M:00Z', 'now'), ?3, ?4)
+              ON CONFLICT(project_id, agent_id, bucket)
+              DO UPDATE SET sent = sent + ?3, received = received + ?4",
+            params![project_id, agent_id, sent, received],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the queue replies to a message should go to: its `reply_to`,
+    /// or else its sender. The message may be pending, archived or
+    /// dead-lettered; `None` if it isn't stored in the project.
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    pub fn reply_address(&self, project_id: &str, message_id: &str) -> DbResult<Option<String>> {
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+        self.with_read_conn(|conn| {
+            let result = conn.query_row(
+                r"SELECT COALESCE(reply_to, from_agent) FROM messages
+                  WHERE id = ?1 AND project_id = ?2
+                  UNION ALL
+                  SELECT COALESCE(reply_to, from_agent) FROM archived_messages
+                  WHERE id = ?1 AND project_id = ?2
+                  UNION ALL
+                  SELECT COALESCE(reply_to, from_agent) FROM dead_letters
+                  WHERE id = ?1 AND project_id = ?2
+                  LIMIT 1",
+                params![id, project_id],
+                |row| row.get(0),
+            );
+            match result {
+                Ok(address) => Ok(Some(address)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Looks up the trace ID of a referenced message in the project: pending,
+    /// archived, dead-lettered, or consumed but still within its delivery
+    /// status retention.
+    fn inherited_trace_id(
+        conn: &Connection,
+        project_id: &str,
+        reference_id: &str,
+    ) -> SqliteResult<Option<String>> {
+        let Ok(id) = reference_id.parse::<i64>() else {
+            return Ok(None);
+        };
+        let result = conn.query_row(
+            r"SELECT trace_id FROM messages WHERE id = ?1 AND project_id = ?2
+              UNION ALL
+              SELECT trace_id FROM archived_messages WHERE id = ?1 AND project_id = ?2
+              UNION ALL
+              SELECT trace_id FROM dead_letters WHERE id = ?1 AND project_id = ?2
+              UNION ALL
+              SELECT trace_id FROM delivery_status WHERE message_id = ?1 AND project_id = ?2
+              LIMIT 1",
+            params![id, project_id],
+            |row| row.get(0),
+        );
+        match result {
+            Ok(trace_id) => Ok(trace_id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Retrieves and consumes messages from an agent's queue.
+    ///
+    /// Messages are returned highest priority first, in strict FIFO (send)
+    /// order within a priority. In at-most-once queues they are deleted; in
+    /// at-least-once queues they are hidden for the visibility timeout until
+    /// acknowledged (see [`DeliveryMode`]), doubling with each redelivery.
+    /// Received messages carry their `attempt` and, when kept in flight, their
+    /// `next_retry_at`. Pinned messages are skipped, and a paused queue (see
+    /// [`pause_queue`](Self::pause_queue)) returns nothing.
+    /// Use [`peek_messages`](Self::peek_messages) to view without consuming.
+    ///
+    /// Selection and deletion happen in one immediate transaction, so sessions
+    /// (or server processes) receiving for the same agent act as a consumer
+    /// group: each message is delivered to exactly one of them.
+    ///
+    /// Limit is capped at [`MAX_MESSAGE_LIMIT`] (500).
+    pub fn receive_messages(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<Message>> {
+        self.receive_filtered(project_id, agent_id, &MessageFilter::default(), limit, None)
+    }
+
+    /// Like [`receive_messages`](Self::receive_messages), but only consumes
+    /// messages matching the filter; others stay queued.
+    ///
+    /// `settings` overrides the queue's delivery settings for this call, e.g.
+    /// to receive with manual acknowledgment from an at-most-once queue. The
+    /// visibility timeout is capped at [`MAX_VISIBILITY_TIMEOUT_SECS`] (12 hours).
+    pub fn receive_filtered(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        filter: &MessageFilter<'_>,
+        limit: Option<u32>,
+        settings: Option<QueueSettings>,
+    ) -> DbResult<Vec<Message>> {
+        self.receive_as(project_id, agent_id, None, filter, limit, settings)
+    }
+
+    /// Like [`receive_filtered`](Self::receive_filtered), on behalf of a named
+    /// member of a group queue: several workers sharing one `agent_id`. The
+    /// consumer is recorded as the receiver of the messages, and messages it
+    /// keeps in flight are claimed by it, so only it can acknowledge or nack
+    /// them by name (see [`ack_message`](Self::ack_message)) until they are
+    /// redelivered to another member.
+    pub fn receive_as(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        consumer: Option<&str>,
+        filter: &MessageFilter<'_>,
+        limit: Option<u32>,
+        settings: Option<QueueSettings>,
+    ) -> DbResult<Vec<Message>> {
+        let consumer = consumer.map(str::trim).filter(|s| !s.is_empty());
+        let limit = limit.unwrap_or(100).min(MAX_MESSAGE_LIMIT);
+        let archive = self.archive_days.load(Ordering::Relaxed) > 0;
+        let settings = settings.map(|settings| QueueSettings {
+            visibility_timeout_secs: settings
+                .visibility_timeout_secs
+                .clamp(1, MAX_VISIBILITY_TIMEOUT_SECS),
+            ..settings
+        });
+
+        let (messages, receipts) = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            Self::move_dead_letters(&tx, Some((project_id, agent_id)))?;
+            Self::record_activity(&tx, project_id, agent_id)?;
+            if Self::is_paused(&tx, project_id, agent_id)? {
+                tx.commit()?;
+                return Ok((Vec::new(), Vec::new()));
+            }
+            let mut messages =
+                Self::query_messages(&tx, project_id, agent_id, filter, limit, false)?;
+            let mut receipts = Vec::new();
+
+            // Delete (or hide until acknowledged) consumed messages in a single statement
+            if !messages.is_empty() {
+                let settings = match settings {
+                    Some(settings) => settings,
+                    None => Self::read_queue_settings(&tx, project_id, agent_id)?,
+                };
+                let ids: Vec<String> = messages.iter().map(|m| m.id.clone()).collect();
+                // Numbered, so the consumer can be bound after the IDs
+                let placeholders: String = (1..=ids.len())
+                    .map(|i| format!("?{i}"))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let sql = match settings.delivery_mode {
+                    DeliveryMode::AtMostOnce => {
+                        if archive {
+                            Self::archive_messages(&tx, &ids)?;
+                        }
+                        format!(
+                            r"DELETE FROM messages WHERE id IN ({placeholders})
+                              RETURNING id, delivery_count + 1, NULL, request_receipt"
+                        )
+                    }
+                    DeliveryMode::AtLeastOnce => format!(
+                        r"UPDATE messages
+                          SET visible_after = strftime('%Y-%m-%dT%H:%M:%SZ', 'now',
+                                '+' || min({timeout} << min(delivery_count, {MAX_BACKOFF_DOUBLINGS}),
+                                           {MAX_VISIBILITY_TIMEOUT_SECS}) || ' seconds'),
+                              delivery_count = delivery_count + 1,
+                              claimed_by = ?{consumer}
+                          WHERE id IN ({placeholders})
+                          RETURNING id, delivery_count, visible_after, request_receipt",
+                        timeout = settings.visibility_timeout_secs,
+                        consumer = ids.len() + 1
+                    ),
+                };
+                let mut deliveries: HashMap<i64, (u32, Option<String>, bool)> = HashMap::new();
+                let mut stmt = tx.prepare(&sql)?;
+                for (i, id) in ids.iter().enumerate() {
+                    stmt.raw_bind_parameter(i + 1, id)?;
+                }
+                if settings.delivery_mode == DeliveryMode::AtLeastOnce {
+                    stmt.raw_bind_parameter(ids.len() + 1, consumer)?;
+                }
+                let mut rows = stmt.raw_query();
+                while let Some(row) = rows.next()? {
+                    deliveries.insert(row.get(0)?, (row.get(1)?, row.get(2)?, row.get(3)?));
+                }
+                let mut stmt = tx.prepare(&format!(
+                    r"UPDATE delivery_status
+                      SET received_at = COALESCE(received_at, strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                          received_by = ?{consumer}
+                      WHERE message_id IN ({placeholders})",
+                    consumer = ids.len() + 1
+                ))?;
+                for (i, id) in ids.iter().enumerate() {
+                    stmt.raw_bind_parameter(i + 1, id)?;
+                }
+                stmt.raw_bind_parameter(ids.len() + 1, consumer)?;
+                stmt.raw_execute()?;
+                for message in &mut messages {
+                    if let Some((attempt, next_retry_at, request_receipt)) = message
+                        .id
+                        .parse()
+                        .ok()
+                        .and_then(|id: i64| deliveries.remove(&id))
+                    {
+                        message.attempt = Some(attempt);
+                        message.next_retry_at = next_retry_at;
+                        if request_receipt && attempt == 1 {
+                            receipts.push((message.id.clone(), message.from_agent.clone()));
+                        }
+                    }
+                }
+                Self::record_queue_stats(&tx, project_id, agent_id, 0, messages.len())?;
+                for (message_id, sender) in &receipts {
+                    Self::insert_receipt(&tx, project_id, agent_id, message_id, sender)?;
+                }
+            }
+
+            tx.commit()?;
+            Ok((messages, receipts))
+        })?;
+        for (_, sender) in &receipts {
+            self.waiters.notify(project_id, sender);
+        }
+        Ok(messages)
+    }
+
+    /// Queues the receipt of a message that asked for one, from its recipient
+    /// back to its sender.
+    fn insert_receipt(
+        conn: &Connection,
+        project_id: &str,
+        agent_id: &str,
+        message_id: &str,
+        sender: &str,
+    ) -> SqliteResult<String> {
+        let mut metadata = serde_json::Map::new();
+        metadata.insert("receipt".to_string(), serde_json::Value::Bool(true));
+        Self::insert_message(
+            conn,
+            &NewMessage {
+                project_id,
+                to_agent: sender,
+                from_agent: agent_id,
+                content: &format!("Receipt: message {message_id} was received by {agent_id}"),
+                reference_id: Some(message_id),
+                metadata: Some(&metadata),
+                ..NewMessage::default()
+            },
+        )
+    }
+
+    /// Peeks at messages in an agent's queue without consuming them.
+    ///
+    /// Messages are returned in delivery order (priority, then FIFO) but remain in the queue.
+    /// Messages awaiting acknowledgment are not included.
+    ///
+    /// Limit is capped at [`MAX_MESSAGE_LIMIT`] (500).
+    pub fn peek_messages(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<Message>> {
+        self.peek_filtered(project_id, agent_id, &MessageFilter::default(), limit)
+    }
+
+    /// Like [`peek_messages`](Self::peek_messages), but only returns messages
+    /// matching the filter.
+    pub fn peek_filtered(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        filter: &MessageFilter<'_>,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<Message>> {
+        let limit = limit.unwrap_or(100).min(MAX_MESSAGE_LIMIT);
+
+        let messages = self.with_read_conn(|conn| {
+            Self::query_messages(conn, project_id, agent_id, filter, limit, true)
+        })?;
+        // Best effort: a peek shouldn't fail just because it can't be recorded
+        if let Err(e) = self.record_peeks(&messages) {
+            tracing::debug!("Failed to record peeked messages: {e}");
+        }
+        Ok(messages)
+    }
+
+    /// Records the first peek of each message in its delivery status.
+    fn record_peeks(&self, messages: &[Message]) -> DbResult<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        self.with_conn(|conn| {
+            let placeholders: String = messages.iter().map(|_| "?").collect::<Vec<_>>().join(",");
+            let mut stmt = conn.prepare(&format!(
+                r"UPDATE delivery_status SET peeked_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+                  WHERE message_id IN ({placeholders}) AND peeked_at IS NULL"
+            ))?;
+            stmt.execute(params_from_iter(messages.iter().map(|m| &m.id)))?;
+            Ok(())
+        })
+    }
+
+    /// Returns the stored messages carrying a trace ID, across all queues in
+    /// the project, in send order. Messages awaiting acknowledgment are
+    /// included; messages already consumed are not. Confidential messages
+    /// are skipped unless `include_confidential` is set.
+    ///
+    /// Limit is capped at [`MAX_MESSAGE_LIMIT`] (500).
+    pub fn trace_messages(
+        &self,
+        project_id: &str,
+        trace_id: &str,
+        include_confidential: bool,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<Message>> {
+        let limit = limit.unwrap_or(100).min(MAX_MESSAGE_LIMIT);
+
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                r"SELECT {MESSAGE_COLUMNS} FROM messages
+                  WHERE project_id = ?1 AND trace_id = ?2 AND (?3 OR NOT confidential)
+                  ORDER BY id ASC
+                  LIMIT ?4"
+            ))?;
+            let messages = stmt
+                .query_map(
+                    params![project_id, trace_id, include_confidential, limit],
+                    Message::from_row,
+                )?
+                .collect::<Result<_, _>>()?;
+            Ok(messages)
+        })
+    }
+
+    /// Returns the conversation a message belongs to: the chain of messages it
+    /// replies to (via `reference_id`) up to the first one, and every reply to
+    /// any of them, transitively. Pending, archived and dead-lettered messages
+    /// are included; consumed messages only if archiving is enabled.
+    ///
+    /// Messages are returned in send order. Limit is capped at
+    /// [`MAX_MESSAGE_LIMIT`] (500).
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    pub fn thread(
+        &self,
+        project_id: &str,
+        message_id: &str,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<ThreadMessage>> {
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+        let limit = limit.unwrap_or(100).min(MAX_MESSAGE_LIMIT);
+
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                r"WITH RECURSIVE
+                    stored AS (
+                        SELECT {MESSAGE_COLUMNS}, to_agent, 'pending' AS state
+                        FROM messages WHERE project_id = ?1
+                        UNION ALL
+                        SELECT {MESSAGE_COLUMNS}, to_agent, 'archived'
+                        FROM archived_messages WHERE project_id = ?1
+                        UNION ALL
+                        SELECT {MESSAGE_COLUMNS}, to_agent, 'dead_letter'
+                        FROM dead_letters WHERE project_id = ?1
+                    ),
+                    ancestors(id) AS (
+                        SELECT ?2
+                        UNION
+                        SELECT CAST(s.reference_id AS INTEGER)
+                        FROM stored s JOIN ancestors a ON s.id = a.id
+                        WHERE s.reference_id IS NOT NULL
+                    ),
+                    thread(id) AS (
+                        SELECT id FROM ancestors
+                        UNION
+                        SELECT s.id FROM stored s JOIN thread t ON s.reference_id = CAST(t.id AS TEXT)
+                    )
+                  SELECT {MESSAGE_COLUMNS}, to_agent, state
+                  FROM stored
+                  WHERE id IN (SELECT id FROM thread)
+                  ORDER BY id ASC
+                  LIMIT ?3"
+            ))?;
+            let messages = stmt
+                .query_map(params![project_id, id, limit], |row| {
+                    Ok(ThreadMessage {
+                        message: Message::from_row(row)?,
+                        to_agent: row.get(14)?,
+                        state: MessageState::from_sql(&row.get::<_, String>(15)?),
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(messages)
+        })
+    }
+
+    /// Returns a message by ID without consuming it: pending (including in
+    /// flight), archived after being consumed, or dead-lettered. Returns
+    /// `None` if the project has no such message.
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    pub fn get_message(
+        &self,
+        project_id: &str,
+        message_id: &str,
+    ) -> DbResult<Option<ThreadMessage>> {
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+
+        self.with_read_conn(|conn| {
+            let result = conn.query_row(
+                &format!(
+                    r"SELECT {MESSAGE_COLUMNS}, to_agent, 'pending'
+                      FROM messages WHERE project_id = ?1 AND id = ?2
+                      UNION ALL
+                      SELECT {MESSAGE_COLUMNS}, to_agent, 'archived'
+                      FROM archived_messages WHERE project_id = ?1 AND id = ?2
+                      UNION ALL
+                      SELECT {MESSAGE_COLUMNS}, to_agent, 'dead_letter'
+                      FROM dead_letters WHERE project_id = ?1 AND id = ?2
+                      LIMIT 1"
+                ),
+                params![project_id, id],
+                |row| {
+                    Ok(ThreadMessage {
+                        message: Message::from_row(row)?,
+                        to_agent: row.get(14)?,
+                        state: MessageState::from_sql(&row.get::<_, String>(15)?),
+                    })
+                },
+            );
+            match result {
+                Ok(message) => Ok(Some(message)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Searches the content of a project's stored messages (and, if
+    /// `include_archived`, its archived messages) with an FTS5 query, best
+    /// matches first. Only messages matching `filter` are returned.
+    ///
+    /// The query supports FTS5 syntax: words, `"phrases"`, `prefix*`, and
+    /// `AND`/`OR`/`NOT`. Limit is capped at [`MAX_MESSAGE_LIMIT`] (500).
+    ///
+    /// # Errors
+    /// - `InvalidSearchQuery` if the query is malformed
+    pub fn search_messages(
+        &self,
+        project_id: &str,
+        query: &str,
+        to_agent: Option<&str>,
+        filter: &MessageFilter<'_>,
+        include_archived: bool,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<ThreadMessage>> {
+        let limit = limit.unwrap_or(100).min(MAX_MESSAGE_LIMIT);
+
+        self.with_read_conn(|conn| {
+            let mut values = vec![
+                Value::Text(query.to_string()),
+                Value::Text(project_id.to_string()),
+                to_agent.map_or(Value::Null, |agent| Value::Text(agent.to_string())),
+                Value::Integer(include_archived.into()),
+                Value::Integer(limit.into()),
+            ];
+            let conditions = filter.conditions(&mut values);
+            let mut stmt = conn.prepare(&format!(
+                r"SELECT {MESSAGE_COLUMNS}, to_agent, 'pending' AS state, rank
+                  FROM messages
+                  JOIN (SELECT rowid, rank FROM messages_fts WHERE messages_fts MATCH ?1) f
+                    ON f.rowid = messages.id
+                  WHERE project_id = ?2 AND (?3 IS NULL OR to_agent = ?3){conditions}
+                  UNION ALL
+                  SELECT {MESSAGE_COLUMNS}, to_agent, 'archived', rank
+                  FROM archived_messages
+                  JOIN (SELECT rowid, rank FROM archived_messages_fts
+                        WHERE ?4 AND archived_messages_fts MATCH ?1) f
+                    ON f.rowid = archived_messages.id
+                  WHERE project_id = ?2 AND (?3 IS NULL OR to_agent = ?3){conditions}
+                  ORDER BY rank, id DESC
+                  LIMIT ?5"
+            ))?;
+            let messages = stmt
+                .query_map(params_from_iter(values), |row| {
+                    Ok(ThreadMessage {
+                        message: Message::from_row(row)?,
+                        to_agent: row.get(14)?,
+                        state: MessageState::from_sql(&row.get::<_, String>(15)?),
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(messages)
+        })
+        .map_err(|e| match e {
+            // The statement itself is fixed, so plain SQL errors come from the query
+            DbError::Sqlite(rusqlite::Error::SqliteFailure(error, Some(reason)))
+                if error.code == ErrorCode::Unknown =>
+            {
+                DbError::InvalidSearchQuery { reason }
+            }
+            e => e,
+        })
+    }
+
+    /// Returns the number of messages pending in an agent's queue.
+    pub fn queue_depth(&self, project_id: &str, agent_id: &str) -> DbResult<u64> {
+        self.with_read_conn(|conn| {
+            conn.query_row(
+                "SELECT COUNT(*) FROM messages WHERE project_id = ?1 AND to_agent = ?2",
+                params![project_id, agent_id],
+                |row| row.get(0),
+            )
+        })
+    }
+
+    /// Returns a counter that changes whenever an agent's queue changes
+    /// (messages sent, received, acknowledged, expired or deleted), or 0 for a
+    /// queue that never held a message.
+    ///
+    /// Maintained by triggers, so it also covers changes made by other server
+    /// processes. It doesn't change when an in-flight message's visibility
+    /// timeout expires.
+    pub fn queue_version(&self, project_id: &str, agent_id: &str) -> DbResult<u64> {
+        self.with_read_conn(|conn| Self::read_queue_version(conn, project_id, agent_id))
+    }
+
+    fn read_queue_version(
+        conn: &Connection,
+        project_id: &str,
+        agent_id: &str,
+    ) -> SqliteResult<u64> {
+        conn.query_row(
+            r"SELECT COALESCE(
+                  (SELECT version FROM queue_versions WHERE project_id = ?1 AND agent_id = ?2),
+                  0)",
+            params![project_id, agent_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Reads a queue's version, deliverable message count and first messages
+    /// in one read transaction, so the three agree even while other sessions
+    /// or processes change the queue (e.g. for an `ETag` that must describe
+    /// the body it comes with).
+    ///
+    /// Limit is capped at [`MAX_MESSAGE_LIMIT`] (500).
+    pub fn peek_snapshot(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        limit: Option<u32>,
+    ) -> DbResult<QueueSnapshot> {
+        let limit = limit.unwrap_or(100).min(MAX_MESSAGE_LIMIT);
+        let filter = MessageFilter::default();
+
+        let snapshot = self.with_read_tx(|conn| {
+            Ok(QueueSnapshot {
+                version: Self::read_queue_version(conn, project_id, agent_id)?,
+                visible: Self::count_visible(conn, project_id, agent_id, &filter, true)?,
+                messages: Self::query_messages(conn, project_id, agent_id, &filter, limit, true)?,
+            })
+        })?;
+        if let Err(e) = self.record_peeks(&snapshot.messages) {
+            tracing::debug!("Failed to record peeked messages: {e}");
+        }
+        Ok(snapshot)
+    }
+
+    /// Summarizes the messages available in an agent's queue (excluding those
+    /// awaiting acknowledgment and pinned ones), or `None` if there are none.
+    pub fn queue_remainder(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+    ) -> DbResult<Option<QueueRemainder>> {
+        self.with_read_conn(|conn| {
+            conn.query_row(
+                &format!(
+                    r"SELECT COUNT(*), MIN(created_at), MAX(created_at)
+                      FROM messages
+                      WHERE project_id = ?1 AND to_agent = ?2 AND {VISIBLE} AND NOT pinned"
+                ),
+                params![project_id, agent_id],
+                |row| {
+                    let count: u64 = row.get(0)?;
+                    if count == 0 {
+                        return Ok(None);
+                    }
+                    Ok(Some(QueueRemainder {
+                        count,
+                        oldest_created_at: row.get(1)?,
+                        newest_created_at: row.get(2)?,
+                    }))
+                },
+            )
+        })
+    }
+
+    /// Returns the depth, oldest message age and dead-letter count of every
+    /// queue with pending or dead-lettered messages, excluding the system project.
+    pub fn queue_overview(&self) -> DbResult<Vec<QueueStatus>> {
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(
+                r"SELECT project_id, agent_id, SUM(depth), MAX(age), SUM(dead_letters)
+                  FROM (
+                      SELECT project_id, to_agent AS agent_id, COUNT(*) AS depth,
+                             MAX(0, CAST(strftime('%s', 'now') AS INTEGER)
+                                    - CAST(strftime('%s', MIN(created_at)) AS INTEGER)) AS age,
+                             0 AS dead_letters
+                      FROM messages
+                      WHERE project_id != ?1
+                      GROUP BY project_id, to_agent
+                      UNION ALL
+                      SELECT project_id, to_agent, 0, 0, COUNT(*)
+                      FROM dead_letters
+                      WHERE project_id != ?1
+                      GROUP BY project_id, to_agent
+                  )
+                  GROUP BY project_id, agent_id
+                  ORDER BY project_id, agent_id",
+            )?;
+            let queues = stmt
+                .query_map(params![SYSTEM_PROJECT], |row| {
+                    Ok(QueueStatus {
+                        project_id: row.get(0)?,
+                        agent_id: row.get(1)?,
+                        depth: row.get(2)?,
+                        oldest_message_age_secs: row.get(3)?,
+                        dead_letters: row.get(4)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(queues)
+        })
+    }
+
+    /// Returns statistics of every queue in a project holding pending
+    /// messages, ordered by agent ID.
+    pub fn queue_stats(&self, project_id: &str) -> DbResult<Vec<QueueStats>> {
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(
+                r"SELECT to_agent, from_agent, COUNT(*),
+                         COALESCE(SUM(visible_after > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')), 0),
+                         MAX(0, CAST(strftime('%s', 'now') AS INTEGER)
+                                - CAST(strftime('%s', MIN(created_at)) AS INTEGER)),
+                         SUM(LENGTH(CAST(content AS BLOB)))
+                  FROM messages
+                  WHERE project_id = ?1
+                  GROUP BY to_agent, from_agent
+                  ORDER BY to_agent, COUNT(*) DESC, from_agent",
+            )?;
+            let mut rows = stmt.query(params![project_id])?;
+            let mut queues: Vec<QueueStats> = Vec::new();
+            while let Some(row) = rows.next()? {
+                let agent_id: String = row.get(0)?;
+                let sender = SenderStats {
+                    from_agent: row.get(1)?,
+                    count: row.get(2)?,
+                    bytes: row.get(5)?,
+                };
+                let in_flight: u64 = row.get(3)?;
+                let age: u64 = row.get(4)?;
+                let queue = match queues.last_mut() {
+                    Some(queue) if queue.agent_id == agent_id => queue,
+                    _ => {
+                        queues.push(QueueStats {
+                            agent_id,
+                            pending: 0,
+                            in_flight: 0,
+                            oldest_message_age_secs: 0,
+                            total_bytes: 0,
+                            senders: Vec::new(),
+                        });
+                        queues.last_mut().expect("queue was just pushed")
+                    }
+                };
+                queue.pending += sender.count;
+                queue.in_flight += in_flight;
+                queue.oldest_message_age_secs = queue.oldest_message_age_secs.max(age);
+                queue.total_bytes += sender.bytes;
+                queue.senders.push(sender);
+            }
+            Ok(queues)
+        })
+    }
+
+    /// Summarizes an agent's queue: pending messages by sender, annotation and
+    /// age, plus in-flight and dead-lettered counts, read in one transaction.
+    pub fn inbox_overview(&self, project_id: &str, agent_id: &str) -> DbResult<InboxOverview> {
+        self.with_read_tx(|conn| {
+            let counts = |sql: &str| -> SqliteResult<Vec<InboxCount>> {
+                let mut stmt = conn.prepare(sql)?;
+                let counts = stmt
+                    .query_map(params![project_id, agent_id], |row| {
+                        Ok(InboxCount {
+                            key: row.get(0)?,
+                            count: row.get(1)?,
+                        })
+                    })?
+                    .collect();
+                counts
+            };
+            let by_sender = counts(
+                r"SELECT from_agent, COUNT(*) FROM messages
+                  WHERE project_id = ?1 AND to_agent = ?2
+                  GROUP BY from_agent
+                  ORDER BY COUNT(*) DESC, from_agent",
+            )?;
+            let by_annotation = counts(
+                r"SELECT a.annotation, COUNT(DISTINCT m.id)
+                  FROM messages m
+                  JOIN annotations a ON a.project_id = m.project_id AND a.message_id = m.id
+                  WHERE m.project_id = ?1 AND m.to_agent = ?2
+                  GROUP BY a.annotation
+                  ORDER BY COUNT(DISTINCT m.id) DESC, a.annotation",
+            )?;
+            let (pending, in_flight, oldest_message_age_secs, by_age) = conn.query_row(
+                r"SELECT COUNT(*),
+                         COALESCE(SUM(visible_after > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')), 0),
+                         COALESCE(MAX(age), 0),
+                         COALESCE(SUM(age < 60), 0),
+                         COALESCE(SUM(age >= 60 AND age < 3600), 0),
+                         COALESCE(SUM(age >= 3600 AND age < 86400), 0),
+                         COALESCE(SUM(age >= 86400), 0)
+                  FROM (SELECT visible_after,
+                               MAX(0, CAST(strftime('%s', 'now') AS INTEGER)
+                                      - CAST(strftime('%s', created_at) AS INTEGER)) AS age
+                        FROM messages
+                        WHERE project_id = ?1 AND to_agent = ?2)",
+                params![project_id, agent_id],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        InboxAges {
+                            under_1m: row.get(3)?,
+                            under_1h: row.get(4)?,
+                            under_1d: row.get(5)?,
+                            older: row.get(6)?,
+                        },
+                    ))
+                },
+            )?;
+            let dead_letters = conn.query_row(
+                "SELECT COUNT(*) FROM dead_letters WHERE project_id = ?1 AND to_agent = ?2",
+                params![project_id, agent_id],
+                |row| row.get(0),
+            )?;
+            Ok(InboxOverview {
+                pending,
+                in_flight,
+                dead_letters,
+                oldest_message_age_secs,
+                by_sender,
+                by_annotation,
+                by_age,
+            })
+        })
+    }
+
+    /// Returns per-minute send/receive counts for a queue over the last `window_minutes`.
+    ///
+    /// The window is capped at [`MAX_HISTORY_WINDOW_MINUTES`] (7 days), which is
+    /// also how long rollups are retained.
+    #[allow(clippy::cast_precision_loss)] // rates are informational
+    pub fn queue_history(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        window_minutes: u32,
+    ) -> DbResult<QueueHistory> {
+        let window_minutes = window_minutes.clamp(1, MAX_HISTORY_WINDOW_MINUTES);
+        let buckets = self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(
+                r"SELECT bucket, sent, received
+                  FROM queue_stats
+                  WHERE project_id = ?1 AND agent_id = ?2
+                    AND bucket > strftime('%Y-%m-%dT%H:%M:00Z', 'now', ?3)
+                  ORDER BY bucket ASC",
+            )?;
+            let buckets = stmt
+                .query_map(
+                    params![project_id, agent_id, format!("-{window_minutes} minutes")],
+                    |row| {
+                        Ok(QueueBucket {
+                            bucket: row.get(0)?,
+                            sent: row.get(1)?,
+                            received: row.get(2)?,
+                        })
+                    },
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(buckets)
+        })?;
+
+        let total_sent = buckets.iter().map(|b| b.sent).sum();
+        let total_received = buckets.iter().map(|b| b.received).sum();
+        Ok(QueueHistory {
+            window_minutes,
+            buckets,
+            total_sent,
+            total_received,
+            send_rate_per_minute: total_sent as f64 / f64::from(window_minutes),
+            receive_rate_per_minute: total_received as f64 / f64::from(window_minutes),
+        })
+    }
+
+    /// Returns the number of deliverable messages in an agent's queue matching
+    /// the filter. Pinned messages aren't deliverable, so they aren't counted,
+    /// and neither is anything in a paused queue.
+    pub fn count_messages(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        filter: &MessageFilter<'_>,
+    ) -> DbResult<u64> {
+        self.with_read_conn(|conn| {
+            if Self::is_paused(conn, project_id, agent_id)? {
+                return Ok(0);
+            }
+            Self::count_visible(conn, project_id, agent_id, filter, false)
+        })
+    }
+
+    fn count_visible(
+        conn: &Connection,
+        project_id: &str,
+        agent_id: &str,
+        filter: &MessageFilter<'_>,
+        include_pinned: bool,
+    ) -> SqliteResult<u64> {
+        let mut values = vec![
+            Value::Text(project_id.to_string()),
+            Value::Text(agent_id.to_string()),
+        ];
+        let conditions = filter.conditions(&mut values);
+        let pinned = if include_pinned {
+            ""
+        } else {
+            " AND NOT pinned"
+        };
+        conn.query_row(
+            &format!(
+                r"SELECT COUNT(*) FROM messages
+                  WHERE project_id = ?1 AND to_agent = ?2 AND {VISIBLE}{pinned}{conditions}"
+            ),
+            params_from_iter(values),
+            |row| row.get(0),
+        )
+    }
+
+    fn query_messages(
+        conn: &Connection,
+        project_id: &str,
+        agent_id: &str,
+        filter: &MessageFilter<'_>,
+        limit: u32,
+        include_pinned: bool,
+    ) -> SqliteResult<Vec<Message>> {
+        let mut values = vec![
+            Value::Text(project_id.to_string()),
+            Value::Text(agent_id.to_string()),
+            Value::Integer(limit.into()),
+        ];
+        let conditions = filter.conditions(&mut values);
+        let pinned = if include_pinned {
+            ""
+        } else {
+            " AND NOT pinned"
+        };
+        let mut stmt = conn.prepare(&format!(
+            r"SELECT {MESSAGE_COLUMNS}
+              FROM messages
+              WHERE project_id = ?1 AND to_agent = ?2
+                AND {VISIBLE}{pinned}{conditions}
+              ORDER BY {DELIVERY_ORDER}
+              LIMIT ?3"
+        ))?;
+
+        let messages = stmt
+            .query_map(params_from_iter(values), Message::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(messages)
+    }
+
+    /// Deletes a specific message by ID. With `dry_run`, the deletion is
+    /// rolled back, only reporting its outcome.
+    ///
+    /// Returns `true` if the message was deleted, `false` if it didn't exist.
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    pub fn delete_message(&self, message_id: &str, dry_run: bool) -> DbResult<bool> {
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+        self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let rows = tx.execute("DELETE FROM messages WHERE id = ?1", params![id])?;
+            if !dry_run {
+                tx.commit()?;
+            }
+            Ok(rows > 0)
+        })
+    }
+
+    /// Deletes every pending message in an agent's queue matching the filter,
+    /// including those awaiting acknowledgment, in one statement. An empty
+    /// filter clears the queue. With `dry_run`, the deletion is rolled back,
+    /// only reporting its outcome.
+    ///
+    /// Returns the number of messages deleted.
+    pub fn delete_messages(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        filter: &MessageFilter<'_>,
+        dry_run: bool,
+    ) -> DbResult<usize> {
+        self.with_conn(|conn| {
+            let mut values = vec![
+                Value::Text(project_id.to_string()),
+                Value::Text(agent_id.to_string()),
+            ];
+            let conditions = filter.conditions(&mut values);
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let rows = tx.execute(
+                &format!(
+                    "DELETE FROM messages WHERE project_id = ?1 AND to_agent = ?2{conditions}"
+                ),
+                params_from_iter(values),
+            )?;
+            if !dry_run {
+                tx.commit()?;
+            }
+            Ok(rows)
+        })
+    }
+
+    /// Withdraws a message its sender regrets: deletes it if it is still
+    /// pending in the project, was sent by `from_agent`, and has never been
+    /// delivered (a message received, even if awaiting acknowledgment, can't
+    /// be recalled).
+    ///
+    /// Returns `true` if the message was recalled. A peeked message can still
+    /// be recalled, since peeking doesn't deliver it.
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    pub fn recall_message(
+        &self,
+        project_id: &str,
+        message_id: &str,
+        from_agent: &str,
+    ) -> DbResult<bool> {
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+        self.with_conn(|conn| {
+            let rows = conn.execute(
+                r"DELETE FROM messages
+                  WHERE id = ?1 AND project_id = ?2 AND from_agent = ?3 AND delivery_count = 0",
+                params![id, project_id, from_agent],
+            )?;
+            Ok(rows > 0)
+        })
+    }
+
+    /// Returns the agent whose queue holds a message, or `None` if it isn't
+    /// queued (anymore).
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    pub fn message_recipient(&self, message_id: &str) -> DbResult<Option<String>> {
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+        self.with_read_conn(|conn| {
+            let result = conn.query_row(
+                "SELECT to_agent FROM messages WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            );
+            match result {
+                Ok(agent_id) => Ok(Some(agent_id)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    /// Acknowledges a message received with at-least-once delivery (from an
+    /// at-least-once queue, or with manual acknowledgment), deleting it.
+    ///
+    /// Returns `true` if the message was acknowledged, `false` if it doesn't
+    /// exist or was never delivered in at-least-once mode. With a `consumer`,
+    /// also `false` if the message was last received by someone else, e.g.
+    /// redelivered to another group member after the consumer's visibility
+    /// timeout expired.
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    pub fn ack_message(&self, message_id: &str, consumer: Option<&str>) -> DbResult<bool> {
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+        let archive = self.archive_days.load(Ordering::Relaxed) > 0;
+        self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            if archive {
+                Self::archive_messages(&tx, &[id.to_string()])?;
+            }
+            let rows = tx.execute(
+                r"DELETE FROM messages
+                  WHERE id = ?1 AND visible_after IS NOT NULL AND (?2 IS NULL OR claimed_by IS ?2)",
+                params![id, consumer.map(str::trim).filter(|s| !s.is_empty())],
+            )?;
+            // Dropping the transaction undoes the archiving of an unacknowledged message
+            if rows > 0 {
+                tx.execute(
+                    r"UPDATE delivery_status
+                      SET acknowledged_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+                      WHERE message_id = ?1",
+                    params![id],
+                )?;
+                tx.commit()?;
+            }
+            Ok(rows > 0)
+        })
+    }
+
+    /// Negatively acknowledges a message received with at-least-once delivery,
+    /// returning it to its queue after `delay_secs` (immediately if 0, capped
+    /// at [`MAX_VISIBILITY_TIMEOUT_SECS`]) instead of when its visibility
+    /// timeout expires. The delivery still counts as an attempt, so the next
+    /// one reports the following attempt number, and a message returned after
+    /// its last allowed attempt is dead-lettered.
+    ///
+    /// Returns `true` if the message was returned, `false` if it doesn't exist
+    /// or isn't awaiting acknowledgment (by `consumer`, if given; see
+    /// [`ack_message`](Self::ack_message)).
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    pub fn nack_message(
+        &self,
+        message_id: &str,
+        delay_secs: u32,
+        consumer: Option<&str>,
+    ) -> DbResult<bool> {
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+        let delay = format!("+{} seconds", delay_secs.min(MAX_VISIBILITY_TIMEOUT_SECS));
+        let queue: Option<(String, String)> = self.with_conn(|conn| {
+            let result = conn.query_row(
+                r"UPDATE messages
+                  SET visible_after = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?2), claimed_by = NULL
+                  WHERE id = ?1 AND visible_after > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+                    AND (?3 IS NULL OR claimed_by IS ?3)
+                  RETURNING project_id, to_agent",
+                params![id, delay, consumer.map(str::trim).filter(|s| !s.is_empty())],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            );
+            match result {
+                Ok(queue) => Ok(Some(queue)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })?;
+        match queue {
+            Some((project_id, agent_id)) => {
+                if delay_secs == 0 {
+                    self.waiters.notify(&project_id, &agent_id);
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Pins or unpins a pending message. Pinned messages stay in their queue,
+    /// visible to peeks, but are skipped by receives until unpinned; they
+    /// still expire and can be deleted.
+    ///
+    /// Returns `true` if the message is pending in the project.
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    pub fn set_pinned(&self, project_id: &str, message_id: &str, pinned: bool) -> DbResult<bool> {
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+        let agent_id: Option<String> = self.with_conn(|conn| {
+            let result = conn.query_row(
+                r"UPDATE messages SET pinned = ?3
+                  WHERE id = ?1 AND project_id = ?2
+                  RETURNING to_agent",
+                params![id, project_id, pinned],
+                |row| row.get(0),
+            );
+            match result {
+                Ok(agent_id) => Ok(Some(agent_id)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })?;
+        match agent_id {
+            Some(agent_id) => {
+                if !pinned {
+                    self.waiters.notify(project_id, &agent_id);
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Hides a pending message from receives and peeks until `until` (an ISO
+    /// 8601 UTC timestamp as stored, e.g. `2025-01-08T12:00:00Z`), after which
+    /// it is delivered again in its usual place in the queue. Snoozing until a
+    /// past time wakes a snoozed message up. The message still expires with its
+    /// TTL meanwhile.
+    ///
+    /// Returns `true` if the message was snoozed, `false` if the project has no
+    /// such message or it is awaiting acknowledgment.
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    pub fn snooze_message(
+        &self,
+        project_id: &str,
+        message_id: &str,
+        until: &str,
+    ) -> DbResult<bool> {
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+        let agent_id: Option<String> = self.with_conn(|conn| {
+            let result = conn.query_row(
+                r"UPDATE messages SET snoozed_until = ?3
+                  WHERE id = ?1 AND project_id = ?2
+                    AND (visible_after IS NULL
+                         OR visible_after <= strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+                  RETURNING to_agent",
+                params![id, project_id, until],
+                |row| row.get(0),
+            );
+            match result {
+                Ok(agent_id) => Ok(Some(agent_id)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })?;
+        match agent_id {
+            Some(agent_id) => {
+                if until <= time::now_iso8601().as_str() {
+                    self.waiters.notify(project_id, &agent_id);
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Returns up to `limit` pinned messages of a project, or of one agent's
+    /// queue, in delivery order within each queue.
+    ///
+    /// Limit is capped at [`MAX_MESSAGE_LIMIT`] (500).
+    pub fn pinned_messages(
+        &self,
+        project_id: &str,
+        agent_id: Option<&str>,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<ThreadMessage>> {
+        let limit = limit.unwrap_or(100).min(MAX_MESSAGE_LIMIT);
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                r"SELECT {MESSAGE_COLUMNS}, to_agent
+                  FROM messages
+                  WHERE project_id = ?1 AND (?2 IS NULL OR to_agent = ?2) AND pinned
+                  ORDER BY to_agent, {DELIVERY_ORDER}
+                  LIMIT ?3"
+            ))?;
+            let messages = stmt
+                .query_map(params![project_id, agent_id, limit], |row| {
+                    Ok(ThreadMessage {
+                        message: Message::from_row(row)?,
+                        to_agent: row.get(14)?,
+                        state: MessageState::Pending,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(messages)
+        })
+    }
+
+    /// Moves expired messages, and in-flight messages whose visibility timeout
+    /// passed after their last allowed delivery, to the dead-letter queue.
+    ///
+    /// Covers a single queue, or all queues if `queue` is `None`. Returns the
+    /// number of messages moved.
+    fn move_dead_letters(conn: &Connection, queue: Option<(&str, &str)>) -> SqliteResult<usize> {
+        let (project_id, agent_id) = queue.unzip();
+        let ids: Vec<i64> = {
+            let mut stmt = conn.prepare(
+                r"INSERT OR REPLACE INTO dead_letters
+                    (id, project_id, to_agent, from_agent, reference_id, content, created_at,
+                     trace_id, priority, metadata, attachments, confidential, seq, tags,
+                     reply_to, delivery_count, reason)
+                  SELECT m.id, m.project_id, m.to_agent, m.from_agent, m.reference_id, m.content,
+                         m.created_at, m.trace_id, m.priority, m.metadata, m.attachments,
+                         m.confidential, m.seq, m.tags, m.reply_to,
+                         m.delivery_count,
+                         CASE WHEN m.expires_at <= strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+                              THEN 'expired' ELSE 'max_delivery_attempts' END
+                  FROM messages m
+                  LEFT JOIN queue_settings s
+                    ON s.project_id = m.project_id AND s.agent_id = m.to_agent
+                  WHERE (?1 IS NULL OR (m.project_id = ?1 AND m.to_agent = ?2))
+                    AND (m.expires_at <= strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+                         OR (m.visible_after <= strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+                             AND m.delivery_count >= COALESCE(s.max_delivery_attempts, ?3)))
+                  RETURNING id",
+            )?;
+            let ids = stmt
+                .query_map(
+                    params![project_id, agent_id, DEFAULT_MAX_DELIVERY_ATTEMPTS],
+                    |row| row.get(0),
+                )?
+                .collect::<Result<_, _>>()?;
+            ids
+        };
+        let mut delete = conn.prepare_cached("DELETE FROM messages WHERE id = ?1")?;
+        for id in &ids {
+            delete.execute(params![id])?;
+        }
+        Ok(ids.len())
+    }
+
+    /// Moves messages that expired unread or exhausted their delivery attempts,
+    /// in every queue, to their project's dead-letter queue.
+    ///
+    /// Receiving from a queue does the same for that queue, so this only needs
+    /// to run periodically. Returns the number of messages moved.
+    pub fn sweep_dead_letters(&self) -> DbResult<usize> {
+        self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let moved = Self::move_dead_letters(&tx, None)?;
+            tx.commit()?;
+            Ok(moved)
+        })
+    }
+
+    /// Removes pending messages, in every queue, that are older than the
+    /// message retention (see [`set_message_retention`](Self::set_message_retention)),
+    /// such as those left in the queues of agents that no longer exist. With
+    /// the archive enabled, they are archived rather than deleted outright.
+    ///
+    /// Returns the number of messages removed.
+    pub fn purge_stale_messages(&self) -> DbResult<usize> {
+        let days = self.message_retention_days.load(Ordering::Relaxed);
+        if days == 0 {
+            return Ok(0);
+        }
+        let archive = self.archive_days.load(Ordering::Relaxed) > 0;
+        self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let cutoff = format!("-{days} days");
+            if archive {
+                let ids: Vec<String> = tx
+                    .prepare(
+                        r"SELECT CAST(id AS TEXT) FROM messages
+                          WHERE created_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1)",
+                    )?
+                    .query_map(params![cutoff], |row| row.get(0))?
+                    .collect::<Result<_, _>>()?;
+                Self::archive_messages(&tx, &ids)?;
+            }
+            let removed = tx.execute(
+                "DELETE FROM messages WHERE created_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1)",
+                params![cutoff],
+            )?;
+            tx.commit()?;
+            Ok(removed)
+        })
+    }
+
+    /// Returns the messages in a project's dead-letter queue, optionally only
+    /// those addressed to one agent, oldest message first.
+    ///
+    /// Limit is capped at [`MAX_MESSAGE_LIMIT`] (500).
+    pub fn dead_letters(
+        &self,
+        project_id: &str,
+        agent_id: Option<&str>,
+        limit: Option<u32>,
+    ) -> DbResult<Vec<DeadLetter>> {
+        let limit = limit.unwrap_or(100).min(MAX_MESSAGE_LIMIT);
+
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                r"SELECT {MESSAGE_COLUMNS}, to_agent, delivery_count, reason, dead_lettered_at
+                  FROM dead_letters
+                  WHERE project_id = ?1 AND (?2 IS NULL OR to_agent = ?2)
+                  ORDER BY id ASC
+                  LIMIT ?3"
+            ))?;
+            let dead_letters = stmt
+                .query_map(params![project_id, agent_id, limit], DeadLetter::from_row)?
+                .collect::<Result<_, _>>()?;
+            Ok(dead_letters)
+        })
+    }
+
+    /// Moves a dead-lettered message back to its queue with a fresh delivery
+    /// count and no expiry. It keeps its ID, so it is delivered in its
+    /// original position.
+    ///
+    /// Returns `true` if the message was requeued, `false` if it isn't in a
+    /// dead-letter queue.
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    pub fn requeue_dead_letter(&self, message_id: &str) -> DbResult<bool> {
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+        let queue: Option<(String, String)> = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let queue = tx.query_row(
+                r"INSERT INTO messages
+                    (id, project_id, to_agent, from_agent, reference_id, content, created_at,
+                     trace_id, priority, metadata, attachments, confidential, seq, tags,
+                     reply_to)
+                  SELECT id, project_id, to_agent, from_agent, reference_id, content, created_at,
+                         trace_id, priority, metadata, attachments, confidential, seq, tags,
+                         reply_to
+                  FROM dead_letters
+                  WHERE id = ?1
+                  RETURNING project_id, to_agent",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            );
+            let queue = match queue {
+                Ok(queue) => Some(queue),
+                Err(rusqlite::Error::QueryReturnedNoRows) => None,
+                Err(e) => return Err(e),
+            };
+            tx.execute("DELETE FROM dead_letters WHERE id = ?1", params![id])?;
+            // Back to pending: earlier deliveries don't count for the new round
+            tx.execute(
+                r"UPDATE delivery_status SET peeked_at = NULL, received_at = NULL
+                  WHERE message_id = ?1",
+                params![id],
+            )?;
+            tx.commit()?;
+            Ok(queue)
+        })?;
+        if let Some((project_id, agent_id)) = &queue {
+            self.waiters.notify(project_id, agent_id);
+        }
+        Ok(queue.is_some())
+    }
+
+    /// Puts messages an agent consumed back into its queue, e.g. after it
+    /// crashed before acting on them. They keep their IDs (and so their
+    /// position), with a fresh delivery count and no expiry.
+    ///
+    /// Restores the given messages if `message_ids` is set, otherwise every
+    /// message the agent consumed in the last `within_secs` seconds. Only
+    /// messages still in the archive can be restored. Returns the IDs of the
+    /// restored messages.
+    ///
+    /// # Errors
+    /// - `ArchiveDisabled` if consumed messages aren't archived
+    /// - `InvalidMessageId` if a message ID is not a valid numeric ID
+    pub fn restore_messages(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        message_ids: Option<&[String]>,
+        within_secs: u64,
+    ) -> DbResult<Vec<String>> {
+        if self.archive_days.load(Ordering::Relaxed) == 0 {
+            return Err(DbError::ArchiveDisabled);
+        }
+        let ids = message_ids
+            .map(|ids| {
+                ids.iter()
+                    .map(|id| {
+                        id.parse::<i64>()
+                            .map_err(|_| DbError::InvalidMessageId { id: id.clone() })
+                    })
+                    .collect::<DbResult<Vec<_>>>()
+            })
+            .transpose()?
+            .map(|ids| serde_json::Value::from(ids).to_string());
+
+        let restored: Vec<i64> = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let restored = {
+                let mut stmt = tx.prepare(
+                    r"INSERT INTO messages
+                        (id, project_id, to_agent, from_agent, reference_id, content, created_at,
+                         trace_id, priority, metadata, attachments, confidential, seq, tags,
+                         reply_to)
+                      SELECT id, project_id, to_agent, from_agent, reference_id, content,
+                             created_at, trace_id, priority, metadata, attachments, confidential,
+                             seq, tags, reply_to
+                      FROM archived_messages
+                      WHERE project_id = ?1 AND to_agent = ?2
+                        AND CASE WHEN ?3 IS NULL
+                                 THEN consumed_at >= strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?4)
+                                 ELSE id IN (SELECT value FROM json_each(?3)) END
+                      ON CONFLICT (id) DO NOTHING
+                      RETURNING id",
+                )?;
+                let rows = stmt.query_map(
+                    params![project_id, agent_id, ids, format!("-{within_secs} seconds")],
+                    |row| row.get(0),
+                )?;
+                rows.collect::<Result<Vec<i64>, _>>()?
+            };
+            for id in &restored {
+                tx.execute("DELETE FROM archived_messages WHERE id = ?1", params![id])?;
+                // Back to pending: earlier deliveries don't count for the new round
+                tx.execute(
+                    r"UPDATE delivery_status
+                      SET peeked_at = NULL, received_at = NULL, acknowledged_at = NULL
+                      WHERE message_id = ?1",
+                    params![id],
+                )?;
+            }
+            tx.commit()?;
+            Ok(restored)
+        })?;
+        if !restored.is_empty() {
+            self.waiters.notify(project_id, agent_id);
+        }
+        Ok(restored.into_iter().map(|id| id.to_string()).collect())
+    }
+
+    /// Copies messages about to be consumed into the archive.
+    ///
+    /// Uses an upsert rather than `INSERT OR REPLACE`, since replacement
+    /// deletes don't fire the triggers maintaining the search index.
+    fn archive_messages(conn: &Connection, ids: &[String]) -> SqliteResult<()> {
+        let mut stmt = conn.prepare_cached(
+            r"INSERT INTO archived_messages
+                (id, project_id, to_agent, from_agent, reference_id, content, created_at,
+                 trace_id, priority, metadata, attachments, confidential, seq, tags, reply_to)
+              SELECT id, project_id, to_agent, from_agent, reference_id, content, created_at,
+                     trace_id, priority, metadata, attachments, confidential, seq, tags, reply_to
+              FROM messages
+              WHERE id = ?1
+              ON CONFLICT (id) DO UPDATE SET
+                project_id = excluded.project_id,
+                to_agent = excluded.to_agent,
+                from_agent = excluded.from_agent,
+                reference_id = excluded.reference_id,
+                content = excluded.content,
+                created_at = excluded.created_at,
+                trace_id = excluded.trace_id,
+                priority = excluded.priority,
+                metadata = excluded.metadata,
+                attachments = excluded.attachments,
+                confidential = excluded.confidential,
+                seq = excluded.seq,
+                tags = excluded.tags,
+                reply_to = excluded.reply_to,
+                consumed_at = excluded.consumed_at",
+        )?;
+        for id in ids {
+            stmt.execute(params![id])?;
+        }
+        Ok(())
+    }
+
+    /// Sets the delivery mode of an agent's queue.
+    ///
+    /// The visibility timeout is capped at [`MAX_VISIBILITY_TIMEOUT_SECS`] (12 hours).
+    pub fn set_queue_settings(
+        &self,
+        project_id: &str,
+        agent_id: &str,
+        settings: QueueSettings,
+    ) -> DbResult<QueueSettings> {
+        let settings = QueueSettings {
+            visibility_timeout_secs: settings
+                .visibility_timeout_secs
+                .clamp(1, MAX_VISIBILITY_TIMEOUT_SECS),
+            max_delivery_attempts: settings.max_delivery_attempts.max(1),
+            ..settings
+        };
+        self.with_conn(|conn| {
+            conn.execute(
+                r"INSERT OR REPLACE INTO queue_settings
+                  (project_id, agent_id, delivery_mode, visibility_timeout_secs,
+                   max_delivery_attempts)
+                  VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    project_id,
+                    agent_id,
+                    settings.delivery_mode.as_str(),
+                    settings.visibility_timeout_secs,
+                    settings.max_delivery_attempts
+                ],
+            )?;
+            Ok(settings)
+        })
+    }
+
+    /// Returns the delivery settings of an agent's queue.
+    pub fn queue_settings(&self, project_id: &str, agent_id: &str) -> DbResult<QueueSettings> {
+        self.with_read_conn(|conn| Self::read_queue_settings(conn, project_id, agent_id))
+    }
+
+    fn read_queue_settings(
+        conn: &Connection,
+        project_id: &str,
+        agent_id: &str,
+    ) -> SqliteResult<QueueSettings> {
+        let result = conn.query_row(
+            r"SELECT delivery_mode, visibility_timeout_secs, max_delivery_attempts
+              FROM queue_settings
+              WHERE project_id = ?1 AND agent_id = ?2",
+            params![project_id, agent_id],
+            |row| {
+                let mode: String = row.get(0)?;
+                Ok(QueueSettings {
+                    delivery_mode: mode.parse().unwrap_or_default(),
+                    visibility_timeout_secs: row.get(1)?,
+                    max_delivery_attempts: row.get(2)?,
+                })
+            },
+        );
+        match result {
+            Ok(settings) => Ok(settings),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(QueueSettings::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Pauses an agent's queue: receives return nothing until it is resumed,
+    /// while sends keep queueing messages. Messages already in flight can
+    /// still be acknowledged.
+    ///
+    /// Returns `true` if the queue was running.
+    pub fn pause_queue(&self, project_id: &str, agent_id: &str) -> DbResult<bool> {
+        self.with_conn(|conn| {
+            let rows = conn.execute(
+                "INSERT OR IGNORE INTO paused_queues (project_id, agent_id) VALUES (?1, ?2)",
+                params![project_id, agent_id],
+            )?;
+            Ok(rows > 0)
+        })
+    }
+
+    /// Resumes a queue paused with [`pause_queue`](Self::pause_queue), waking
+    /// agents waiting on it.
+    ///
+    /// Returns `true` if the queue was paused.
+    pub fn resume_queue(&self, project_id: &str, agent_id: &str) -> DbResult<bool> {
+        let resumed = self.with_conn(|conn| {
+            let rows = conn.execute(
+                "DELETE FROM paused_queues WHERE project_id = ?1 AND agent_id = ?2",
+                params![project_id, agent_id],
+            )?;
+            Ok(rows > 0)
+        })?;
+        if resumed {
+            self.waiters.notify(project_id, agent_id);
+        }
+        Ok(resumed)
+    }
+
+    /// Returns whether an agent's queue is paused.
+    pub fn queue_paused(&self, project_id: &str, agent_id: &str) -> DbResult<bool> {
+        self.with_read_conn(|conn| Self::is_paused(conn, project_id, agent_id))
+    }
+
+    fn is_paused(conn: &Connection, project_id: &str, agent_id: &str) -> SqliteResult<bool> {
+        conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM paused_queues WHERE project_id = ?1 AND agent_id = ?2)",
+            params![project_id, agent_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// Attaches an annotation to a message on behalf of an agent.
+    ///
+    /// The message doesn't need to be stored anymore, so receivers can
+    /// annotate messages they have consumed. Attaching the same annotation
+    /// again only refreshes its timestamp. Annotations are kept for
+    /// [`ANNOTATION_RETENTION_DAYS`] (7 days).
+    ///
+    /// # Errors
+    /// - `EmptyField` if project_id, agent_id or annotation is empty
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    /// - `ContentTooLarge` if annotation exceeds 64 bytes
+    pub fn annotate_message(
+        &self,
+        project_id: &str,
+        message_id: &str,
+        agent_id: &str,
+        annotation: &str,
+    ) -> DbResult<Annotation> {
+        if project_id.trim().is_empty() {
+            return Err(DbError::EmptyField {
+                field: "project_id",
+            });
+        }
+        if agent_id.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "agent_id" });
+        }
+        let annotation = annotation.trim();
+        if annotation.is_empty() {
+            return Err(DbError::EmptyField {
+                field: "annotation",
+            });
+        }
+        if annotation.len() > MAX_ANNOTATION_SIZE {
+            return Err(DbError::ContentTooLarge {
+                size: annotation.len(),
+                limit: MAX_ANNOTATION_SIZE,
+            });
+        }
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+
+        self.with_conn(|conn| {
+            conn.query_row(
+                r"INSERT OR REPLACE INTO annotations (project_id, message_id, agent_id, annotation)
+                  VALUES (?1, ?2, ?3, ?4)
+                  RETURNING message_id, agent_id, annotation, created_at",
+                params![project_id, id, agent_id, annotation],
+                Annotation::from_row,
+            )
+        })
+    }
+
+    /// Returns the annotations attached to the given messages, grouped by
+    /// message and oldest first.
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if a message ID is not a valid numeric ID
+    pub fn annotations(
+        &self,
+        project_id: &str,
+        message_ids: &[String],
+    ) -> DbResult<Vec<Annotation>> {
+        let ids = message_ids
+            .iter()
+            .map(|id| {
+                id.parse::<i64>()
+                    .map(Value::Integer)
+                    .map_err(|_| DbError::InvalidMessageId { id: id.clone() })
+            })
+            .collect::<DbResult<Vec<_>>>()?;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.with_read_conn(|conn| {
+            let placeholders = (2..=ids.len() + 1)
+                .map(|i| format!("?{i}"))
+                .collect::<Vec<_>>()
+                .join(",");
+            let mut stmt = conn.prepare(&format!(
+                r"SELECT message_id, agent_id, annotation, created_at
+                  FROM annotations
+                  WHERE project_id = ?1 AND message_id IN ({placeholders})
+                  ORDER BY message_id, created_at, rowid"
+            ))?;
+            let values = std::iter::once(Value::Text(project_id.to_string())).chain(ids);
+            let annotations = stmt
+                .query_map(params_from_iter(values), Annotation::from_row)?
+                .collect::<Result<_, _>>()?;
+            Ok(annotations)
+        })
+    }
+
+    /// Reports what became of the given messages: still queued (and whether
+    /// the recipient peeked at them), received, acknowledged, dead-lettered or
+    /// deleted. Statuses are kept for [`DELIVERY_STATUS_RETENTION_DAYS`] after
+    /// sending, or as long as the message is still queued.
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if a message ID is not a valid numeric ID
+    pub fn message_statuses(
+        &self,
+        project_id: &str,
+        message_ids: &[String],
+    ) -> DbResult<Vec<MessageStatus>> {
+        let ids = message_ids
+            .iter()
+            .map(|id| {
+                id.parse::<i64>()
+                    .map_err(|_| DbError::InvalidMessageId { id: id.clone() })
+            })
+            .collect::<DbResult<Vec<_>>>()?;
+
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(
+                r"SELECT
+                    CASE
+                      WHEN m.id IS NOT NULL THEN
+                        CASE WHEN s.received_at IS NOT NULL THEN 'received'
+                             WHEN s.peeked_at IS NOT NULL THEN 'peeked'
+                             ELSE 'pending' END
+                      WHEN d.id IS NOT NULL THEN 'dead_lettered'
+                      WHEN s.acknowledged_at IS NOT NULL THEN 'acknowledged'
+                      WHEN s.received_at IS NOT NULL THEN 'received'
+                      WHEN s.message_id IS NOT NULL THEN 'deleted'
+                    END,
+                    s.to_agent, s.sent_at, s.peeked_at, s.received_at, s.acknowledged_at,
+                    s.received_by
+                  FROM (SELECT ?2 AS id) k
+                  LEFT JOIN delivery_status s ON s.message_id = k.id AND s.project_id = ?1
+                  LEFT JOIN messages m ON m.id = k.id AND m.project_id = ?1
+                  LEFT JOIN dead_letters d ON d.id = k.id AND d.project_id = ?1",
+            )?;
+            ids.iter()
+                .map(|&id| {
+                    stmt.query_row(params![project_id, id], |row| {
+                        Ok(MessageStatus {
+                            message_id: id.to_string(),
+                            status: DeliveryStatus::from_sql(
+                                row.get::<_, Option<String>>(0)?.as_deref(),
+                            ),
+                            to_agent: row.get(1)?,
+                            sent_at: row.get(2)?,
+                            peeked_at: row.get(3)?,
+                            received_at: row.get(4)?,
+                            acknowledged_at: row.get(5)?,
+                            received_by: row.get(6)?,
+                        })
+                    })
+                })
+                .collect()
+        })
+    }
+
+    /// Records a typed link from one message to another, e.g. that `source`
+    /// blocks `target`. Like annotations, links can refer to consumed messages.
+    /// Linking again is a no-op.
+    ///
+    /// # Errors
+    /// - `EmptyField` if project_id is empty
+    /// - `InvalidMessageId` if either message ID is not a valid numeric ID
+    /// - `SelfLink` if both IDs are the same
+    pub fn link_messages(
+        &self,
+        project_id: &str,
+        source_id: &str,
+        target_id: &str,
+        kind: LinkKind,
+    ) -> DbResult<()> {
+        if project_id.trim().is_empty() {
+            return Err(DbError::EmptyField {
+                field: "project_id",
+            });
+        }
+        let parse = |id: &str| {
+            id.parse::<i64>()
+                .map_err(|_| DbError::InvalidMessageId { id: id.to_string() })
+        };
+        let (source, target) = (parse(source_id)?, parse(target_id)?);
+        if source == target {
+            return Err(DbError::SelfLink {
+                id: source_id.to_string(),
+            });
+        }
+
+        self.with_conn(|conn| {
+            conn.execute(
+                r"INSERT OR IGNORE INTO message_links (project_id, source_id, target_id, kind)
+                  VALUES (?1, ?2, ?3, ?4)",
+                params![project_id, source, target, kind.as_str()],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Returns the messages linked to or from a message, oldest link first.
+    ///
+    /// # Errors
+    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
+    pub fn related_messages(
+        &self,
+        project_id: &str,
+        message_id: &str,
+    ) -> DbResult<Vec<RelatedMessage>> {
+        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
+            id: message_id.to_string(),
+        })?;
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(
+                r"SELECT target_id, kind, 0, created_at FROM message_links
+                  WHERE project_id = ?1 AND source_id = ?2
+                  UNION ALL
+                  SELECT source_id, kind, 1, created_at FROM message_links
+                  WHERE project_id = ?1 AND target_id = ?2
+                  ORDER BY 4, 1",
+            )?;
+            let related = stmt
+                .query_map(params![project_id, id], |row| {
+                    let kind: String = row.get(1)?;
+                    let incoming: bool = row.get(2)?;
+                    // Unknown kinds can only come from a newer server version
+                    let relation = match kind.parse::<LinkKind>() {
+                        Ok(kind) if incoming => kind.inverse_str().to_string(),
+                        _ => kind,
+                    };
+                    Ok(RelatedMessage {
+                        message_id: row.get::<_, i64>(0)?.to_string(),
+                        relation,
+                        linked_at: row.get(3)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(related)
+        })
+    }
+
+    // -------------------------------------------------------------------------
+    // Agent groups
+    // -------------------------------------------------------------------------
+
+    /// Defines an agent group, whose members share the messages sent to it
+    /// with [`send_to_group`](Self::send_to_group), or changes the strategy
+    /// of an existing one. Returns whether the group was created.
+    ///
+    /// # Errors
+    /// - `EmptyField` if project_id or group_id is empty
+    pub fn create_group(
+        &self,
+        project_id: &str,
+        group_id: &str,
+        strategy: GroupStrategy,
+    ) -> DbResult<bool> {
+        if project_id.trim().is_empty() {
+            return Err(DbError::EmptyField {
+                field: "project_id",
+            });
+        }
+        if group_id.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "group_id" });
+        }
+        self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let updated = tx.execute(
+                "UPDATE agent_groups SET strategy = ?3 WHERE project_id = ?1 AND group_id = ?2",
+                params![project_id, group_id, strategy.as_str()],
+            )?;
+            if updated == 0 {
+                tx.execute(
+                    "INSERT INTO agent_groups (project_id, group_id, strategy) VALUES (?1, ?2, ?3)",
+                    params![project_id, group_id, strategy.as_str()],
+                )?;
+            }
+            tx.commit()?;
+            Ok(updated == 0)
+        })
+    }
+
+    /// Adds an agent to a group, returning `false` if it was already a member.
+    ///
+    /// # Errors
+    /// - `EmptyField` if agent_id is empty
+    /// - `UnknownGroup` if the group doesn't exist
+    pub fn add_group_member(
+        &self,
+        project_id: &str,
+        group_id: &str,
+        agent_id: &str,
+    ) -> DbResult<bool> {
+        if agent_id.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "agent_id" });
+        }
+        self.with_conn(|conn| {
+            let exists: bool = conn.query_row(
+                "SELECT EXISTS(SELECT 1 FROM agent_groups WHERE project_id = ?1 AND group_id = ?2)",
+                params![project_id, group_id],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                return Ok(Err(DbError::UnknownGroup {
+                    project_id: project_id.to_string(),
+                    group_id: group_id.to_string(),
+                }));
+            }
+            let added = conn.execute(
+                r"INSERT OR IGNORE INTO agent_group_members (project_id, group_id, agent_id)
+                  VALUES (?1, ?2, ?3)",
+                params![project_id, group_id, agent_id],
+            )?;
+            Ok(Ok(added > 0))
+        })?
+    }
+
+    /// Removes an agent from a group, returning whether it was a member.
+    pub fn remove_group_member(
+        &self,
+        project_id: &str,
+        group_id: &str,
+        agent_id: &str,
+    ) -> DbResult<bool> {
+        self.with_conn(|conn| {
+            let removed = conn.execute(
+                r"DELETE FROM agent_group_members
+                  WHERE project_id = ?1 AND group_id = ?2 AND agent_id = ?3",
+                params![project_id, group_id, agent_id],
+            )?;
+            Ok(removed > 0)
+        })
+    }
+
+    /// Returns the member the next message sent to a group would go to,
+    /// without sending anything.
+    ///
+    /// # Errors
+    /// - `UnknownGroup` if the group doesn't exist
+    /// - `EmptyGroup` if it has no members
+    pub fn next_group_member(&self, project_id: &str, group_id: &str) -> DbResult<String> {
+        self.with_read_conn(|conn| Self::pick_group_member(conn, project_id, group_id, false))?
+    }
+
+    /// Sends a message to one member of an agent group, chosen by the group's
+    /// strategy. The message's `to_agent` is ignored. Picking the member and
+    /// queueing the message happen in one transaction, so concurrent sends
+    /// take turns.
+    ///
+    /// # Errors
+    /// Same as [`send_message`](Self::send_message), plus
+    /// - `UnknownGroup` if the group doesn't exist
+    /// - `EmptyGroup` if it has no members
+    pub fn send_to_group(&self, message: &NewMessage<'_>, group_id: &str) -> DbResult<Delivery> {
+        Self::validate(message)?;
+        self.check_schema(message)?;
+        self.check_attachments(message)?;
+        let delivery = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let agent_id = match Self::pick_group_member(&tx, message.project_id, group_id, true)? {
+                Ok(agent_id) => agent_id,
+                Err(e) => return Ok(Err(e)),
+            };
+            let copy = NewMessage {
+                to_agent: &agent_id,
+                ..*message
+            };
+            if let Some(e) = self.queue_full(&tx, &copy)? {
+                return Ok(Err(e));
+            }
+            let message_id = Self::insert_message(&tx, &copy)?;
+            tx.commit()?;
+            Ok(Ok(Delivery {
+                agent_id,
+                message_id,
+            }))
+        })??;
+        self.waiters.notify(message.project_id, &delivery.agent_id);
+        Ok(delivery)
+    }
+
+    /// Chooses the member of a group the next message goes to, moving the
+    /// round-robin position past it if `advance` is set.
+    fn pick_group_member(
+        conn: &Connection,
+        project_id: &str,
+        group_id: &str,
+        advance: bool,
+    ) -> SqliteResult<DbResult<String>> {
+        let group = conn.query_row(
+            "SELECT strategy, next_member FROM agent_groups WHERE project_id = ?1 AND group_id = ?2",
+            params![project_id, group_id],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?)),
+        );
+        let (strategy, next_member) = match group {
+            Ok(group) => group,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Ok(Err(DbError::UnknownGroup {
+                    project_id: project_id.to_string(),
+                    group_id: group_id.to_string(),
+                }))
+            }
+            Err(e) => return Err(e),
+        };
+        let mut stmt = conn.prepare(
+            r"SELECT g.agent_id FROM agent_group_members g
+              LEFT JOIN (SELECT to_agent, COUNT(*) AS queued FROM messages
+                         WHERE project_id = ?1 GROUP BY to_agent) q ON q.to_agent = g.agent_id
+              WHERE g.project_id = ?1 AND g.group_id = ?2
+              ORDER BY CASE WHEN ?3 THEN COALESCE(q.queued, 0) ELSE 0 END, g.agent_id",
+        )?;
+        let least_loaded = strategy.parse() == Ok(GroupStrategy::LeastLoaded);
+        let mut members = stmt
+            .query_map(params![project_id, group_id, least_loaded], |row| {
+                row.get(0)
+            })?
+            .collect::<Result<Vec<String>, _>>()?;
+        if members.is_empty() {
+            return Ok(Err(DbError::EmptyGroup {
+                group_id: group_id.to_string(),
+            }));
+        }
+        if least_loaded {
+            return Ok(Ok(members.swap_remove(0)));
+        }
+        #[allow(clippy::cast_possible_truncation)] // reduced modulo the member count
+        let position = (next_member % members.len() as u64) as usize;
+        if advance {
+            conn.execute(
+                "UPDATE agent_groups SET next_member = ?3 WHERE project_id = ?1 AND group_id = ?2",
+                params![project_id, group_id, position + 1],
+            )?;
+        }
+        Ok(Ok(members.swap_remove(position)))
+    }
+
+    // -------------------------------------------------------------------------
+    // Recurring messages
+    // -------------------------------------------------------------------------
+
+    /// Creates a schedule that sends a message whenever `cron_expr` fires
+    /// (see [`crate::cron`]).
+    ///
+    /// # Errors
+    /// - `EmptyField` if project_id, to_agent or from_agent is empty
+    /// - `ContentTooLarge` if content exceeds 1,048,576 bytes
+    /// - `InvalidSchedule` if the cron expression is invalid or never fires
+    pub fn create_schedule(&self, message: &NewMessage<'_>, cron_expr: &str) -> DbResult<Schedule> {
+        if message.to_agent.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "to_agent" });
+        }
+        Self::validate(message)?;
+        let next_run_at = Self::next_run(cron_expr, time::unix_seconds())?;
+
+        self.with_conn(|conn| {
+            conn.query_row(
+                &format!(
+                    r"INSERT INTO schedules
+                        (project_id, to_agent, from_agent, content, cron_expr, next_run_at)
+                      VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                      RETURNING {}",
+                    Schedule::COLUMNS
+                ),
+                params![
+                    message.project_id,
+                    message.to_agent,
+                    message.from_agent,
+                    message.content,
+                    cron_expr.trim(),
+                    next_run_at
+                ],
+                Schedule::from_row,
+            )
+        })
+    }
+
+    /// Returns the next time a cron expression fires after `after` (Unix seconds),
+    /// formatted like stored timestamps.
+    fn next_run(cron_expr: &str, after: i64) -> DbResult<String> {
+        let schedule: CronSchedule = cron_expr
+            .parse()
+            .map_err(|reason| DbError::InvalidSchedule { reason })?;
+        let next = schedule
+            .next_after(after)
+            .ok_or_else(|| DbError::InvalidSchedule {
+                reason: format!("Cron expression '{cron_expr}' never fires"),
+            })?;
+        Ok(time::UtcDateTime::from_unix_seconds(next).to_iso8601())
+    }
+
+    /// Returns a project's recurring message schedules, oldest first.
+    pub fn schedules(&self, project_id: &str) -> DbResult<Vec<Schedule>> {
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM schedules WHERE project_id = ?1 ORDER BY id",
+                Schedule::COLUMNS
+            ))?;
+            let schedules = stmt
+                .query_map(params![project_id], Schedule::from_row)?
+                .collect::<Result<_, _>>()?;
+            Ok(schedules)
+        })
+    }
+
+    /// Deletes a recurring message schedule.
     ///
-    /// Returns the message ID.
+    /// Returns `true` if the schedule was cancelled, `false` if it didn't exist.
+    pub fn cancel_schedule(&self, project_id: &str, schedule_id: i64) -> DbResult<bool> {
+        self.with_conn(|conn| {
+            let rows = conn.execute(
+                "DELETE FROM schedules WHERE project_id = ?1 AND id = ?2",
+                params![project_id, schedule_id],
+            )?;
+            Ok(rows > 0)
+        })
+    }
+
+    /// Sends the message of every schedule that is due, and advances each to
+    /// its next run.
+    ///
+    /// Runs missed while the server was down are not caught up: a late
+    /// schedule sends once, then continues from the current time. Returns the
+    /// schedules that fired, as they were before advancing.
+    pub fn run_due_schedules(&self) -> DbResult<Vec<Schedule>> {
+        let now = time::unix_seconds();
+        let now_iso = time::UtcDateTime::from_unix_seconds(now).to_iso8601();
+        let due = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let due: Vec<Schedule> = {
+                let mut stmt = tx.prepare(&format!(
+                    "SELECT {} FROM schedules WHERE next_run_at <= ?1 ORDER BY next_run_at, id",
+                    Schedule::COLUMNS
+                ))?;
+                let due = stmt
+                    .query_map(params![now_iso], Schedule::from_row)?
+                    .collect::<Result<_, _>>()?;
+                due
+            };
+            for schedule in &due {
+                Self::insert_message(
+                    &tx,
+                    &NewMessage {
+                        project_id: &schedule.project_id,
+                        to_agent: &schedule.to_agent,
+                        from_agent: &schedule.from_agent,
+                        content: &schedule.content,
+                        ..NewMessage::default()
+                    },
+                )?;
+                // Expressions were validated on creation; drop any that no longer fire
+                match Self::next_run(&schedule.cron_expr, now) {
+                    Ok(next_run_at) => tx.execute(
+                        "UPDATE schedules SET next_run_at = ?2 WHERE id = ?1",
+                        params![schedule.schedule_id, next_run_at],
+                    )?,
+                    Err(_) => tx.execute(
+                        "DELETE FROM schedules WHERE id = ?1",
+                        params![schedule.schedule_id],
+                    )?,
+                };
+            }
+            tx.commit()?;
+            Ok(due)
+        })?;
+        for schedule in &due {
+            self.waiters
+                .notify(&schedule.project_id, &schedule.to_agent);
+        }
+        Ok(due)
+    }
+
+    // -------------------------------------------------------------------------
+    // Watchdogs
+    // -------------------------------------------------------------------------
+
+    /// Watches an agent: if it neither heartbeats nor receives messages for
+    /// `interval_secs`, [`Database::check_watchdogs`] alerts the supervisor.
+    ///
+    /// Setting a watchdog again replaces the supervisor and interval and
+    /// restarts the interval. The interval is capped at
+    /// [`MAX_WATCHDOG_INTERVAL_SECS`] (7 days).
     ///
     /// # Errors
-    /// - `EmptyField` if `project_id` or `to_agent` is empty (Note: `from_agent` is validated
-    ///   at the API layer, which defaults empty values to "anonymous")
-    /// - `ContentTooLarge` if content exceeds 1,048,576 bytes
-    pub fn send_message(
+    /// - `EmptyField` if project_id, agent_id or supervisor is empty
+    pub fn set_watchdog(
         &self,
         project_id: &str,
-        to_agent: &str,
-        from_agent: &str,
+        agent_id: &str,
+        supervisor: &str,
+        interval_secs: u64,
+    ) -> DbResult<Watchdog> {
+        for (field, value) in [
+            ("project_id", project_id),
+            ("agent_id", agent_id),
+            ("supervisor", supervisor),
+        ] {
+            if value.trim().is_empty() {
+                return Err(DbError::EmptyField { field });
+            }
+        }
+        let interval_secs = interval_secs.clamp(1, MAX_WATCHDOG_INTERVAL_SECS);
+
+        self.with_conn(|conn| {
+            conn.query_row(
+                &format!(
+                    r"INSERT INTO watchdogs (project_id, agent_id, supervisor, interval_secs)
+                      VALUES (?1, ?2, ?3, ?4)
+                      ON CONFLICT (project_id, agent_id) DO UPDATE SET
+                        supervisor = excluded.supervisor,
+                        interval_secs = excluded.interval_secs,
+                        last_activity_at = excluded.last_activity_at,
+                        alerted_at = NULL
+                      RETURNING {}",
+                    Watchdog::COLUMNS
+                ),
+                params![project_id, agent_id, supervisor, interval_secs],
+                Watchdog::from_row,
+            )
+        })
+    }
+
+    /// Removes an agent's watchdog.
+    ///
+    /// Returns `true` if the watchdog was removed, `false` if there was none.
+    pub fn clear_watchdog(&self, project_id: &str, agent_id: &str) -> DbResult<bool> {
+        self.with_conn(|conn| {
+            let rows = conn.execute(
+                "DELETE FROM watchdogs WHERE project_id = ?1 AND agent_id = ?2",
+                params![project_id, agent_id],
+            )?;
+            Ok(rows > 0)
+        })
+    }
+
+    /// Records a heartbeat from an agent.
+    ///
+    /// Returns `true` if the agent has a watchdog, `false` otherwise.
+    pub fn heartbeat(&self, project_id: &str, agent_id: &str) -> DbResult<bool> {
+        self.with_conn(|conn| Ok(Self::record_activity(conn, project_id, agent_id)? > 0))
+    }
+
+    /// Resets the watchdog interval of an agent, if it has one.
+    fn record_activity(conn: &Connection, project_id: &str, agent_id: &str) -> SqliteResult<usize> {
+        conn.execute(
+            r"UPDATE watchdogs SET last_activity_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+              WHERE project_id = ?1 AND agent_id = ?2",
+            params![project_id, agent_id],
+        )
+    }
+
+    /// Alerts the supervisors of agents that have been quiet for longer than
+    /// their watchdog interval, and tells them when an alerted agent is
+    /// active again.
+    ///
+    /// Each quiet period produces a single alert, sent from [`SYSTEM_AGENT`]
+    /// to the supervisor's queue in the watchdog's project.
+    pub fn check_watchdogs(&self) -> DbResult<WatchdogCheck> {
+        let check = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let select = |condition: &str| -> SqliteResult<Vec<Watchdog>> {
+                let mut stmt = tx.prepare(&format!(
+                    "SELECT {} FROM watchdogs WHERE {condition} ORDER BY project_id, agent_id",
+                    Watchdog::COLUMNS
+                ))?;
+                let watchdogs = stmt
+                    .query_map([], Watchdog::from_row)?
+                    .collect::<Result<_, _>>()?;
+                Ok(watchdogs)
+            };
+            let check = WatchdogCheck {
+                alerted: select(
+                    r"alerted_at IS NULL
+                      AND last_activity_at <= strftime('%Y-%m-%dT%H:%M:%SZ', 'now',
+                                                       '-' || interval_secs || ' seconds')",
+                )?,
+                recovered: select("last_activity_at >= alerted_at")?,
+            };
+
+            for watchdog in &check.alerted {
+                let content = format!(
+                    "Watchdog: agent '{}' has not sent a heartbeat or received messages for {} seconds (last activity: {})",
+                    watchdog.agent_id, watchdog.interval_secs, watchdog.last_activity_at
+                );
+                Self::send_watchdog_message(&tx, watchdog, &content, Priority::High)?;
+                tx.execute(
+                    r"UPDATE watchdogs SET alerted_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+                      WHERE project_id = ?1 AND agent_id = ?2",
+                    params![watchdog.project_id, watchdog.agent_id],
+                )?;
+            }
+            for watchdog in &check.recovered {
+                let content = format!(
+                    "Watchdog: agent '{}' is active again (last activity: {})",
+                    watchdog.agent_id, watchdog.last_activity_at
+                );
+                Self::send_watchdog_message(&tx, watchdog, &content, Priority::Normal)?;
+                tx.execute(
+                    "UPDATE watchdogs SET alerted_at = NULL WHERE project_id = ?1 AND agent_id = ?2",
+                    params![watchdog.project_id, watchdog.agent_id],
+                )?;
+            }
+            tx.commit()?;
+            Ok(check)
+        })?;
+        for watchdog in check.alerted.iter().chain(&check.recovered) {
+            self.waiters
+                .notify(&watchdog.project_id, &watchdog.supervisor);
+        }
+        Ok(check)
+    }
+
+    fn send_watchdog_message(
+        conn: &Connection,
+        watchdog: &Watchdog,
         content: &str,
-        reference_id: Option<&str>,
-    ) -> DbResult<String> {
+        priority: Priority,
+    ) -> SqliteResult<String> {
+        Self::insert_message(
+            conn,
+            &NewMessage {
+                project_id: &watchdog.project_id,
+                to_agent: &watchdog.supervisor,
+                from_agent: SYSTEM_AGENT,
+                content,
+                priority,
+                ..NewMessage::default()
+            },
+        )
+    }
+
+    // -------------------------------------------------------------------------
+    // Milestone operations
+    // -------------------------------------------------------------------------
+
+    /// Marks a named milestone as reached in a project.
+    ///
+    /// Setting a milestone again replaces its payload and timestamp.
+    ///
+    /// # Errors
+    /// - `EmptyField` if project_id or name is empty
+    /// - `ContentTooLarge` if payload exceeds 65,536 bytes
+    pub fn set_milestone(
+        &self,
+        project_id: &str,
+        name: &str,
+        payload: Option<&str>,
+    ) -> DbResult<Milestone> {
         if project_id.trim().is_empty() {
             return Err(DbError::EmptyField {
                 field: "project_id",
             });
         }
-        if to_agent.trim().is_empty() {
-            return Err(DbError::EmptyField { field: "to_agent" });
-        }
-        if from_agent.trim().is_empty() {
-            return Err(DbError::EmptyField {
-                field: "from_agent",
-            });
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(DbError::EmptyField { field: "name" });
         }
-        if content.len() > MAX_MESSAGE_SIZE {
-            return Err(DbError::ContentTooLarge {
-                size: content.len(),
-                limit: MAX_MESSAGE_SIZE,
-            });
+        if let Some(payload) = payload {
+            if payload.len() > MAX_CONTEXT_VALUE_SIZE {
+                return Err(DbError::ContentTooLarge {
+                    size: payload.len(),
+                    limit: MAX_CONTEXT_VALUE_SIZE,
+                });
+            }
         }
 
         self.with_conn(|conn| {
-            conn.execute(
-                r"INSERT INTO messages (project_id, to_agent, from_agent, reference_id, content)
-                  VALUES (?1, ?2, ?3, ?4, ?5)",
-                params![project_id, to_agent, from_agent, reference_id, content],
-            )?;
-            Ok(conn.last_insert_rowid().to_string())
+            conn.query_row(
+                r"INSERT OR REPLACE INTO milestones (project_id, name, payload)
+                  VALUES (?1, ?2, ?3)
+                  RETURNING name, payload, reached_at",
+                params![project_id, name, payload],
+                Milestone::from_row,
+            )
         })
     }
 
-    /// Retrieves and consumes messages from an agent's queue.
-    ///
-    /// Messages are returned in chronological order and deleted from the queue.
-    /// Use [`peek_messages`](Self::peek_messages) to view without consuming.
+    /// Gets a milestone, or `None` if it hasn't been reached.
+    pub fn milestone(&self, project_id: &str, name: &str) -> DbResult<Option<Milestone>> {
+        self.with_read_conn(|conn| {
+            let result = conn.query_row(
+                r"SELECT name, payload, reached_at
+                  FROM milestones
+                  WHERE project_id = ?1 AND name = ?2",
+                params![project_id, name.trim()],
+                Milestone::from_row,
+            );
+            match result {
+                Ok(milestone) => Ok(Some(milestone)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    // -------------------------------------------------------------------------
+    // Project operations
+    // -------------------------------------------------------------------------
+
+    /// Deletes all data belonging to a project (every table in `PROJECT_TABLES`).
     ///
-    /// Limit is capped at [`MAX_MESSAGE_LIMIT`] (500).
-    pub fn receive_messages(
+    /// Returns the number of rows deleted.
+    pub fn purge_project(&self, project_id: &str) -> DbResult<usize> {
+        self.with_conn(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            let rows = Self::delete_project_rows(&tx, project_id)?;
+            tx.commit()?;
+            Ok(rows)
+        })
+    }
+
+    fn delete_project_rows(conn: &Connection, project_id: &str) -> SqliteResult<usize> {
+        let mut rows = 0;
+        for table in PROJECT_TABLES {
+            rows += conn.execute(
+                &format!("DELETE FROM {table} WHERE project_id = ?1"),
+                params![project_id],
+            )?;
+        }
+        conn.execute(
+            "INSERT INTO events (project_id, kind) VALUES (?1, 'project_purged')",
+            params![project_id],
+        )?;
+        Ok(rows)
+    }
+
+    /// Returns up to `limit` events of a project's feed after position
+    /// `after_seq` (default 100, max 500): messages enqueued, consumed and
+    /// removed, context changes, and admin actions such as queue settings
+    /// changes, schema registrations and purges. Events are kept for
+    /// [`EVENT_RETENTION_DAYS`].
+    pub fn events_since(
         &self,
         project_id: &str,
-        agent_id: &str,
+        after_seq: u64,
         limit: Option<u32>,
-    ) -> DbResult<Vec<Message>> {
+    ) -> DbResult<EventPage> {
         let limit = limit.unwrap_or(100).min(MAX_MESSAGE_LIMIT);
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(
+                r"SELECT seq, kind, agent_id, subject, created_at FROM events
+                  WHERE project_id = ?1 AND seq > ?2
+                  ORDER BY seq LIMIT ?3",
+            )?;
+            let events = stmt
+                .query_map(params![project_id, after_seq, limit], |row| {
+                    Ok(Event {
+                        seq: row.get(0)?,
+                        kind: row.get(1)?,
+                        agent_id: row.get(2)?,
+                        subject: row.get(3)?,
+                        created_at: row.get(4)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            // seq is gapless until pruning starts at the oldest events
+            let oldest: Option<u64> =
+                conn.query_row("SELECT MIN(seq) FROM events", [], |row| row.get(0))?;
+            Ok(EventPage {
+                next_seq: events.last().map_or(after_seq, |event| event.seq),
+                pruned: oldest.is_some_and(|oldest| oldest > after_seq.saturating_add(1)),
+                events,
+            })
+        })
+    }
 
-        self.with_conn(|conn| {
-            let messages = Self::query_messages(conn, project_id, agent_id, limit)?;
+    /// Bootstraps a new project from an existing one, copying its shared
+    /// context and/or agent queue settings. Messages are never copied.
+    ///
+    /// # Errors
+    /// - `EmptyField` if either project ID is empty
+    /// - `ProjectNotEmpty` if the destination project already holds data
+    pub fn clone_project(
+        &self,
+        source: &str,
+        destination: &str,
+        options: CloneOptions,
+    ) -> DbResult<CloneSummary> {
+        if source.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "src" });
+        }
+        if destination.trim().is_empty() {
+            return Err(DbError::EmptyField { field: "dst" });
+        }
 
-            // Delete consumed messages in a single statement
-            if !messages.is_empty() {
-                let ids: Vec<String> = messages.iter().map(|m| m.id.clone()).collect();
-                let placeholders: String = ids.iter().map(|_| "?").collect::<Vec<_>>().join(",");
-                let sql = format!("DELETE FROM messages WHERE id IN ({placeholders})");
-                let mut stmt = conn.prepare(&sql)?;
-                for (i, id) in ids.iter().enumerate() {
-                    stmt.raw_bind_parameter(i + 1, id)?;
+        self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            for table in PROJECT_TABLES {
+                let exists: bool = tx.query_row(
+                    &format!("SELECT EXISTS(SELECT 1 FROM {table} WHERE project_id = ?1)"),
+                    params![destination],
+                    |row| row.get(0),
+                )?;
+                if exists {
+                    // The closure can only fail with SQLite errors, so domain
+                    // errors are passed out as the success value
+                    return Ok(Err(DbError::ProjectNotEmpty {
+                        project_id: destination.to_string(),
+                    }));
                 }
-                stmt.raw_execute()?;
             }
 
-            Ok(messages)
+            let mut summary = CloneSummary {
+                context_keys: 0,
+                agents: 0,
+            };
+            if options.context {
+                summary.context_keys = tx.execute(
+                    &format!(
+                        r"INSERT INTO context (project_id, key, value, confidential, expires_at)
+                          SELECT ?2, key, value, confidential, expires_at FROM context
+                          WHERE project_id = ?1 AND (?3 OR NOT confidential) AND {LIVE_CONTEXT}"
+                    ),
+                    params![source, destination, options.confidential],
+                )?;
+            }
+            if options.agents {
+                summary.agents = tx.execute(
+                    r"INSERT INTO queue_settings
+                        (project_id, agent_id, delivery_mode, visibility_timeout_secs)
+                      SELECT ?2, agent_id, delivery_mode, visibility_timeout_secs
+                      FROM queue_settings WHERE project_id = ?1",
+                    params![source, destination],
+                )?;
+            }
+            tx.commit()?;
+            Ok(Ok(summary))
+        })?
+    }
+
+    /// Makes a project ephemeral: all its data is purged once `ttl_secs` have
+    /// elapsed, leaving only a summary (see [`expired_projects`](Self::expired_projects)).
+    ///
+    /// Calling this again for the same project moves the expiry time.
+    /// The TTL is capped at [`MAX_PROJECT_TTL_SECS`] (90 days).
+    ///
+    /// # Errors
+    /// - `EmptyField` if project_id is empty
+    pub fn set_project_expiry(&self, project_id: &str, ttl_secs: u64) -> DbResult<ProjectExpiry> {
+        if project_id.trim().is_empty() {
+            return Err(DbError::EmptyField {
+                field: "project_id",
+            });
+        }
+        let ttl_secs = ttl_secs.clamp(1, MAX_PROJECT_TTL_SECS);
+        self.with_conn(|conn| {
+            conn.query_row(
+                r"INSERT INTO project_expiry (project_id, expires_at)
+                  VALUES (?1, strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?2))
+                  ON CONFLICT(project_id) DO UPDATE SET expires_at = excluded.expires_at
+                  RETURNING project_id, created_at, expires_at",
+                params![project_id, format!("+{ttl_secs} seconds")],
+                |row| {
+                    Ok(ProjectExpiry {
+                        project_id: row.get(0)?,
+                        created_at: row.get(1)?,
+                        expires_at: row.get(2)?,
+                    })
+                },
+            )
         })
     }
 
-    /// Peeks at messages in an agent's queue without consuming them.
+    /// Purges every ephemeral project whose expiry time has passed, recording
+    /// a summary of each.
+    ///
+    /// Returns the summaries of the purged projects.
+    pub fn purge_expired_projects(&self) -> DbResult<Vec<ExpiredProject>> {
+        self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let expired: Vec<String> = tx
+                .prepare(
+                    r"SELECT project_id FROM project_expiry
+                      WHERE expires_at <= strftime('%Y-%m-%dT%H:%M:%SZ', 'now')",
+                )?
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+
+            let mut summaries = Vec::with_capacity(expired.len());
+            for project_id in expired {
+                let summary = tx.query_row(
+                    r"INSERT INTO expired_projects
+                        (project_id, created_at, expires_at, agents, messages_sent,
+                         pending_messages, context_keys, milestones)
+                      SELECT project_id, created_at, expires_at,
+                        (SELECT COUNT(*) FROM (
+                            SELECT agent_id FROM queue_stats WHERE project_id = ?1
+                            UNION SELECT to_agent FROM messages WHERE project_id = ?1
+                            UNION SELECT from_agent FROM messages WHERE project_id = ?1)),
+                        (SELECT COALESCE(SUM(sent), 0) FROM queue_stats WHERE project_id = ?1),
+                        (SELECT COUNT(*) FROM messages WHERE project_id = ?1),
+                        (SELECT COUNT(*) FROM context WHERE project_id = ?1),
+                        (SELECT COUNT(*) FROM milestones WHERE project_id = ?1)
+                      FROM project_expiry WHERE project_id = ?1
+                      RETURNING project_id, created_at, expires_at, purged_at, agents,
+                        messages_sent, pending_messages, context_keys, milestones",
+                    params![project_id],
+                    ExpiredProject::from_row,
+                )?;
+                Self::delete_project_rows(&tx, &project_id)?;
+                summaries.push(summary);
+            }
+            tx.commit()?;
+            Ok(summaries)
+        })
+    }
+
+    /// Returns summaries of purged ephemeral projects, most recent first.
+    pub fn expired_projects(&self, limit: u32) -> DbResult<Vec<ExpiredProject>> {
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(
+                r"SELECT project_id, created_at, expires_at, purged_at, agents,
+                    messages_sent, pending_messages, context_keys, milestones
+                  FROM expired_projects
+                  ORDER BY purged_at DESC, rowid DESC
+                  LIMIT ?1",
+            )?;
+            let summaries = stmt
+                .query_map(params![limit], ExpiredProject::from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(summaries)
+        })
+    }
+
+    /// Purges every project whose ID starts with `prefix`.
     ///
-    /// Messages are returned in chronological order but remain in the queue.
+    /// Returns the number of projects purged.
+    pub fn purge_projects_with_prefix(&self, prefix: &str) -> DbResult<usize> {
+        let projects: Vec<String> = self.with_read_conn(|conn| {
+            let union = PROJECT_TABLES
+                .iter()
+                .map(|table| {
+                    format!(
+                        "SELECT project_id FROM {table} WHERE substr(project_id, 1, length(?1)) = ?1"
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" UNION ");
+            let mut stmt = conn.prepare(&union)?;
+            let projects = stmt
+                .query_map(params![prefix], |row| row.get(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(projects)
+        })?;
+        for project_id in &projects {
+            self.purge_project(project_id)?;
+        }
+        Ok(projects.len())
+    }
+
+    // -------------------------------------------------------------------------
+    // API tokens
+    // -------------------------------------------------------------------------
+
+    /// Issues an API token for an agent, valid for `ttl_secs` (capped at
+    /// [`MAX_TOKEN_TTL_SECS`]) or indefinitely, and limited to `tools` if
+    /// given. Returns the token and its secret, which is not stored and cannot
+    /// be retrieved later.
     ///
-    /// Limit is capped at [`MAX_MESSAGE_LIMIT`] (500).
-    pub fn peek_messages(
+    /// # Errors
+    /// - `EmptyField` if agent_id is empty
+    pub fn create_api_token(
         &self,
-        project_id: &str,
         agent_id: &str,
-        limit: Option<u32>,
-    ) -> DbResult<Vec<Message>> {
-        let limit = limit.unwrap_or(100).min(MAX_MESSAGE_LIMIT);
-
-        self.with_conn(|conn| Self::query_messages(conn, project_id, agent_id, limit))
+        name: Option<&str>,
+        roles: &[String],
+        tools: Option<&[String]>,
+        ttl_secs: Option<u64>,
+    ) -> DbResult<(ApiToken, String)> {
+        let agent_id = agent_id.trim();
+        if agent_id.is_empty() {
+            return Err(DbError::EmptyField { field: "agent_id" });
+        }
+        let name = name.map(str::trim).filter(|name| !name.is_empty());
+        self.with_conn(|conn| Self::insert_api_token(conn, agent_id, name, roles, tools, ttl_secs))
     }
 
-    fn query_messages(
+    fn insert_api_token(
         conn: &Connection,
-        project_id: &str,
         agent_id: &str,
-        limit: u32,
-    ) -> SqliteResult<Vec<Message>> {
-        let mut stmt = conn.prepare(
-            r"SELECT id, from_agent, reference_id, content, created_at
-              FROM messages
-              WHERE project_id = ?1 AND to_agent = ?2
-              ORDER BY created_at ASC
-              LIMIT ?3",
+        name: Option<&str>,
+        roles: &[String],
+        tools: Option<&[String]>,
+        ttl_secs: Option<u64>,
+    ) -> SqliteResult<(ApiToken, String)> {
+        let token_id = format!("tok_{}", hex(&random_bytes::<8>()));
+        let secret = format!("{API_TOKEN_PREFIX}{}", hex(&random_bytes::<32>()));
+        let expires_in = ttl_secs.map(|ttl| format!("+{} seconds", ttl.min(MAX_TOKEN_TTL_SECS)));
+        let token = conn.query_row(
+            &format!(
+                r"INSERT INTO api_tokens
+                    (id, name, agent_id, roles, secret_hash, expires_at, tools)
+                  VALUES (?1, ?2, ?3, ?4, ?5, strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?6), ?7)
+                  RETURNING {}",
+                ApiToken::COLUMNS
+            ),
+            params![
+                token_id,
+                name,
+                agent_id,
+                serde_json::Value::from(roles).to_string(),
+                hex(&Sha256::digest(&secret)),
+                expires_in,
+                tools.map(|tools| serde_json::Value::from(tools).to_string())
+            ],
+            ApiToken::from_row,
         )?;
+        Ok((token, secret))
+    }
 
-        let messages = stmt
-            .query_map(params![project_id, agent_id, limit], |row| {
-                Ok(Message {
-                    id: row.get::<_, i64>(0)?.to_string(),
-                    from_agent: row.get(1)?,
-                    reference_id: row.get(2)?,
-                    content: row.get(3)?,
-                    created_at: row.get(4)?,
-                })
-            })?
-            .collect::<Result<Vec<_>, _>>()?;
-
-        Ok(messages)
+    /// Replaces an API token with a new one for the same agent, name, roles
+    /// and tools, valid for `ttl_secs` or indefinitely. The old token stays valid
+    /// for `overlap_secs` more (capped at [`MAX_TOKEN_TTL_SECS`]; sooner if it
+    /// was due to expire earlier), so clients can switch without downtime.
+    ///
+    /// Returns the new token and its secret.
+    ///
+    /// # Errors
+    /// - `UnknownToken` if no token has the ID
+    pub fn rotate_api_token(
+        &self,
+        token_id: &str,
+        overlap_secs: u64,
+        ttl_secs: Option<u64>,
+    ) -> DbResult<(ApiToken, String)> {
+        let rotated = self.with_conn(|conn| {
+            let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+            let old = match tx.query_row(
+                &format!("SELECT {} FROM api_tokens WHERE id = ?1", ApiToken::COLUMNS),
+                params![token_id],
+                ApiToken::from_row,
+            ) {
+                Ok(old) => old,
+                Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let (token, secret) = Self::insert_api_token(
+                &tx,
+                &old.agent_id,
+                old.name.as_deref(),
+                &old.roles,
+                old.tools.as_deref(),
+                ttl_secs,
+            )?;
+            tx.execute(
+                r"UPDATE api_tokens
+                  SET expires_at = MIN(COALESCE(expires_at, '9999'),
+                                       strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?2)),
+                      replaced_by = ?3
+                  WHERE id = ?1",
+                params![
+                    token_id,
+                    format!("+{} seconds", overlap_secs.min(MAX_TOKEN_TTL_SECS)),
+                    token.token_id
+                ],
+            )?;
+            tx.commit()?;
+            Ok(Some((token, secret)))
+        })?;
+        rotated.ok_or_else(|| DbError::UnknownToken {
+            token_id: token_id.to_string(),
+        })
     }
 
-    /// Deletes a specific message by ID.
+    /// Makes an API token expire `in_secs` from now (immediately if 0).
     ///
-    /// Returns `true` if the message was deleted, `false` if it didn't exist.
+    /// Returns the updated token.
     ///
     /// # Errors
-    /// - `InvalidMessageId` if the message ID is not a valid numeric ID
-    pub fn delete_message(&self, message_id: &str) -> DbResult<bool> {
-        let id: i64 = message_id.parse().map_err(|_| DbError::InvalidMessageId {
-            id: message_id.to_string(),
+    /// - `UnknownToken` if no token has the ID
+    pub fn expire_api_token(&self, token_id: &str, in_secs: u64) -> DbResult<ApiToken> {
+        let token = self.with_conn(|conn| {
+            match conn.query_row(
+                &format!(
+                    r"UPDATE api_tokens
+                      SET expires_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?2)
+                      WHERE id = ?1
+                      RETURNING {}",
+                    ApiToken::COLUMNS
+                ),
+                params![
+                    token_id,
+                    format!("+{} seconds", in_secs.min(MAX_TOKEN_TTL_SECS))
+                ],
+                ApiToken::from_row,
+            ) {
+                Ok(token) => Ok(Some(token)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
         })?;
-        self.with_conn(|conn| {
-            let rows = conn.execute("DELETE FROM messages WHERE id = ?1", params![id])?;
-            Ok(rows > 0)
+        token.ok_or_else(|| DbError::UnknownToken {
+            token_id: token_id.to_string(),
+        })
+    }
+
+    /// Returns API tokens (all, or an agent's), newest first.
+    pub fn api_tokens(&self, agent_id: Option<&str>) -> DbResult<Vec<ApiToken>> {
+        self.with_read_conn(|conn| {
+            let mut stmt = conn.prepare(&format!(
+                r"SELECT {} FROM api_tokens
+                  WHERE ?1 IS NULL OR agent_id = ?1
+                  ORDER BY created_at DESC, id",
+                ApiToken::COLUMNS
+            ))?;
+            let tokens = stmt
+                .query_map(params![agent_id], ApiToken::from_row)?
+                .collect::<Result<_, _>>()?;
+            Ok(tokens)
+        })
+    }
+
+    /// Looks up the unexpired API token with the given secret, recording its use.
+    pub fn verify_api_token(&self, secret: &str) -> DbResult<Option<ApiToken>> {
+        let hash = hex(&Sha256::digest(secret));
+        let token = self.with_read_conn(|conn| {
+            match conn.query_row(
+                &format!(
+                    r"SELECT {} FROM api_tokens
+                      WHERE secret_hash = ?1
+                        AND (expires_at IS NULL
+                             OR expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))",
+                    ApiToken::COLUMNS
+                ),
+                params![hash],
+                ApiToken::from_row,
+            ) {
+                Ok(token) => Ok(Some(token)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })?;
+        if let Some(token) = &token {
+            let stale = token.last_used_at.as_deref().is_none_or(|last_used| {
+                time::UtcDateTime::parse_iso8601(last_used)
+                    .is_none_or(|at| time::unix_seconds() - at.to_unix_seconds() >= 60)
+            });
+            if stale {
+                // Usage tracking is best-effort; a failure must not reject the caller
+                if let Err(e) = self.with_conn(|conn| {
+                    conn.execute(
+                        r"UPDATE api_tokens
+                          SET last_used_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+                          WHERE id = ?1",
+                        params![token.token_id],
+                    )
+                }) {
+                    tracing::debug!("Failed to record API token use: {e}");
+                }
+            }
+        }
+        Ok(token)
+    }
+
+    // -------------------------------------------------------------------------
+    // Maintenance operations
+    // -------------------------------------------------------------------------
+
+    /// Analyzes storage usage: per-table sizes, largest items, and fragmentation.
+    #[allow(clippy::cast_precision_loss)] // ratios are informational
+    pub fn storage_analyze(&self) -> DbResult<StorageReport> {
+        self.with_read_conn(|conn| {
+            let tables = conn
+                .prepare(
+                    r"SELECT name, COUNT(*), SUM(pgsize), SUM(unused)
+                      FROM dbstat
+                      GROUP BY name
+                      ORDER BY SUM(pgsize) DESC",
+                )?
+                .query_map([], |row| {
+                    Ok(TableSize {
+                        name: row.get(0)?,
+                        pages: row.get(1)?,
+                        bytes: row.get(2)?,
+                        unused_bytes: row.get(3)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let largest_messages = conn
+                .prepare(
+                    r"SELECT id, project_id, length(CAST(content AS BLOB)) AS size
+                      FROM messages
+                      ORDER BY size DESC
+                      LIMIT ?1",
+                )?
+                .query_map(params![LARGEST_ITEMS_LIMIT], |row| {
+                    Ok(LargeItem {
+                        id: row.get::<_, i64>(0)?.to_string(),
+                        project_id: row.get(1)?,
+                        bytes: row.get(2)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let largest_context_values = conn
+                .prepare(
+                    r"SELECT key, project_id, length(CAST(value AS BLOB)) AS size
+                      FROM context
+                      ORDER BY size DESC
+                      LIMIT ?1",
+                )?
+                .query_map(params![LARGEST_ITEMS_LIMIT], |row| {
+                    Ok(LargeItem {
+                        id: row.get(0)?,
+                        project_id: row.get(1)?,
+                        bytes: row.get(2)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let message_payload_bytes: u64 = conn.query_row(
+                "SELECT COALESCE(SUM(length(CAST(content AS BLOB))), 0) FROM messages",
+                [],
+                |row| row.get(0),
+            )?;
+            let message_storage_ratio = tables
+                .iter()
+                .find(|t| t.name == "messages")
+                .filter(|_| message_payload_bytes > 0)
+                .map(|t| t.bytes as f64 / message_payload_bytes as f64);
+
+            let page_size: u64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+            let page_count: u64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+            let freelist_count: u64 =
+                conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+            let free_ratio = if page_count == 0 {
+                0.0
+            } else {
+                freelist_count as f64 / page_count as f64
+            };
+
+            Ok(StorageReport {
+                tables,
+                largest_messages,
+                largest_context_values,
+                message_payload_bytes,
+                message_storage_ratio,
+                fragmentation: Fragmentation {
+                    page_size,
+                    page_count,
+                    freelist_count,
+                    free_ratio,
+                },
+            })
         })
     }
+
+    /// Runs heavy housekeeping: prunes expired queue history, delivery statuses,
+    /// feed events, context values, abandoned uploads and attachments no stored message references anymore, refreshes query
+    /// planner statistics, rebuilds the database file to reclaim free pages,
+    /// and refreshes the snapshot used for reads while degraded.
+    ///
+    /// This holds the database lock for the duration of the `VACUUM`, so it
+    /// should only be called from the maintenance scheduler.
+    pub fn run_maintenance(&self) -> DbResult<()> {
+        self.with_conn(|conn| {
+            conn.execute(
+                "DELETE FROM queue_stats WHERE bucket < strftime('%Y-%m-%dT%H:%M:00Z', 'now', ?1)",
+                params![format!("-{MAX_HISTORY_WINDOW_MINUTES} minutes")],
+            )?;
+            conn.execute(
+                "DELETE FROM annotations WHERE created_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1)",
+                params![format!("-{ANNOTATION_RETENTION_DAYS} days")],
+            )?;
+            conn.execute(
+                "DELETE FROM archived_messages WHERE consumed_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1)",
+                params![format!("-{} days", self.archive_days.load(Ordering::Relaxed))],
+            )?;
+            conn.execute(
+                "DELETE FROM context_leases WHERE expires_at <= strftime('%Y-%m-%dT%H:%M:%SZ', 'now')",
+                [],
+            )?;
+            conn.execute(
+                "DELETE FROM events WHERE created_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1)",
+                params![format!("-{EVENT_RETENTION_DAYS} days")],
+            )?;
+            conn.execute(&format!("DELETE FROM context WHERE NOT {LIVE_CONTEXT}"), [])?;
+            conn.execute(
+                r"DELETE FROM context_history WHERE id IN (
+                      SELECT id FROM (
+                          SELECT id, ROW_NUMBER() OVER (
+                              PARTITION BY project_id, key ORDER BY id DESC) AS newer
+                          FROM context_history)
+                      WHERE newer > ?1)",
+                params![CONTEXT_HISTORY_VERSIONS],
+            )?;
+            conn.execute(
+                r"DELETE FROM message_upload_chunks WHERE upload_id IN (
+                      SELECT id FROM message_uploads
+                      WHERE created_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1))",
+                params![format!("-{UPLOAD_RETENTION_HOURS} hours")],
+            )?;
+            conn.execute(
+                "DELETE FROM message_uploads WHERE created_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1)",
+                params![format!("-{UPLOAD_RETENTION_HOURS} hours")],
+            )?;
+            conn.execute(
+                r"DELETE FROM delivery_status
+                  WHERE sent_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1)
+                    AND message_id NOT IN (SELECT id FROM messages)
+                    AND message_id NOT IN (SELECT id FROM dead_letters)",
+                params![format!("-{DELIVERY_STATUS_RETENTION_DAYS} days")],
+            )?;
+            conn.execute(
+                r"DELETE FROM attachments
+                  WHERE created_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1)
+                    AND hash NOT IN (
+                        SELECT j.value FROM messages m, json_each(m.attachments) j
+                        WHERE m.attachments IS NOT NULL
+                        UNION SELECT j.value FROM archived_messages m, json_each(m.attachments) j
+                        WHERE m.attachments IS NOT NULL
+                        UNION SELECT j.value FROM dead_letters m, json_each(m.attachments) j
+                        WHERE m.attachments IS NOT NULL
+                    )",
+                params![format!("-{UNREFERENCED_ATTACHMENT_RETENTION_HOURS} hours")],
+            )?;
+            conn.execute_batch("PRAGMA optimize; VACUUM;")
+        })?;
+        self.write_snapshot()
+    }
+
+    /// Copies the database to the snapshot location using SQLite's online backup.
+    ///
+    /// The copy is written to a temporary file and renamed into place, so the
+    /// previous snapshot stays intact if the backup fails midway.
+    pub fn write_snapshot(&self) -> DbResult<()> {
+        let Some(snapshot) = &self.snapshot_path else {
+            return Ok(());
+        };
+        let mut partial = snapshot.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+
+        self.with_conn(|conn| conn.backup(rusqlite::DatabaseName::Main, &partial, None))?;
+        std::fs::rename(&partial, snapshot)?;
+        Ok(())
+    }
+}
+
+/// Formats bytes as lowercase hex.
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            // Writing to a String cannot fail
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Returns bytes from the operating system's secure random source.
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    getrandom::fill(&mut bytes).expect("OS random source is unavailable");
+    bytes
 }
@@ -0,0 +1,58 @@
+//! Purging of expired ephemeral projects, dead-lettering of expired messages,
+//! and removal of messages past the retention period.
+//!
+//! Projects given a lifetime with `create_ephemeral_project` are purged shortly
+//! after they expire, independently of the maintenance windows, since their
+//! expiry time is part of the contract with the agents using them. Likewise,
+//! messages that expired unread or exhausted their delivery attempts are moved
+//! to the dead-letter queue even if nobody receives from their queue, and
+//! with `--message-retention-days`, messages nobody received in time are
+//! removed, so abandoned queues don't accumulate forever.
+
+use crate::db::Database;
+use std::time::Duration;
+
+/// How often expired projects and messages are looked for.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Purges expired projects, dead-letters messages, and removes stale messages
+/// until the task is cancelled.
+pub async fn run(db: Database) {
+    let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        let purge_db = db.clone();
+        match tokio::task::spawn_blocking(move || purge_db.purge_expired_projects()).await {
+            Ok(Ok(expired)) => {
+                for project in expired {
+                    tracing::info!(
+                        "Purged expired project {} ({} messages sent, {} pending)",
+                        project.project_id,
+                        project.messages_sent,
+                        project.pending_messages
+                    );
+                }
+            }
+            Ok(Err(e)) => tracing::warn!("Failed to purge expired projects: {e}"),
+            Err(e) => tracing::warn!("Expired project purge task panicked: {e}"),
+        }
+
+        let sweep_db = db.clone();
+        match tokio::task::spawn_blocking(move || sweep_db.sweep_dead_letters()).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(moved)) => tracing::info!("Moved {moved} messages to dead-letter queues"),
+            Ok(Err(e)) => tracing::warn!("Failed to sweep dead letters: {e}"),
+            Err(e) => tracing::warn!("Dead-letter sweep task panicked: {e}"),
+        }
+
+        let retention_db = db.clone();
+        match tokio::task::spawn_blocking(move || retention_db.purge_stale_messages()).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(removed)) => tracing::info!("Removed {removed} messages past their retention"),
+            Ok(Err(e)) => tracing::warn!("Failed to remove messages past their retention: {e}"),
+            Err(e) => tracing::warn!("Message retention task panicked: {e}"),
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Periodic jobs configured in the config file.
+//!
+//! Each `[[jobs]]` entry runs a built-in action on a cron schedule (see
+//! [`crate::cron`]; evaluated in UTC), so routine housekeeping needs no
+//! external cron:
+//!
+//! ```toml
+//! [[jobs]]
+//! name = "nightly-maintenance"
+//! schedule = "0 3 * * *"
+//! action = "maintenance"
+//!
+//! [[jobs]]
+//! name = "weekly-sandbox-cleanup"
+//! schedule = "0 4 * * 0"
+//! action = "purge"
+//! prefix = "sandbox/"
+//! ```
+//!
+//! Actions: `maintenance` (vacuum, statistics, snapshot), `snapshot` (refresh
+//! the degraded-mode snapshot only), `purge` (delete a `project_id`, or every
+//! project starting with `prefix`), `digest` (post a summary of non-empty
+//! queues, optionally of one `project_id`, to the operator queue), and
+//! `canary` (one canary round trip through `project_id`, default `_canary`).
+//! Jobs run one at a time, so a long job delays the others; a job that came
+//! due several times meanwhile runs once.
+
+use crate::canary;
+use crate::config::ConfigError;
+use crate::cron::CronSchedule;
+use crate::db::{Database, DbError};
+use crate::metrics::Metrics;
+use crate::time;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// How often due jobs are looked for.
+const TICK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Most queues listed individually in a digest.
+const MAX_DIGEST_QUEUES: usize = 20;
+
+/// A built-in action run on a schedule.
+#[derive(Debug, Clone, Deserialize)]
+pub struct JobConfig {
+    /// Unique job name, used in logs.
+    pub name: String,
+    /// Cron expression, e.g. `0 3 * * *` or `@daily`.
+    #[serde(deserialize_with = "cron_schedule")]
+    pub schedule: CronSchedule,
+    #[serde(flatten)]
+    pub action: JobAction,
+}
+
+fn cron_schedule<'de, D: Deserializer<'de>>(deserializer: D) -> Result<CronSchedule, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+/// What a job does.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum JobAction {
+    /// Background maintenance: vacuum, statistics, cleanup, and snapshot.
+    Maintenance,
+    /// Refresh the snapshot served while the database is degraded.
+    Snapshot,
+    /// Delete all data of a project, or of every project with an ID prefix.
+    Purge {
+        #[serde(default)]
+        project_id: Option<String>,
+        #[serde(default)]
+        prefix: Option<String>,
+    },
+    /// Post a summary of non-empty queues to the operator queue.
+    Digest {
+        /// Only summarize this project's queues (default: all projects).
+        #[serde(default)]
+        project_id: Option<String>,
+    },
+    /// Send a canary message round trip, recording its latency.
+    Canary {
+        #[serde(default = "default_canary_project")]
+        project_id: String,
+    },
+}
+
+fn default_canary_project() -> String {
+    "_canary".to_string()
+}
+
+/// Checks that job names are unique and purges name exactly one target.
+pub fn validate(jobs: &[JobConfig]) -> Result<(), ConfigError> {
+    let invalid = |job: &JobConfig, reason: &str| ConfigError::InvalidJob {
+        name: job.name.clone(),
+        reason: reason.to_string(),
+    };
+    let mut names = HashSet::new();
+    for job in jobs {
+        if !names.insert(job.name.as_str()) {
+            return Err(invalid(job, "duplicate job name"));
+        }
+        if let JobAction::Purge { project_id, prefix } = &job.action {
+            match (project_id.as_deref(), prefix.as_deref()) {
+                (Some(target), None) | (None, Some(target)) if !target.trim().is_empty() => {}
+                _ => {
+                    return Err(invalid(
+                        job,
+                        "purge needs exactly one non-empty project_id or prefix",
+                    ))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs jobs when they come due until the task is cancelled.
+pub async fn run(db: Database, metrics: Metrics, jobs: Vec<JobConfig>) {
+    if jobs.is_empty() {
+        return;
+    }
+    let now = time::unix_seconds();
+    let mut next_runs: Vec<Option<i64>> = jobs
+        .iter()
+        .map(|job| job.schedule.next_after(now))
+        .collect();
+    let mut ticker = tokio::time::interval(TICK_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        let now = time::unix_seconds();
+        for (job, next_run) in jobs.iter().zip(&mut next_runs) {
+            if next_run.is_some_and(|at| at <= now) {
+                run_job(&db, &metrics, job).await;
+                // Computed after the run, so runs missed meanwhile are skipped
+                *next_run = job.schedule.next_after(time::unix_seconds());
+            }
+        }
+    }
+}
+
+async fn run_job(db: &Database, metrics: &Metrics, job: &JobConfig) {
+    let db = db.clone();
+    let metrics = metrics.clone();
+    let action = job.action.clone();
+    match tokio::task::spawn_blocking(move || run_action(&db, &metrics, &action)).await {
+        Ok(Ok(summary)) => tracing::info!("Job '{}' completed: {summary}", job.name),
+        Ok(Err(e)) => tracing::warn!("Job '{}' failed: {e}", job.name),
+        Err(e) => tracing::warn!("Job '{}' panicked: {e}", job.name),
+    }
+}
+
+/// Runs a blocking action, returning a summary of what it did.
+fn run_action(db: &Database, metrics: &Metrics, action: &JobAction) -> Result<String, DbError> {
+    match action {
+        JobAction::Maintenance => {
+            db.run_maintenance()?;
+            Ok("maintenance ran".to_string())
+        }
+        JobAction::Snapshot => {
+            db.write_snapshot()?;
+            Ok("snapshot written".to_string())
+        }
+        JobAction::Purge {
+            project_id: Some(project_id),
+            ..
+        } => {
+            let rows = db.purge_project(project_id)?;
+            Ok(format!("purged {rows} rows of '{project_id}'"))
+        }
+        JobAction::Purge {
+            prefix: Some(prefix),
+            ..
+        } => {
+            let projects = db.purge_projects_with_prefix(prefix)?;
+            Ok(format!(
+                "purged {projects} projects starting with '{prefix}'"
+            ))
+        }
+        JobAction::Purge { .. } => Ok("nothing to purge".to_string()),
+        JobAction::Digest { project_id } => {
+            let digest = digest(db, project_id.as_deref())?;
+            db.post_system_alert(&digest);
+            Ok("digest posted".to_string())
+        }
+        JobAction::Canary { project_id } => {
+            canary::round_trip(db, metrics, project_id);
+            Ok("canary sent".to_string())
+        }
+    }
+}
+
+/// Summarizes the non-empty queues, deepest first.
+fn digest(db: &Database, project_id: Option<&str>) -> Result<String, DbError> {
+    let mut queues = db.queue_overview()?;
+    queues.retain(|queue| project_id.is_none_or(|p| p == queue.project_id));
+    queues.sort_by_key(|queue| std::cmp::Reverse(queue.depth));
+
+    let pending: u64 = queues.iter().map(|queue| queue.depth).sum();
+    let dead_letters: u64 = queues.iter().map(|queue| queue.dead_letters).sum();
+    let mut digest = format!(
+        "Queue digest{}: {} non-empty queues, {pending} pending messages, {dead_letters} dead letters",
+        project_id.map(|p| format!(" for {p}")).unwrap_or_default(),
+        queues.len()
+    );
+    for queue in queues.iter().take(MAX_DIGEST_QUEUES) {
+        // Writing to a String cannot fail
+        let _ = write!(
+            digest,
+            "\n- {}/{}: {} pending, oldest {}s",
+            queue.project_id, queue.agent_id, queue.depth, queue.oldest_message_age_secs
+        );
+    }
+    if queues.len() > MAX_DIGEST_QUEUES {
+        let _ = write!(
+            digest,
+            "\n- ... and {} more",
+            queues.len() - MAX_DIGEST_QUEUES
+        );
+    }
+    Ok(digest)
+}
@@ -17,9 +17,42 @@
 //! let server = MailboxServer::new(db);
 //! // Use server with MCP transport...
 //! ```
+//!
+//! To embed the engine in another program instead, use the stable API in
+//! [`core`]; disabling default features leaves out the MCP server and rmcp.
 
+pub mod alerts;
+pub mod auth;
+pub mod canary;
+pub mod compression;
+pub mod config;
+pub mod core;
+pub mod cron;
 pub mod db;
+pub mod expiry;
+pub mod jobs;
+pub mod limits;
+pub mod maintenance;
+pub mod metrics;
+pub mod rate_limit;
+pub mod scheduler;
+pub mod schema;
+#[cfg(feature = "server")]
+pub mod sdk;
+pub mod secrets;
+#[cfg(feature = "server")]
+pub mod sessions;
+mod sql_trace;
+pub mod statsd;
+pub mod template;
+#[cfg(feature = "testkit")]
+pub mod testkit;
+pub mod time;
+#[cfg(feature = "server")]
 pub mod tools;
+pub mod transform;
+pub mod watchdog;
 
-pub use db::{Database, Message};
+pub use db::{Database, DbHealth, Message, NewMessage};
+#[cfg(feature = "server")]
 pub use tools::MailboxServer;
@@ -1,9 +1,33 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Json,
+};
 use clap::Parser;
-use mailbox_mcp::{Database, MailboxServer};
+use mailbox_mcp::auth::{self, Authenticator, Identity};
+use mailbox_mcp::config::Config;
+use mailbox_mcp::db::DbError;
+use mailbox_mcp::limits::ConnectionLimit;
+use mailbox_mcp::maintenance::{self, MaintenanceSchedule, MaintenanceWindow};
+use mailbox_mcp::metrics::Metrics;
+use mailbox_mcp::rate_limit::{RateLimit, SendRateLimiter};
+use mailbox_mcp::sessions::SessionRegistry;
+use mailbox_mcp::time::TimestampFormat;
+use mailbox_mcp::tools::BackpressureConfig;
+use mailbox_mcp::transform::Pipeline;
+use mailbox_mcp::{alerts, canary, compression, expiry, jobs, scheduler, sdk, statsd, watchdog};
+use mailbox_mcp::{Database, DbHealth, MailboxServer};
 use rmcp::transport::streamable_http_server::{
     session::local::LocalSessionManager, StreamableHttpServerConfig, StreamableHttpService,
 };
+use std::path::PathBuf;
+use std::time::Duration;
 use tokio::signal;
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::timeout::{RequestBodyTimeoutLayer, TimeoutLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 /// Local-only MCP server bound to 127.0.0.1
@@ -17,6 +41,195 @@ struct Args {
     /// Port to listen on
     #[arg(short, long, default_value = "3000")]
     port: u16,
+
+    /// Path to a TOML configuration file
+    #[arg(short, long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Daily UTC window (HH:MM-HH:MM) during which background maintenance may run.
+    /// Can be repeated; if omitted, maintenance runs whenever it is due.
+    #[arg(long = "maintenance-window", value_name = "HH:MM-HH:MM")]
+    maintenance_windows: Vec<MaintenanceWindow>,
+
+    /// Minimum hours between background maintenance runs
+    #[arg(long, default_value = "24", value_parser = clap::value_parser!(u64).range(1..))]
+    maintenance_interval_hours: u64,
+
+    /// Recipient queue depth at which senders are asked to back off
+    #[arg(long, default_value = "1000")]
+    backpressure_queue_depth: u64,
+
+    /// Messages each sender may send per minute within a project, counting
+    /// each copy of a fan-out; further sends fail with a rate_limited
+    /// throttle hint (0 disables the limit)
+    #[arg(long, default_value = "0")]
+    send_rate_per_minute: u32,
+
+    /// Messages a sender may send in a burst before --send-rate-per-minute
+    /// applies [default: the per-minute rate]
+    #[arg(long)]
+    send_burst: Option<u32>,
+
+    /// Database lock wait (milliseconds) at which callers are asked to back off
+    #[arg(long, default_value = "250")]
+    backpressure_lock_wait_ms: u64,
+
+    /// Suggested retry delay (milliseconds) returned in throttle hints
+    #[arg(long, default_value = "1000")]
+    backpressure_retry_after_ms: u64,
+
+    /// Database location to use if the primary path is unwritable
+    /// [default: <temp dir>/mailbox-mcp/mailbox.db]
+    #[arg(long, value_name = "PATH")]
+    fallback_db_path: Option<PathBuf>,
+
+    /// Default timestamp format in responses: utc, epoch_ms, or a fixed offset like +02:00
+    #[arg(long, default_value = "utc")]
+    timestamp_format: TimestampFormat,
+
+    /// Give each MCP session an ephemeral `sandbox/<session-id>` project,
+    /// deleted when the session ends
+    #[arg(long)]
+    session_sandbox: bool,
+
+    /// Log every SQL statement with its duration and row counts (debug level)
+    #[arg(long)]
+    trace_sql: bool,
+
+    /// Keep consumed messages in an archive for this many days, so get_thread
+    /// can still show them (0 disables archiving)
+    #[arg(long, default_value = "0")]
+    archive_days: u32,
+
+    /// Remove pending messages older than this many days, e.g. left for agents
+    /// that no longer exist; archived instead with --archive-days (0 keeps them)
+    #[arg(long, default_value = "0")]
+    message_retention_days: u32,
+
+    /// Most messages a recipient queue may hold; sends beyond it fail with
+    /// QueueFull (0 disables the limit)
+    #[arg(long, default_value = "0")]
+    max_queue_messages: u64,
+
+    /// Most bytes of message content a recipient queue may hold; sends beyond
+    /// it fail with QueueFull (0 disables the limit)
+    #[arg(long, default_value = "0")]
+    max_queue_bytes: u64,
+
+    /// Largest accepted request body in bytes (attachments are sent base64-encoded)
+    #[arg(long, default_value = "16777216")]
+    max_request_bytes: usize,
+
+    /// Seconds a request may take to send its body and to get a response
+    /// started (MCP tool calls stream their results, so long waits are unaffected)
+    #[arg(long, default_value = "30")]
+    request_timeout_secs: u64,
+
+    /// Maximum open connections; further clients wait until one closes
+    #[arg(long, default_value = "1024")]
+    max_connections: usize,
+
+    /// Print the schema migrations the database needs, without applying them, and exit
+    #[arg(long)]
+    migrate_dry_run: bool,
+
+    /// Check the database for broken cross-table invariants, print them, and
+    /// exit (with an error if any are broken). The database is opened
+    /// read-only and must be at this server's schema version
+    #[arg(long)]
+    doctor: bool,
+
+    /// Print a description of the exposed tools (json) or a client for them
+    /// (python, typescript), and exit
+    #[arg(long, value_name = "LANGUAGE")]
+    generate_sdk: Option<sdk::Language>,
+
+    /// Issue an API token with the admin role for this agent, print it, and
+    /// exit (to bootstrap token management when authentication is enabled)
+    #[arg(long, value_name = "AGENT_ID")]
+    issue_admin_token: Option<String>,
+}
+
+/// Readiness probe: 200 while healthy, 503 while the database is degraded.
+async fn readyz(State(db): State<Database>) -> impl IntoResponse {
+    let health = db.health();
+    let status = match health {
+        DbHealth::Healthy => StatusCode::OK,
+        DbHealth::Degraded { .. } => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(health))
+}
+
+/// Query parameters of `GET /peek`.
+#[derive(serde::Deserialize)]
+struct PeekQuery {
+    project_id: String,
+    agent_id: String,
+    limit: Option<u32>,
+}
+
+/// Plain-HTTP `peek_messages` for polling clients.
+///
+/// Responses carry an `ETag` made of the queue version and the number of
+/// deliverable messages (which changes without a version bump when a
+/// visibility timeout or TTL runs out); requests whose `If-None-Match` still
+/// matches get `304 Not Modified` without a body. An authenticated caller
+/// may only peek at its own queue without the admin role.
+async fn peek(
+    State(db): State<Database>,
+    Query(query): Query<PeekQuery>,
+    identity: Option<Extension<Identity>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(Extension(identity)) = identity {
+        if !identity.may_act_as(&query.agent_id) {
+            let message = format!(
+                "Authenticated as '{}'; peeking at '{}' requires the '{}' role",
+                identity.agent_id,
+                query.agent_id,
+                auth::ADMIN_ROLE
+            );
+            return (StatusCode::FORBIDDEN, message).into_response();
+        }
+    }
+    let internal_error =
+        |e: DbError| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+    // Read together, so the tag always describes the body it comes with
+    let snapshot = match db.peek_snapshot(&query.project_id, &query.agent_id, query.limit) {
+        Ok(snapshot) => snapshot,
+        Err(e) => return internal_error(e),
+    };
+    let etag = format!("\"{}-{}\"", snapshot.version, snapshot.visible);
+    if etag_matches(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+    (
+        [
+            (header::ETAG, etag),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        Json(serde_json::json!({ "messages": snapshot.messages })),
+    )
+        .into_response()
+}
+
+/// Returns `true` if an `If-None-Match` header lists the tag (or `*`).
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Metrics in the Prometheus text exposition format.
+async fn prometheus(metrics: Metrics) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render_prometheus(),
+    )
 }
 
 async fn shutdown_signal() {
@@ -59,18 +272,195 @@ async fn main() -> anyhow::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let db = Database::new()?;
-    let server = MailboxServer::new(db);
+    let config = args
+        .config
+        .as_deref()
+        .map(Config::load)
+        .transpose()?
+        .unwrap_or_default();
+
+    if let Some(language) = args.generate_sdk {
+        let tools = MailboxServer::tool_definitions(&config.tools)?;
+        println!("{}", sdk::generate(&sdk::describe(&tools), language));
+        return Ok(());
+    }
+
+    if args.migrate_dry_run {
+        let path = Database::default_path()?;
+        let plan = Database::migration_plan(&path)?;
+        println!(
+            "Database {} is at schema version {}",
+            path.display(),
+            plan.current_version
+        );
+        if plan.pending.is_empty() && !plan.imports_legacy_tables {
+            println!("No migrations pending");
+        }
+        for (version, description) in &plan.pending {
+            println!("Would apply migration {version}: {description}");
+        }
+        if plan.imports_legacy_tables {
+            println!("Would import and drop legacy tables");
+        }
+        if let Some(backup) = &plan.backup_path {
+            println!(
+                "The database would first be backed up to {}",
+                backup.display()
+            );
+        }
+        return Ok(());
+    }
+
+    if args.doctor {
+        let broken = Database::check_invariants_at(&Database::default_path()?)?;
+        if broken.is_empty() {
+            println!("All invariants hold");
+            return Ok(());
+        }
+        for violation in &broken {
+            println!(
+                "{}: {} ({} violations)",
+                violation.invariant, violation.description, violation.violations
+            );
+        }
+        anyhow::bail!("{} invariants broken", broken.len());
+    }
+
+    let (db, warning) = match Database::new() {
+        Ok(db) => (db, None),
+        // Only when the primary can't be used at all: falling back from a
+        // busy, corrupted or newer database would hide its data behind an
+        // empty one
+        Err(e) if e.is_inaccessible() => {
+            let fallback = args
+                .fallback_db_path
+                .unwrap_or_else(Database::default_fallback_path);
+            let warning = format!(
+                "Primary database is unavailable ({e}); using fallback database at {}. \
+                 Data stored here may not persist across reboots.",
+                fallback.display()
+            );
+            tracing::warn!("{warning}");
+            (Database::open(&fallback)?, Some(warning))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if let Some(agent_id) = &args.issue_admin_token {
+        let (token, secret) = db.create_api_token(
+            agent_id,
+            Some("bootstrap"),
+            &[auth::ADMIN_ROLE.into()],
+            None,
+            None,
+        )?;
+        tracing::info!(
+            "Issued API token {} for '{}'",
+            token.token_id,
+            token.agent_id
+        );
+        // Logs share stdout, so the secret goes last on its own line
+        println!("{secret}");
+        return Ok(());
+    }
+
+    if args.trace_sql {
+        db.enable_sql_trace();
+    }
+    if args.archive_days > 0 {
+        db.enable_archive(args.archive_days);
+    }
+    db.set_message_retention(args.message_retention_days);
+    db.set_queue_quota(args.max_queue_messages, args.max_queue_bytes);
+
+    let mut server = MailboxServer::new(db.clone())
+        .with_backpressure(BackpressureConfig {
+            max_queue_depth: args.backpressure_queue_depth,
+            max_lock_wait: Duration::from_millis(args.backpressure_lock_wait_ms),
+            retry_after: Duration::from_millis(args.backpressure_retry_after_ms),
+        })
+        .with_timestamp_format(args.timestamp_format)
+        .with_transforms(Pipeline::from_config(&config.transforms)?)
+        .with_tool_config(&config.tools)?;
+    if args.send_rate_per_minute > 0 {
+        server = server.with_send_rate_limiter(SendRateLimiter::new(RateLimit {
+            per_minute: args.send_rate_per_minute,
+            burst: args.send_burst.unwrap_or(args.send_rate_per_minute),
+        }));
+    }
+    if let Some(warning) = warning {
+        server = server.with_warning(warning);
+    }
+
+    let schedule = MaintenanceSchedule {
+        windows: args.maintenance_windows,
+        interval: args
+            .maintenance_interval_hours
+            .checked_mul(3600)
+            .map(Duration::from_secs)
+            .ok_or_else(|| anyhow::anyhow!("--maintenance-interval-hours is too large"))?,
+    };
+    tokio::spawn(maintenance::run(db.clone(), schedule));
+    tokio::spawn(alerts::run(db.clone(), config.alerts));
+    tokio::spawn(expiry::run(db.clone()));
+    tokio::spawn(scheduler::run(db.clone()));
+    tokio::spawn(watchdog::run(db.clone()));
+
+    let metrics = Metrics::new();
+    if let Some(canary) = config.canary {
+        tokio::spawn(canary::run(db.clone(), metrics.clone(), canary));
+    }
+    tokio::spawn(jobs::run(db.clone(), metrics.clone(), config.jobs));
+    if let Some(statsd) = config.statsd {
+        tokio::spawn(statsd::run(metrics.clone(), statsd));
+    }
+
+    let mut sessions = SessionRegistry::new(LocalSessionManager::default().into());
+    if args.session_sandbox {
+        sessions = sessions.with_sandboxes(db.clone());
+    }
+    let server = server.with_sessions(sessions.clone());
 
     let service = StreamableHttpService::new(
         move || Ok(server.clone()),
-        LocalSessionManager::default().into(),
+        sessions.into(),
         StreamableHttpServerConfig::default(),
     );
 
-    let app = axum::Router::new().nest_service("/mcp", service);
+    let mut app = axum::Router::new()
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(move || prometheus(metrics.clone())));
+    let mut api = axum::Router::new().route("/peek", get(peek));
+    let mut mcp = axum::Router::new().fallback_service(service);
+    if let Some(compression) = &config.compression {
+        app = app.layer(compression.layer());
+        api = api.layer(compression.layer());
+        mcp = mcp
+            .layer(compression.layer())
+            .layer(middleware::map_request(compression::uncompressed_streams));
+    }
+    let mut api = api.nest_service("/mcp", mcp);
+    if let Some(auth) = config.auth {
+        api = api.layer(middleware::from_fn_with_state(
+            Authenticator::new(auth).with_tokens(db.clone()),
+            auth::require_bearer,
+        ));
+    }
+    let request_timeout = Duration::from_secs(args.request_timeout_secs.max(1));
+    let app = app
+        .merge(api)
+        .layer(RequestBodyLimitLayer::new(args.max_request_bytes))
+        .layer(RequestBodyTimeoutLayer::new(request_timeout))
+        .layer(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            request_timeout,
+        ))
+        .with_state(db);
     let addr = format!("{HOST}:{}", args.port);
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    let listener = ConnectionLimit::new(
+        tokio::net::TcpListener::bind(&addr).await?,
+        args.max_connections,
+    );
 
     tracing::info!("Mailbox MCP server listening on http://{}/mcp", addr);
 
@@ -0,0 +1,123 @@
+//! Background maintenance scheduling for mailbox-mcp.
+//!
+//! Heavy housekeeping (vacuum, optimizer statistics) is deferred to configured
+//! maintenance windows so that its I/O doesn't coincide with peak agent activity.
+
+use crate::db::Database;
+use crate::time::UtcDateTime;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How often the scheduler wakes up to check whether maintenance is due.
+const TICK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A daily time range (UTC) during which background maintenance may run.
+///
+/// Windows may wrap around midnight, e.g. `22:00-02:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    /// Start of the window in minutes since midnight UTC (inclusive).
+    start: u32,
+    /// End of the window in minutes since midnight UTC (exclusive).
+    end: u32,
+}
+
+impl MaintenanceWindow {
+    /// Returns `true` if the given minute of the day (UTC) falls inside the window.
+    #[must_use]
+    pub const fn contains(&self, minute_of_day: u32) -> bool {
+        if self.start <= self.end {
+            minute_of_day >= self.start && minute_of_day < self.end
+        } else {
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+}
+
+fn parse_time_of_day(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.trim().split_once(':')?;
+    let hours: u32 = hours.parse().ok()?;
+    let minutes: u32 = minutes.parse().ok()?;
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = String;
+
+    /// Parses a window in `HH:MM-HH:MM` format (UTC).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid maintenance window '{s}' (expected HH:MM-HH:MM)");
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let start = parse_time_of_day(start).ok_or_else(invalid)?;
+        let end = parse_time_of_day(end).ok_or_else(invalid)?;
+        if start == end {
+            return Err(format!("Maintenance window '{s}' is empty"));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl fmt::Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// Schedule controlling when background maintenance runs.
+#[derive(Debug, Clone)]
+pub struct MaintenanceSchedule {
+    /// Windows during which maintenance may run. Empty means "any time".
+    pub windows: Vec<MaintenanceWindow>,
+    /// Minimum time between two maintenance runs.
+    pub interval: Duration,
+}
+
+impl MaintenanceSchedule {
+    /// Returns `true` if maintenance may run at the given minute of the day (UTC).
+    #[must_use]
+    pub fn allows(&self, minute_of_day: u32) -> bool {
+        self.windows.is_empty() || self.windows.iter().any(|w| w.contains(minute_of_day))
+    }
+}
+
+fn current_minute_of_day() -> u32 {
+    let now = UtcDateTime::now();
+    now.hour * 60 + now.minute
+}
+
+/// Runs the maintenance scheduler until the task is cancelled.
+///
+/// Maintenance is attempted at most once per `schedule.interval`, and only while
+/// the current time falls inside one of the configured windows. Runs that come
+/// due outside a window are deferred until the next window opens. Without
+/// windows, the first run comes one interval after startup, so that restarts
+/// don't block writers behind a vacuum.
+pub async fn run(db: Database, schedule: MaintenanceSchedule) {
+    let mut last_run = schedule.windows.is_empty().then(tokio::time::Instant::now);
+    let mut ticker = tokio::time::interval(TICK_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        let due = last_run.is_none_or(|t| t.elapsed() >= schedule.interval);
+        if !due || !schedule.allows(current_minute_of_day()) {
+            continue;
+        }
+
+        let db = db.clone();
+        match tokio::task::spawn_blocking(move || db.run_maintenance()).await {
+            Ok(Ok(())) => tracing::info!("Background maintenance completed"),
+            Ok(Err(e)) => tracing::warn!("Background maintenance failed: {e}"),
+            Err(e) => tracing::warn!("Background maintenance task panicked: {e}"),
+        }
+        last_run = Some(tokio::time::Instant::now());
+    }
+}
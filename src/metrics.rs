@@ -0,0 +1,107 @@
+//! In-process metrics registry.
+//!
+//! Background tasks record counters and gauges here; they are exported in the
+//! Prometheus text format at `GET /metrics` and, if configured, pushed to
+//! StatsD (see [`crate::statsd`]).
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+/// Kind of a metric, as reported to exporters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    /// Monotonically increasing count.
+    Counter,
+    /// Value that can go up and down.
+    Gauge,
+}
+
+impl MetricKind {
+    const fn prometheus_type(self) -> &'static str {
+        match self {
+            Self::Counter => "counter",
+            Self::Gauge => "gauge",
+        }
+    }
+}
+
+/// Current value of a single metric.
+#[derive(Debug, Clone)]
+pub struct MetricValue {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    pub value: f64,
+}
+
+/// Shared registry of named metrics. Cloning is cheap and shares state.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    values: Arc<Mutex<BTreeMap<&'static str, MetricValue>>>,
+}
+
+impl Metrics {
+    /// Creates an empty registry.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn values(&self) -> std::sync::MutexGuard<'_, BTreeMap<&'static str, MetricValue>> {
+        self.values
+            .lock()
+            .expect("Metrics mutex poisoned - this indicates a bug")
+    }
+
+    fn update(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        kind: MetricKind,
+        f: impl FnOnce(&mut f64),
+    ) {
+        let mut values = self.values();
+        let entry = values.entry(name).or_insert(MetricValue {
+            name,
+            help,
+            kind,
+            value: 0.0,
+        });
+        f(&mut entry.value);
+    }
+
+    /// Increments a counter by one.
+    pub fn increment(&self, name: &'static str, help: &'static str) {
+        self.update(name, help, MetricKind::Counter, |v| *v += 1.0);
+    }
+
+    /// Sets a gauge to the given value.
+    pub fn set_gauge(&self, name: &'static str, help: &'static str, value: f64) {
+        self.update(name, help, MetricKind::Gauge, |v| *v = value);
+    }
+
+    /// Returns the current value of every metric, ordered by name.
+    #[must_use]
+    pub fn snapshot(&self) -> Vec<MetricValue> {
+        self.values().values().cloned().collect()
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    #[must_use]
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        for metric in self.snapshot() {
+            // Writing to a String cannot fail
+            let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+            let _ = writeln!(
+                out,
+                "# TYPE {} {}",
+                metric.name,
+                metric.kind.prometheus_type()
+            );
+            let _ = writeln!(out, "{} {}", metric.name, metric.value);
+        }
+        out
+    }
+}
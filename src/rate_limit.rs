@@ -0,0 +1,101 @@
+//! Per-sender rate limiting of message sends.
+//!
+//! Each `(project, sender)` pair gets a token bucket holding up to `burst`
+//! sends, refilled at `per_minute` sends per minute. A send is allowed while
+//! the bucket holds a token, and once made costs one token per copy
+//! delivered. Buckets live in memory, so limits apply per server process and
+//! reset on restart.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Buckets tracked before full (idle) buckets are dropped.
+const MAX_TRACKED_SENDERS: usize = 10_000;
+
+/// Send rate allowed per sender.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Sustained sends per minute.
+    pub per_minute: u32,
+    /// Sends allowed in a burst (at least 1).
+    pub burst: u32,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets keyed by project and sender.
+#[derive(Debug, Clone)]
+pub struct SendRateLimiter {
+    limit: RateLimit,
+    buckets: Arc<Mutex<HashMap<(String, String), Bucket>>>,
+}
+
+impl SendRateLimiter {
+    /// Creates a limiter with empty history: every sender starts with a full burst.
+    #[must_use]
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit: RateLimit {
+                per_minute: limit.per_minute.max(1),
+                burst: limit.burst.max(1),
+            },
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Returns `Ok` if the sender may send now, or how long until it may.
+    pub fn check(&self, project_id: &str, from_agent: &str) -> Result<(), Duration> {
+        let tokens = self.update(project_id, from_agent, |_| {});
+        if tokens >= 1.0 {
+            Ok(())
+        } else {
+            let per_sec = f64::from(self.limit.per_minute) / 60.0;
+            Err(Duration::from_secs_f64((1.0 - tokens) / per_sec))
+        }
+    }
+
+    /// Takes a token for each of `sends` copies already sent. A fan-out
+    /// larger than the remaining tokens (or concurrent sends racing the
+    /// check) puts the bucket into debt, which delays the sender's next send.
+    pub fn charge(&self, project_id: &str, from_agent: &str, sends: usize) {
+        let sends = u32::try_from(sends).unwrap_or(u32::MAX);
+        self.update(project_id, from_agent, |tokens| {
+            *tokens -= f64::from(sends);
+        });
+    }
+
+    /// Refills the sender's bucket, applies `change` to its tokens, and
+    /// returns the tokens it held before the change.
+    fn update(&self, project_id: &str, from_agent: &str, change: impl FnOnce(&mut f64)) -> f64 {
+        let now = Instant::now();
+        let burst = f64::from(self.limit.burst);
+        let per_sec = f64::from(self.limit.per_minute) / 60.0;
+        let refill = |bucket: &Bucket| {
+            (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_sec).min(burst)
+        };
+
+        let mut buckets = self
+            .buckets
+            .lock()
+            .expect("Rate limiter mutex poisoned - this indicates a bug");
+        if buckets.len() >= MAX_TRACKED_SENDERS {
+            buckets.retain(|_, bucket| refill(bucket) < burst);
+        }
+        let bucket = buckets
+            .entry((project_id.to_string(), from_agent.to_string()))
+            .or_insert(Bucket {
+                tokens: burst,
+                updated: now,
+            });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        let tokens = bucket.tokens;
+        change(&mut bucket.tokens);
+        tokens
+    }
+}
@@ -0,0 +1,35 @@
+//! Sending of recurring messages.
+//!
+//! Schedules created with `schedule_recurring` are checked a few times a
+//! minute, so messages go out within seconds of their cron time.
+
+use crate::db::Database;
+use std::time::Duration;
+
+/// How often due schedules are looked for.
+const TICK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sends recurring messages until the task is cancelled.
+pub async fn run(db: Database) {
+    let mut ticker = tokio::time::interval(TICK_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        let db = db.clone();
+        match tokio::task::spawn_blocking(move || db.run_due_schedules()).await {
+            Ok(Ok(fired)) => {
+                for schedule in fired {
+                    tracing::debug!(
+                        "Sent message for schedule {} to {}/{}",
+                        schedule.schedule_id,
+                        schedule.project_id,
+                        schedule.to_agent
+                    );
+                }
+            }
+            Ok(Err(e)) => tracing::warn!("Failed to send recurring messages: {e}"),
+            Err(e) => tracing::warn!("Recurring message task panicked: {e}"),
+        }
+    }
+}
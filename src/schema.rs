@@ -0,0 +1,45 @@
+//! JSON Schema validation of message content.
+//!
+//! Projects register named schemas with
+//! [`Database::register_message_schema`](crate::db::Database::register_message_schema);
+//! senders can then name one to have the content checked before the message
+//! is queued. Drafts 4, 6, 7, 2019-09 and 2020-12 are supported (detected from
+//! `$schema`, defaulting to 2020-12). Only references within the schema itself
+//! are resolved.
+
+use jsonschema::Validator;
+use serde_json::Value;
+
+/// Most violations listed in a single error.
+const MAX_REPORTED_ERRORS: usize = 5;
+
+/// Compiles a schema, checking it against its meta-schema.
+pub fn compile(schema: &Value) -> Result<Validator, String> {
+    jsonschema::validator_for(schema).map_err(|e| e.to_string())
+}
+
+/// Checks that `content` is a JSON document valid against the schema.
+///
+/// On failure, describes up to [`MAX_REPORTED_ERRORS`] violations, each
+/// prefixed by the JSON pointer of the offending value.
+pub fn check(validator: &Validator, content: &str) -> Result<(), String> {
+    let instance: Value =
+        serde_json::from_str(content).map_err(|e| format!("content is not valid JSON: {e}"))?;
+    let errors: Vec<String> = validator
+        .iter_errors(&instance)
+        .take(MAX_REPORTED_ERRORS)
+        .map(|error| {
+            let path = error.instance_path().to_string();
+            if path.is_empty() {
+                error.to_string()
+            } else {
+                format!("{path}: {error}")
+            }
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; "))
+    }
+}
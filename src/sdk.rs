@@ -0,0 +1,395 @@
+//! Client SDK generation.
+//!
+//! `mailbox-mcp --generate-sdk <LANGUAGE>` prints a description of the tools
+//! the configured server exposes, for tooling that doesn't speak MCP:
+//!
+//! - `json`: every tool with its description, its parameters flattened to
+//!   name, type, and whether they are required, and its full input schema
+//! - `python`: a dependency-free client class with one method per tool
+//! - `typescript`: the same, using `fetch`
+//!
+//! The clients speak MCP's streamable HTTP transport: they initialize a
+//! session on first use and return each tool's JSON result. Regenerate them
+//! when the server is upgraded or its `[tools]` configuration changes.
+
+use rmcp::model::Tool;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Output of `--generate-sdk`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Json,
+    Python,
+    Typescript,
+}
+
+impl FromStr for Language {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "python" | "py" => Ok(Self::Python),
+            "typescript" | "ts" => Ok(Self::Typescript),
+            other => Err(format!(
+                "Unknown SDK language '{other}' (expected json, python, or typescript)"
+            )),
+        }
+    }
+}
+
+/// Machine-readable description of a server's tools.
+#[derive(Debug, Serialize)]
+pub struct ServerDescription {
+    pub server: &'static str,
+    pub version: &'static str,
+    pub tools: Vec<ToolDescription>,
+}
+
+/// A tool and its parameters.
+#[derive(Debug, Serialize)]
+pub struct ToolDescription {
+    pub name: String,
+    pub description: String,
+    pub params: Vec<ParamDescription>,
+    /// The JSON Schema the parameters are validated against.
+    pub input_schema: Value,
+}
+
+/// A top-level tool parameter.
+#[derive(Debug, Serialize)]
+pub struct ParamDescription {
+    pub name: String,
+    /// `string`, `integer`, `number`, `boolean`, `object`, `array<T>`, or `any`.
+    #[serde(rename = "type")]
+    pub param_type: String,
+    pub required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// Describes the given tools, required parameters first.
+#[must_use]
+pub fn describe(tools: &[Tool]) -> ServerDescription {
+    let tools = tools
+        .iter()
+        .map(|tool| {
+            let schema = tool.input_schema.as_ref();
+            let required: Vec<&str> = schema
+                .get("required")
+                .and_then(Value::as_array)
+                .map(|names| names.iter().filter_map(Value::as_str).collect())
+                .unwrap_or_default();
+            let mut params: Vec<ParamDescription> = schema
+                .get("properties")
+                .and_then(Value::as_object)
+                .into_iter()
+                .flatten()
+                .map(|(name, property)| ParamDescription {
+                    name: name.clone(),
+                    param_type: param_type(property),
+                    required: required.contains(&name.as_str()),
+                    description: property
+                        .get("description")
+                        .and_then(Value::as_str)
+                        .map(str::to_string),
+                })
+                .collect();
+            params.sort_by_key(|param| !param.required);
+            ToolDescription {
+                name: tool.name.to_string(),
+                description: tool.description.as_deref().unwrap_or_default().to_string(),
+                params,
+                input_schema: Value::Object(tool.input_schema.as_ref().clone()),
+            }
+        })
+        .collect();
+    ServerDescription {
+        server: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        tools,
+    }
+}
+
+fn param_type(schema: &Value) -> String {
+    if schema.get("$ref").is_some() {
+        return "object".to_string();
+    }
+    match schema.get("type").and_then(Value::as_str) {
+        Some("array") => format!(
+            "array<{}>",
+            schema.get("items").map_or("any".to_string(), param_type)
+        ),
+        Some(kind @ ("string" | "integer" | "number" | "boolean" | "object")) => kind.to_string(),
+        _ => "any".to_string(),
+    }
+}
+
+/// Renders the description in the given language.
+#[must_use]
+pub fn generate(description: &ServerDescription, language: Language) -> String {
+    match language {
+        Language::Json => serde_json::to_string_pretty(description)
+            .expect("Tool descriptions are always serializable"),
+        Language::Python => python(description),
+        Language::Typescript => typescript(description),
+    }
+}
+
+/// Makes a tool or parameter name usable as an identifier.
+fn identifier(name: &str, reserved: &[&str]) -> String {
+    let mut ident: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    if reserved.contains(&ident.as_str()) {
+        ident.push('_');
+    }
+    ident
+}
+
+const PYTHON_RESERVED: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "call", "class",
+    "continue", "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if",
+    "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try",
+    "while", "with", "yield",
+];
+
+const PYTHON_PRELUDE: &str = r#"class MailboxError(Exception):
+    """A tool call failed; `data` holds structured details, if any."""
+
+    def __init__(self, message: str, data: Any = None):
+        super().__init__(message)
+        self.data = data
+
+
+class MailboxClient:
+    def __init__(self, url: str = "http://127.0.0.1:3000/mcp", token: Optional[str] = None):
+        self._url = url
+        self._token = token
+        self._session: Optional[str] = None
+        self._next_id = 0
+
+    def _post(self, payload: dict) -> Optional[dict]:
+        headers = {
+            "Content-Type": "application/json",
+            "Accept": "application/json, text/event-stream",
+        }
+        if self._token:
+            headers["Authorization"] = f"Bearer {self._token}"
+        if self._session:
+            headers["Mcp-Session-Id"] = self._session
+        request = urllib.request.Request(self._url, json.dumps(payload).encode(), headers)
+        with urllib.request.urlopen(request) as response:
+            self._session = response.headers.get("Mcp-Session-Id", self._session)
+            body = response.read().decode()
+        reply = None
+        for line in body.splitlines():
+            if line.startswith("data:") and line[5:].strip():
+                reply = json.loads(line[5:])
+        if reply is None and body.strip().startswith("{"):
+            reply = json.loads(body)
+        return reply
+
+    def _request(self, method: str, params: dict) -> dict:
+        self._next_id += 1
+        reply = self._post({"jsonrpc": "2.0", "id": self._next_id, "method": method, "params": params})
+        if reply is None:
+            raise MailboxError(f"No response to {method}")
+        if "error" in reply:
+            raise MailboxError(reply["error"]["message"], reply["error"].get("data"))
+        return reply["result"]
+
+    def call(self, tool: str, arguments: dict[str, Any]) -> Any:
+        """Calls a tool by name, omitting arguments that are None."""
+        if self._session is None:
+            self._request("initialize", {
+                "protocolVersion": "2024-11-05",
+                "capabilities": {},
+                "clientInfo": {"name": "mailbox-mcp-python", "version": SERVER_VERSION},
+            })
+            self._post({"jsonrpc": "2.0", "method": "notifications/initialized"})
+        arguments = {name: value for name, value in arguments.items() if value is not None}
+        result = self._request("tools/call", {"name": tool, "arguments": arguments})
+        text = "".join(block.get("text", "") for block in result.get("content", []))
+        if result.get("isError"):
+            raise MailboxError(text)
+        try:
+            return json.loads(text)
+        except ValueError:
+            return text
+"#;
+
+fn python_type(param_type: &str) -> String {
+    match param_type {
+        "string" => "str".to_string(),
+        "integer" => "int".to_string(),
+        "number" => "float".to_string(),
+        "boolean" => "bool".to_string(),
+        "object" => "dict[str, Any]".to_string(),
+        _ => match param_type
+            .strip_prefix("array<")
+            .and_then(|item| item.strip_suffix('>'))
+        {
+            Some(item) => format!("list[{}]", python_type(item)),
+            None => "Any".to_string(),
+        },
+    }
+}
+
+fn python(description: &ServerDescription) -> String {
+    let mut out = format!(
+        "\"\"\"Client for {} {}, generated by `mailbox-mcp --generate-sdk python`. Do not edit.\"\"\"\n\n\
+         import json\nimport urllib.request\nfrom typing import Any, Optional\n\n\
+         SERVER_VERSION = \"{}\"\n\n\n{PYTHON_PRELUDE}",
+        description.server, description.version, description.version
+    );
+    // Writing to a String cannot fail
+    for tool in &description.tools {
+        let method = identifier(&tool.name, PYTHON_RESERVED);
+        let mut signature = String::new();
+        let mut arguments = String::new();
+        for param in &tool.params {
+            let name = identifier(&param.name, PYTHON_RESERVED);
+            let param_type = python_type(&param.param_type);
+            if param.required {
+                let _ = write!(signature, ", {name}: {param_type}");
+            } else {
+                let _ = write!(signature, ", {name}: Optional[{param_type}] = None");
+            }
+            let _ = write!(arguments, "{:?}: {name}, ", param.name);
+        }
+        let docstring = tool
+            .description
+            .replace('\\', "\\\\")
+            .replace("\"\"\"", "\\\"\"\"");
+        let _ = write!(
+            out,
+            "\n    def {method}(self{signature}) -> Any:\n        \"\"\"{docstring}\"\"\"\n        return self.call({:?}, {{{}}})\n",
+            tool.name,
+            arguments.trim_end_matches(", ")
+        );
+    }
+    out
+}
+
+fn typescript_type(param_type: &str) -> String {
+    match param_type {
+        "string" => "string".to_string(),
+        "integer" | "number" => "number".to_string(),
+        "boolean" => "boolean".to_string(),
+        "object" => "Record<string, unknown>".to_string(),
+        _ => match param_type
+            .strip_prefix("array<")
+            .and_then(|item| item.strip_suffix('>'))
+        {
+            Some(item) => format!("{}[]", typescript_type(item)),
+            None => "unknown".to_string(),
+        },
+    }
+}
+
+const TYPESCRIPT_PRELUDE: &str = r#"export class MailboxError extends Error {
+  constructor(message: string, readonly data?: unknown) {
+    super(message);
+  }
+}
+
+export class MailboxClient {
+  private session?: string;
+  private nextId = 0;
+
+  constructor(
+    private readonly url = "http://127.0.0.1:3000/mcp",
+    private readonly token?: string,
+  ) {}
+
+  private async post(payload: object): Promise<any> {
+    const headers: Record<string, string> = {
+      "Content-Type": "application/json",
+      Accept: "application/json, text/event-stream",
+    };
+    if (this.token) headers["Authorization"] = `Bearer ${this.token}`;
+    if (this.session) headers["Mcp-Session-Id"] = this.session;
+    const response = await fetch(this.url, { method: "POST", headers, body: JSON.stringify(payload) });
+    if (!response.ok) throw new MailboxError(`HTTP ${response.status}`);
+    this.session = response.headers.get("Mcp-Session-Id") ?? this.session;
+    const body = await response.text();
+    let reply: any;
+    for (const line of body.split("\n")) {
+      if (line.startsWith("data:") && line.slice(5).trim()) reply = JSON.parse(line.slice(5));
+    }
+    if (reply === undefined && body.trim().startsWith("{")) reply = JSON.parse(body);
+    return reply;
+  }
+
+  private async request(method: string, params: object): Promise<any> {
+    const reply = await this.post({ jsonrpc: "2.0", id: ++this.nextId, method, params });
+    if (reply === undefined) throw new MailboxError(`No response to ${method}`);
+    if (reply.error) throw new MailboxError(reply.error.message, reply.error.data);
+    return reply.result;
+  }
+
+  /** Calls a tool by name. */
+  async call(tool: string, args: object): Promise<unknown> {
+    if (this.session === undefined) {
+      await this.request("initialize", {
+        protocolVersion: "2024-11-05",
+        capabilities: {},
+        clientInfo: { name: "mailbox-mcp-typescript", version: SERVER_VERSION },
+      });
+      await this.post({ jsonrpc: "2.0", method: "notifications/initialized" });
+    }
+    const result = await this.request("tools/call", { name: tool, arguments: args });
+    const text = (result.content ?? []).map((block: any) => block.text ?? "").join("");
+    if (result.isError) throw new MailboxError(text);
+    try {
+      return JSON.parse(text);
+    } catch {
+      return text;
+    }
+  }
+"#;
+
+/// Client members tool methods must not shadow.
+const TYPESCRIPT_RESERVED: &[&str] = &["call", "constructor", "post", "request"];
+
+fn typescript(description: &ServerDescription) -> String {
+    let mut out = format!(
+        "// Client for {} {}, generated by `mailbox-mcp --generate-sdk typescript`. Do not edit.\n\nexport const SERVER_VERSION = \"{}\";\n\n{TYPESCRIPT_PRELUDE}",
+        description.server, description.version, description.version
+    );
+    // Writing to a String cannot fail
+    for tool in &description.tools {
+        let method = identifier(&tool.name, TYPESCRIPT_RESERVED);
+        let mut fields = String::new();
+        for param in &tool.params {
+            let _ = write!(
+                fields,
+                " {:?}{}: {};",
+                param.name,
+                if param.required { "" } else { "?" },
+                typescript_type(&param.param_type)
+            );
+        }
+        let params = if tool.params.iter().any(|param| param.required) {
+            format!("params: {{{fields} }}")
+        } else {
+            format!("params: {{{fields} }} = {{}}")
+        };
+        let comment = tool.description.replace("*/", "*\\/");
+        let _ = write!(
+            out,
+            "\n  /** {comment} */\n  {method}({params}): Promise<unknown> {{\n    return this.call({:?}, params);\n  }}\n",
+            tool.name
+        );
+    }
+    out.push_str("}\n");
+    out
+}
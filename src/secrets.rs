@@ -0,0 +1,150 @@
+//! Secret references in the configuration file.
+//!
+//! Any string in the config file may embed `${env:NAME}`, replaced by the
+//! environment variable `NAME`, or `${secret:NAME}`, replaced by the entry
+//! `NAME` of an [age](https://age-encryption.org)-encrypted secrets file:
+//!
+//! ```toml
+//! [secrets]
+//! file = "/etc/mailbox-mcp/secrets.toml.age"
+//! identity_file = "/etc/mailbox-mcp/key.txt"
+//!
+//! [[alerts.rules]]
+//! # name, metric, threshold, ...
+//! webhook = "https://hooks.example.com/${secret:hook_token}"
+//! ```
+//!
+//! The secrets file decrypts to a TOML table of string values (e.g.
+//! `hook_token = "..."`). It is encrypted either to an age key, whose identity
+//! file is given as `identity_file`, or with a passphrase read from the
+//! environment variable named by `passphrase_env`. Unresolvable references
+//! are configuration errors; there is no escape syntax, as no setting needs a
+//! literal `${`.
+
+use crate::config::ConfigError;
+use age::secrecy::SecretString;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// Encrypted secrets file settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecretsConfig {
+    /// age-encrypted (binary or armored) TOML table of secrets.
+    pub file: PathBuf,
+    /// age identity file holding the key the secrets are encrypted to.
+    #[serde(default)]
+    pub identity_file: Option<PathBuf>,
+    /// Environment variable holding the passphrase the secrets are encrypted with.
+    #[serde(default)]
+    pub passphrase_env: Option<String>,
+}
+
+fn invalid(reason: impl ToString) -> ConfigError {
+    ConfigError::InvalidSecret {
+        reason: reason.to_string(),
+    }
+}
+
+impl SecretsConfig {
+    /// Decrypts the secrets file.
+    pub fn load(&self) -> Result<HashMap<String, String>, ConfigError> {
+        let identity: Box<dyn age::Identity> = match (&self.identity_file, &self.passphrase_env) {
+            (Some(path), None) => {
+                let mut identities = age::IdentityFile::from_file(path.display().to_string())
+                    .map_err(|e| invalid(format!("cannot read {}: {e}", path.display())))?
+                    .into_identities()
+                    .map_err(invalid)?;
+                if identities.len() != 1 {
+                    return Err(invalid(format!(
+                        "{} must hold exactly one identity",
+                        path.display()
+                    )));
+                }
+                identities.remove(0)
+            }
+            (None, Some(var)) => {
+                let passphrase = std::env::var(var)
+                    .map_err(|_| invalid(format!("environment variable {var} is not set")))?;
+                Box::new(age::scrypt::Identity::new(SecretString::from(passphrase)))
+            }
+            _ => {
+                return Err(invalid(
+                    "set exactly one of identity_file and passphrase_env",
+                ))
+            }
+        };
+
+        let file = std::fs::File::open(&self.file)
+            .map_err(|e| invalid(format!("cannot read {}: {e}", self.file.display())))?;
+        let decryptor = age::Decryptor::new(age::armor::ArmoredReader::new(file))
+            .map_err(|e| invalid(format!("{}: {e}", self.file.display())))?;
+        let mut contents = String::new();
+        decryptor
+            .decrypt(std::iter::once(identity.as_ref()))
+            .map_err(|e| invalid(format!("cannot decrypt {}: {e}", self.file.display())))?
+            .read_to_string(&mut contents)
+            .map_err(|e| invalid(format!("cannot decrypt {}: {e}", self.file.display())))?;
+        toml::from_str(&contents).map_err(|e| {
+            invalid(format!(
+                "{} is not a table of strings: {e}",
+                self.file.display()
+            ))
+        })
+    }
+}
+
+/// Replaces the secret references in every string of a TOML value.
+pub fn resolve(
+    value: &mut toml::Value,
+    secrets: Option<&HashMap<String, String>>,
+) -> Result<(), ConfigError> {
+    match value {
+        toml::Value::String(s) if s.contains("${") => *s = substitute(s, secrets)?,
+        toml::Value::Array(values) => {
+            for value in values {
+                resolve(value, secrets)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                resolve(value, secrets)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn substitute(s: &str, secrets: Option<&HashMap<String, String>>) -> Result<String, ConfigError> {
+    let mut resolved = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        resolved.push_str(&rest[..start]);
+        let reference = &rest[start + 2..];
+        let end = reference
+            .find('}')
+            .ok_or_else(|| invalid(format!("unterminated reference in '{s}'")))?;
+        let value = match reference[..end].split_once(':') {
+            Some(("env", name)) => std::env::var(name)
+                .map_err(|_| invalid(format!("environment variable {name} is not set")))?,
+            Some(("secret", name)) => secrets
+                .ok_or_else(|| invalid(format!("${{secret:{name}}} used without [secrets]")))?
+                .get(name)
+                .cloned()
+                .ok_or_else(|| invalid(format!("no secret named '{name}'")))?,
+            _ => {
+                return Err(invalid(format!(
+                    "unknown reference '${{{}}}' (expected env:NAME or secret:NAME)",
+                    &reference[..end]
+                )))
+            }
+        };
+        resolved.push_str(&value);
+        rest = &reference[end + 1..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
@@ -0,0 +1,239 @@
+//! Tracking of active MCP sessions for admin visibility.
+//!
+//! Session lifetime is owned by rmcp's [`LocalSessionManager`]; this registry
+//! wraps it to layer metadata on top (client info, bound agent, last activity),
+//! expose forced disconnects, and clean up per-session sandbox projects.
+
+use crate::db::Database;
+use crate::time;
+use futures::Stream;
+use rmcp::model::{ClientJsonRpcMessage, ServerJsonRpcMessage};
+use rmcp::transport::common::server_side_http::ServerSseMessage;
+use rmcp::transport::streamable_http_server::session::local::LocalSessionManager;
+use rmcp::transport::streamable_http_server::session::{SessionId, SessionManager};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Prefix of the ephemeral projects created for sessions in sandbox mode.
+pub const SANDBOX_PROJECT_PREFIX: &str = "sandbox/";
+
+/// Returns the sandbox project ID for a session.
+#[must_use]
+pub fn sandbox_project(session_id: &str) -> String {
+    format!("{SANDBOX_PROJECT_PREFIX}{session_id}")
+}
+
+/// Metadata about an active MCP session.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct SessionInfo {
+    pub session_id: String,
+    /// Client implementation name, as reported during initialization.
+    pub client_name: Option<String>,
+    /// Client implementation version, as reported during initialization.
+    pub client_version: Option<String>,
+    /// Agent identity most recently used by this session (`agent_id` or `from_agent`).
+    pub agent_id: Option<String>,
+    /// Time of the first tool call seen on this session.
+    pub first_seen: Option<String>,
+    /// Time of the most recent tool call on this session.
+    pub last_activity: Option<String>,
+    /// Number of tool calls made on this session.
+    pub tool_calls: u64,
+    /// Ephemeral project owned by this session, deleted when it ends.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sandbox_project: Option<String>,
+}
+
+/// Registry of active sessions, shared by all per-session server instances.
+///
+/// The registry is itself a [`SessionManager`] delegating to a
+/// [`LocalSessionManager`], so it observes every session closing.
+#[derive(Clone)]
+pub struct SessionRegistry {
+    manager: Arc<LocalSessionManager>,
+    info: Arc<Mutex<HashMap<String, SessionInfo>>>,
+    /// Database used to purge sandbox projects; `None` disables sandbox mode.
+    sandbox_db: Option<Database>,
+}
+
+impl SessionRegistry {
+    /// Creates a registry backed by the given session manager.
+    #[must_use]
+    pub fn new(manager: Arc<LocalSessionManager>) -> Self {
+        Self {
+            manager,
+            info: Arc::new(Mutex::new(HashMap::new())),
+            sandbox_db: None,
+        }
+    }
+
+    /// Enables sandbox mode: each session gets an ephemeral project
+    /// (see [`sandbox_project`]) that is purged when the session ends.
+    ///
+    /// Sandbox projects left behind by a previous run are purged immediately.
+    #[must_use]
+    pub fn with_sandboxes(mut self, db: Database) -> Self {
+        match db.purge_projects_with_prefix(SANDBOX_PROJECT_PREFIX) {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("Purged {count} orphaned sandbox projects"),
+            Err(e) => tracing::warn!("Failed to purge orphaned sandbox projects: {e}"),
+        }
+        self.sandbox_db = Some(db);
+        self
+    }
+
+    /// Returns the sandbox project for a session, if sandbox mode is enabled.
+    #[must_use]
+    pub fn sandbox_for(&self, session_id: &str) -> Option<String> {
+        self.sandbox_db
+            .as_ref()
+            .map(|_| sandbox_project(session_id))
+    }
+
+    fn info(&self) -> std::sync::MutexGuard<'_, HashMap<String, SessionInfo>> {
+        self.info
+            .lock()
+            .expect("Session registry mutex poisoned - this indicates a bug")
+    }
+
+    /// Records a tool call made on a session.
+    pub fn record_activity(
+        &self,
+        session_id: &str,
+        client: Option<(&str, &str)>,
+        agent_id: Option<&str>,
+    ) {
+        let now = time::now_iso8601();
+        let mut info = self.info();
+        let entry = info
+            .entry(session_id.to_string())
+            .or_insert_with(|| SessionInfo {
+                session_id: session_id.to_string(),
+                first_seen: Some(now.clone()),
+                sandbox_project: self.sandbox_for(session_id),
+                ..SessionInfo::default()
+            });
+        if let Some((name, version)) = client {
+            entry.client_name = Some(name.to_string());
+            entry.client_version = Some(version.to_string());
+        }
+        if let Some(agent_id) = agent_id {
+            entry.agent_id = Some(agent_id.to_string());
+        }
+        entry.last_activity = Some(now);
+        entry.tool_calls += 1;
+    }
+
+    /// Lists all active sessions, including those that haven't called a tool yet.
+    ///
+    /// Metadata for sessions that have since closed is pruned.
+    pub async fn list(&self) -> Vec<SessionInfo> {
+        let active: Vec<String> = self
+            .manager
+            .sessions
+            .read()
+            .await
+            .keys()
+            .map(ToString::to_string)
+            .collect();
+
+        let mut info = self.info();
+        info.retain(|id, _| active.contains(id));
+        let mut sessions: Vec<SessionInfo> = active
+            .into_iter()
+            .map(|id| {
+                info.get(&id).cloned().unwrap_or_else(|| SessionInfo {
+                    sandbox_project: self.sandbox_for(&id),
+                    session_id: id,
+                    ..SessionInfo::default()
+                })
+            })
+            .collect();
+        sessions.sort_by(|a, b| a.first_seen.cmp(&b.first_seen));
+        sessions
+    }
+
+    /// Forcibly terminates a session.
+    ///
+    /// Returns `true` if the session existed and was closed.
+    pub async fn disconnect(&self, session_id: &str) -> bool {
+        let id: SessionId = Arc::from(session_id);
+        let existed = self.manager.has_session(&id).await.unwrap_or(false);
+        if existed {
+            if let Err(e) = self.close_session(&id).await {
+                tracing::warn!("Failed to close session {session_id}: {e}");
+            }
+            tracing::info!("Session {session_id} disconnected by admin");
+        }
+        existed
+    }
+
+    /// Drops session metadata and purges the session's sandbox project.
+    fn on_session_closed(&self, session_id: &str) {
+        self.info().remove(session_id);
+        if let Some(db) = &self.sandbox_db {
+            let project_id = sandbox_project(session_id);
+            if let Err(e) = db.purge_project(&project_id) {
+                tracing::warn!("Failed to purge sandbox project {project_id}: {e}");
+            }
+        }
+    }
+}
+
+impl SessionManager for SessionRegistry {
+    type Error = <LocalSessionManager as SessionManager>::Error;
+    type Transport = <LocalSessionManager as SessionManager>::Transport;
+
+    async fn create_session(&self) -> Result<(SessionId, Self::Transport), Self::Error> {
+        self.manager.create_session().await
+    }
+
+    async fn initialize_session(
+        &self,
+        id: &SessionId,
+        message: ClientJsonRpcMessage,
+    ) -> Result<ServerJsonRpcMessage, Self::Error> {
+        self.manager.initialize_session(id, message).await
+    }
+
+    async fn has_session(&self, id: &SessionId) -> Result<bool, Self::Error> {
+        self.manager.has_session(id).await
+    }
+
+    async fn close_session(&self, id: &SessionId) -> Result<(), Self::Error> {
+        let result = self.manager.close_session(id).await;
+        self.on_session_closed(id);
+        result
+    }
+
+    async fn create_stream(
+        &self,
+        id: &SessionId,
+        message: ClientJsonRpcMessage,
+    ) -> Result<impl Stream<Item = ServerSseMessage> + Send + Sync + 'static, Self::Error> {
+        self.manager.create_stream(id, message).await
+    }
+
+    async fn accept_message(
+        &self,
+        id: &SessionId,
+        message: ClientJsonRpcMessage,
+    ) -> Result<(), Self::Error> {
+        self.manager.accept_message(id, message).await
+    }
+
+    async fn create_standalone_stream(
+        &self,
+        id: &SessionId,
+    ) -> Result<impl Stream<Item = ServerSseMessage> + Send + Sync + 'static, Self::Error> {
+        self.manager.create_standalone_stream(id).await
+    }
+
+    async fn resume(
+        &self,
+        id: &SessionId,
+        last_event_id: String,
+    ) -> Result<impl Stream<Item = ServerSseMessage> + Send + Sync + 'static, Self::Error> {
+        self.manager.resume(id, last_event_id).await
+    }
+}
@@ -0,0 +1,83 @@
+//! Per-statement SQL logging for `--trace-sql`.
+//!
+//! rusqlite's `trace`/`profile` hooks don't expose row counts, so this
+//! registers SQLite's `sqlite3_trace_v2` callback directly: row events are
+//! counted per statement, and each completed statement is logged at debug
+//! level with its duration, rows returned, and rows changed.
+
+use rusqlite::{ffi, Connection};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::{c_int, c_uint, c_void};
+use std::time::Duration;
+
+thread_local! {
+    /// Rows produced so far by each running statement, keyed by statement pointer.
+    ///
+    /// Statements run to completion on the thread holding the connection lock,
+    /// so row and profile events for a statement arrive on the same thread.
+    static ROWS: RefCell<HashMap<usize, u64>> = RefCell::new(HashMap::new());
+}
+
+/// Starts logging every statement executed on the connection.
+pub fn install(conn: &Connection) {
+    #[allow(clippy::cast_sign_loss)] // mask constants are small positive values
+    let mask = (ffi::SQLITE_TRACE_ROW | ffi::SQLITE_TRACE_PROFILE) as c_uint;
+    // SAFETY: the handle is valid for the lifetime of `conn`, and the callback
+    // neither retains pointers nor uses the context argument.
+    let rc =
+        unsafe { ffi::sqlite3_trace_v2(conn.handle(), mask, Some(callback), std::ptr::null_mut()) };
+    if rc != ffi::SQLITE_OK {
+        tracing::warn!("Failed to enable SQL tracing (SQLite error {rc})");
+    }
+}
+
+/// `sqlite3_trace_v2` callback.
+///
+/// For both event kinds `p` is the `sqlite3_stmt`; for profile events `x`
+/// points to the elapsed time in nanoseconds.
+unsafe extern "C" fn callback(
+    event: c_uint,
+    _ctx: *mut c_void,
+    p: *mut c_void,
+    x: *mut c_void,
+) -> c_int {
+    #[allow(clippy::cast_possible_wrap)] // event codes are small positive values
+    let event = event as c_int;
+    let key = p as usize;
+    if event == ffi::SQLITE_TRACE_ROW {
+        ROWS.with(|rows| *rows.borrow_mut().entry(key).or_insert(0) += 1);
+    } else if event == ffi::SQLITE_TRACE_PROFILE {
+        let stmt = p.cast::<ffi::sqlite3_stmt>();
+        let rows = ROWS
+            .with(|rows| rows.borrow_mut().remove(&key))
+            .unwrap_or(0);
+        // SAFETY: SQLite passes a valid statement and elapsed-time pointer for
+        // profile events, valid for the duration of the callback.
+        let (nanos, sql, changes) = unsafe {
+            let nanos = *x.cast::<i64>();
+            let sql = ffi::sqlite3_sql(stmt);
+            let sql = if sql.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(sql).to_string_lossy().into_owned()
+            };
+            let changes = if ffi::sqlite3_stmt_readonly(stmt) == 0 {
+                Some(ffi::sqlite3_changes(ffi::sqlite3_db_handle(stmt)))
+            } else {
+                None
+            };
+            (nanos, sql, changes)
+        };
+        let duration = Duration::from_nanos(u64::try_from(nanos).unwrap_or(0));
+        let sql = sql.split_whitespace().collect::<Vec<_>>().join(" ");
+        match changes {
+            Some(changes) => {
+                tracing::debug!(target: "mailbox_mcp::sql", ?duration, rows, changes, "{sql}");
+            }
+            None => tracing::debug!(target: "mailbox_mcp::sql", ?duration, rows, "{sql}"),
+        }
+    }
+    0
+}
@@ -0,0 +1,133 @@
+//! StatsD metrics export over UDP.
+//!
+//! When the `[statsd]` config section is present, the contents of the
+//! [`Metrics`] registry are periodically sent to a StatsD server (such as the
+//! Datadog agent):
+//!
+//! ```toml
+//! [statsd]
+//! address = "127.0.0.1:8125"
+//! prefix = "mailbox."
+//! interval_secs = 10
+//! ```
+
+use crate::metrics::{MetricKind, Metrics};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Largest datagram sent; lines are batched up to this size.
+const MAX_DATAGRAM: usize = 1432;
+
+/// StatsD export settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsdConfig {
+    /// StatsD server address (`host:port`).
+    pub address: String,
+    /// Prepended to every metric name.
+    pub prefix: String,
+    /// Seconds between flushes.
+    pub interval_secs: u64,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:8125".to_string(),
+            prefix: String::new(),
+            interval_secs: 10,
+        }
+    }
+}
+
+/// Formats metrics as StatsD lines.
+///
+/// Gauges are sent as-is; counters are sent as the increase since the
+/// previous flush, tracked in `last_counters`.
+fn format_lines(
+    metrics: &Metrics,
+    prefix: &str,
+    last_counters: &mut HashMap<&'static str, f64>,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    for metric in metrics.snapshot() {
+        match metric.kind {
+            MetricKind::Gauge => lines.push(format!("{prefix}{}:{}|g", metric.name, metric.value)),
+            MetricKind::Counter => {
+                let previous = last_counters
+                    .insert(metric.name, metric.value)
+                    .unwrap_or(0.0);
+                let delta = metric.value - previous;
+                if delta > 0.0 {
+                    lines.push(format!("{prefix}{}:{delta}|c", metric.name));
+                }
+            }
+        }
+    }
+    lines
+}
+
+/// Sends lines to the socket, packing as many as fit into each datagram.
+async fn send_lines(socket: &UdpSocket, lines: &[String]) -> std::io::Result<()> {
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+            socket.send(datagram.as_bytes()).await?;
+            datagram.clear();
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        // Writing to a String cannot fail
+        let _ = write!(datagram, "{line}");
+    }
+    if !datagram.is_empty() {
+        socket.send(datagram.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Flushes metrics to StatsD until the task is cancelled.
+pub async fn run(metrics: Metrics, config: StatsdConfig) {
+    let socket = match connect(&config.address).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            tracing::warn!(
+                "StatsD export disabled: cannot reach {}: {e}",
+                config.address
+            );
+            return;
+        }
+    };
+
+    let mut last_counters = HashMap::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+
+    loop {
+        ticker.tick().await;
+
+        let lines = format_lines(&metrics, &config.prefix, &mut last_counters);
+        if let Err(e) = send_lines(&socket, &lines).await {
+            tracing::debug!("Failed to send StatsD metrics: {e}");
+        }
+    }
+}
+
+/// Resolves the server address and connects a UDP socket of the matching family.
+async fn connect(address: &str) -> std::io::Result<UdpSocket> {
+    let addr = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses"))?;
+    let local = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
@@ -0,0 +1,82 @@
+//! Placeholder substitution in message templates.
+//!
+//! Projects register named templates with
+//! [`Database::register_message_template`](crate::db::Database::register_message_template);
+//! `{{name}}` placeholders in a template are replaced by the values of the
+//! variables given when it is rendered. Names consist of ASCII letters,
+//! digits, `_`, `-` and `.`, optionally surrounded by spaces inside the
+//! braces; braces around anything else are left as they are. String values
+//! are inserted verbatim, other JSON values as their JSON text.
+
+use serde_json::{Map, Value};
+
+/// Returns the distinct placeholder names of a template, in order of first use.
+#[must_use]
+pub fn placeholders(template: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for (_, name, _) in Placeholders::new(template) {
+        if !names.iter().any(|known| known == name) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+/// Substitutes every placeholder, or returns the name of the first one
+/// without a value.
+pub fn render(template: &str, variables: &Map<String, Value>) -> Result<String, String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut copied = 0;
+    for (start, name, end) in Placeholders::new(template) {
+        let value = variables.get(name).ok_or_else(|| name.to_string())?;
+        rendered.push_str(&template[copied..start]);
+        match value {
+            Value::String(text) => rendered.push_str(text),
+            other => rendered.push_str(&other.to_string()),
+        }
+        copied = end;
+    }
+    rendered.push_str(&template[copied..]);
+    Ok(rendered)
+}
+
+/// Iterates over the placeholders of a template as (start, name, end) byte
+/// positions, the span covering the braces.
+struct Placeholders<'a> {
+    template: &'a str,
+    pos: usize,
+}
+
+impl<'a> Placeholders<'a> {
+    fn new(template: &'a str) -> Self {
+        Self { template, pos: 0 }
+    }
+}
+
+impl<'a> Iterator for Placeholders<'a> {
+    type Item = (usize, &'a str, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(offset) = self.template[self.pos..].find("{{") {
+            let start = self.pos + offset;
+            let inner = start + 2;
+            let Some(length) = self.template[inner..].find("}}") else {
+                self.pos = self.template.len();
+                return None;
+            };
+            let name = self.template[inner..inner + length].trim_matches(' ');
+            let valid = !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'-' | b'.'));
+            if valid {
+                self.pos = inner + length + 2;
+                return Some((start, name, self.pos));
+            }
+            // Not a placeholder; a later "{{" may still open one
+            self.pos = start + 1;
+        }
+        self.pos = self.template.len();
+        None
+    }
+}
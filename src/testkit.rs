@@ -0,0 +1,345 @@
+//! Harness for integration tests of multi-agent workflows (enabled by the
+//! `testkit` feature).
+//!
+//! [`TestServer`] runs a mailbox server in-process, on a throwaway database
+//! and an ephemeral localhost port, so the agents under test can connect to
+//! [`TestServer::url`] like they would to a real server. [`FakeAgent`]s play
+//! the other parties of the workflow directly against the database: they send
+//! messages, wait for expected ones, and answer with programmed replies,
+//! optionally after an injected latency.
+//!
+//! ```no_run
+//! use mailbox_mcp::testkit::{Expect, TestServer};
+//! use std::time::Duration;
+//!
+//! # async fn example() -> Result<(), mailbox_mcp::testkit::TestkitError> {
+//! let server = TestServer::start().await?;
+//! let reviewer = server
+//!     .agent("owner/repo", "reviewer")
+//!     .with_latency(Duration::from_millis(200));
+//! let _responder = reviewer.respond(|message| {
+//!     message.content.contains("review").then(|| "LGTM".to_string())
+//! });
+//!
+//! // ... point the agent under test at server.url(), then check its effects:
+//! let author = server.agent("owner/repo", "author");
+//! let reply = author
+//!     .expect(Expect::any().from("reviewer"), Duration::from_secs(5))
+//!     .await?;
+//! assert_eq!(reply.content, "LGTM");
+//! server.check_invariants()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::db::{Database, DbError, InvariantViolation, Message, MessageFilter, NewMessage};
+use crate::tools::MailboxServer;
+use rmcp::transport::streamable_http_server::{
+    session::local::LocalSessionManager, StreamableHttpServerConfig, StreamableHttpService,
+};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+/// How often waits re-check the queue for changes made by other processes.
+const RECHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Numbers the databases of servers started by this process.
+static NEXT_SERVER: AtomicU32 = AtomicU32::new(0);
+
+/// Errors raised by the test harness.
+#[derive(Error, Debug)]
+pub enum TestkitError {
+    /// A database operation failed.
+    #[error(transparent)]
+    Db(#[from] DbError),
+
+    /// The server could not be started.
+    #[error("Failed to start test server: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// No matching message arrived in time.
+    #[error("Agent '{agent_id}' received no message matching {expectation} within {waited:?}")]
+    Timeout {
+        agent_id: String,
+        expectation: String,
+        waited: Duration,
+    },
+
+    /// The database was left inconsistent.
+    #[error("Database invariants broken: {}", describe_violations(.0))]
+    Invariants(Vec<InvariantViolation>),
+}
+
+fn describe_violations(violations: &[InvariantViolation]) -> String {
+    violations
+        .iter()
+        .map(|v| format!("{} ({} violations)", v.invariant, v.violations))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A mailbox server running in-process on a throwaway database.
+///
+/// The server stops, and its database is deleted, when this is dropped.
+pub struct TestServer {
+    db: Database,
+    url: String,
+    dir: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Starts a server with default settings on an ephemeral port.
+    pub async fn start() -> Result<Self, TestkitError> {
+        Self::start_with(|server| server).await
+    }
+
+    /// Starts a server, letting `configure` adjust it first (e.g. with
+    /// [`MailboxServer::with_backpressure`]).
+    pub async fn start_with(
+        configure: impl FnOnce(MailboxServer) -> MailboxServer,
+    ) -> Result<Self, TestkitError> {
+        let dir = std::env::temp_dir().join(format!(
+            "mailbox-mcp-testkit-{}-{}",
+            std::process::id(),
+            NEXT_SERVER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let db = Database::open(&dir.join("mailbox.db"))?;
+        let server = configure(MailboxServer::new(db.clone()));
+        let service = StreamableHttpService::new(
+            move || Ok(server.clone()),
+            Arc::new(LocalSessionManager::default()),
+            StreamableHttpServerConfig::default(),
+        );
+        let app = axum::Router::new().nest_service("/mcp", service);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/mcp", listener.local_addr()?);
+        let task = tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, app).await {
+                tracing::warn!("Test server stopped: {e}");
+            }
+        });
+        Ok(Self { db, url, dir, task })
+    }
+
+    /// URL of the MCP endpoint, e.g. `http://127.0.0.1:54321/mcp`.
+    #[must_use]
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The server's database, for setting up state and inspecting results.
+    #[must_use]
+    pub fn db(&self) -> &Database {
+        &self.db
+    }
+
+    /// Checks the database's cross-table invariants (see
+    /// [`Database::check_invariants`]); call it at the end of a test to catch
+    /// inconsistencies the workflow left behind.
+    pub fn check_invariants(&self) -> Result<(), TestkitError> {
+        let broken = self.db.check_invariants()?;
+        if broken.is_empty() {
+            Ok(())
+        } else {
+            Err(TestkitError::Invariants(broken))
+        }
+    }
+
+    /// Returns a fake agent with the given identity in a project.
+    #[must_use]
+    pub fn agent(&self, project_id: &str, agent_id: &str) -> FakeAgent {
+        FakeAgent {
+            db: self.db.clone(),
+            project_id: project_id.to_string(),
+            agent_id: agent_id.to_string(),
+            latency: Duration::ZERO,
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Which messages an [`expect`](FakeAgent::expect) waits for. All given
+/// conditions must hold.
+#[derive(Debug, Clone, Default)]
+pub struct Expect {
+    from_agent: Option<String>,
+    content_contains: Option<String>,
+    reference_id: Option<String>,
+}
+
+impl Expect {
+    /// Matches any message.
+    #[must_use]
+    pub fn any() -> Self {
+        Self::default()
+    }
+
+    /// Only messages from this agent.
+    #[must_use]
+    pub fn from(mut self, agent_id: &str) -> Self {
+        self.from_agent = Some(agent_id.to_string());
+        self
+    }
+
+    /// Only messages whose content contains this substring.
+    #[must_use]
+    pub fn containing(mut self, text: &str) -> Self {
+        self.content_contains = Some(text.to_string());
+        self
+    }
+
+    /// Only replies to this message.
+    #[must_use]
+    pub fn replying_to(mut self, message_id: &str) -> Self {
+        self.reference_id = Some(message_id.to_string());
+        self
+    }
+}
+
+impl std::fmt::Display for Expect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut conditions = Vec::new();
+        if let Some(from_agent) = &self.from_agent {
+            conditions.push(format!("from '{from_agent}'"));
+        }
+        if let Some(text) = &self.content_contains {
+            conditions.push(format!("containing '{text}'"));
+        }
+        if let Some(reference_id) = &self.reference_id {
+            conditions.push(format!("replying to {reference_id}"));
+        }
+        if conditions.is_empty() {
+            f.write_str("any message")
+        } else {
+            write!(f, "a message {}", conditions.join(" and "))
+        }
+    }
+}
+
+/// A scripted participant in a workflow under test.
+#[derive(Clone)]
+pub struct FakeAgent {
+    db: Database,
+    project_id: String,
+    agent_id: String,
+    latency: Duration,
+}
+
+impl FakeAgent {
+    /// Delays each message this agent sends, simulating a slow agent.
+    #[must_use]
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// The agent's ID.
+    #[must_use]
+    pub fn agent_id(&self) -> &str {
+        &self.agent_id
+    }
+
+    /// Sends a message to another agent, after the injected latency.
+    pub async fn send(&self, to_agent: &str, content: &str) -> Result<String, TestkitError> {
+        self.send_message(to_agent, content, None).await
+    }
+
+    /// Replies to a message, to its `reply_to` queue or else its sender, after
+    /// the injected latency.
+    pub async fn reply(&self, message: &Message, content: &str) -> Result<String, TestkitError> {
+        let to_agent = message.reply_to.as_deref().unwrap_or(&message.from_agent);
+        self.send_message(to_agent, content, Some(&message.id))
+            .await
+    }
+
+    async fn send_message(
+        &self,
+        to_agent: &str,
+        content: &str,
+        reference_id: Option<&str>,
+    ) -> Result<String, TestkitError> {
+        tokio::time::sleep(self.latency).await;
+        Ok(self.db.send(&NewMessage {
+            project_id: &self.project_id,
+            to_agent,
+            from_agent: &self.agent_id,
+            content,
+            reference_id,
+            ..NewMessage::default()
+        })?)
+    }
+
+    /// Waits for a matching message and receives it. Other messages stay queued.
+    pub async fn expect(&self, expect: Expect, timeout: Duration) -> Result<Message, TestkitError> {
+        let reference_ids = expect.reference_id.clone().map(|id| vec![id]);
+        let filter = MessageFilter {
+            from_agent: expect.from_agent.as_deref(),
+            content_contains: expect.content_contains.as_deref(),
+            reference_ids: reference_ids.as_deref(),
+            ..MessageFilter::default()
+        };
+        let deadline = tokio::time::Instant::now() + timeout;
+        let notify = self.db.subscribe_queue(&self.project_id, &self.agent_id);
+
+        loop {
+            // Register before checking, so a send in between still wakes us
+            let notified = notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let received = self.db.receive_filtered(
+                &self.project_id,
+                &self.agent_id,
+                &filter,
+                Some(1),
+                None,
+            )?;
+            if let Some(message) = received.into_iter().next() {
+                return Ok(message);
+            }
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                return Err(TestkitError::Timeout {
+                    agent_id: self.agent_id.clone(),
+                    expectation: expect.to_string(),
+                    waited: timeout,
+                });
+            }
+            let _ = tokio::time::timeout_at(deadline.min(now + RECHECK_INTERVAL), notified).await;
+        }
+    }
+
+    /// Answers every message this agent receives, until the returned task is
+    /// aborted: `rule` returns the reply, or `None` to leave a message
+    /// unanswered. Replies reference the message they answer and are sent
+    /// after the injected latency.
+    pub fn respond(
+        &self,
+        rule: impl Fn(&Message) -> Option<String> + Send + 'static,
+    ) -> JoinHandle<Result<(), TestkitError>> {
+        let agent = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let message = match agent.expect(Expect::any(), Duration::from_secs(60)).await {
+                    Ok(message) => message,
+                    Err(TestkitError::Timeout { .. }) => continue,
+                    Err(e) => return Err(e),
+                };
+                if let Some(reply) = rule(&message) {
+                    agent.reply(&message, &reply).await?;
+                }
+            }
+        })
+    }
+}
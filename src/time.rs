@@ -0,0 +1,217 @@
+//! Wall-clock helpers.
+//!
+//! Timestamps are formatted the same way SQLite's
+//! `strftime('%Y-%m-%dT%H:%M:%SZ', 'now')` formats them in the database, so
+//! values computed in Rust compare correctly with stored ones.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Broken-down UTC date and time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UtcDateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// Day of the week, 0 = Sunday.
+    pub weekday: u32,
+}
+
+impl UtcDateTime {
+    /// Converts seconds since the Unix epoch to a UTC date and time.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // all values are range-bound
+    pub const fn from_unix_seconds(secs: i64) -> Self {
+        let days = secs.div_euclid(86_400);
+        let secs_of_day = secs.rem_euclid(86_400);
+
+        // Civil-from-days algorithm (Howard Hinnant)
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u32,
+            minute: (secs_of_day / 60 % 60) as u32,
+            second: (secs_of_day % 60) as u32,
+            // 1970-01-01 was a Thursday
+            weekday: (days + 4).rem_euclid(7) as u32,
+        }
+    }
+
+    /// Converts back to seconds since the Unix epoch.
+    #[must_use]
+    pub const fn to_unix_seconds(&self) -> i64 {
+        // Days-from-civil algorithm (Howard Hinnant)
+        let year = if self.month <= 2 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let month = self.month as i64;
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let doy = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe - 719_468;
+        days * 86_400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+
+    /// Parses a timestamp as stored in the database, e.g. `2025-01-08T12:00:00Z`.
+    #[must_use]
+    pub fn parse_iso8601(s: &str) -> Option<Self> {
+        let s = s.strip_suffix('Z')?;
+        let (date, time) = s.split_once('T')?;
+        let mut date = date.splitn(3, '-');
+        let mut time = time.splitn(3, ':');
+        let parsed = Self {
+            year: date.next()?.parse().ok()?,
+            month: date.next()?.parse().ok()?,
+            day: date.next()?.parse().ok()?,
+            hour: time.next()?.parse().ok()?,
+            minute: time.next()?.parse().ok()?,
+            second: time.next()?.parse().ok()?,
+            weekday: 0,
+        };
+        let valid = (1..=12).contains(&parsed.month)
+            && (1..=31).contains(&parsed.day)
+            && parsed.hour < 24
+            && parsed.minute < 60
+            && parsed.second < 60;
+        // Round-trip to fill in the weekday
+        valid.then(|| Self::from_unix_seconds(parsed.to_unix_seconds()))
+    }
+
+    /// Returns the current UTC date and time.
+    #[must_use]
+    pub fn now() -> Self {
+        Self::from_unix_seconds(unix_seconds())
+    }
+
+    /// Formats as ISO 8601 with second precision, e.g. `2025-01-08T12:00:00Z`.
+    #[must_use]
+    pub fn to_iso8601(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Returns the current time as seconds since the Unix epoch.
+#[must_use]
+pub fn unix_seconds() -> i64 {
+    i64::try_from(unix_millis() / 1000).unwrap_or(i64::MAX)
+}
+
+/// Returns the current time as milliseconds since the Unix epoch.
+#[must_use]
+pub fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis())
+}
+
+/// Returns the current UTC time formatted as ISO 8601, e.g. `2025-01-08T12:00:00Z`.
+#[must_use]
+pub fn now_iso8601() -> String {
+    UtcDateTime::now().to_iso8601()
+}
+
+/// How timestamps are rendered in tool responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimestampFormat {
+    /// ISO 8601 in UTC, e.g. `2025-01-08T12:00:00Z` (the storage format).
+    #[default]
+    Utc,
+    /// Milliseconds since the Unix epoch, as a JSON number.
+    EpochMillis,
+    /// ISO 8601 shifted to a fixed UTC offset (in minutes), e.g. `2025-01-08T14:00:00+02:00`.
+    Offset(i32),
+}
+
+impl TimestampFormat {
+    /// Renders a stored timestamp in this format.
+    ///
+    /// Values that don't parse as stored timestamps are returned unchanged.
+    #[must_use]
+    pub fn render(self, stored: &str) -> serde_json::Value {
+        let Some(parsed) = UtcDateTime::parse_iso8601(stored) else {
+            return stored.into();
+        };
+        match self {
+            Self::Utc => stored.into(),
+            Self::EpochMillis => (parsed.to_unix_seconds() * 1000).into(),
+            Self::Offset(minutes) => {
+                let local = UtcDateTime::from_unix_seconds(
+                    parsed.to_unix_seconds() + i64::from(minutes) * 60,
+                );
+                let sign = if minutes < 0 { '-' } else { '+' };
+                let abs = minutes.unsigned_abs();
+                format!(
+                    "{}{sign}{:02}:{:02}",
+                    local.to_iso8601().trim_end_matches('Z'),
+                    abs / 60,
+                    abs % 60
+                )
+                .into()
+            }
+        }
+    }
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    /// Parses `utc`, `epoch_ms`, or a fixed offset such as `+05:30` / `-08:00`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("Invalid timestamp format '{s}' (expected utc, epoch_ms, or ±HH:MM)");
+        match s.trim() {
+            "utc" | "UTC" | "Z" | "" => Ok(Self::Utc),
+            "epoch_ms" => Ok(Self::EpochMillis),
+            offset => {
+                let (sign, rest) = match offset.as_bytes().first() {
+                    Some(b'+') => (1, &offset[1..]),
+                    Some(b'-') => (-1, &offset[1..]),
+                    _ => return Err(invalid()),
+                };
+                let (hours, minutes) = rest.split_once(':').ok_or_else(invalid)?;
+                let hours: i32 = hours.parse().map_err(|_| invalid())?;
+                let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+                if hours > 14 || minutes >= 60 {
+                    return Err(invalid());
+                }
+                Ok(Self::Offset(sign * (hours * 60 + minutes)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for TimestampFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Utc => f.write_str("utc"),
+            Self::EpochMillis => f.write_str("epoch_ms"),
+            Self::Offset(minutes) => {
+                let sign = if *minutes < 0 { '-' } else { '+' };
+                let abs = minutes.unsigned_abs();
+                write!(f, "{sign}{:02}:{:02}", abs / 60, abs % 60)
+            }
+        }
+    }
+}
@@ -1,17 +1,59 @@
 //! MCP tool handlers for mailbox-mcp.
 
-use crate::db::{Database, Message};
+use crate::auth::{Identity, ADMIN_ROLE};
+use crate::config::{ConfigError, ToolsConfig};
+use crate::db::{
+    CloneOptions, Database, DbError, DeliveryMode, GroupStrategy, LinkKind, Message, MessageFilter,
+    NewMessage, Priority, QueueCursor, QueueRemainder, QueueSettings, ThreadMessage,
+    DEFAULT_MAX_DELIVERY_ATTEMPTS, DEFAULT_TOKEN_OVERLAP_SECS, DEFAULT_VISIBILITY_TIMEOUT_SECS,
+    MAX_MESSAGE_LIMIT, MAX_MESSAGE_SIZE, SYSTEM_AGENT,
+};
+use crate::rate_limit::SendRateLimiter;
+use crate::sessions::SessionRegistry;
+use crate::time::{self, TimestampFormat};
+use crate::transform::Pipeline;
+use axum::http::request::Parts;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
 use rmcp::{
+    handler::server::tool::ToolCallContext,
     handler::server::{router::tool::ToolRouter, wrapper::Parameters},
     model::{
-        CallToolResult, Content, ErrorData as McpError, Implementation, ProtocolVersion,
-        ServerCapabilities, ServerInfo,
+        CallToolRequestParam, CallToolResult, Content, ErrorData as McpError, Extensions,
+        Implementation, ListToolsResult, PaginatedRequestParam, ProtocolVersion,
+        ServerCapabilities, ServerInfo, Tool,
     },
-    schemars, tool, tool_handler, tool_router, ServerHandler,
+    schemars,
+    service::RequestContext,
+    tool, tool_router, Peer, RoleServer, ServerHandler,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// HTTP header carrying the streamable-HTTP session ID.
+const SESSION_ID_HEADER: &str = "mcp-session-id";
+
+/// Default and maximum time the waiting tools (`wait_for_messages`, `collect_replies`,
+/// `send_and_wait_reply`, `await_milestone`) block.
+const DEFAULT_WAIT_TIMEOUT_SECS: u64 = 30;
+const MAX_WAIT_TIMEOUT_SECS: u64 = 300;
+
+/// How often the waiting tools re-check their condition.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often `wait_for_messages` re-checks the queue without a notification, to
+/// catch messages sent by other server processes and expired visibility timeouts.
+const WAIT_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far back `restore_messages` looks by default, in seconds.
+const DEFAULT_RESTORE_WINDOW_SECS: u64 = 300;
+
+/// Message fields holding timestamps, rendered according to the requested format.
+const TIMESTAMP_FIELDS: &[&str] = &["created_at", "next_retry_at"];
 
 // =============================================================================
 // Parameter types
@@ -26,6 +68,45 @@ pub struct ContextSetParams {
     /// Project ID (e.g., "owner/repo"). Omit for global context.
     #[serde(default)]
     pub project_id: Option<String>,
+    /// Classify the key confidential: clone_project skips it unless
+    /// include_confidential is set. Default: false.
+    #[serde(default)]
+    pub confidential: bool,
+    /// Seconds until the key reads as absent (default: never; max: 7776000 = 90 days).
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// Who is writing, checked against the key's lease. Defaults to the
+    /// authenticated agent, or "anonymous".
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Only check whether the value could be set, without storing it.
+    /// Default: false.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ContextSetManyParams {
+    /// Keys and the values to store under them (max 100 keys, each value max
+    /// 65,536 bytes).
+    pub entries: BTreeMap<String, String>,
+    /// Project ID (e.g., "owner/repo"). Omit for global context.
+    #[serde(default)]
+    pub project_id: Option<String>,
+    /// Classify every key confidential. Default: false.
+    #[serde(default)]
+    pub confidential: bool,
+    /// Seconds until every key reads as absent (default: never; max: 7776000 = 90 days).
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// Who is writing, checked against the keys' leases. Defaults to the
+    /// authenticated agent, or "anonymous".
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Only check whether the values could be set, without storing them.
+    /// Default: false.
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
@@ -37,6 +118,27 @@ pub struct ContextGetParams {
     pub project_id: Option<String>,
 }
 
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ContextGetManyParams {
+    /// The keys to retrieve (max 100).
+    pub keys: Vec<String>,
+    /// Project ID (e.g., "owner/repo"). Omit for global context.
+    #[serde(default)]
+    pub project_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ContextHistoryParams {
+    /// The key whose history to retrieve.
+    pub key: String,
+    /// Project ID (e.g., "owner/repo"). Omit for global context.
+    #[serde(default)]
+    pub project_id: Option<String>,
+    /// Maximum versions to return (default: 20, max: 50).
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
 pub struct ContextDeleteParams {
     /// The key to delete.
@@ -44,6 +146,53 @@ pub struct ContextDeleteParams {
     /// Project ID (e.g., "owner/repo"). Omit for global context.
     #[serde(default)]
     pub project_id: Option<String>,
+    /// Who is deleting, checked against the key's lease. Defaults to the
+    /// authenticated agent, or "anonymous".
+    #[serde(default)]
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ContextAppendParams {
+    /// The key holding the list.
+    pub key: String,
+    /// The entry to append.
+    pub entry: String,
+    /// Entries to keep, oldest evicted first (1-1000).
+    pub max_entries: u32,
+    /// Project ID (e.g., "owner/repo"). Omit for global context.
+    #[serde(default)]
+    pub project_id: Option<String>,
+    /// Who is writing, checked against the key's lease. Defaults to the
+    /// authenticated agent, or "anonymous".
+    #[serde(default)]
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ContextLeaseParams {
+    /// The key to lease.
+    pub key: String,
+    /// Lease duration in seconds (1-86400).
+    pub ttl_secs: u64,
+    /// Project ID (e.g., "owner/repo"). Omit for global context.
+    #[serde(default)]
+    pub project_id: Option<String>,
+    /// Lease holder. Defaults to the authenticated agent, or "anonymous".
+    #[serde(default)]
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ContextReleaseParams {
+    /// The leased key.
+    pub key: String,
+    /// Project ID (e.g., "owner/repo"). Omit for global context.
+    #[serde(default)]
+    pub project_id: Option<String>,
+    /// Lease holder. Defaults to the authenticated agent, or "anonymous".
+    #[serde(default)]
+    pub owner: Option<String>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
@@ -53,20 +202,354 @@ pub struct ContextListParams {
     pub project_id: Option<String>,
 }
 
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ContextSnapshotParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Optional label, e.g. "before-migration".
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ContextRestoreParams {
+    /// Snapshot ID returned by context_snapshot.
+    pub snapshot_id: i64,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ListContextSnapshotsParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ContextWatchParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Agent whose queue receives the change notices.
+    pub agent_id: String,
+    /// The key to watch, or the key prefix with prefix: true.
+    #[serde(default)]
+    pub key: String,
+    /// Watch every key starting with key (an empty key matches all keys).
+    /// Default: false.
+    #[serde(default)]
+    pub prefix: bool,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ListContextWatchesParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Only list this agent's watches.
+    #[serde(default)]
+    pub agent_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct RegisterMessageSchemaParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Schema name, referenced by send_message's schema parameter.
+    pub name: String,
+    /// JSON Schema document (max 65,536 bytes serialized).
+    pub schema: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct RegisterMessageTemplateParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Template name, referenced by send_from_template.
+    pub name: String,
+    /// Message content with {{name}} placeholders (max 65,536 bytes).
+    pub template: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ListMessageTemplatesParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct SendFromTemplateParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Name of a template registered with register_message_template.
+    pub template: String,
+    /// Values of the template's placeholders. Strings are inserted as they
+    /// are, other values as JSON.
+    #[serde(default)]
+    pub variables: serde_json::Map<String, serde_json::Value>,
+    /// Target agent ID to receive the message.
+    pub to_agent: String,
+    /// Sender agent ID. Defaults to the authenticated agent, or "anonymous"
+    /// without authentication.
+    #[serde(default)]
+    pub from_agent: Option<String>,
+    /// Reference to a previous message ID (for request/response linking).
+    #[serde(default)]
+    pub reference_id: Option<String>,
+    /// Agent whose queue replies should go to instead of the sender's.
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// Trace ID correlating a chain of messages.
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    /// "low", "normal" (default), "high" or "urgent".
+    #[serde(default)]
+    pub priority: Option<String>,
+    /// Seconds until an unread message is moved to the dead-letter queue.
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// JSON object returned with the message (max 4096 bytes serialized).
+    #[serde(default)]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Labels receivers can filter on (max 8, each max 64 bytes).
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    /// Classify the message confidential.
+    #[serde(default)]
+    pub confidential: bool,
+    /// Have a receipt queued back to from_agent when the message is first received.
+    #[serde(default)]
+    pub request_receipt: bool,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ListMessageSchemasParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct AttachParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Base64-encoded data (max 10,485,760 bytes decoded).
+    pub data: String,
+    /// MIME type, e.g. "image/png" or "application/gzip".
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BeginMessageParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct AppendChunkParams {
+    /// Upload ID returned by begin_message.
+    pub upload_id: String,
+    /// Next part of the content (max 1,048,576 bytes).
+    pub data: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct CommitMessageParams {
+    /// Upload ID returned by begin_message.
+    pub upload_id: String,
+    /// Target agent ID to receive the message.
+    pub to_agent: String,
+    /// Sender agent ID. Defaults to the authenticated agent, or "anonymous"
+    /// without authentication.
+    #[serde(default)]
+    pub from_agent: Option<String>,
+    /// Reference to a previous message ID (for request/response linking).
+    #[serde(default)]
+    pub reference_id: Option<String>,
+    /// Agent whose queue replies should go to instead of the sender's.
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// Trace ID correlating a chain of messages.
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    /// "low", "normal" (default), "high" or "urgent".
+    #[serde(default)]
+    pub priority: Option<String>,
+    /// Seconds until an unread message is moved to the dead-letter queue.
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// JSON object returned with the message (max 4096 bytes serialized).
+    #[serde(default)]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Labels receivers can filter on (max 8, each max 64 bytes).
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    /// Classify the message confidential.
+    #[serde(default)]
+    pub confidential: bool,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct GetAttachmentParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Attachment hash, as listed in a message's attachments.
+    pub hash: String,
+}
+
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
 pub struct SendMessageParams {
-    /// Project ID (e.g., "owner/repo"). Required, cannot be empty.
+    /// Project ID (e.g., "owner/repo"). Required unless project_ids is given.
+    #[serde(default)]
     pub project_id: String,
-    /// Target agent ID to receive the message. Required, cannot be empty.
+    /// Send a copy to to_agent in each of these projects instead of
+    /// project_id (max 100, duplicates ignored). schema and attachments must
+    /// exist in every project. Returns {"deliveries": [{"project_id",
+    /// "message_id"}]}.
+    #[serde(default)]
+    pub project_ids: Option<Vec<String>>,
+    /// Target agent ID to receive the message. Containing *, ? or [...], it
+    /// is a case-sensitive glob pattern such as "reviewer-*": a copy goes
+    /// atomically to every agent known in the project, except the sender,
+    /// whose ID matches, and {"deliveries": [{"agent_id", "message_id"}]} is
+    /// returned (empty if none matches). Required unless to_agents or
+    /// to_group is given, or reference_id is: replies then go to the
+    /// referenced message's reply_to, or its sender.
+    #[serde(default)]
     pub to_agent: String,
+    /// Send a copy to each of these agents instead of to_agent, atomically
+    /// (max 100, duplicates ignored). Returns {"deliveries": [{"agent_id",
+    /// "message_id"}]}.
+    #[serde(default)]
+    pub to_agents: Option<Vec<String>>,
+    /// Send to one member of this agent group (see create_group) instead of
+    /// to_agent. Returns {"agent_id", "message_id"}.
+    #[serde(default)]
+    pub to_group: Option<String>,
+    /// Message content (max 1,048,576 bytes).
+    pub content: String,
+    /// Sender agent ID. Defaults to the authenticated agent, or "anonymous"
+    /// without authentication, if not specified or empty.
+    #[serde(default)]
+    pub from_agent: Option<String>,
+    /// Reference to a previous message ID (for request/response linking).
+    #[serde(default)]
+    pub reference_id: Option<String>,
+    /// Agent whose queue replies should go to instead of the sender's, e.g. a
+    /// shared results queue.
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// Correlation ID for following a request across agents. Inherited from the
+    /// referenced message if omitted.
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    /// Delivery priority: "low", "normal" (default), "high", or "urgent".
+    /// Higher priorities are received first.
+    #[serde(default)]
+    pub priority: Option<String>,
+    /// Seconds after which the message is moved to the dead-letter queue if
+    /// still unread (max: 7776000, i.e. 90 days). Default: never expires.
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// Arbitrary JSON object (max 4,096 bytes serialized), e.g. routing hints
+    /// or model names, returned with the message.
+    #[serde(default)]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Name of a schema registered with register_message_schema. If given,
+    /// content must be a JSON document valid against it.
+    #[serde(default)]
+    pub schema: Option<String>,
+    /// Hashes of attachments uploaded with attach (max 16).
+    #[serde(default)]
+    pub attachments: Option<Vec<String>>,
+    /// Classify the message confidential: export_conversation skips it unless
+    /// include_confidential is set. Default: false.
+    #[serde(default)]
+    pub confidential: bool,
+    /// Routing tags (max 8, each max 64 bytes), for receivers to filter on.
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    /// Have a receipt queued back to from_agent when the message is first
+    /// received: a message referencing it, with metadata {"receipt": true}.
+    /// Default: false.
+    #[serde(default)]
+    pub request_receipt: bool,
+    /// Only check whether the message could be sent, and to which queues,
+    /// without sending it: validation, schema, attachment, queue quota and
+    /// rate limit checks run (failing like a real send), and {"dry_run": true,
+    /// "deliveries": [{"project_id", "agent_id"}]} lists the queues that
+    /// would receive a copy. Default: false.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BroadcastMessageParams {
+    /// Project ID (e.g., "owner/repo"). Required, cannot be empty.
+    pub project_id: String,
     /// Message content (max 1,048,576 bytes).
     pub content: String,
-    /// Sender agent ID. Defaults to "anonymous" if not specified or empty.
+    /// Sender agent ID. Defaults to the authenticated agent, or "anonymous"
+    /// without authentication, if not specified or empty.
+    /// The sender does not receive a copy.
     #[serde(default)]
     pub from_agent: Option<String>,
     /// Reference to a previous message ID (for request/response linking).
     #[serde(default)]
     pub reference_id: Option<String>,
+    /// Agent whose queue replies should go to instead of the sender's, e.g. a
+    /// shared results queue.
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// Correlation ID for following a request across agents. Inherited from the
+    /// referenced message if omitted.
+    #[serde(default)]
+    pub trace_id: Option<String>,
+    /// Delivery priority: "low", "normal" (default), "high", or "urgent".
+    /// Higher priorities are received first.
+    #[serde(default)]
+    pub priority: Option<String>,
+    /// Seconds after which the message is moved to the dead-letter queue if
+    /// still unread (max: 7776000, i.e. 90 days). Default: never expires.
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// Arbitrary JSON object (max 4,096 bytes serialized), e.g. routing hints
+    /// or model names, returned with the message.
+    #[serde(default)]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Hashes of attachments uploaded with attach (max 16).
+    #[serde(default)]
+    pub attachments: Option<Vec<String>>,
+    /// Classify the message confidential: export_conversation skips it unless
+    /// include_confidential is set. Default: false.
+    #[serde(default)]
+    pub confidential: bool,
+    /// Routing tags (max 8, each max 64 bytes), for receivers to filter on.
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+}
+
+/// Optional filters on the messages returned by receive and peek.
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct MessageFilterParams {
+    /// Only messages from this agent.
+    #[serde(default)]
+    pub from_agent: Option<String>,
+    /// Only replies to this message ID.
+    #[serde(default)]
+    pub reference_id: Option<String>,
+    /// Only messages created at or after this ISO 8601 UTC timestamp (e.g. "2025-01-08T12:00:00Z").
+    #[serde(default)]
+    pub since: Option<String>,
+    /// Only messages whose content contains this text (case-sensitive).
+    #[serde(default)]
+    pub content_contains: Option<String>,
+    /// Only messages whose metadata has all of these key/value pairs.
+    #[serde(default)]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Only messages with a sequence number (seq) greater than this, e.g. the
+    /// highest seq already processed.
+    #[serde(default)]
+    pub after_seq: Option<u64>,
+    /// Only messages carrying all of these tags.
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
@@ -78,205 +561,3474 @@ pub struct ReceiveMessagesParams {
     /// Maximum messages to receive (default: 100, max: 500).
     #[serde(default)]
     pub limit: Option<u32>,
+    #[serde(flatten)]
+    pub filter: MessageFilterParams,
+    /// "auto" (default: follow the queue's delivery mode) or "manual" (keep
+    /// messages in flight until acknowledged with ack_message).
+    #[serde(default)]
+    pub ack_mode: Option<String>,
+    /// Seconds messages received with ack_mode "manual" stay hidden before
+    /// redelivery (default: the queue's visibility timeout, max: 43200).
+    #[serde(default)]
+    pub visibility_timeout_secs: Option<u32>,
+    /// Name of the worker receiving, when several share agent_id as a group
+    /// queue (e.g. "worker-3").
+    #[serde(default)]
+    pub consumer: Option<String>,
+    /// Timestamp format: "utc" (default ISO 8601), "epoch_ms", or a fixed offset like "+02:00".
+    #[serde(default)]
+    pub timestamp_format: Option<String>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
-pub struct PeekMessagesParams {
+pub struct WaitForMessagesParams {
+    #[serde(flatten)]
+    pub receive: ReceiveMessagesParams,
+    /// Maximum seconds to wait for a message (default: 30, max: 300).
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct CollectRepliesParams {
     /// Project ID (e.g., "owner/repo").
     pub project_id: String,
-    /// Agent ID to peek messages for.
+    /// Agent ID whose queue the replies arrive in (usually the original sender).
     pub agent_id: String,
-    /// Maximum messages to peek (default: 100, max: 500).
+    /// Collect replies whose reference_id is one of these message IDs.
     #[serde(default)]
-    pub limit: Option<u32>,
+    pub message_ids: Option<Vec<String>>,
+    /// Collect replies carrying this trace ID instead.
+    #[serde(default)]
+    pub conversation_id: Option<String>,
+    /// Number of replies to wait for.
+    pub expected_count: u32,
+    /// Maximum seconds to wait (default: 30, max: 300).
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Timestamp format: "utc" (default ISO 8601), "epoch_ms", or a fixed offset like "+02:00".
+    #[serde(default)]
+    pub timestamp_format: Option<String>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
-pub struct DeleteMessageParams {
-    /// Message ID to delete (numeric string).
-    pub message_id: String,
+pub struct SendAndWaitReplyParams {
+    #[serde(flatten)]
+    pub message: SendMessageParams,
+    /// Maximum seconds to wait for the reply (default: 30, max: 300).
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Timestamp format: "utc" (default ISO 8601), "epoch_ms", or a fixed offset like "+02:00".
+    #[serde(default)]
+    pub timestamp_format: Option<String>,
 }
 
-// =============================================================================
-// Server implementation
-// =============================================================================
+/// A chat-transcript message in OpenAI/Anthropic format.
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ChatMessage {
+    /// "system", "user", or "assistant".
+    pub role: String,
+    /// Text content: a string, or an array of content blocks of which only
+    /// `{"type": "text", "text": "..."}` blocks are accepted.
+    pub content: serde_json::Value,
+}
 
-/// MCP server for agent-to-agent communication.
-#[derive(Clone)]
-pub struct MailboxServer {
-    db: Arc<Database>,
-    tool_router: ToolRouter<Self>,
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ExportConversationParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Trace ID of the thread to export.
+    pub conversation_id: String,
+    /// Agent whose messages become "assistant" turns; others become "user" turns.
+    pub agent_id: String,
+    /// Maximum messages to export (default: 100, max: 500).
+    #[serde(default)]
+    pub limit: Option<u32>,
+    /// Admin override: also export messages classified confidential.
+    #[serde(default)]
+    pub include_confidential: bool,
 }
 
-impl MailboxServer {
-    /// Creates a new server with the given database.
-    #[must_use]
-    pub fn new(db: Database) -> Self {
-        Self {
-            db: Arc::new(db),
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ImportConversationParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Trace ID given to the imported thread.
+    pub conversation_id: String,
+    /// Agent playing the "assistant" role; receives the user and system turns.
+    pub agent_id: String,
+    /// Agent playing the "user" role; receives the assistant turns (default: "user").
+    #[serde(default)]
+    pub counterpart: Option<String>,
+    /// Transcript to import, oldest first.
+    pub messages: Vec<ChatMessage>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ScheduleRecurringParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Agent to send the message to.
+    pub to_agent: String,
+    /// Message content (max 1,048,576 bytes).
+    pub content: String,
+    /// Five-field cron expression in UTC ("minute hour day-of-month month
+    /// day-of-week", e.g. "0 9 * * 1-5"), or @hourly, @daily, @weekly, @monthly.
+    pub cron_expr: String,
+    /// Sender agent ID. Defaults to "system".
+    #[serde(default)]
+    pub from_agent: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ListSchedulesParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct CancelScheduleParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Schedule ID returned by schedule_recurring.
+    pub schedule_id: i64,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct SetWatchdogParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Agent to watch.
+    pub agent_id: String,
+    /// Agent alerted when the watched agent goes quiet.
+    pub supervisor: String,
+    /// Seconds the agent may go without a heartbeat or receive (max: 604800).
+    pub interval_secs: u64,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct QueueParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Agent ID whose queue to pause or resume.
+    pub agent_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ClearWatchdogParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Watched agent.
+    pub agent_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct HeartbeatParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Agent reporting that it is alive.
+    pub agent_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct SetMilestoneParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Milestone name (e.g., "design-approved").
+    pub name: String,
+    /// Optional data for agents waiting on the milestone (max 65,536 bytes).
+    #[serde(default)]
+    pub payload: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct AwaitMilestoneParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Milestone name to wait for.
+    pub name: String,
+    /// Maximum seconds to wait (default: 30, max: 300).
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct PeekMessagesParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Agent ID to peek messages for.
+    pub agent_id: String,
+    /// Maximum messages to peek (default: 100, max: 500).
+    #[serde(default)]
+    pub limit: Option<u32>,
+    /// Continue after the previous page: its next_cursor.
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(flatten)]
+    pub filter: MessageFilterParams,
+    /// Timestamp format: "utc" (default ISO 8601), "epoch_ms", or a fixed offset like "+02:00".
+    #[serde(default)]
+    pub timestamp_format: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct GetThreadParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Any message ID in the conversation (numeric string).
+    pub message_id: String,
+    /// Maximum messages to return (default: 100, max: 500).
+    #[serde(default)]
+    pub limit: Option<u32>,
+    /// Timestamp format: "utc" (default ISO 8601), "epoch_ms", or a fixed offset like "+02:00".
+    #[serde(default)]
+    pub timestamp_format: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct GetMessageParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Message ID (numeric string), e.g. a reference_id.
+    pub message_id: String,
+    /// Timestamp format: "utc" (default ISO 8601), "epoch_ms", or a fixed offset like "+02:00".
+    #[serde(default)]
+    pub timestamp_format: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct SearchMessagesParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Full-text query, e.g. "deploy plan" or "\"release plan\" OR roadmap".
+    pub query: String,
+    /// Only messages addressed to this agent (default: all agents, or the
+    /// authenticated agent without the admin role).
+    #[serde(default)]
+    pub agent_id: Option<String>,
+    #[serde(flatten)]
+    pub filter: MessageFilterParams,
+    /// Also search messages already consumed (requires --archive-days).
+    #[serde(default)]
+    pub include_archived: bool,
+    /// Maximum messages to return (default: 100, max: 500).
+    #[serde(default)]
+    pub limit: Option<u32>,
+    /// Timestamp format: "utc" (default ISO 8601), "epoch_ms", or a fixed offset like "+02:00".
+    #[serde(default)]
+    pub timestamp_format: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct QueueHistoryParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Agent ID whose queue to report on.
+    pub agent_id: String,
+    /// Window in minutes (default: 60, max: 10080 = 7 days).
+    #[serde(default)]
+    pub window_minutes: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct QueueStatsParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct InboxOverviewParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Agent ID whose queue to summarize.
+    pub agent_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct QueueVersionParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Agent ID whose queue to check.
+    pub agent_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct DeleteMessageParams {
+    /// Message ID to delete (numeric string).
+    pub message_id: String,
+    /// Only report whether the message would be deleted. Default: false.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct DeleteMessagesParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Agent ID whose queue to delete from.
+    pub agent_id: String,
+    #[serde(flatten)]
+    pub filter: MessageFilterParams,
+    /// Only messages created more than this many seconds ago.
+    #[serde(default)]
+    pub older_than_secs: Option<u64>,
+    /// Only report how many messages would be deleted. Default: false.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct RecallMessageParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Message ID to recall (numeric string), as returned by send_message.
+    pub message_id: String,
+    /// Sender agent ID; must match the message's sender. Defaults to the
+    /// authenticated agent, or "anonymous" without authentication.
+    #[serde(default)]
+    pub from_agent: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct PinMessageParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// ID of a pending message (numeric string).
+    pub message_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct SnoozeMessageParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// ID of a pending message (numeric string).
+    pub message_id: String,
+    /// When the message becomes receivable again, as an ISO 8601 UTC timestamp
+    /// (e.g., "2025-01-08T12:00:00Z"); a past time wakes it up right away.
+    pub until: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ListPinnedParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Only messages pinned in this agent's queue (default: all queues, or
+    /// the authenticated agent's without the admin role).
+    #[serde(default)]
+    pub agent_id: Option<String>,
+    /// Maximum messages to return (default: 100, max: 500).
+    #[serde(default)]
+    pub limit: Option<u32>,
+    /// Timestamp format: "utc" (default ISO 8601), "epoch_ms", or a fixed offset like "+02:00".
+    #[serde(default)]
+    pub timestamp_format: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct AckMessageParams {
+    /// Message ID to acknowledge (numeric string).
+    pub message_id: String,
+    /// Group queue worker that received the message; if given, the message
+    /// is only acknowledged while it is still in flight to this worker.
+    #[serde(default)]
+    pub consumer: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct NackMessageParams {
+    /// Message ID to hand back (numeric string).
+    pub message_id: String,
+    /// Seconds before the message is delivered again (default: 0, max: 43200).
+    #[serde(default)]
+    pub delay_secs: Option<u32>,
+    /// Group queue worker that received the message; if given, the message
+    /// is only handed back while it is still in flight to this worker.
+    #[serde(default)]
+    pub consumer: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct AnnotateMessageParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Message ID to annotate (numeric string).
+    pub message_id: String,
+    /// Agent attaching the annotation (usually the receiver). Defaults to the
+    /// authenticated agent; required without authentication.
+    #[serde(default)]
+    pub agent_id: Option<String>,
+    /// Marker such as "ack", "rejected", "needs-clarification", or an emoji (max 64 bytes).
+    pub annotation: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct GetAnnotationsParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Message IDs whose annotations to return.
+    pub message_ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct MessageStatusParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// IDs of the messages to check.
+    pub message_ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct LinkMessagesParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Source message ID (numeric string).
+    pub message_id: String,
+    /// Target message ID (numeric string).
+    pub related_id: String,
+    /// Link type, read as "message_id <kind> related_id": "duplicates",
+    /// "blocks", or "follows_from".
+    pub kind: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct GetRelatedParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Message ID whose links to return (numeric string).
+    pub message_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct SetDeliveryModeParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Agent ID whose queue to configure.
+    pub agent_id: String,
+    /// "at_most_once" (delete on receive, the default) or "at_least_once" (delete on ack).
+    pub mode: String,
+    /// Seconds a received message stays hidden awaiting ack before redelivery
+    /// (default: 300, max: 43200). Only used in at_least_once mode.
+    #[serde(default)]
+    pub visibility_timeout_secs: Option<u32>,
+    /// Deliveries without ack after which a message is moved to the
+    /// dead-letter queue (default: 5).
+    #[serde(default)]
+    pub max_delivery_attempts: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct CreateGroupParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Group ID to send to with to_group.
+    pub group_id: String,
+    /// "round_robin" (default: each member in turn) or "least_loaded" (the
+    /// member with the fewest queued messages).
+    #[serde(default)]
+    pub strategy: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct GroupMemberParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Group ID.
+    pub group_id: String,
+    /// Member agent ID.
+    pub agent_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct PeekDeadLettersParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Only dead letters addressed to this agent (default: all agents, or the
+    /// authenticated agent without the admin role).
+    #[serde(default)]
+    pub agent_id: Option<String>,
+    /// Maximum messages to return (default: 100, max: 500).
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct RequeueDeadLetterParams {
+    /// Message ID to requeue (numeric string).
+    pub message_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct RestoreMessagesParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Agent whose consumed messages to put back.
+    pub agent_id: String,
+    /// Message IDs to restore. Default: every message consumed in the last
+    /// within_secs seconds.
+    #[serde(default)]
+    pub message_ids: Option<Vec<String>>,
+    /// How far back to restore when message_ids is omitted (default: 300).
+    #[serde(default)]
+    pub within_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct CreateEphemeralProjectParams {
+    /// Project ID (e.g., "owner/repo#123" or "ci/run-4567").
+    pub project_id: String,
+    /// Seconds until the project's data is purged (max: 7776000 = 90 days).
+    pub ttl_secs: u64,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct CloneProjectParams {
+    /// Project to copy from.
+    pub src: String,
+    /// New project to create; must not hold any data yet.
+    pub dst: String,
+    /// What to copy: "context" (shared context keys) and/or "agents" (queue
+    /// settings such as delivery modes). Default: both.
+    #[serde(default)]
+    pub include: Option<Vec<String>>,
+    /// Admin override: also copy context keys classified confidential.
+    #[serde(default)]
+    pub include_confidential: bool,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ListExpiredProjectsParams {
+    /// Maximum summaries to return (default: 50, max: 500).
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct EventsSinceParams {
+    /// Project ID (e.g., "owner/repo").
+    pub project_id: String,
+    /// Return events after this position: next_seq from the previous call
+    /// (default: 0, from the start of the feed).
+    #[serde(default)]
+    pub after_seq: u64,
+    /// Maximum events to return (default: 100, max: 500).
+    #[serde(default)]
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct DisconnectSessionParams {
+    /// Session ID to terminate (from list_sessions).
+    pub session_id: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct CreateApiTokenParams {
+    /// Agent identity of callers using the token.
+    pub agent_id: String,
+    /// Optional label, e.g. the deployment using the token.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Roles granted, e.g. ["admin"] (default: none).
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Tools callers using the token may call, e.g. ["context_get",
+    /// "context_set"] (default: all tools the server exposes).
+    #[serde(default)]
+    pub tools: Option<Vec<String>>,
+    /// Seconds until the token expires (default: never; max: 1 year).
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct RotateApiTokenParams {
+    /// Token to replace.
+    pub token_id: String,
+    /// Seconds the old token stays valid alongside the new one (default: 3600).
+    #[serde(default)]
+    pub overlap_secs: Option<u64>,
+    /// Seconds until the new token expires (default: never; max: 1 year).
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ExpireApiTokenParams {
+    /// Token to expire.
+    pub token_id: String,
+    /// Seconds until it expires (default: 0, immediately).
+    #[serde(default)]
+    pub in_secs: u64,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ListApiTokensParams {
+    /// Only list this agent's tokens.
+    #[serde(default)]
+    pub agent_id: Option<String>,
+}
+
+// =============================================================================
+// Server implementation
+// =============================================================================
+
+/// Thresholds at which the server asks clients to slow down.
+#[derive(Debug, Clone)]
+pub struct BackpressureConfig {
+    /// Recipient queue depth at or above which senders receive a throttle hint.
+    pub max_queue_depth: u64,
+    /// Lock wait at or above which any caller receives a throttle hint.
+    pub max_lock_wait: Duration,
+    /// Suggested delay before the client's next call.
+    pub retry_after: Duration,
+}
+
+impl Default for BackpressureConfig {
+    fn default() -> Self {
+        Self {
+            max_queue_depth: 1000,
+            max_lock_wait: Duration::from_millis(250),
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Structured hint telling well-behaved clients to back off.
+#[derive(Debug, Serialize)]
+struct ThrottleHint {
+    /// Why the hint was issued: `queue_depth` or `lock_contention`.
+    reason: &'static str,
+    /// Suggested delay before the next call, in milliseconds.
+    retry_after_ms: u128,
+    /// Current depth of the affected queue, if relevant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_depth: Option<u64>,
+}
+
+/// MCP server for agent-to-agent communication.
+#[derive(Clone)]
+pub struct MailboxServer {
+    db: Arc<Database>,
+    backpressure: BackpressureConfig,
+    /// Limits how fast each sender may send; unlimited if absent.
+    send_rate_limiter: Option<SendRateLimiter>,
+    /// Operational warnings surfaced to clients via server info.
+    warnings: Vec<String>,
+    started_at: Instant,
+    /// Default rendering of timestamps in responses.
+    timestamp_format: TimestampFormat,
+    sessions: Option<SessionRegistry>,
+    /// Applied to the content of messages sent through the tools.
+    transforms: Pipeline,
+    tool_router: ToolRouter<Self>,
+}
+
+impl MailboxServer {
+    /// Creates a new server with the given database.
+    #[must_use]
+    pub fn new(db: Database) -> Self {
+        Self {
+            db: Arc::new(db),
+            backpressure: BackpressureConfig::default(),
+            send_rate_limiter: None,
+            warnings: Vec::new(),
+            started_at: Instant::now(),
+            timestamp_format: TimestampFormat::default(),
+            sessions: None,
+            transforms: Pipeline::default(),
             tool_router: Self::tool_router(),
         }
     }
-}
 
-fn json_response(value: &serde_json::Value) -> CallToolResult {
-    CallToolResult::success(vec![Content::text(value.to_string())])
-}
+    /// Sets the default timestamp format used in responses.
+    #[must_use]
+    pub const fn with_timestamp_format(mut self, format: TimestampFormat) -> Self {
+        self.timestamp_format = format;
+        self
+    }
+
+    /// Resolves a per-call timestamp format, falling back to the server default.
+    fn timestamp_format(&self, requested: Option<&str>) -> Result<TimestampFormat, McpError> {
+        requested.map_or(Ok(self.timestamp_format), |format| {
+            format
+                .parse()
+                .map_err(|e: String| McpError::invalid_params(e, None))
+        })
+    }
+
+    /// Builds a `{"messages": [...]}` response with timestamps rendered in the
+    /// requested format, a summary of messages still queued (`remaining`,
+    /// `remaining_range`) if given, and a throttle hint if the server is contended.
+    fn messages_response(
+        &self,
+        messages: &[Message],
+        remainder: Option<&QueueRemainder>,
+        next_cursor: Option<QueueCursor>,
+        timestamp_format: Option<&str>,
+    ) -> Result<CallToolResult, McpError> {
+        let format = self.timestamp_format(timestamp_format)?;
+        let mut response = json!({ "messages": render_messages(messages, format) });
+        if let Some(cursor) = next_cursor {
+            response["next_cursor"] = json!(cursor.to_string());
+        }
+        if let Some(remainder) = remainder {
+            response["remaining"] = json!(remainder.count);
+            response["remaining_range"] = json!({
+                "oldest_created_at": format.render(&remainder.oldest_created_at),
+                "newest_created_at": format.render(&remainder.newest_created_at),
+            });
+        }
+        if let Some(hint) = self.throttle_hint(None) {
+            response["throttle"] = json!(hint);
+        }
+        Ok(json_response(&response))
+    }
+
+    /// Receives messages for `receive_messages` and `wait_for_messages`.
+    fn receive(&self, params: &ReceiveMessagesParams) -> Result<CallToolResult, McpError> {
+        let settings = match params.ack_mode.as_deref() {
+            None | Some("auto") => None,
+            Some("manual") => {
+                let settings = self
+                    .db
+                    .queue_settings(&params.project_id, &params.agent_id)
+                    .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+                Some(QueueSettings {
+                    delivery_mode: DeliveryMode::AtLeastOnce,
+                    visibility_timeout_secs: params
+                        .visibility_timeout_secs
+                        .unwrap_or(settings.visibility_timeout_secs),
+                    ..settings
+                })
+            }
+            Some(other) => {
+                return Err(McpError::invalid_params(
+                    format!("Invalid ack_mode '{other}' (expected auto or manual)"),
+                    None,
+                ))
+            }
+        };
+        let filter = message_filter(&params.filter)?;
+        let paused = self
+            .db
+            .queue_paused(&params.project_id, &params.agent_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        if paused {
+            return Ok(json_response(&json!({ "messages": [], "paused": true })));
+        }
+        let messages = self
+            .db
+            .receive_as(
+                &params.project_id,
+                &params.agent_id,
+                params.consumer.as_deref(),
+                &filter,
+                params.limit,
+                settings,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        for message in &messages {
+            tracing::debug!(
+                message_id = %message.id,
+                trace_id = message.trace_id.as_deref(),
+                agent_id = %params.agent_id,
+                "Message delivered"
+            );
+        }
+        let remainder = self
+            .db
+            .queue_remainder(&params.project_id, &params.agent_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        self.messages_response(
+            &messages,
+            remainder.as_ref(),
+            None,
+            params.timestamp_format.as_deref(),
+        )
+    }
+
+    /// Enables session tracking and the admin session tools.
+    #[must_use]
+    pub fn with_sessions(mut self, sessions: SessionRegistry) -> Self {
+        self.sessions = Some(sessions);
+        self
+    }
+
+    /// Applies the enabled list, tool aliases and hidden tools from the configuration.
+    ///
+    /// Disabled tools are removed from the router entirely, so they can neither
+    /// be listed nor called, under their own name or an alias.
+    ///
+    /// # Errors
+    /// - `UnknownTool` if an enabled tool, alias target or hidden tool doesn't exist
+    /// - `AliasCollision` if an alias reuses the name of an existing tool
+    /// - `AliasOfDisabledTool` if an alias points to a hidden or disabled tool
+    pub fn with_tool_config(mut self, config: &ToolsConfig) -> Result<Self, ConfigError> {
+        self.tool_router = Self::configured_router(self.tool_router, config)?;
+        Ok(self)
+    }
+
+    /// Returns the definitions of the tools a server with this configuration
+    /// exposes, sorted by name.
+    ///
+    /// # Errors
+    /// Same as [`with_tool_config`](Self::with_tool_config).
+    pub fn tool_definitions(config: &ToolsConfig) -> Result<Vec<Tool>, ConfigError> {
+        let mut tools = Self::configured_router(Self::tool_router(), config)?.list_all();
+        tools.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(tools)
+    }
+
+    fn configured_router(
+        mut router: ToolRouter<Self>,
+        config: &ToolsConfig,
+    ) -> Result<ToolRouter<Self>, ConfigError> {
+        let canonical = router.clone();
+        for (alias, target) in &config.aliases {
+            let Some(route) = canonical.map.get(target.as_str()) else {
+                return Err(ConfigError::UnknownTool {
+                    name: target.clone(),
+                    section: "tools.aliases",
+                });
+            };
+            if router.has_route(alias) {
+                return Err(ConfigError::AliasCollision {
+                    alias: alias.clone(),
+                });
+            }
+            let disabled_by = if config.hidden.contains(target) {
+                Some("tools.hidden")
+            } else if config
+                .enabled
+                .as_ref()
+                .is_some_and(|enabled| !enabled.contains(target))
+            {
+                Some("tools.enabled")
+            } else {
+                None
+            };
+            if let Some(section) = disabled_by {
+                return Err(ConfigError::AliasOfDisabledTool {
+                    alias: alias.clone(),
+                    target: target.clone(),
+                    section,
+                });
+            }
+            let mut route = route.clone();
+            route.attr.name = alias.clone().into();
+            router.add_route(route);
+        }
+        for name in &config.hidden {
+            if !canonical.has_route(name) {
+                return Err(ConfigError::UnknownTool {
+                    name: name.clone(),
+                    section: "tools.hidden",
+                });
+            }
+            router.remove_route(name);
+        }
+        if let Some(enabled) = &config.enabled {
+            if let Some(name) = enabled.iter().find(|name| !canonical.has_route(name)) {
+                return Err(ConfigError::UnknownTool {
+                    name: name.clone(),
+                    section: "tools.enabled",
+                });
+            }
+            for name in canonical.map.keys() {
+                if !enabled.iter().any(|e| e == name) {
+                    router.remove_route(name);
+                }
+            }
+        }
+        Ok(router)
+    }
+
+    fn session_registry(&self) -> Result<&SessionRegistry, McpError> {
+        self.sessions
+            .as_ref()
+            .ok_or_else(|| McpError::invalid_request("Session tracking is not enabled", None))
+    }
+
+    /// Records session activity for a tool call, binding the agent identity
+    /// found in its arguments (`agent_id` or `from_agent`).
+    fn record_session_activity(
+        &self,
+        request: &CallToolRequestParam,
+        context: &RequestContext<RoleServer>,
+    ) {
+        let Some(sessions) = &self.sessions else {
+            return;
+        };
+        let Some(session_id) = session_id(&context.extensions) else {
+            return;
+        };
+        let agent_id = identity(&context.extensions)
+            .map(|identity| identity.agent_id.as_str())
+            .or_else(|| {
+                request.arguments.as_ref().and_then(|args| {
+                    ["agent_id", "from_agent"]
+                        .iter()
+                        .find_map(|key| args.get(*key).and_then(serde_json::Value::as_str))
+                })
+            });
+        let client = context.peer.peer_info().map(|info| {
+            (
+                info.client_info.name.as_str(),
+                info.client_info.version.as_str(),
+            )
+        });
+        sessions.record_activity(&session_id, client, agent_id);
+    }
+
+    /// Adds an operational warning that is included in the server instructions.
+    #[must_use]
+    pub fn with_warning(mut self, warning: impl Into<String>) -> Self {
+        self.warnings.push(warning.into());
+        self
+    }
+
+    /// Limits how fast each sender may send messages within a project.
+    #[must_use]
+    pub fn with_send_rate_limiter(mut self, limiter: SendRateLimiter) -> Self {
+        self.send_rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Sets the pipeline applied to the content of sent messages.
+    #[must_use]
+    pub fn with_transforms(mut self, transforms: Pipeline) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    /// Sets the thresholds used to issue throttle hints.
+    #[must_use]
+    pub fn with_backpressure(mut self, backpressure: BackpressureConfig) -> Self {
+        self.backpressure = backpressure;
+        self
+    }
+
+    fn instructions(&self) -> String {
+        let mut instructions = "Mailbox MCP server for agent-to-agent communication".to_string();
+        for warning in &self.warnings {
+            instructions.push_str("\n\nWARNING: ");
+            instructions.push_str(warning);
+        }
+        instructions
+    }
+
+    /// Computes a throttle hint from lock contention and, for sends, the recipient's queue depth.
+    fn throttle_hint(&self, queue_depth: Option<u64>) -> Option<ThrottleHint> {
+        let retry_after_ms = self.backpressure.retry_after.as_millis();
+        if let Some(depth) = queue_depth.filter(|d| *d >= self.backpressure.max_queue_depth) {
+            return Some(ThrottleHint {
+                reason: "queue_depth",
+                retry_after_ms,
+                queue_depth: Some(depth),
+            });
+        }
+        (self.db.last_lock_wait() >= self.backpressure.max_lock_wait).then_some(ThrottleHint {
+            reason: "lock_contention",
+            retry_after_ms,
+            queue_depth,
+        })
+    }
+
+    /// Fails if an authenticated caller settles a message queued for another
+    /// agent without the admin role (see [`require_agent`]).
+    fn require_recipient(&self, extensions: &Extensions, message_id: &str) -> Result<(), McpError> {
+        if identity(extensions).is_none() {
+            return Ok(());
+        }
+        let recipient = self
+            .db
+            .message_recipient(message_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        match recipient {
+            Some(agent_id) => require_agent(extensions, &agent_id),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails with an invalid request error carrying a `rate_limited`
+    /// throttle hint if the sender has used up its rate limit. Nothing is
+    /// taken: successful sends are charged with
+    /// [`charge_send_rate`](Self::charge_send_rate).
+    fn check_send_rate(&self, project_id: &str, from_agent: &str) -> Result<(), McpError> {
+        let Some(limiter) = &self.send_rate_limiter else {
+            return Ok(());
+        };
+        limiter.check(project_id, from_agent).map_err(|retry_after| {
+            McpError::invalid_request(
+                format!(
+                    "Rate limit exceeded for sender '{from_agent}' in project '{project_id}'; retry in {} ms",
+                    retry_after.as_millis()
+                ),
+                Some(json!(ThrottleHint {
+                    reason: "rate_limited",
+                    retry_after_ms: retry_after.as_millis(),
+                    queue_depth: None,
+                })),
+            )
+        })
+    }
+
+    /// Charges the sender's rate limit for `copies` messages it sent.
+    fn charge_send_rate(&self, project_id: &str, from_agent: &str, copies: usize) {
+        if let Some(limiter) = &self.send_rate_limiter {
+            limiter.charge(project_id, from_agent, copies);
+        }
+    }
+
+    /// Returns the agent a single-recipient send goes to: `to_agent`, or for
+    /// a reply without one, the referenced message's reply address.
+    fn recipient<'a>(&self, params: &'a SendMessageParams) -> Result<Cow<'a, str>, McpError> {
+        let reference_id = match &params.reference_id {
+            Some(reference_id)
+                if params.to_agent.trim().is_empty()
+                    && params.to_agents.is_none()
+                    && params.to_group.is_none() =>
+            {
+                reference_id
+            }
+            _ => return Ok(Cow::Borrowed(&params.to_agent)),
+        };
+        self.db
+            .reply_address(&params.project_id, reference_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?
+            .map(Cow::Owned)
+            .ok_or_else(|| {
+                McpError::invalid_params(
+                    format!("to_agent is required: message {reference_id} is no longer stored, so its reply address is unknown"),
+                    None,
+                )
+            })
+    }
+
+    /// Converts a send failure into a tool error. A full recipient queue is
+    /// reported as an invalid request carrying a `queue_full` throttle hint,
+    /// so senders can tell it apart from server faults and back off.
+    fn send_error(&self, e: DbError) -> McpError {
+        match e {
+            DbError::QueueFull { depth, .. } => McpError::invalid_request(
+                e.to_string(),
+                Some(json!(ThrottleHint {
+                    reason: "queue_full",
+                    retry_after_ms: self.backpressure.retry_after.as_millis(),
+                    queue_depth: Some(depth),
+                })),
+            ),
+            e => McpError::internal_error(e.to_string(), None),
+        }
+    }
+}
+
+/// Extracts the streamable-HTTP session ID from the request extensions, if any.
+fn session_id(extensions: &Extensions) -> Option<String> {
+    extensions
+        .get::<Parts>()
+        .and_then(|parts| parts.headers.get(SESSION_ID_HEADER))
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Returns the caller verified by bearer-token authentication, if enabled.
+fn identity(extensions: &Extensions) -> Option<&Identity> {
+    extensions
+        .get::<Parts>()
+        .and_then(|parts| parts.extensions.get::<Identity>())
+}
+
+/// Fails unless the caller may use admin tools and overrides: with
+/// authentication enabled, only callers with the admin role can.
+fn require_admin(extensions: &Extensions) -> Result<(), McpError> {
+    match identity(extensions) {
+        Some(identity) if !identity.has_role(ADMIN_ROLE) => Err(McpError::invalid_request(
+            format!("The '{ADMIN_ROLE}' role is required"),
+            None,
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Fails if an authenticated caller acts as another agent without the admin
+/// role. Without authentication, callers may act as any agent.
+fn require_agent(extensions: &Extensions, agent_id: &str) -> Result<(), McpError> {
+    match identity(extensions) {
+        Some(identity) if !identity.may_act_as(agent_id) => Err(McpError::invalid_request(
+            format!(
+                "Authenticated as '{}'; acting as '{}' requires the '{ADMIN_ROLE}' role",
+                identity.agent_id,
+                agent_id.trim()
+            ),
+            None,
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the queue a read is limited to: `agent_id` if given (an
+/// authenticated caller may only name its own without the admin role), else
+/// the authenticated caller's, or none (all queues) for admins and without
+/// authentication.
+fn readable_queue<'a>(
+    agent_id: Option<&'a str>,
+    extensions: &'a Extensions,
+) -> Result<Option<&'a str>, McpError> {
+    if let Some(agent_id) = agent_id {
+        require_agent(extensions, agent_id)?;
+        return Ok(Some(agent_id));
+    }
+    Ok(identity(extensions)
+        .filter(|identity| !identity.has_role(ADMIN_ROLE))
+        .map(|identity| identity.agent_id.as_str()))
+}
+
+/// Returns `true` if the caller may read a message: without authentication,
+/// with the admin role, or if it sent or received the message.
+fn may_read(extensions: &Extensions, message: &ThreadMessage) -> bool {
+    identity(extensions).is_none_or(|identity| {
+        identity.may_act_as(&message.to_agent) || identity.may_act_as(&message.message.from_agent)
+    })
+}
+
+/// Resolves the sender of a message: `from_agent` if given (trimmed), else the
+/// authenticated caller, else "anonymous". An authenticated caller may only
+/// name another agent with the admin role.
+fn sender<'a>(
+    from_agent: Option<&'a str>,
+    extensions: &'a Extensions,
+) -> Result<&'a str, McpError> {
+    let from_agent = from_agent.map(str::trim).filter(|s| !s.is_empty());
+    if let Some(from_agent) = from_agent {
+        require_agent(extensions, from_agent)?;
+    }
+    Ok(from_agent
+        .or_else(|| identity(extensions).map(|identity| identity.agent_id.as_str()))
+        .unwrap_or("anonymous"))
+}
+
+/// Parses an optional priority parameter, defaulting to normal.
+fn parse_priority(priority: Option<&str>) -> Result<Priority, McpError> {
+    priority.map_or(Ok(Priority::Normal), |priority| {
+        priority
+            .parse()
+            .map_err(|e: String| McpError::invalid_params(e, None))
+    })
+}
+
+/// Extracts the text of a chat message's content: a plain string, or the
+/// concatenated text of an array of text blocks.
+fn chat_text(content: &serde_json::Value) -> Result<String, McpError> {
+    match content {
+        serde_json::Value::String(text) => Ok(text.clone()),
+        serde_json::Value::Array(blocks) => blocks
+            .iter()
+            .map(
+                |block| match (block["type"].as_str(), block["text"].as_str()) {
+                    (Some("text"), Some(text)) => Ok(text),
+                    _ => Err(McpError::invalid_params(
+                        "Only text content blocks can be imported",
+                        None,
+                    )),
+                },
+            )
+            .collect::<Result<Vec<_>, _>>()
+            .map(|texts| texts.join("\n")),
+        _ => Err(McpError::invalid_params(
+            "Message content must be a string or an array of content blocks",
+            None,
+        )),
+    }
+}
+
+/// Converts receive/peek filter parameters to a message filter.
+fn message_filter(params: &MessageFilterParams) -> Result<MessageFilter<'_>, McpError> {
+    let since = match &params.since {
+        Some(since) => {
+            let parsed = time::UtcDateTime::parse_iso8601(since.trim()).ok_or_else(|| {
+                McpError::invalid_params(
+                    format!("Invalid since '{since}' (expected an ISO 8601 UTC timestamp like 2025-01-08T12:00:00Z)"),
+                    None,
+                )
+            })?;
+            Some(parsed.to_unix_seconds())
+        }
+        None => None,
+    };
+    Ok(MessageFilter {
+        reference_ids: params.reference_id.as_ref().map(std::slice::from_ref),
+        from_agent: params.from_agent.as_deref(),
+        since,
+        content_contains: params.content_contains.as_deref(),
+        metadata: params.metadata.as_ref(),
+        after_seq: params.after_seq,
+        tags: params.tags.as_deref(),
+        ..MessageFilter::default()
+    })
+}
+
+/// Returns when a waiting tool given `timeout_secs` should give up.
+fn wait_deadline(timeout_secs: Option<u64>) -> tokio::time::Instant {
+    let timeout = timeout_secs
+        .unwrap_or(DEFAULT_WAIT_TIMEOUT_SECS)
+        .min(MAX_WAIT_TIMEOUT_SECS);
+    tokio::time::Instant::now() + Duration::from_secs(timeout)
+}
+
+/// Serializes messages with their timestamps rendered in the given format.
+fn render_messages<T: Serialize>(messages: &[T], format: TimestampFormat) -> serde_json::Value {
+    let mut messages = json!(messages);
+    if format != TimestampFormat::Utc {
+        for message in messages.as_array_mut().into_iter().flatten() {
+            for field in TIMESTAMP_FIELDS {
+                if let Some(value) = message.get_mut(*field) {
+                    if let Some(stored) = value.as_str() {
+                        *value = format.render(stored);
+                    }
+                }
+            }
+        }
+    }
+    messages
+}
+
+fn json_response(value: &serde_json::Value) -> CallToolResult {
+    CallToolResult::success(vec![Content::text(value.to_string())])
+}
+
+#[tool_router]
+impl MailboxServer {
+    /// Set a context value.
+    #[tool(
+        description = "Set a context value. Omit project_id for global context. confidential: true classifies the key confidential, so clone_project skips it unless include_confidential is set; each set replaces the classification. Optional ttl_secs (max 90 days) makes the value ephemeral, e.g. a claim or heartbeat: after that many seconds the key reads as absent and is later deleted; each set without ttl_secs makes the key permanent again. Returns {\"ok\": true}. With dry_run: true, runs every check without storing the value and returns {\"ok\": true, \"dry_run\": true}. Errors: EmptyField if key is empty, ContentTooLarge if value > 65536 bytes, Conflict if the key is leased to another owner (see context_lease)."
+    )]
+    async fn context_set(
+        &self,
+        Parameters(params): Parameters<ContextSetParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        self.db
+            .context_set(
+                params.project_id.as_deref(),
+                &params.key,
+                &params.value,
+                params.confidential,
+                params.ttl_secs,
+                Some(sender(params.owner.as_deref(), &extensions)?),
+                params.dry_run,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        if params.dry_run {
+            return Ok(json_response(&json!({ "ok": true, "dry_run": true })));
+        }
+        Ok(json_response(&json!({ "ok": true })))
+    }
+
+    /// Set several context values atomically.
+    #[tool(
+        description = "Set several context values in one all-or-nothing transaction, e.g. a plan, its status and its owner, so readers never see some updated and others not. Omit project_id for global context. entries maps keys to values; confidential and ttl_secs (as for context_set) apply to every key. If any key fails (empty key, oversized value, or leased to another owner), nothing is stored. Returns {\"ok\": true, \"keys\": count}. With dry_run: true, runs every check without storing anything and adds \"dry_run\": true. Errors: EmptyField if entries or a key is empty, TooManyKeys if more than 100 entries, ContentTooLarge if a value > 65536 bytes, Conflict if a key is leased to another owner (see context_lease)."
+    )]
+    async fn context_set_many(
+        &self,
+        Parameters(params): Parameters<ContextSetManyParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let entries: Vec<(&str, &str)> = params
+            .entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        self.db
+            .context_set_many(
+                params.project_id.as_deref(),
+                &entries,
+                params.confidential,
+                params.ttl_secs,
+                Some(sender(params.owner.as_deref(), &extensions)?),
+                params.dry_run,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        if params.dry_run {
+            return Ok(json_response(
+                &json!({ "ok": true, "keys": entries.len(), "dry_run": true }),
+            ));
+        }
+        Ok(json_response(&json!({ "ok": true, "keys": entries.len() })))
+    }
+
+    /// Get a context value.
+    #[tool(
+        description = "Get a context value. Omit project_id for global context. Returns {\"found\": true, \"value\": \"...\"} or {\"found\": false}."
+    )]
+    async fn context_get(
+        &self,
+        Parameters(params): Parameters<ContextGetParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let value = self
+            .db
+            .context_get(params.project_id.as_deref(), &params.key)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+
+        #[allow(clippy::option_if_let_else)] // match is clearer here
+        let response = match value {
+            Some(v) => json!({ "found": true, "value": v }),
+            None => json!({ "found": false }),
+        };
+        Ok(json_response(&response))
+    }
+
+    /// Get several context values at once.
+    #[tool(
+        description = "Get several context values in one call, e.g. to load an agent's working state at session start instead of calling context_get for each key. Omit project_id for global context. The values are read together, so they are consistent with each other. Returns {\"values\": {key: value, ...}, \"missing\": [keys that don't exist]}. Errors: TooManyKeys if more than 100 keys are given."
+    )]
+    async fn context_get_many(
+        &self,
+        Parameters(params): Parameters<ContextGetManyParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let values = self
+            .db
+            .context_get_many(params.project_id.as_deref(), &params.keys)
+            .map_err(|e| McpError::invalid_params(e.to_string(), None))?;
+
+        let mut found = serde_json::Map::new();
+        let mut missing = Vec::new();
+        for (key, value) in params.keys.iter().zip(values) {
+            match value {
+                Some(value) => {
+                    found.insert(key.clone(), json!(value));
+                }
+                None => missing.push(key),
+            }
+        }
+        Ok(json_response(
+            &json!({ "values": found, "missing": missing }),
+        ))
+    }
+
+    /// Show who changed a context key, and when.
+    #[tool(
+        description = "Get the history of a context key, newest first: every value it has held (including the current one) with the author who wrote it and when, and deletions as a null value. Authors are the owner given to context_set/context_set_many/context_append/context_delete (by default the authenticated agent, or \"anonymous\"). The last 50 versions of each key are kept. Omit project_id for global context. Default limit: 20, max: 50. Returns {\"versions\": [{\"value\", \"author\", \"changed_at\"}]}."
+    )]
+    async fn context_history(
+        &self,
+        Parameters(params): Parameters<ContextHistoryParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let versions = self
+            .db
+            .context_history(params.project_id.as_deref(), &params.key, params.limit)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "versions": versions })))
+    }
+
+    /// Delete a context value.
+    #[tool(
+        description = "Delete a context value. Omit project_id for global context. Returns {\"deleted\": true} or {\"deleted\": false}. Errors: Conflict if the key is leased to another owner (see context_lease)."
+    )]
+    async fn context_delete(
+        &self,
+        Parameters(params): Parameters<ContextDeleteParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let deleted = self
+            .db
+            .context_delete(
+                params.project_id.as_deref(),
+                &params.key,
+                Some(sender(params.owner.as_deref(), &extensions)?),
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "deleted": deleted })))
+    }
+
+    /// Append to a bounded context list.
+    #[tool(
+        description = "Append an entry to a bounded list stored under a context key, e.g. a shared log of recent decisions. The list is a JSON array of strings (read it with context_get), created if the key doesn't exist; once it holds max_entries (1-1000), or would exceed 65536 bytes, the oldest entries are evicted. Omit project_id for global context. Returns {\"entries\": n, \"evicted\": n}. Errors: NotAList if the key holds another value, Conflict if the key is leased to another owner (see context_lease)."
+    )]
+    async fn context_append(
+        &self,
+        Parameters(params): Parameters<ContextAppendParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let appended = self
+            .db
+            .context_append(
+                params.project_id.as_deref(),
+                &params.key,
+                &params.entry,
+                params.max_entries,
+                Some(sender(params.owner.as_deref(), &extensions)?),
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(appended)))
+    }
+
+    /// Lease a context key for exclusive writes.
+    #[tool(
+        description = "Take exclusive write access to a context key for ttl_secs (1-86400): until the lease expires or is released, context_set and context_delete by other owners fail with Conflict. Reads are unaffected, and the key need not exist yet. Leasing again renews the lease. Omit project_id for global context; owner defaults to the authenticated agent. Returns {\"key\": \"...\", \"owner\": \"...\", \"expires_at\": \"...\"}. Errors: Conflict if another owner holds the lease."
+    )]
+    async fn context_lease(
+        &self,
+        Parameters(params): Parameters<ContextLeaseParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let lease = self
+            .db
+            .context_lease(
+                params.project_id.as_deref(),
+                &params.key,
+                sender(params.owner.as_deref(), &extensions)?,
+                params.ttl_secs,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(lease)))
+    }
+
+    /// Release a context key lease.
+    #[tool(
+        description = "Release a lease taken with context_lease, letting other owners write the key again. Omit project_id for global context; owner defaults to the authenticated agent. Returns {\"released\": true} or {\"released\": false} if the owner held no lease on the key."
+    )]
+    async fn context_release(
+        &self,
+        Parameters(params): Parameters<ContextReleaseParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let released = self
+            .db
+            .context_release(
+                params.project_id.as_deref(),
+                &params.key,
+                sender(params.owner.as_deref(), &extensions)?,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "released": released })))
+    }
+
+    /// List all context keys.
+    #[tool(
+        description = "List all context keys. Omit project_id for global context. Returns {\"keys\": [\"key1\", \"key2\", ...]}."
+    )]
+    async fn context_list(
+        &self,
+        Parameters(params): Parameters<ContextListParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let keys = self
+            .db
+            .context_list(params.project_id.as_deref())
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "keys": keys })))
+    }
+
+    /// Checkpoint a project's context.
+    #[tool(
+        description = "Save an immutable snapshot of a project's context (all keys and values), optionally labelled with name, so it can be rolled back with context_restore. Returns {\"snapshot_id\", \"project_id\", \"name\", \"keys\", \"created_at\"}. Errors: EmptyField if project_id is empty."
+    )]
+    async fn context_snapshot(
+        &self,
+        Parameters(params): Parameters<ContextSnapshotParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let snapshot = self
+            .db
+            .context_snapshot(&params.project_id, params.name.as_deref())
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(snapshot)))
+    }
+
+    /// Roll a project's context back to a snapshot.
+    #[tool(
+        description = "Replace a project's context with a snapshot taken by context_snapshot: keys added since are removed and changed or deleted keys get their old values back. The snapshot is kept and can be restored again. Returns {\"restored\": true, \"snapshot_id\", \"project_id\", \"name\", \"keys\", \"created_at\"} or {\"restored\": false} if the snapshot doesn't exist."
+    )]
+    async fn context_restore(
+        &self,
+        Parameters(params): Parameters<ContextRestoreParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let snapshot = self
+            .db
+            .context_restore(params.snapshot_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+
+        let response = match snapshot {
+            Some(snapshot) => {
+                let mut response = json!(snapshot);
+                response["restored"] = json!(true);
+                response
+            }
+            None => json!({ "restored": false }),
+        };
+        Ok(json_response(&response))
+    }
+
+    /// List a project's context snapshots.
+    #[tool(
+        description = "List a project's context snapshots, oldest first. Returns {\"snapshots\": [{\"snapshot_id\", \"name\", \"keys\", \"created_at\", ...}]}."
+    )]
+    async fn list_context_snapshots(
+        &self,
+        Parameters(params): Parameters<ListContextSnapshotsParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let snapshots = self
+            .db
+            .context_snapshots(&params.project_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "snapshots": snapshots })))
+    }
+
+    /// Get notified when context keys change.
+    #[tool(
+        description = "Watch a project context key, or with prefix: true every key starting with key (an empty key watches them all), instead of polling context_get: whenever context_set, context_set_many or context_append changes a matching value, or context_delete deletes one, agent_id's queue gets a message from \"system\" with metadata {\"context_watch\": true, \"key\", \"change\": \"set\"|\"deleted\", \"author\"}, which wait_for_messages wakes on. Changes the agent makes itself aren't reported, nor are context_restore and expiry. Watching again keeps the existing watch. Returns {\"project_id\", \"agent_id\", \"key\", \"prefix\", \"created_at\"}. Errors: EmptyField if project_id/agent_id empty, or key empty without prefix."
+    )]
+    async fn context_watch(
+        &self,
+        Parameters(params): Parameters<ContextWatchParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let watch = self
+            .db
+            .context_watch(
+                &params.project_id,
+                &params.agent_id,
+                &params.key,
+                params.prefix,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(watch)))
+    }
+
+    /// Stop watching context keys.
+    #[tool(
+        description = "Remove a watch set with context_watch, given the same key and prefix. Returns {\"removed\": true} or {\"removed\": false} if there was no such watch."
+    )]
+    async fn context_unwatch(
+        &self,
+        Parameters(params): Parameters<ContextWatchParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let removed = self
+            .db
+            .context_unwatch(
+                &params.project_id,
+                &params.agent_id,
+                &params.key,
+                params.prefix,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "removed": removed })))
+    }
+
+    /// List context watches.
+    #[tool(
+        description = "List a project's context watches, optionally only agent_id's, ordered by agent and key. Returns {\"watches\": [{\"project_id\", \"agent_id\", \"key\", \"prefix\", \"created_at\"}]}."
+    )]
+    async fn list_context_watches(
+        &self,
+        Parameters(params): Parameters<ListContextWatchesParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let watches = self
+            .db
+            .context_watches(&params.project_id, params.agent_id.as_deref())
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "watches": watches })))
+    }
+
+    /// Upload binary data for messages to reference.
+    #[tool(
+        description = "Upload a binary attachment (diff, image, tarball, ...) to a project for messages to carry. data is base64-encoded, max 10485760 bytes decoded. Pass the returned hash in send_message's or broadcast_message's attachments; receivers download it with get_attachment. Identical data is stored once. Attachments no message references are removed after 24 hours. Returns {\"hash\", \"project_id\", \"content_type\", \"size\", \"created_at\"}. Errors: EmptyField if project_id/data empty, ContentTooLarge if data > 10485760 bytes."
+    )]
+    async fn attach(
+        &self,
+        Parameters(params): Parameters<AttachParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let data = BASE64.decode(params.data.trim()).map_err(|e| {
+            McpError::invalid_params(format!("data is not valid base64: {e}"), None)
+        })?;
+        let attachment = self
+            .db
+            .store_attachment(&params.project_id, &data, params.content_type.as_deref())
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(attachment)))
+    }
+
+    /// Start a chunked upload of oversized message content.
+    #[tool(
+        description = "Start sending a message whose content is too large for send_message (over 1048576 bytes), such as a big diff or log: append the content in order with append_chunk, then send it with commit_message. Uploads not committed within 24 hours are discarded. Returns {\"upload_id\": \"...\"}. Errors: EmptyField if project_id empty."
+    )]
+    async fn begin_message(
+        &self,
+        Parameters(params): Parameters<BeginMessageParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let upload_id = self
+            .db
+            .begin_upload(&params.project_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "upload_id": upload_id })))
+    }
+
+    /// Append a part of the content of a chunked upload.
+    #[tool(
+        description = "Append the next part of a message's content to an upload started with begin_message. Chunks are joined in the order they are appended, as is. Returns {\"upload_id\", \"chunks\", \"size\"} with the totals so far. Errors: UnknownUpload if the upload doesn't exist (committed or expired), EmptyField if data empty, ContentTooLarge if data > 1048576 bytes or the upload would exceed 10485760 bytes."
+    )]
+    async fn append_chunk(
+        &self,
+        Parameters(params): Parameters<AppendChunkParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let progress = self
+            .db
+            .append_chunk(&params.upload_id, &params.data)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(progress)))
+    }
+
+    /// Send the content of a chunked upload as a message.
+    #[tool(
+        description = "Send the content assembled with begin_message and append_chunk to to_agent, in the upload's project, then discard the upload. Content up to 1048576 bytes is sent as the message content. Larger content is stored as a text/plain attachment and the message content says so; receivers download it with get_attachment using the hash in the message's attachments. Accepts send_message's reference_id, reply_to, trace_id, priority, ttl_secs, metadata, tags and confidential. Returns {\"message_id\", \"size\"}, plus {\"attachment\": hash} when the content was stored as an attachment. Errors: UnknownUpload if the upload doesn't exist (committed or expired), otherwise as send_message."
+    )]
+    async fn commit_message(
+        &self,
+        Parameters(params): Parameters<CommitMessageParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let from_agent = sender(params.from_agent.as_deref(), &extensions)?;
+        let upload = self
+            .db
+            .upload_content(&params.upload_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?
+            .ok_or_else(|| {
+                McpError::invalid_params(
+                    DbError::UnknownUpload {
+                        upload_id: params.upload_id.clone(),
+                    }
+                    .to_string(),
+                    None,
+                )
+            })?;
+        self.check_send_rate(&upload.project_id, from_agent)?;
+        let content = self.transforms.apply(&upload.content);
+        let size = content.len();
+
+        let (content, attachment) = if size <= MAX_MESSAGE_SIZE {
+            (content, None)
+        } else {
+            let attachment = self
+                .db
+                .store_attachment(
+                    &upload.project_id,
+                    content.as_bytes(),
+                    Some("text/plain; charset=utf-8"),
+                )
+                .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+            let notice = format!(
+                "[{size} bytes of content stored as attachment {}; download it with get_attachment]",
+                attachment.hash
+            );
+            (Cow::Owned(notice), Some(attachment.hash))
+        };
+        let attachments = attachment.clone().map(|hash| vec![hash]);
+
+        let message_id = self
+            .db
+            .send(&NewMessage {
+                project_id: &upload.project_id,
+                to_agent: &params.to_agent,
+                from_agent,
+                content: &content,
+                reference_id: params.reference_id.as_deref(),
+                reply_to: params.reply_to.as_deref(),
+                trace_id: params.trace_id.as_deref(),
+                priority: parse_priority(params.priority.as_deref())?,
+                ttl_secs: params.ttl_secs,
+                metadata: params.metadata.as_ref(),
+                attachments: attachments.as_deref(),
+                confidential: params.confidential,
+                tags: params.tags.as_deref(),
+                ..NewMessage::default()
+            })
+            .map_err(|e| self.send_error(e))?;
+        self.charge_send_rate(&upload.project_id, from_agent, 1);
+        self.db
+            .discard_upload(&params.upload_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        tracing::debug!(message_id, size, "Chunked message sent");
+
+        let mut response = json!({ "message_id": message_id, "size": size });
+        if let Some(hash) = attachment {
+            response["attachment"] = json!(hash);
+        }
+        Ok(json_response(&response))
+    }
+
+    /// Download an attachment.
+    #[tool(
+        description = "Download an attachment referenced by a message. Returns {\"found\": true, \"hash\", \"project_id\", \"content_type\", \"size\", \"created_at\", \"data\"} with base64-encoded data, or {\"found\": false} if the project has no attachment with that hash."
+    )]
+    async fn get_attachment(
+        &self,
+        Parameters(params): Parameters<GetAttachmentParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let attachment = self
+            .db
+            .attachment(&params.project_id, &params.hash)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+
+        let response = match attachment {
+            Some((attachment, data)) => {
+                let mut response = json!(attachment);
+                response["found"] = json!(true);
+                response["data"] = json!(BASE64.encode(data));
+                response
+            }
+            None => json!({ "found": false }),
+        };
+        Ok(json_response(&response))
+    }
+
+    /// Register a JSON Schema for message content.
+    #[tool(
+        description = "Register a JSON Schema (draft 4 to 2020-12, detected from $schema) under a name in a project, replacing any schema already registered under that name. Senders pass the name as send_message's schema parameter to have content validated before it is queued, so malformed payloads are rejected up front instead of when the receiver parses them. Returns {\"project_id\", \"name\", \"schema\", \"created_at\", \"updated_at\"}. Errors: EmptyField if project_id/name empty, ContentTooLarge if schema > 65536 bytes, InvalidSchema if schema is not a valid JSON Schema."
+    )]
+    async fn register_message_schema(
+        &self,
+        Parameters(params): Parameters<RegisterMessageSchemaParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let schema = self
+            .db
+            .register_message_schema(&params.project_id, &params.name, &params.schema)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(schema)))
+    }
+
+    /// List a project's message schemas.
+    #[tool(
+        description = "List the JSON Schemas registered in a project with register_message_schema, by name. Returns {\"schemas\": [{\"name\", \"schema\", \"created_at\", \"updated_at\", ...}]}."
+    )]
+    async fn list_message_schemas(
+        &self,
+        Parameters(params): Parameters<ListMessageSchemasParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let schemas = self
+            .db
+            .message_schemas(&params.project_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "schemas": schemas })))
+    }
+
+    /// Register a message template.
+    #[tool(
+        description = "Register a message template under a name in a project, replacing any template already registered under that name: message content with {{name}} placeholders (names of letters, digits, _, - and .), filled in by send_from_template. Lets orchestrators that send the same structured task prompt over and over send only what varies. Returns {\"project_id\", \"name\", \"template\", \"placeholders\", \"created_at\", \"updated_at\"}. Errors: EmptyField if project_id/name/template empty, ContentTooLarge if template > 65536 bytes."
+    )]
+    async fn register_message_template(
+        &self,
+        Parameters(params): Parameters<RegisterMessageTemplateParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let template = self
+            .db
+            .register_message_template(&params.project_id, &params.name, &params.template)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(template)))
+    }
+
+    /// List a project's message templates.
+    #[tool(
+        description = "List the message templates registered in a project with register_message_template, by name. Returns {\"templates\": [{\"name\", \"template\", \"placeholders\", \"created_at\", \"updated_at\", ...}]}."
+    )]
+    async fn list_message_templates(
+        &self,
+        Parameters(params): Parameters<ListMessageTemplatesParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let templates = self
+            .db
+            .message_templates(&params.project_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "templates": templates })))
+    }
+
+    /// Render a template and send the result.
+    #[tool(
+        description = "Send a message rendered from a template registered with register_message_template: each {{name}} placeholder is replaced by variables[name] (strings verbatim, other values as JSON; unused variables are ignored), and the result is sent to to_agent like send_message's content. Accepts send_message's reference_id, reply_to, trace_id, priority, ttl_secs, metadata, tags, confidential and request_receipt. Returns {\"message_id\"}. Errors: UnknownTemplate if the template isn't registered, MissingTemplateVariable if a placeholder has no value, otherwise as send_message."
+    )]
+    async fn send_from_template(
+        &self,
+        Parameters(params): Parameters<SendFromTemplateParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let from_agent = sender(params.from_agent.as_deref(), &extensions)?;
+        let rendered = self
+            .db
+            .render_template(&params.project_id, &params.template, &params.variables)
+            .map_err(|e| McpError::invalid_params(e.to_string(), None))?;
+        self.check_send_rate(&params.project_id, from_agent)?;
+        let content = self.transforms.apply(&rendered);
+
+        let message_id = self
+            .db
+            .send(&NewMessage {
+                project_id: &params.project_id,
+                to_agent: &params.to_agent,
+                from_agent,
+                content: &content,
+                reference_id: params.reference_id.as_deref(),
+                reply_to: params.reply_to.as_deref(),
+                trace_id: params.trace_id.as_deref(),
+                priority: parse_priority(params.priority.as_deref())?,
+                ttl_secs: params.ttl_secs,
+                metadata: params.metadata.as_ref(),
+                confidential: params.confidential,
+                tags: params.tags.as_deref(),
+                request_receipt: params.request_receipt,
+                ..NewMessage::default()
+            })
+            .map_err(|e| self.send_error(e))?;
+        self.charge_send_rate(&params.project_id, from_agent, 1);
+        tracing::debug!(message_id, template = %params.template, "Templated message sent");
+        Ok(json_response(&json!({ "message_id": message_id })))
+    }
+
+    /// Send a message to an agent's queue.
+    #[tool(
+        description = "Send a message to an agent's queue. To send copies to several agents, give to_agents or a glob pattern as to_agent; to send to one member of an agent group, give to_group; to cross-post to several projects, give project_ids (see the parameter descriptions). Returns {\"message_id\": \"...\"} (other shapes for the modes above), plus {\"throttle\": {\"reason\", \"retry_after_ms\", \"queue_depth\"}} when the server is overloaded (wait retry_after_ms before sending again). Errors: EmptyField, ContentTooLarge, TooManyRecipients, TooManyAttachments, TooManyTags, UnknownSchema, SchemaViolation, UnknownAttachment, UnknownGroup, EmptyGroup, QueueFull (with a queue_full throttle hint as error data)."
+    )]
+    async fn send_message(
+        &self,
+        Parameters(params): Parameters<SendMessageParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let from_agent = sender(params.from_agent.as_deref(), &extensions)?;
+        match &params.project_ids {
+            Some(project_ids) => {
+                if !params.project_id.is_empty() {
+                    return Err(McpError::invalid_params(
+                        "Give either project_id or project_ids, not both",
+                        None,
+                    ));
+                }
+                if params.to_agents.is_some() {
+                    return Err(McpError::invalid_params(
+                        "to_agents can't be combined with project_ids",
+                        None,
+                    ));
+                }
+                for project_id in project_ids {
+                    self.check_send_rate(project_id, from_agent)?;
+                }
+            }
+            None => self.check_send_rate(&params.project_id, from_agent)?,
+        }
+        if params.to_agents.is_some() && !params.to_agent.is_empty() {
+            return Err(McpError::invalid_params(
+                "Give either to_agent or to_agents, not both",
+                None,
+            ));
+        }
+        if params.to_group.is_some()
+            && (!params.to_agent.is_empty()
+                || params.to_agents.is_some()
+                || params.project_ids.is_some())
+        {
+            return Err(McpError::invalid_params(
+                "to_group can't be combined with to_agent, to_agents or project_ids",
+                None,
+            ));
+        }
+
+        let to_agent = self.recipient(&params)?;
+        let pattern = params.to_agents.is_none()
+            && params.to_group.is_none()
+            && Database::is_agent_pattern(&to_agent);
+        if pattern && params.project_ids.is_some() {
+            return Err(McpError::invalid_params(
+                "to_agent patterns can't be combined with project_ids",
+                None,
+            ));
+        }
+
+        let span = tracing::info_span!(
+            "send_message",
+            project_id = %params.project_id,
+            to_agent = %to_agent,
+            from_agent,
+            trace_id = params.trace_id.as_deref(),
+        );
+        let _guard = span.enter();
+
+        let content = self.transforms.apply(&params.content);
+        let message = NewMessage {
+            project_id: &params.project_id,
+            to_agent: &to_agent,
+            from_agent,
+            content: &content,
+            reference_id: params.reference_id.as_deref(),
+            reply_to: params.reply_to.as_deref(),
+            trace_id: params.trace_id.as_deref(),
+            priority: parse_priority(params.priority.as_deref())?,
+            ttl_secs: params.ttl_secs,
+            metadata: params.metadata.as_ref(),
+            schema: params.schema.as_deref(),
+            attachments: params.attachments.as_deref(),
+            confidential: params.confidential,
+            tags: params.tags.as_deref(),
+            request_receipt: params.request_receipt,
+        };
+        if params.dry_run {
+            let member;
+            let matching;
+            let queues = match (&params.project_ids, &params.to_agents) {
+                (Some(project_ids), _) => Database::distinct_recipients(project_ids, "project_ids")
+                    .map_err(|e| self.send_error(e))?
+                    .into_iter()
+                    .map(|project_id| (project_id, &*to_agent))
+                    .collect(),
+                (None, Some(to_agents)) => Database::distinct_recipients(to_agents, "to_agents")
+                    .map_err(|e| self.send_error(e))?
+                    .into_iter()
+                    .map(|agent_id| (params.project_id.as_str(), agent_id))
+                    .collect(),
+                (None, None) => match &params.to_group {
+                    Some(group_id) => {
+                        member = self
+                            .db
+                            .next_group_member(&params.project_id, group_id)
+                            .map_err(|e| self.send_error(e))?;
+                        vec![(params.project_id.as_str(), member.as_str())]
+                    }
+                    None if pattern => {
+                        matching = self
+                            .db
+                            .matching_agents(&params.project_id, &to_agent, from_agent)
+                            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+                        matching
+                            .iter()
+                            .map(|agent_id| (params.project_id.as_str(), agent_id.as_str()))
+                            .collect()
+                    }
+                    None => vec![(params.project_id.as_str(), &*to_agent)],
+                },
+            };
+            let mut deliveries = Vec::with_capacity(queues.len());
+            for (project_id, to_agent) in queues {
+                self.db
+                    .check_send(&NewMessage {
+                        project_id,
+                        to_agent,
+                        ..message
+                    })
+                    .map_err(|e| self.send_error(e))?;
+                deliveries.push(json!({ "project_id": project_id, "agent_id": to_agent }));
+            }
+            return Ok(json_response(
+                &json!({ "dry_run": true, "deliveries": deliveries }),
+            ));
+        }
+        if let Some(project_ids) = &params.project_ids {
+            let deliveries = self
+                .db
+                .send_to_projects(&message, project_ids)
+                .map_err(|e| self.send_error(e))?;
+            for delivery in &deliveries {
+                self.charge_send_rate(&delivery.project_id, from_agent, 1);
+            }
+            tracing::debug!(projects = deliveries.len(), "Message cross-posted");
+
+            let mut response = json!({ "deliveries": deliveries });
+            if let Some(hint) = self.throttle_hint(None) {
+                response["throttle"] = json!(hint);
+            }
+            return Ok(json_response(&response));
+        }
+        if let Some(to_agents) = &params.to_agents {
+            let deliveries = self
+                .db
+                .send_to_agents(&message, to_agents)
+                .map_err(|e| self.send_error(e))?;
+            self.charge_send_rate(&params.project_id, from_agent, deliveries.len());
+            tracing::debug!(recipients = deliveries.len(), "Message sent");
+
+            let mut response = json!({ "deliveries": deliveries });
+            if let Some(hint) = self.throttle_hint(None) {
+                response["throttle"] = json!(hint);
+            }
+            return Ok(json_response(&response));
+        }
+
+        if pattern {
+            let deliveries = self
+                .db
+                .send_to_matching(&message, &to_agent)
+                .map_err(|e| self.send_error(e))?;
+            self.charge_send_rate(&params.project_id, from_agent, deliveries.len());
+            tracing::debug!(
+                recipients = deliveries.len(),
+                "Message sent to matching agents"
+            );
+
+            let mut response = json!({ "deliveries": deliveries });
+            if let Some(hint) = self.throttle_hint(None) {
+                response["throttle"] = json!(hint);
+            }
+            return Ok(json_response(&response));
+        }
+        if let Some(group_id) = &params.to_group {
+            let delivery = self
+                .db
+                .send_to_group(&message, group_id)
+                .map_err(|e| self.send_error(e))?;
+            self.charge_send_rate(&params.project_id, from_agent, 1);
+            tracing::debug!(message_id = %delivery.message_id, agent_id = %delivery.agent_id, "Message sent to group");
+
+            let queue_depth = self
+                .db
+                .queue_depth(&params.project_id, &delivery.agent_id)
+                .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+            let mut response = json!(delivery);
+            if let Some(hint) = self.throttle_hint(Some(queue_depth)) {
+                response["throttle"] = json!(hint);
+            }
+            return Ok(json_response(&response));
+        }
+
+        let message_id = self.db.send(&message).map_err(|e| self.send_error(e))?;
+        self.charge_send_rate(&params.project_id, from_agent, 1);
+        tracing::debug!(message_id, "Message sent");
+
+        let queue_depth = self
+            .db
+            .queue_depth(&params.project_id, &to_agent)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        let mut response = json!({ "message_id": message_id });
+        if let Some(hint) = self.throttle_hint(Some(queue_depth)) {
+            response["throttle"] = json!(hint);
+        }
+        Ok(json_response(&response))
+    }
+
+    /// Send a copy of a message to every agent in a project.
+    #[tool(
+        description = "Send a copy of a message to every agent in a project (except the sender): agents with queued messages, agents sent messages in the last 7 days, and agents with queue settings. All copies share the same attachments, tags and reply_to, if any, and confidential classification. Returns {\"deliveries\": [{\"agent_id\", \"message_id\"}]}. Errors: EmptyField if project_id empty, ContentTooLarge if content > 1048576 bytes, UnknownAttachment if an attachment hash isn't stored in the project, QueueFull if a recipient queue is at the server's quota (no copy is sent)."
+    )]
+    async fn broadcast_message(
+        &self,
+        Parameters(params): Parameters<BroadcastMessageParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let from_agent = sender(params.from_agent.as_deref(), &extensions)?;
+        self.check_send_rate(&params.project_id, from_agent)?;
+        let content = self.transforms.apply(&params.content);
+
+        let deliveries = self
+            .db
+            .broadcast_message(&NewMessage {
+                project_id: &params.project_id,
+                from_agent,
+                content: &content,
+                reference_id: params.reference_id.as_deref(),
+                reply_to: params.reply_to.as_deref(),
+                trace_id: params.trace_id.as_deref(),
+                priority: parse_priority(params.priority.as_deref())?,
+                ttl_secs: params.ttl_secs,
+                metadata: params.metadata.as_ref(),
+                attachments: params.attachments.as_deref(),
+                confidential: params.confidential,
+                tags: params.tags.as_deref(),
+                ..NewMessage::default()
+            })
+            .map_err(|e| self.send_error(e))?;
+        self.charge_send_rate(&params.project_id, from_agent, deliveries.len());
+        tracing::debug!(
+            project_id = %params.project_id,
+            from_agent,
+            recipients = deliveries.len(),
+            "Message broadcast"
+        );
+
+        let mut response = json!({ "deliveries": deliveries });
+        if let Some(hint) = self.throttle_hint(None) {
+            response["throttle"] = json!(hint);
+        }
+        Ok(json_response(&response))
+    }
+
+    /// Receive and consume messages from an agent's queue.
+    #[tool(
+        description = "Receive and consume messages from an agent's queue. Messages are deleted after retrieval (in at_least_once queues, hidden until acknowledged with ack_message instead). With ack_mode \"manual\", messages are always kept in flight for visibility_timeout_secs (default: the queue's, 300 unless configured; max: 43200) and reappear unless acknowledged with ack_message, so nothing is lost if the agent crashes mid-processing. Optional filters (from_agent, reference_id, since as an ISO 8601 UTC timestamp, content_contains, metadata key/value pairs, after_seq, tags the message must all carry) limit which messages are consumed; others stay queued. Workers sharing agent_id as a group queue each get different messages; naming themselves with consumer records who received each message (see message_status) and lets ack_message/nack_message with the same consumer refuse messages since redelivered to another worker. Pinned messages (see pin_message) are skipped; a paused queue (see pause_queue) returns no messages and \"paused\": true. Default limit: 100, max: 500 (values above 500 are silently capped). timestamp_format: utc (default), epoch_ms, or a fixed offset like +02:00. Returns {\"messages\": [...]}; if messages are still queued afterwards, also {\"remaining\": count, \"remaining_range\": {\"oldest_created_at\", \"newest_created_at\"}}."
+    )]
+    async fn receive_messages(
+        &self,
+        Parameters(params): Parameters<ReceiveMessagesParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_agent(&extensions, &params.agent_id)?;
+        self.receive(&params)
+    }
+
+    /// Block until messages arrive, then receive them.
+    #[tool(
+        description = "Long-poll version of receive_messages: block until at least one message (matching the filters, if given) is queued for the agent (or timeout_secs elapses; default: 30, max: 300), then receive like receive_messages with the same limit, filters, ack_mode, visibility_timeout_secs and timestamp_format. Wakes as soon as a message is sent, so agents don't need to poll in a loop. Returns the same response as receive_messages; messages is empty if nothing arrived in time."
+    )]
+    async fn wait_for_messages(
+        &self,
+        Parameters(params): Parameters<WaitForMessagesParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let receive = &params.receive;
+        require_agent(&extensions, &receive.agent_id)?;
+        let filter = message_filter(&receive.filter)?;
+        let deadline = wait_deadline(params.timeout_secs);
+        let notify = self
+            .db
+            .subscribe_queue(&receive.project_id, &receive.agent_id);
+
+        loop {
+            // Register before checking, so a send in between still wakes us
+            let notified = notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let available = self
+                .db
+                .count_messages(&receive.project_id, &receive.agent_id, &filter)
+                .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+            let now = tokio::time::Instant::now();
+            if available > 0 || now >= deadline {
+                break;
+            }
+            let _ =
+                tokio::time::timeout_at(deadline.min(now + WAIT_RECHECK_INTERVAL), notified).await;
+        }
+
+        self.receive(receive)
+    }
+
+    /// Wait for a set of replies and consume them together.
+    #[tool(
+        description = "Scatter-gather helper: wait until expected_count replies are queued for agent_id (or timeout_secs elapses; default: 30, max: 300), then receive them together. Replies are messages whose reference_id is in message_ids, or whose trace_id equals conversation_id (give exactly one). Other messages stay queued. Returns {\"messages\": [...], \"complete\": true|false}; complete is false if fewer than expected_count replies arrived in time."
+    )]
+    async fn collect_replies(
+        &self,
+        Parameters(params): Parameters<CollectRepliesParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_agent(&extensions, &params.agent_id)?;
+        let filter = match (&params.message_ids, &params.conversation_id) {
+            (Some(message_ids), None) => MessageFilter {
+                reference_ids: Some(message_ids),
+                ..MessageFilter::default()
+            },
+            (None, Some(conversation_id)) => MessageFilter {
+                trace_id: Some(conversation_id),
+                ..MessageFilter::default()
+            },
+            _ => {
+                return Err(McpError::invalid_params(
+                    "Exactly one of message_ids or conversation_id is required",
+                    None,
+                ))
+            }
+        };
+        let format = self.timestamp_format(params.timestamp_format.as_deref())?;
+        let deadline = wait_deadline(params.timeout_secs);
+
+        let complete = loop {
+            let available = self
+                .db
+                .count_messages(&params.project_id, &params.agent_id, &filter)
+                .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+            if available >= u64::from(params.expected_count) {
+                break true;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                break false;
+            }
+            tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+        };
+
+        let messages = self
+            .db
+            .receive_filtered(
+                &params.project_id,
+                &params.agent_id,
+                &filter,
+                Some(MAX_MESSAGE_LIMIT),
+                None,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        let complete = complete && messages.len() >= params.expected_count as usize;
+        Ok(json_response(&json!({
+            "messages": render_messages(&messages, format),
+            "complete": complete,
+        })))
+    }
+
+    /// Send a request and wait for its reply.
+    #[tool(
+        description = "Request/response in one call: send a message like send_message, then block until a reply (a message whose reference_id is the new message's ID) is queued for from_agent (or reply_to, if given), or timeout_secs elapses (default: 30, max: 300). The reply is received (removed from the queue); other messages stay queued. Returns {\"message_id\": \"...\", \"reply\": {...} | null}; reply is null if none arrived in time, in which case a later reply can still be collected with receive_messages or collect_replies. Errors: same as send_message."
+    )]
+    async fn send_and_wait_reply(
+        &self,
+        Parameters(params): Parameters<SendAndWaitReplyParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let message = &params.message;
+        if message.to_agents.is_some() || message.project_ids.is_some() {
+            return Err(McpError::invalid_params(
+                "to_agents and project_ids are not supported here; use send_message and collect_replies",
+                None,
+            ));
+        }
+        if message.dry_run {
+            return Err(McpError::invalid_params(
+                "dry_run is not supported here; use send_message",
+                None,
+            ));
+        }
+        let format = self.timestamp_format(params.timestamp_format.as_deref())?;
+        let from_agent = sender(message.from_agent.as_deref(), &extensions)?;
+        if let Some(reply_to) = message.reply_to.as_deref().filter(|s| !s.trim().is_empty()) {
+            // The reply is received from this queue
+            require_agent(&extensions, reply_to)?;
+        }
+        self.check_send_rate(&message.project_id, from_agent)?;
+        let to_agent = self.recipient(message)?;
+        if message.to_group.is_none() && Database::is_agent_pattern(&to_agent) {
+            return Err(McpError::invalid_params(
+                "to_agent patterns are not supported here; use send_message and collect_replies",
+                None,
+            ));
+        }
+        let content = self.transforms.apply(&message.content);
+
+        let new_message = NewMessage {
+            project_id: &message.project_id,
+            to_agent: &to_agent,
+            from_agent,
+            content: &content,
+            reference_id: message.reference_id.as_deref(),
+            reply_to: message.reply_to.as_deref(),
+            trace_id: message.trace_id.as_deref(),
+            priority: parse_priority(message.priority.as_deref())?,
+            ttl_secs: message.ttl_secs,
+            metadata: message.metadata.as_ref(),
+            schema: message.schema.as_deref(),
+            attachments: message.attachments.as_deref(),
+            confidential: message.confidential,
+            tags: message.tags.as_deref(),
+            request_receipt: message.request_receipt,
+        };
+        let message_id = match &message.to_group {
+            Some(group_id) => self
+                .db
+                .send_to_group(&new_message, group_id)
+                .map(|delivery| delivery.message_id),
+            None => self.db.send(&new_message),
+        }
+        .map_err(|e| self.send_error(e))?;
+        self.charge_send_rate(&message.project_id, from_agent, 1);
+        tracing::debug!(message_id, "Message sent, waiting for reply");
+
+        let reference_ids = [message_id.clone()];
+        let filter = MessageFilter {
+            reference_ids: Some(&reference_ids),
+            ..MessageFilter::default()
+        };
+        let deadline = wait_deadline(params.timeout_secs);
+        let reply_queue = message
+            .reply_to
+            .as_deref()
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .unwrap_or(from_agent);
+        let notify = self.db.subscribe_queue(&message.project_id, reply_queue);
+
+        let reply = loop {
+            // Register before checking, so a reply in between still wakes us
+            let notified = notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let mut replies = self
+                .db
+                .receive_filtered(&message.project_id, reply_queue, &filter, Some(1), None)
+                .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+            let now = tokio::time::Instant::now();
+            if !replies.is_empty() || now >= deadline {
+                break replies.pop();
+            }
+            let _ =
+                tokio::time::timeout_at(deadline.min(now + WAIT_RECHECK_INTERVAL), notified).await;
+        };
+
+        let reply = reply.map(|reply| render_messages(&[reply], format)[0].take());
+        Ok(json_response(&json!({
+            "message_id": message_id,
+            "reply": reply,
+        })))
+    }
+
+    /// Send a message on a recurring schedule.
+    #[tool(
+        description = "Have the server send a message to an agent on a recurring schedule, e.g. periodic \"post your status\" prompts. cron_expr is a five-field cron expression in UTC (\"minute hour day-of-month month day-of-week\", supporting *, lists, ranges and */steps) or @hourly, @daily, @weekly, @monthly. Returns {\"schedule_id\", \"project_id\", \"to_agent\", \"from_agent\", \"content\", \"cron_expr\", \"next_run_at\", \"created_at\"}. Errors: EmptyField if project_id/to_agent empty, ContentTooLarge if content > 1048576 bytes, InvalidSchedule if cron_expr is invalid or never fires."
+    )]
+    async fn schedule_recurring(
+        &self,
+        Parameters(params): Parameters<ScheduleRecurringParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let from_agent = params
+            .from_agent
+            .as_deref()
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .unwrap_or(SYSTEM_AGENT);
+        let content = self.transforms.apply(&params.content);
+        let schedule = self
+            .db
+            .create_schedule(
+                &NewMessage {
+                    project_id: &params.project_id,
+                    to_agent: &params.to_agent,
+                    from_agent,
+                    content: &content,
+                    ..NewMessage::default()
+                },
+                &params.cron_expr,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(schedule)))
+    }
+
+    /// List a project's recurring message schedules.
+    #[tool(
+        description = "List a project's recurring message schedules, oldest first. Returns {\"schedules\": [{\"schedule_id\", \"to_agent\", \"from_agent\", \"content\", \"cron_expr\", \"next_run_at\", ...}]}."
+    )]
+    async fn list_schedules(
+        &self,
+        Parameters(params): Parameters<ListSchedulesParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let schedules = self
+            .db
+            .schedules(&params.project_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "schedules": schedules })))
+    }
+
+    /// Stop a recurring message schedule.
+    #[tool(
+        description = "Cancel a recurring message schedule. Returns {\"cancelled\": true} or {\"cancelled\": false} if it doesn't exist in the project."
+    )]
+    async fn cancel_schedule(
+        &self,
+        Parameters(params): Parameters<CancelScheduleParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let cancelled = self
+            .db
+            .cancel_schedule(&params.project_id, params.schedule_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "cancelled": cancelled })))
+    }
+
+    /// Alert a supervisor when an agent goes quiet.
+    #[tool(
+        description = "Watch an agent: if it neither calls heartbeat nor receives messages for interval_secs (max: 604800), the supervisor gets a high-priority alert message from \"system\"; once the agent is active again the alert clears and the supervisor is told. Setting a watchdog again replaces it. Returns {\"project_id\", \"agent_id\", \"supervisor\", \"interval_secs\", \"last_activity_at\", \"created_at\"}. Errors: EmptyField if project_id/agent_id/supervisor empty."
+    )]
+    async fn set_watchdog(
+        &self,
+        Parameters(params): Parameters<SetWatchdogParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let watchdog = self
+            .db
+            .set_watchdog(
+                &params.project_id,
+                &params.agent_id,
+                &params.supervisor,
+                params.interval_secs,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(watchdog)))
+    }
+
+    /// Stop watching an agent.
+    #[tool(
+        description = "Remove an agent's watchdog. Returns {\"cleared\": true} or {\"cleared\": false} if the agent had none."
+    )]
+    async fn clear_watchdog(
+        &self,
+        Parameters(params): Parameters<ClearWatchdogParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let cleared = self
+            .db
+            .clear_watchdog(&params.project_id, &params.agent_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "cleared": cleared })))
+    }
+
+    /// Report that an agent is alive.
+    #[tool(
+        description = "Report that an agent is alive, resetting its watchdog interval (receiving messages does this too). Returns {\"watched\": true|false}, telling whether the agent has a watchdog."
+    )]
+    async fn heartbeat(
+        &self,
+        Parameters(params): Parameters<HeartbeatParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_agent(&extensions, &params.agent_id)?;
+        let watched = self
+            .db
+            .heartbeat(&params.project_id, &params.agent_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "watched": watched })))
+    }
+
+    /// Declare that a named milestone has been reached.
+    #[tool(
+        description = "Mark a named milestone as reached in a project, with an optional payload, waking agents blocked in await_milestone. Setting it again replaces the payload. Returns {\"name\", \"payload\", \"reached_at\"}. Errors: EmptyField if project_id/name empty, ContentTooLarge if payload > 65536 bytes."
+    )]
+    async fn set_milestone(
+        &self,
+        Parameters(params): Parameters<SetMilestoneParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let milestone = self
+            .db
+            .set_milestone(&params.project_id, &params.name, params.payload.as_deref())
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(milestone)))
+    }
+
+    /// Wait until a named milestone has been reached.
+    #[tool(
+        description = "Wait until a named milestone is reached in a project (returns immediately if it already was), for up to timeout_secs (default: 30, max: 300). Returns {\"reached\": true, \"milestone\": {\"name\", \"payload\", \"reached_at\"}} or {\"reached\": false, \"milestone\": null} on timeout."
+    )]
+    async fn await_milestone(
+        &self,
+        Parameters(params): Parameters<AwaitMilestoneParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let deadline = wait_deadline(params.timeout_secs);
+        let milestone = loop {
+            let milestone = self
+                .db
+                .milestone(&params.project_id, &params.name)
+                .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+            if milestone.is_some() || tokio::time::Instant::now() >= deadline {
+                break milestone;
+            }
+            tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+        };
+        Ok(json_response(&json!({
+            "reached": milestone.is_some(),
+            "milestone": milestone,
+        })))
+    }
+
+    /// Peek at messages without consuming them.
+    #[tool(
+        description = "Peek at messages in an agent's queue without consuming them. Messages remain in queue. Includes pinned messages, which receive_messages skips. Accepts the same optional filters as receive_messages (from_agent, reference_id, since, content_contains, metadata, after_seq, tags). Each message carries a seq number, increasing with every message sent to the queue, so pass the highest seq seen as after_seq to see only newer messages. Default limit: 100, max: 500 (values above 500 are silently capped). To page through a deep queue, pass the next_cursor of one page as cursor to get the following one; pages follow delivery order, so messages sent meanwhile only appear on later pages if they sort after the cursor. timestamp_format: utc (default), epoch_ms, or a fixed offset like +02:00. Returns {\"messages\": [...]}, plus \"next_cursor\" if the page is full and more messages may follow."
+    )]
+    async fn peek_messages(
+        &self,
+        Parameters(params): Parameters<PeekMessagesParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_agent(&extensions, &params.agent_id)?;
+        let mut filter = message_filter(&params.filter)?;
+        filter.after = params
+            .cursor
+            .as_deref()
+            .map(|cursor| cursor.trim().parse::<QueueCursor>())
+            .transpose()
+            .map_err(|e| McpError::invalid_params(e, None))?;
+        let limit = params.limit.unwrap_or(100).min(MAX_MESSAGE_LIMIT);
+        let messages = self
+            .db
+            .peek_filtered(&params.project_id, &params.agent_id, &filter, Some(limit))
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        let next_cursor = messages
+            .last()
+            .filter(|_| messages.len() == limit as usize)
+            .and_then(QueueCursor::after);
+        self.messages_response(
+            &messages,
+            None,
+            next_cursor,
+            params.timestamp_format.as_deref(),
+        )
+    }
+
+    /// Export a thread as a chat transcript.
+    #[tool(
+        description = "Export the messages of a thread (trace_id = conversation_id) as an OpenAI/Anthropic-style messages array, oldest first. Messages from agent_id become \"assistant\" turns, messages from the system agent \"system\" turns, and all others \"user\" turns. Only messages still stored (not yet consumed) are included, and confidential messages only with the include_confidential admin override. Default limit: 100, max: 500. Returns {\"messages\": [{\"role\", \"content\"}]}."
+    )]
+    async fn export_conversation(
+        &self,
+        Parameters(params): Parameters<ExportConversationParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        if params.include_confidential {
+            require_admin(&extensions)?;
+        }
+        let messages = self
+            .db
+            .trace_messages(
+                &params.project_id,
+                &params.conversation_id,
+                params.include_confidential,
+                params.limit,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        let transcript: Vec<ChatMessage> = messages
+            .into_iter()
+            .map(|message| {
+                let role = if message.from_agent == params.agent_id {
+                    "assistant"
+                } else if message.from_agent == SYSTEM_AGENT {
+                    "system"
+                } else {
+                    "user"
+                };
+                ChatMessage {
+                    role: role.to_string(),
+                    content: message.content.into(),
+                }
+            })
+            .collect();
+        Ok(json_response(&json!({ "messages": transcript })))
+    }
+
+    /// Import a chat transcript as a thread.
+    #[tool(
+        description = "Import an OpenAI/Anthropic-style messages array as a mailbox thread with trace_id = conversation_id, each message replying to the previous one. \"user\" turns are sent from counterpart (default: \"user\") to agent_id, \"assistant\" turns from agent_id to counterpart, and \"system\" turns from the system agent to agent_id. Content may be a string or an array of text blocks. Returns {\"message_ids\": [...]}. Errors: EmptyField if project_id/agent_id empty, ContentTooLarge if a message > 1048576 bytes."
+    )]
+    async fn import_conversation(
+        &self,
+        Parameters(params): Parameters<ImportConversationParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let counterpart = params.counterpart.as_deref().unwrap_or("user");
+        let texts = params
+            .messages
+            .iter()
+            .map(|message| {
+                chat_text(&message.content).map(|text| self.transforms.apply(&text).into_owned())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let messages = params
+            .messages
+            .iter()
+            .zip(&texts)
+            .map(|(message, content)| {
+                let (from_agent, to_agent) = match message.role.as_str() {
+                    "user" => (counterpart, params.agent_id.as_str()),
+                    "assistant" => (params.agent_id.as_str(), counterpart),
+                    "system" => (SYSTEM_AGENT, params.agent_id.as_str()),
+                    other => {
+                        return Err(McpError::invalid_params(
+                            format!("Unknown role '{other}' (expected system, user, or assistant)"),
+                            None,
+                        ))
+                    }
+                };
+                Ok(NewMessage {
+                    project_id: &params.project_id,
+                    to_agent,
+                    from_agent,
+                    content,
+                    trace_id: Some(&params.conversation_id),
+                    ..NewMessage::default()
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let message_ids = self
+            .db
+            .send_thread(&messages)
+            .map_err(|e| self.send_error(e))?;
+        Ok(json_response(&json!({ "message_ids": message_ids })))
+    }
+
+    /// Reconstruct the conversation a message belongs to.
+    #[tool(
+        description = "Get the full conversation a message belongs to, without consuming anything: the messages it replies to (following reference_id) and all replies to those, transitively, in send order. Includes pending, dead-lettered and, if the server archives consumed messages (--archive-days), already-consumed messages. Default limit: 100, max: 500. Returns {\"messages\": [{...message fields, \"to_agent\", \"state\": \"pending\"|\"archived\"|\"dead_letter\"}]}. Errors: InvalidMessageId if ID is not numeric."
+    )]
+    async fn get_thread(
+        &self,
+        Parameters(params): Parameters<GetThreadParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let format = self.timestamp_format(params.timestamp_format.as_deref())?;
+        let mut messages = self
+            .db
+            .thread(&params.project_id, &params.message_id, params.limit)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        messages.retain(|message| may_read(&extensions, message));
+        Ok(json_response(&json!({
+            "messages": render_messages(&messages, format),
+        })))
+    }
+
+    /// Fetch one message by ID.
+    #[tool(
+        description = "Get a single message by ID without consuming it, e.g. to look up the message a reference_id points to. Finds pending (including unacknowledged), dead-lettered and, if the server archives consumed messages (--archive-days), already-consumed messages. Returns {\"message\": {...message fields, \"to_agent\", \"state\": \"pending\"|\"archived\"|\"dead_letter\"}}, or {\"message\": null} if the project has no such message. Errors: InvalidMessageId if ID is not numeric."
+    )]
+    async fn get_message(
+        &self,
+        Parameters(params): Parameters<GetMessageParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let format = self.timestamp_format(params.timestamp_format.as_deref())?;
+        let message = self
+            .db
+            .get_message(&params.project_id, &params.message_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        if let Some(message) = message.as_ref().filter(|m| !may_read(&extensions, m)) {
+            require_agent(&extensions, &message.to_agent)?;
+        }
+        let message = message
+            .map(|message| render_messages(std::slice::from_ref(&message), format)[0].take());
+        Ok(json_response(&json!({ "message": message })))
+    }
+
+    /// Search message content.
+    #[tool(
+        description = "Full-text search over a project's messages without consuming anything, best matches first. query uses SQLite FTS5 syntax: words (all must match), \"exact phrases\", prefix*, OR, NOT. Optionally only messages addressed to agent_id and matching the receive_messages filters (from_agent, reference_id, since, content_contains, metadata key/value pairs, tags); with include_archived, also messages already consumed (if the server archives them, --archive-days). Default limit: 100, max: 500. Returns {\"messages\": [{...message fields, \"to_agent\", \"state\": \"pending\"|\"archived\"}]}. Errors: InvalidSearchQuery if the query is malformed."
+    )]
+    async fn search_messages(
+        &self,
+        Parameters(params): Parameters<SearchMessagesParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let format = self.timestamp_format(params.timestamp_format.as_deref())?;
+        let filter = message_filter(&params.filter)?;
+        let agent_id = readable_queue(params.agent_id.as_deref(), &extensions)?;
+        let messages = self
+            .db
+            .search_messages(
+                &params.project_id,
+                &params.query,
+                agent_id,
+                &filter,
+                params.include_archived,
+                params.limit,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({
+            "messages": render_messages(&messages, format),
+        })))
+    }
+
+    /// Report per-minute send/receive throughput for a queue.
+    #[tool(
+        description = "Report a queue's throughput history: per-minute send/receive counts over a window (default: 60 minutes, max: 7 days). Returns {\"window_minutes\", \"buckets\": [{\"bucket\", \"sent\", \"received\"}], \"total_sent\", \"total_received\", \"send_rate_per_minute\", \"receive_rate_per_minute\"}. Minutes without activity are omitted."
+    )]
+    async fn queue_history(
+        &self,
+        Parameters(params): Parameters<QueueHistoryParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let history = self
+            .db
+            .queue_history(
+                &params.project_id,
+                &params.agent_id,
+                params.window_minutes.unwrap_or(60),
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(history)))
+    }
+
+    /// Report the current state of a project's queues.
+    #[tool(
+        description = "Report statistics of every queue in a project holding pending messages, e.g. to tell whether a worker is stuck before sending it more work. Returns {\"queues\": [{\"agent_id\", \"pending\", \"in_flight\", \"oldest_message_age_secs\", \"total_bytes\", \"senders\": [{\"from_agent\", \"count\", \"bytes\"}]}]}. pending includes in_flight messages (received but awaiting acknowledgment); total_bytes is the content size of the pending messages."
+    )]
+    async fn queue_stats(
+        &self,
+        Parameters(params): Parameters<QueueStatsParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let queues = self
+            .db
+            .queue_stats(&params.project_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "queues": queues })))
+    }
+
+    /// Summarize an agent's inbox in one call.
+    #[tool(
+        description = "Summarize an agent's queue in one call, e.g. to get oriented at the start of a turn before receiving. Returns {\"pending\", \"in_flight\", \"dead_letters\", \"oldest_message_age_secs\", \"by_sender\": [{\"key\", \"count\"}], \"by_annotation\": [{\"key\", \"count\"}], \"by_age\": {\"under_1m\", \"under_1h\", \"under_1d\", \"older\"}}. pending includes in_flight messages (received but awaiting acknowledgment); by_annotation counts pending messages carrying each annotation (see annotate_message)."
+    )]
+    async fn inbox_overview(
+        &self,
+        Parameters(params): Parameters<InboxOverviewParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_agent(&extensions, &params.agent_id)?;
+        let overview = self
+            .db
+            .inbox_overview(&params.project_id, &params.agent_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(overview)))
+    }
+
+    /// Cheaply check whether a queue changed.
+    #[tool(
+        description = "Get a counter that changes whenever an agent's queue changes (messages sent, received, acknowledged, expired or deleted), so frequent pollers can skip peek_messages/receive_messages while it stays the same. 0 for a queue that never held a message. It does not change when an in-flight message's visibility timeout expires. Returns {\"version\": n}."
+    )]
+    async fn queue_version(
+        &self,
+        Parameters(params): Parameters<QueueVersionParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let version = self
+            .db
+            .queue_version(&params.project_id, &params.agent_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "version": version })))
+    }
+
+    /// Delete a specific message by ID.
+    #[tool(
+        description = "Delete a specific message by ID. Returns {\"deleted\": true} or {\"deleted\": false}; with dry_run: true, reports the same without deleting, plus \"dry_run\": true. Errors: InvalidMessageId if ID is not numeric."
+    )]
+    async fn delete_message(
+        &self,
+        Parameters(params): Parameters<DeleteMessageParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        self.require_recipient(&extensions, &params.message_id)?;
+        let deleted = self
+            .db
+            .delete_message(&params.message_id, params.dry_run)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        if params.dry_run {
+            return Ok(json_response(
+                &json!({ "deleted": deleted, "dry_run": true }),
+            ));
+        }
+        Ok(json_response(&json!({ "deleted": deleted })))
+    }
+
+    /// Delete all messages in a queue matching filters.
+    #[tool(
+        description = "Delete every message in an agent's queue matching the filters in one call, e.g. to clean up after a failed experiment. Accepts the filters of receive_messages (from_agent, reference_id, since, content_contains, metadata, after_seq, tags) plus older_than_secs (only messages created more than that many seconds ago). Messages awaiting acknowledgment are deleted too; without filters, the whole queue is cleared. With dry_run: true, nothing is deleted and the response adds \"dry_run\": true, so the count can be checked first. Returns {\"deleted\": count}."
+    )]
+    async fn delete_messages(
+        &self,
+        Parameters(params): Parameters<DeleteMessagesParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_agent(&extensions, &params.agent_id)?;
+        let mut filter = message_filter(&params.filter)?;
+        filter.before = params.older_than_secs.map(|secs| {
+            time::unix_seconds().saturating_sub(i64::try_from(secs).unwrap_or(i64::MAX))
+        });
+        let deleted = self
+            .db
+            .delete_messages(
+                &params.project_id,
+                &params.agent_id,
+                &filter,
+                params.dry_run,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        if params.dry_run {
+            return Ok(json_response(
+                &json!({ "deleted": deleted, "dry_run": true }),
+            ));
+        }
+        Ok(json_response(&json!({ "deleted": deleted })))
+    }
+
+    /// Withdraw a sent message that hasn't been delivered yet.
+    #[tool(
+        description = "Recall a message you sent, e.g. an instruction you realize is wrong, deleting it from the recipient's queue. Only succeeds if from_agent is the message's sender and the message has not been received yet (peeked messages can still be recalled). Returns {\"recalled\": true}, or {\"recalled\": false} if the message doesn't exist, was sent by someone else, or was already delivered. Errors: InvalidMessageId if ID is not numeric."
+    )]
+    async fn recall_message(
+        &self,
+        Parameters(params): Parameters<RecallMessageParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let from_agent = sender(params.from_agent.as_deref(), &extensions)?;
+        let recalled = self
+            .db
+            .recall_message(&params.project_id, &params.message_id, from_agent)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "recalled": recalled })))
+    }
+
+    /// Keep a message in its queue without letting receives consume it.
+    #[tool(
+        description = "Pin a pending message, e.g. standing instructions every session should see: receive_messages (and wait_for_messages) skip it while peek_messages and list_pinned still show it, flagged \"pinned\": true. Pinned messages still expire with their TTL and can be deleted; unpin_message makes them receivable again. Returns {\"pinned\": true}, or {\"pinned\": false} if the project has no such pending message. Errors: InvalidMessageId if ID is not numeric."
+    )]
+    async fn pin_message(
+        &self,
+        Parameters(params): Parameters<PinMessageParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        self.require_recipient(&extensions, &params.message_id)?;
+        let pinned = self
+            .db
+            .set_pinned(&params.project_id, &params.message_id, true)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "pinned": pinned })))
+    }
+
+    /// Let receives consume a pinned message again.
+    #[tool(
+        description = "Unpin a message pinned with pin_message, so receive_messages delivers it again in its usual place in the queue. Returns {\"unpinned\": true}, or {\"unpinned\": false} if the project has no such pending message. Errors: InvalidMessageId if ID is not numeric."
+    )]
+    async fn unpin_message(
+        &self,
+        Parameters(params): Parameters<PinMessageParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        self.require_recipient(&extensions, &params.message_id)?;
+        let unpinned = self
+            .db
+            .set_pinned(&params.project_id, &params.message_id, false)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "unpinned": unpinned })))
+    }
 
-fn messages_response(messages: &[Message]) -> CallToolResult {
-    json_response(&json!({ "messages": messages }))
-}
+    /// Postpone a pending message until a given time.
+    #[tool(
+        description = "Snooze a pending message, e.g. a low-priority item to come back to later: it is hidden from receive_messages and peek_messages until the given time (an ISO 8601 UTC timestamp), then delivered again in its usual place in the queue. Snoozing until a past time wakes a snoozed message up. The message still expires with its TTL while snoozed. Returns {\"snoozed\": true}, or {\"snoozed\": false} if the project has no such pending message or it is awaiting acknowledgment. Errors: InvalidMessageId if ID is not numeric."
+    )]
+    async fn snooze_message(
+        &self,
+        Parameters(params): Parameters<SnoozeMessageParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        self.require_recipient(&extensions, &params.message_id)?;
+        let until = time::UtcDateTime::parse_iso8601(params.until.trim()).ok_or_else(|| {
+            McpError::invalid_params(
+                format!(
+                    "Invalid until '{}' (expected an ISO 8601 UTC timestamp like 2025-01-08T12:00:00Z)",
+                    params.until
+                ),
+                None,
+            )
+        })?;
+        let snoozed = self
+            .db
+            .snooze_message(&params.project_id, &params.message_id, &until.to_iso8601())
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "snoozed": snoozed })))
+    }
 
-#[tool_router]
-impl MailboxServer {
-    /// Set a context value.
+    /// List the pinned messages of a project or queue.
     #[tool(
-        description = "Set a context value. Omit project_id for global context. Returns {\"ok\": true}. Errors: EmptyField if key is empty, ContentTooLarge if value > 65536 bytes."
+        description = "List pinned messages without consuming them: a project's, or only those in agent_id's queue, grouped by queue in delivery order. Default limit: 100, max: 500. Returns {\"messages\": [{...message fields, \"to_agent\", \"state\": \"pending\"}]}."
     )]
-    async fn context_set(
+    async fn list_pinned(
         &self,
-        Parameters(params): Parameters<ContextSetParams>,
+        Parameters(params): Parameters<ListPinnedParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let format = self.timestamp_format(params.timestamp_format.as_deref())?;
+        let agent_id = readable_queue(params.agent_id.as_deref(), &extensions)?;
+        let messages = self
+            .db
+            .pinned_messages(&params.project_id, agent_id, params.limit)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({
+            "messages": render_messages(&messages, format),
+        })))
+    }
+
+    /// Acknowledge a message received with at-least-once delivery.
+    #[tool(
+        description = "Acknowledge a message received from an at_least_once queue or with ack_mode \"manual\", deleting it so it is not redelivered. Returns {\"acknowledged\": true} or {\"acknowledged\": false} if the message doesn't exist, wasn't received awaiting acknowledgment, or was already acknowledged, or, with consumer, if it was since redelivered to another worker of the group queue. Errors: InvalidMessageId if ID is not numeric."
+    )]
+    async fn ack_message(
+        &self,
+        Parameters(params): Parameters<AckMessageParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        self.require_recipient(&extensions, &params.message_id)?;
+        let acknowledged = self
+            .db
+            .ack_message(&params.message_id, params.consumer.as_deref())
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "acknowledged": acknowledged })))
+    }
+
+    /// Hand a message received with at-least-once delivery back to its queue.
+    #[tool(
+        description = "Negatively acknowledge a message received from an at_least_once queue or with ack_mode \"manual\", e.g. when the worker can't handle it: the message returns to the queue after delay_secs (default: 0, i.e. immediately; max: 43200) instead of when its visibility timeout expires. The delivery counts as an attempt: the next one carries the next attempt number, and a message nacked on its queue's last allowed attempt moves to the dead-letter queue. Returns {\"requeued\": true} or {\"requeued\": false} if the message doesn't exist or isn't awaiting acknowledgment (by consumer, if given). Errors: InvalidMessageId if ID is not numeric."
+    )]
+    async fn nack_message(
+        &self,
+        Parameters(params): Parameters<NackMessageParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        self.require_recipient(&extensions, &params.message_id)?;
+        let requeued = self
+            .db
+            .nack_message(
+                &params.message_id,
+                params.delay_secs.unwrap_or(0),
+                params.consumer.as_deref(),
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "requeued": requeued })))
+    }
+
+    /// Attach a lightweight annotation to a message.
+    #[tool(
+        description = "Attach an annotation (e.g. \"ack\", \"rejected\", \"needs-clarification\", or an emoji) to a message, as low-cost feedback to its sender without a reply message. Works on messages already consumed. Attaching the same annotation again only refreshes its timestamp. Annotations are kept for 7 days. Returns {\"message_id\", \"agent_id\", \"annotation\", \"created_at\"}. Errors: EmptyField if project_id/agent_id/annotation empty, InvalidMessageId if ID is not numeric, ContentTooLarge if annotation > 64 bytes."
+    )]
+    async fn annotate_message(
+        &self,
+        Parameters(params): Parameters<AnnotateMessageParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let agent_id = match params.agent_id.as_deref().map(str::trim) {
+            Some(agent_id) if !agent_id.is_empty() => {
+                require_agent(&extensions, agent_id)?;
+                agent_id
+            }
+            _ => identity(&extensions).map_or("", |identity| identity.agent_id.as_str()),
+        };
+        let annotation = self
+            .db
+            .annotate_message(
+                &params.project_id,
+                &params.message_id,
+                agent_id,
+                &params.annotation,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(annotation)))
+    }
+
+    /// List the annotations attached to messages.
+    #[tool(
+        description = "Get the annotations attached to messages, e.g. by the sender checking how its messages were received. Returns {\"annotations\": [{\"message_id\", \"agent_id\", \"annotation\", \"created_at\"}]}, grouped by message, oldest first. Errors: InvalidMessageId if an ID is not numeric."
+    )]
+    async fn get_annotations(
+        &self,
+        Parameters(params): Parameters<GetAnnotationsParams>,
     ) -> Result<CallToolResult, McpError> {
+        let annotations = self
+            .db
+            .annotations(&params.project_id, &params.message_ids)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "annotations": annotations })))
+    }
+
+    /// Check the delivery status of sent messages.
+    #[tool(
+        description = "Check what became of sent messages, e.g. to tell \"not yet seen\" from \"seen but not answered\". status is pending (queued, unseen), peeked (queued, seen by the recipient with peek_messages), received (delivered by a receive; with at-least-once delivery, possibly not yet acknowledged), acknowledged, dead_lettered, deleted (removed before being received), or unknown (not sent in this project, or sent more than 7 days ago). Returns {\"statuses\": [{\"message_id\", \"status\", \"to_agent\", \"sent_at\", \"peeked_at\", \"received_at\", \"received_by\", \"acknowledged_at\"}]}, timestamps omitted until they happen. Errors: InvalidMessageId if an ID is not numeric."
+    )]
+    async fn message_status(
+        &self,
+        Parameters(params): Parameters<MessageStatusParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let statuses = self
+            .db
+            .message_statuses(&params.project_id, &params.message_ids)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "statuses": statuses })))
+    }
+
+    /// Link two messages with a typed relation.
+    #[tool(
+        description = "Record a typed link between two messages, read as \"message_id <kind> related_id\": duplicates, blocks, or follows_from. Links work on consumed messages too, so task dependency structures can live in the mailbox. Linking again is a no-op. Returns {\"ok\": true}. Errors: EmptyField if project_id empty, InvalidMessageId if an ID is not numeric, SelfLink if both IDs are equal."
+    )]
+    async fn link_messages(
+        &self,
+        Parameters(params): Parameters<LinkMessagesParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let kind: LinkKind = params
+            .kind
+            .parse()
+            .map_err(|e: String| McpError::invalid_params(e, None))?;
         self.db
-            .context_set(params.project_id.as_deref(), &params.key, &params.value)
+            .link_messages(
+                &params.project_id,
+                &params.message_id,
+                &params.related_id,
+                kind,
+            )
             .map_err(|e| McpError::internal_error(e.to_string(), None))?;
         Ok(json_response(&json!({ "ok": true })))
     }
 
-    /// Get a context value.
+    /// List the messages linked to or from a message.
     #[tool(
-        description = "Get a context value. Omit project_id for global context. Returns {\"found\": true, \"value\": \"...\"} or {\"found\": false}."
+        description = "Get the messages linked to or from a message. Each relation is given from message_id's point of view: duplicates/blocks/follows_from for its own links, duplicated_by/blocked_by/followed_by for links pointing at it. Returns {\"related\": [{\"message_id\", \"relation\", \"linked_at\"}]}, oldest link first. Errors: InvalidMessageId if ID is not numeric."
     )]
-    async fn context_get(
+    async fn get_related(
         &self,
-        Parameters(params): Parameters<ContextGetParams>,
+        Parameters(params): Parameters<GetRelatedParams>,
     ) -> Result<CallToolResult, McpError> {
-        let value = self
+        let related = self
             .db
-            .context_get(params.project_id.as_deref(), &params.key)
+            .related_messages(&params.project_id, &params.message_id)
             .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "related": related })))
+    }
 
-        #[allow(clippy::option_if_let_else)] // match is clearer here
-        let response = match value {
-            Some(v) => json!({ "found": true, "value": v }),
-            None => json!({ "found": false }),
-        };
-        Ok(json_response(&response))
+    /// Configure delivery semantics of a queue.
+    #[tool(
+        description = "Set the delivery mode of an agent's queue. at_most_once (default): receive_messages deletes messages. at_least_once: received messages stay hidden for visibility_timeout_secs (default: 300) and are redelivered unless acknowledged with ack_message; after max_delivery_attempts (default: 5) unacknowledged deliveries, a message is moved to the dead-letter queue. Returns {\"delivery_mode\", \"visibility_timeout_secs\", \"max_delivery_attempts\"}."
+    )]
+    async fn set_delivery_mode(
+        &self,
+        Parameters(params): Parameters<SetDeliveryModeParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_agent(&extensions, &params.agent_id)?;
+        let delivery_mode: DeliveryMode = params
+            .mode
+            .parse()
+            .map_err(|e: String| McpError::invalid_params(e, None))?;
+        let settings = self
+            .db
+            .set_queue_settings(
+                &params.project_id,
+                &params.agent_id,
+                QueueSettings {
+                    delivery_mode,
+                    visibility_timeout_secs: params
+                        .visibility_timeout_secs
+                        .unwrap_or(DEFAULT_VISIBILITY_TIMEOUT_SECS),
+                    max_delivery_attempts: params
+                        .max_delivery_attempts
+                        .unwrap_or(DEFAULT_MAX_DELIVERY_ATTEMPTS),
+                },
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(settings)))
     }
 
-    /// Delete a context value.
+    /// Freeze a queue's intake.
     #[tool(
-        description = "Delete a context value. Omit project_id for global context. Returns {\"deleted\": true} or {\"deleted\": false}."
+        description = "Pause an agent's queue, e.g. to freeze the intake of a misbehaving agent while debugging it without deleting anything: until resume_queue, receive_messages returns {\"messages\": [], \"paused\": true} and wait_for_messages waits, while sends keep queueing messages. Messages already in flight can still be acknowledged. Returns {\"paused\": true}, or {\"paused\": false} if the queue was already paused."
     )]
-    async fn context_delete(
+    async fn pause_queue(
         &self,
-        Parameters(params): Parameters<ContextDeleteParams>,
+        Parameters(params): Parameters<QueueParams>,
+        extensions: Extensions,
     ) -> Result<CallToolResult, McpError> {
-        let deleted = self
+        require_agent(&extensions, &params.agent_id)?;
+        let paused = self
             .db
-            .context_delete(params.project_id.as_deref(), &params.key)
+            .pause_queue(&params.project_id, &params.agent_id)
             .map_err(|e| McpError::internal_error(e.to_string(), None))?;
-        Ok(json_response(&json!({ "deleted": deleted })))
+        Ok(json_response(&json!({ "paused": paused })))
     }
 
-    /// List all context keys.
+    /// Let a paused queue deliver again.
     #[tool(
-        description = "List all context keys. Omit project_id for global context. Returns {\"keys\": [\"key1\", \"key2\", ...]}."
+        description = "Resume a queue paused with pause_queue: receives deliver the messages that accumulated meanwhile, and waiting wait_for_messages calls wake up. Returns {\"resumed\": true}, or {\"resumed\": false} if the queue wasn't paused."
     )]
-    async fn context_list(
+    async fn resume_queue(
         &self,
-        Parameters(params): Parameters<ContextListParams>,
+        Parameters(params): Parameters<QueueParams>,
+        extensions: Extensions,
     ) -> Result<CallToolResult, McpError> {
-        let keys = self
+        require_agent(&extensions, &params.agent_id)?;
+        let resumed = self
             .db
-            .context_list(params.project_id.as_deref())
+            .resume_queue(&params.project_id, &params.agent_id)
             .map_err(|e| McpError::internal_error(e.to_string(), None))?;
-        Ok(json_response(&json!({ "keys": keys })))
+        Ok(json_response(&json!({ "resumed": resumed })))
     }
 
-    /// Send a message to an agent's queue.
+    /// Define an agent group that shares the messages sent to it.
     #[tool(
-        description = "Send a message to an agent's queue. Returns {\"message_id\": \"...\"}. Errors: EmptyField if project_id/to_agent empty, ContentTooLarge if content > 1048576 bytes."
+        description = "Define an agent group for load balancing across identical workers: a send_message with to_group goes to one member, chosen by strategy: round_robin (default: each member in turn, in agent ID order) or least_loaded (the member with the fewest queued messages, pending or in flight). Calling it for an existing group changes its strategy. Add members with add_group_member. Returns {\"created\": true|false}. Errors: EmptyField if project_id/group_id empty."
     )]
-    async fn send_message(
+    async fn create_group(
         &self,
-        Parameters(params): Parameters<SendMessageParams>,
+        Parameters(params): Parameters<CreateGroupParams>,
     ) -> Result<CallToolResult, McpError> {
-        // Handle empty from_agent as "anonymous", trim whitespace for consistency
-        let from_agent = params
-            .from_agent
+        let strategy: GroupStrategy = params
+            .strategy
             .as_deref()
-            .map(str::trim)
-            .filter(|s| !s.is_empty())
-            .unwrap_or("anonymous");
+            .map_or(Ok(GroupStrategy::default()), str::parse)
+            .map_err(|e: String| McpError::invalid_params(e, None))?;
+        let created = self
+            .db
+            .create_group(&params.project_id, &params.group_id, strategy)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "created": created })))
+    }
 
-        let message_id = self
+    /// Add an agent to a group.
+    #[tool(
+        description = "Add an agent to an agent group, so it gets a share of the messages sent with to_group. Returns {\"added\": true} or {\"added\": false} if it was already a member. Errors: EmptyField if agent_id empty, UnknownGroup if the group doesn't exist."
+    )]
+    async fn add_group_member(
+        &self,
+        Parameters(params): Parameters<GroupMemberParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let added = self
+            .db
+            .add_group_member(&params.project_id, &params.group_id, &params.agent_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "added": added })))
+    }
+
+    /// Remove an agent from a group.
+    #[tool(
+        description = "Remove an agent from an agent group; messages already sent to it stay in its queue. Returns {\"removed\": true|false}."
+    )]
+    async fn remove_group_member(
+        &self,
+        Parameters(params): Parameters<GroupMemberParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let removed = self
+            .db
+            .remove_group_member(&params.project_id, &params.group_id, &params.agent_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "removed": removed })))
+    }
+
+    /// Inspect a project's dead-letter queue.
+    #[tool(
+        description = "Peek at a project's dead-letter queue: messages that expired unread (ttl_secs) or were delivered max_delivery_attempts times without being acknowledged. Optionally only those addressed to agent_id. Default limit: 100, max: 500. Returns {\"dead_letters\": [{...message fields, \"to_agent\", \"delivery_count\", \"reason\": \"expired\"|\"max_delivery_attempts\", \"dead_lettered_at\"}]}."
+    )]
+    async fn peek_dead_letters(
+        &self,
+        Parameters(params): Parameters<PeekDeadLettersParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let agent_id = readable_queue(params.agent_id.as_deref(), &extensions)?;
+        let dead_letters = self
+            .db
+            .dead_letters(&params.project_id, agent_id, params.limit)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "dead_letters": dead_letters })))
+    }
+
+    /// Return a dead-lettered message to its queue (admin).
+    #[tool(
+        description = "Move a message from the dead-letter queue back to its agent's queue, with its original ID and position, a fresh delivery count, and no expiry. Returns {\"requeued\": true} or {\"requeued\": false} if the message isn't dead-lettered. Errors: InvalidMessageId if ID is not numeric."
+    )]
+    async fn requeue_dead_letter(
+        &self,
+        Parameters(params): Parameters<RequeueDeadLetterParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_admin(&extensions)?;
+        let requeued = self
+            .db
+            .requeue_dead_letter(&params.message_id)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "requeued": requeued })))
+    }
+
+    /// Put recently consumed messages back into a queue (admin).
+    #[tool(
+        description = "Undo a receive: put messages agent_id consumed back into its queue, e.g. after it crashed before acting on them. Restores the given message_ids, or by default every message consumed in the last within_secs (default: 300). Messages keep their IDs and position, with a fresh delivery count and no expiry. Requires the message archive (--archive-days); only messages still archived can be restored. Returns {\"restored\": [\"id\", ...]}. Errors: ArchiveDisabled if archiving is off, InvalidMessageId if an ID is not numeric."
+    )]
+    async fn restore_messages(
+        &self,
+        Parameters(params): Parameters<RestoreMessagesParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_admin(&extensions)?;
+        let restored = self
             .db
-            .send_message(
+            .restore_messages(
                 &params.project_id,
-                &params.to_agent,
-                from_agent,
-                &params.content,
-                params.reference_id.as_deref(),
+                &params.agent_id,
+                params.message_ids.as_deref(),
+                params.within_secs.unwrap_or(DEFAULT_RESTORE_WINDOW_SECS),
             )
             .map_err(|e| McpError::internal_error(e.to_string(), None))?;
-        Ok(json_response(&json!({ "message_id": message_id })))
+        Ok(json_response(&json!({ "restored": restored })))
     }
 
-    /// Receive and consume messages from an agent's queue.
+    /// Give a project a limited lifetime (admin).
     #[tool(
-        description = "Receive and consume messages from an agent's queue. Messages are deleted after retrieval. Default limit: 100, max: 500 (values above 500 are silently capped). Returns {\"messages\": [...]}."
+        description = "Make a project ephemeral: all its messages, context, milestones and settings are purged ttl_secs from now (max: 90 days), keeping only a summary visible via list_expired_projects. Calling again moves the expiry. Returns {\"project_id\", \"created_at\", \"expires_at\"}. Errors: EmptyField if project_id empty."
     )]
-    async fn receive_messages(
+    async fn create_ephemeral_project(
         &self,
-        Parameters(params): Parameters<ReceiveMessagesParams>,
+        Parameters(params): Parameters<CreateEphemeralProjectParams>,
+        extensions: Extensions,
     ) -> Result<CallToolResult, McpError> {
-        let messages = self
+        require_admin(&extensions)?;
+        let expiry = self
             .db
-            .receive_messages(&params.project_id, &params.agent_id, params.limit)
+            .set_project_expiry(&params.project_id, params.ttl_secs)
             .map_err(|e| McpError::internal_error(e.to_string(), None))?;
-        Ok(messages_response(&messages))
+        Ok(json_response(&json!(expiry)))
     }
 
-    /// Peek at messages without consuming them.
+    /// Bootstrap a new project from an existing one.
     #[tool(
-        description = "Peek at messages in an agent's queue without consuming them. Messages remain in queue. Default limit: 100, max: 500 (values above 500 are silently capped). Returns {\"messages\": [...]}."
+        description = "Create a project from an existing one as a template, copying its shared context and/or agent queue settings but never its messages. include: [\"context\", \"agents\"] (default: both). Context keys classified confidential are skipped unless include_confidential (admin override) is set. Returns {\"context_keys\": n, \"agents\": n} copied. Errors: EmptyField if src/dst empty, ProjectNotEmpty if dst already holds data."
     )]
-    async fn peek_messages(
+    async fn clone_project(
         &self,
-        Parameters(params): Parameters<PeekMessagesParams>,
+        Parameters(params): Parameters<CloneProjectParams>,
+        extensions: Extensions,
     ) -> Result<CallToolResult, McpError> {
-        let messages = self
+        if params.include_confidential {
+            require_admin(&extensions)?;
+        }
+        let mut options = match &params.include {
+            None => CloneOptions {
+                context: true,
+                agents: true,
+                ..CloneOptions::default()
+            },
+            Some(include) => {
+                let mut options = CloneOptions::default();
+                for item in include {
+                    match item.as_str() {
+                        "context" => options.context = true,
+                        "agents" => options.agents = true,
+                        other => {
+                            return Err(McpError::invalid_params(
+                                format!(
+                                    "Cannot clone '{other}' (expected \"context\" or \"agents\")"
+                                ),
+                                None,
+                            ))
+                        }
+                    }
+                }
+                options
+            }
+        };
+        options.confidential = params.include_confidential;
+        let summary = self
             .db
-            .peek_messages(&params.project_id, &params.agent_id, params.limit)
+            .clone_project(&params.src, &params.dst, options)
             .map_err(|e| McpError::internal_error(e.to_string(), None))?;
-        Ok(messages_response(&messages))
+        Ok(json_response(&json!(summary)))
     }
 
-    /// Delete a specific message by ID.
+    /// List summaries of purged ephemeral projects.
     #[tool(
-        description = "Delete a specific message by ID. Returns {\"deleted\": true} or {\"deleted\": false}. Errors: InvalidMessageId if ID is not numeric."
+        description = "List summaries of ephemeral projects that expired and were purged, most recent first. Returns {\"projects\": [{\"project_id\", \"created_at\", \"expires_at\", \"purged_at\", \"agents\", \"messages_sent\", \"pending_messages\", \"context_keys\", \"milestones\"}]}."
     )]
-    async fn delete_message(
+    async fn list_expired_projects(
         &self,
-        Parameters(params): Parameters<DeleteMessageParams>,
+        Parameters(params): Parameters<ListExpiredProjectsParams>,
     ) -> Result<CallToolResult, McpError> {
-        let deleted = self
+        let projects = self
             .db
-            .delete_message(&params.message_id)
+            .expired_projects(params.limit.unwrap_or(50).min(MAX_MESSAGE_LIMIT))
             .map_err(|e| McpError::internal_error(e.to_string(), None))?;
-        Ok(json_response(&json!({ "deleted": deleted })))
+        Ok(json_response(&json!({ "projects": projects })))
+    }
+
+    /// Read a project's event feed incrementally.
+    #[tool(
+        description = "Read a project's changelog feed, e.g. to keep an external index or mirror in sync with one incremental call. Returns events after after_seq (default: 0), oldest first, up to limit (default: 100, max: 500): message_enqueued, message_consumed and message_removed (with agent_id and the message ID as subject), context_set and context_deleted (the key as subject), queue_settings_changed, queue_paused and queue_resumed (agent_id), schema_registered (the schema name as subject), project_expiry_set and project_purged. Returns {\"events\": [{\"seq\", \"kind\", \"agent_id\", \"subject\", \"created_at\"}], \"next_seq\", \"pruned\"}; pass next_seq as after_seq on the next call. Events are kept for 7 days; pruned: true means some after after_seq are gone and the mirror should resynchronize."
+    )]
+    async fn events_since(
+        &self,
+        Parameters(params): Parameters<EventsSinceParams>,
+    ) -> Result<CallToolResult, McpError> {
+        let page = self
+            .db
+            .events_since(&params.project_id, params.after_seq, params.limit)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(page)))
+    }
+
+    /// Keepalive and latency probe.
+    #[tool(
+        description = "Cheap no-op to keep the session alive and measure latency. Returns {\"pong\": true, \"server_time\": \"...\", \"server_time_ms\": ..., \"uptime_seconds\": ..., \"session_id\": \"...\", \"client\": {\"name\", \"version\"}}, plus \"sandbox_project\" when the server runs in session sandbox mode (an ephemeral project deleted when the session ends)."
+    )]
+    async fn ping(
+        &self,
+        extensions: Extensions,
+        peer: Peer<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        let client = peer.peer_info().map(
+            |info| json!({ "name": info.client_info.name, "version": info.client_info.version }),
+        );
+        let session_id = session_id(&extensions);
+        let mut response = json!({
+            "pong": true,
+            "server_time": time::now_iso8601(),
+            "server_time_ms": time::unix_millis(),
+            "uptime_seconds": self.started_at.elapsed().as_secs(),
+            "session_id": session_id,
+            "client": client,
+        });
+        if let Some(sandbox) = session_id
+            .as_deref()
+            .zip(self.sessions.as_ref())
+            .and_then(|(id, sessions)| sessions.sandbox_for(id))
+        {
+            response["sandbox_project"] = json!(sandbox);
+        }
+        Ok(json_response(&response))
+    }
+
+    /// List active MCP sessions (admin).
+    #[tool(
+        description = "Admin: list active MCP sessions. Returns {\"sessions\": [{\"session_id\", \"client_name\", \"client_version\", \"agent_id\", \"first_seen\", \"last_activity\", \"tool_calls\"}]}."
+    )]
+    async fn list_sessions(&self, extensions: Extensions) -> Result<CallToolResult, McpError> {
+        require_admin(&extensions)?;
+        let sessions = self.session_registry()?.list().await;
+        Ok(json_response(&json!({ "sessions": sessions })))
+    }
+
+    /// Forcibly terminate an MCP session (admin).
+    #[tool(
+        description = "Admin: forcibly terminate an MCP session, e.g. a stuck or misbehaving client. Returns {\"disconnected\": true} or {\"disconnected\": false} if no such session exists."
+    )]
+    async fn disconnect_session(
+        &self,
+        Parameters(params): Parameters<DisconnectSessionParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_admin(&extensions)?;
+        let disconnected = self
+            .session_registry()?
+            .disconnect(&params.session_id)
+            .await;
+        Ok(json_response(&json!({ "disconnected": disconnected })))
+    }
+
+    /// Analyze database storage usage (admin).
+    #[tool(
+        description = "Admin: analyze database storage. Returns per-table sizes, largest messages and context values, message payload bytes vs on-disk bytes (message_storage_ratio), and fragmentation (free pages reclaimable by VACUUM)."
+    )]
+    async fn storage_analyze(&self, extensions: Extensions) -> Result<CallToolResult, McpError> {
+        require_admin(&extensions)?;
+        let report = self
+            .db
+            .storage_analyze()
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(report)))
+    }
+
+    /// Issue an API token (admin).
+    #[tool(
+        description = "Admin: issue an API token for agent_id, accepted as a bearer token when authentication is enabled. Returns {\"token\": {\"token_id\", \"name\", \"agent_id\", \"roles\", \"created_at\", \"expires_at\", \"last_used_at\"}, \"secret\"}; the secret is shown only once. Errors: EmptyField if agent_id empty."
+    )]
+    async fn create_api_token(
+        &self,
+        Parameters(params): Parameters<CreateApiTokenParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_admin(&extensions)?;
+        if let Some(tool) = params
+            .tools
+            .iter()
+            .flatten()
+            .find(|tool| !self.tool_router.has_route(tool))
+        {
+            return Err(McpError::invalid_params(
+                format!("Unknown tool '{tool}'"),
+                None,
+            ));
+        }
+        let (token, secret) = self
+            .db
+            .create_api_token(
+                &params.agent_id,
+                params.name.as_deref(),
+                &params.roles,
+                params.tools.as_deref(),
+                params.ttl_secs,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "token": token, "secret": secret })))
+    }
+
+    /// Replace an API token, keeping the old one valid for a while (admin).
+    #[tool(
+        description = "Admin: rotate an API token without downtime. Issues a new token for the same agent, name and roles, and makes the old one expire after overlap_secs (default: 3600) so clients can switch over. Returns {\"token\", \"secret\"} for the new token, as create_api_token does. Errors: UnknownToken."
+    )]
+    async fn rotate_api_token(
+        &self,
+        Parameters(params): Parameters<RotateApiTokenParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_admin(&extensions)?;
+        let (token, secret) = self
+            .db
+            .rotate_api_token(
+                &params.token_id,
+                params.overlap_secs.unwrap_or(DEFAULT_TOKEN_OVERLAP_SECS),
+                params.ttl_secs,
+            )
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "token": token, "secret": secret })))
+    }
+
+    /// Revoke an API token (admin).
+    #[tool(
+        description = "Admin: make an API token expire in_secs from now (default: immediately). Returns the updated token. Errors: UnknownToken."
+    )]
+    async fn expire_api_token(
+        &self,
+        Parameters(params): Parameters<ExpireApiTokenParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_admin(&extensions)?;
+        let token = self
+            .db
+            .expire_api_token(&params.token_id, params.in_secs)
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!(token)))
+    }
+
+    /// List API tokens (admin).
+    #[tool(
+        description = "Admin: list API tokens, including expired ones, newest first. Returns {\"tokens\": [{\"token_id\", \"name\", \"agent_id\", \"roles\", \"created_at\", \"expires_at\", \"last_used_at\", \"replaced_by\"}]}. Secrets are never listed."
+    )]
+    async fn list_api_tokens(
+        &self,
+        Parameters(params): Parameters<ListApiTokensParams>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        require_admin(&extensions)?;
+        let tokens = self
+            .db
+            .api_tokens(params.agent_id.as_deref())
+            .map_err(|e| McpError::internal_error(e.to_string(), None))?;
+        Ok(json_response(&json!({ "tokens": tokens })))
     }
 }
 
-#[tool_handler]
 impl ServerHandler for MailboxServer {
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        self.record_session_activity(&request, &context);
+        if let Some(identity) = identity(&context.extensions) {
+            tracing::info!(
+                target: "mailbox_mcp::audit",
+                tool = %request.name,
+                agent_id = %identity.agent_id,
+                token_id = identity.token_id.as_deref().unwrap_or("-"),
+                "Tool call"
+            );
+            if !identity.may_call(&request.name) {
+                return Err(McpError::invalid_request(
+                    format!("Tool '{}' is not enabled for this token", request.name),
+                    None,
+                ));
+            }
+        }
+        let tcc = ToolCallContext::new(self, request, context);
+        self.tool_router.call(tcc).await
+    }
+
+    async fn list_tools(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, McpError> {
+        let mut tools = self.tool_router.list_all();
+        if let Some(identity) = identity(&context.extensions) {
+            tools.retain(|tool| identity.may_call(&tool.name));
+        }
+        Ok(ListToolsResult {
+            tools,
+            meta: None,
+            next_cursor: None,
+        })
+    }
+
     fn get_info(&self) -> ServerInfo {
         ServerInfo {
             protocol_version: ProtocolVersion::V_2024_11_05,
             capabilities: ServerCapabilities::builder().enable_tools().build(),
             server_info: Implementation::from_build_env(),
-            instructions: Some("Mailbox MCP server for agent-to-agent communication".to_string()),
+            instructions: Some(self.instructions()),
         }
     }
 }
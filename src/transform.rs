@@ -0,0 +1,247 @@
+//! Message content transformations applied at send time.
+//!
+//! Each `[[transforms]]` entry adds a stage to a pipeline that rewrites the
+//! content of messages sent through the tools, in order, before they are
+//! validated and stored:
+//!
+//! ```toml
+//! [[transforms]]
+//! type = "redact"
+//! patterns = ["sk-[A-Za-z0-9]{20,}", "(?i)password=\\S+"]
+//!
+//! [[transforms]]
+//! type = "normalize_markdown"
+//!
+//! [[transforms]]
+//! type = "truncate"
+//! max_bytes = 65536
+//! ```
+//!
+//! Built-in stages: `redact` (replace regex matches with `replacement`,
+//! default `[REDACTED]`), `compress_whitespace` (collapse runs of spaces and
+//! blank lines), `normalize_markdown` (LF line endings, `-` bullets, no
+//! trailing whitespace outside code blocks), and `truncate` (cut content to
+//! `max_bytes`, ending with a notice of how much was removed). Deployments
+//! embedding the server can add their own stages by implementing
+//! [`Transform`].
+//!
+//! Stages see the content only; schemas are checked against the transformed
+//! content, so pipelines that rewrite JSON payloads should be used with care.
+
+use crate::config::ConfigError;
+use regex::Regex;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// A stage of the content pipeline.
+pub trait Transform: Send + Sync {
+    /// Returns the transformed content.
+    fn apply(&self, content: String) -> String;
+}
+
+/// Ordered transformation stages applied to outgoing message content.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    stages: Vec<Arc<dyn Transform>>,
+}
+
+impl Pipeline {
+    /// Builds the pipeline described by the configuration.
+    ///
+    /// # Errors
+    /// `InvalidTransform` if a stage is malformed, e.g. has an invalid pattern.
+    pub fn from_config(config: &[TransformConfig]) -> Result<Self, ConfigError> {
+        config.iter().try_fold(Self::default(), |pipeline, stage| {
+            Ok(pipeline.with_stage(stage.build()?))
+        })
+    }
+
+    /// Appends a stage.
+    #[must_use]
+    pub fn with_stage(mut self, stage: Arc<dyn Transform>) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    /// Runs content through every stage, borrowing it if there are none.
+    #[must_use]
+    pub fn apply<'a>(&self, content: &'a str) -> Cow<'a, str> {
+        if self.stages.is_empty() {
+            return Cow::Borrowed(content);
+        }
+        Cow::Owned(
+            self.stages
+                .iter()
+                .fold(content.to_string(), |content, stage| stage.apply(content)),
+        )
+    }
+}
+
+/// A built-in pipeline stage.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TransformConfig {
+    /// Replaces every match of the patterns.
+    Redact {
+        /// Regular expressions (Rust `regex` syntax).
+        patterns: Vec<String>,
+        #[serde(default = "default_replacement")]
+        replacement: String,
+    },
+    /// Collapses runs of spaces and tabs, and of blank lines.
+    CompressWhitespace,
+    /// Normalizes line endings, bullets, and trailing whitespace.
+    NormalizeMarkdown,
+    /// Cuts content longer than `max_bytes`, appending a notice.
+    Truncate { max_bytes: usize },
+}
+
+fn default_replacement() -> String {
+    "[REDACTED]".to_string()
+}
+
+impl TransformConfig {
+    fn build(&self) -> Result<Arc<dyn Transform>, ConfigError> {
+        let invalid = |reason: String| ConfigError::InvalidTransform { reason };
+        Ok(match self {
+            Self::Redact {
+                patterns,
+                replacement,
+            } => {
+                if patterns.is_empty() {
+                    return Err(invalid("redact needs at least one pattern".to_string()));
+                }
+                let patterns = patterns
+                    .iter()
+                    .map(|pattern| {
+                        Regex::new(pattern).map_err(|e| invalid(format!("redact pattern: {e}")))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Arc::new(Redact {
+                    patterns,
+                    replacement: replacement.clone(),
+                })
+            }
+            Self::CompressWhitespace => Arc::new(CompressWhitespace),
+            Self::NormalizeMarkdown => Arc::new(NormalizeMarkdown),
+            Self::Truncate { max_bytes } => {
+                if *max_bytes < MIN_TRUNCATE_BYTES {
+                    return Err(invalid(format!(
+                        "truncate max_bytes must be at least {MIN_TRUNCATE_BYTES}"
+                    )));
+                }
+                Arc::new(Truncate {
+                    max_bytes: *max_bytes,
+                })
+            }
+        })
+    }
+}
+
+/// Replaces matches of regular expressions.
+pub struct Redact {
+    patterns: Vec<Regex>,
+    replacement: String,
+}
+
+impl Transform for Redact {
+    fn apply(&self, content: String) -> String {
+        self.patterns.iter().fold(content, |content, pattern| {
+            match pattern.replace_all(&content, self.replacement.as_str()) {
+                Cow::Borrowed(_) => content,
+                Cow::Owned(replaced) => replaced,
+            }
+        })
+    }
+}
+
+/// Collapses runs of spaces and tabs into one space, strips trailing
+/// whitespace, and collapses runs of blank lines into one.
+pub struct CompressWhitespace;
+
+impl Transform for CompressWhitespace {
+    fn apply(&self, content: String) -> String {
+        let mut compressed = String::with_capacity(content.len());
+        let mut blank_lines = 0;
+        for line in content.trim().lines() {
+            let line = line.split([' ', '\t']).filter(|word| !word.is_empty());
+            let mut words = line.peekable();
+            if words.peek().is_none() {
+                blank_lines += 1;
+                continue;
+            }
+            if !compressed.is_empty() {
+                compressed.push_str(if blank_lines > 0 { "\n\n" } else { "\n" });
+            }
+            blank_lines = 0;
+            for (i, word) in words.enumerate() {
+                if i > 0 {
+                    compressed.push(' ');
+                }
+                compressed.push_str(word);
+            }
+        }
+        compressed
+    }
+}
+
+/// Normalizes Markdown: LF line endings, `-` for `*`/`+` bullets, no trailing
+/// whitespace, and no leading or trailing blank lines. Fenced code blocks are
+/// left as they are.
+pub struct NormalizeMarkdown;
+
+impl Transform for NormalizeMarkdown {
+    fn apply(&self, content: String) -> String {
+        let mut normalized = String::with_capacity(content.len());
+        let mut in_code_block = false;
+        for line in content.lines() {
+            let indented = line.trim_start();
+            let indent = &line[..line.len() - indented.len()];
+            if indented.starts_with("```") || indented.starts_with("~~~") {
+                in_code_block = !in_code_block;
+            }
+            if in_code_block {
+                normalized.push_str(line);
+            } else if let Some(item) = indented
+                .strip_prefix("* ")
+                .or_else(|| indented.strip_prefix("+ "))
+            {
+                normalized.push_str(indent);
+                normalized.push_str("- ");
+                normalized.push_str(item.trim_end());
+            } else {
+                normalized.push_str(line.trim_end());
+            }
+            normalized.push('\n');
+        }
+        normalized.trim_matches('\n').to_string()
+    }
+}
+
+/// Shortest `max_bytes` accepted, leaving room for the notice.
+const MIN_TRUNCATE_BYTES: usize = 64;
+
+/// Cuts content to at most `max_bytes`, ending with a truncation notice.
+pub struct Truncate {
+    max_bytes: usize,
+}
+
+impl Transform for Truncate {
+    fn apply(&self, mut content: String) -> String {
+        if content.len() <= self.max_bytes {
+            return content;
+        }
+        // The notice's length depends on the count it reports; allowing for
+        // the longest count keeps the result within the limit.
+        let reserved = format!("\n[truncated {} bytes]", content.len()).len();
+        let mut end = self.max_bytes - reserved;
+        while !content.is_char_boundary(end) {
+            end -= 1;
+        }
+        let removed = content.len() - end;
+        content.truncate(end);
+        content.push_str(&format!("\n[truncated {removed} bytes]"));
+        content
+    }
+}
@@ -0,0 +1,44 @@
+//! Agent watchdogs.
+//!
+//! Watchdogs created with `set_watchdog` are checked every few seconds; the
+//! supervisor is alerted shortly after an agent's interval passes without a
+//! heartbeat or receive, and told again once the agent is back.
+
+use crate::db::Database;
+use std::time::Duration;
+
+/// How often watchdogs are checked.
+const TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Checks watchdogs until the task is cancelled.
+pub async fn run(db: Database) {
+    let mut ticker = tokio::time::interval(TICK_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        let db = db.clone();
+        match tokio::task::spawn_blocking(move || db.check_watchdogs()).await {
+            Ok(Ok(check)) => {
+                for watchdog in check.alerted {
+                    tracing::info!(
+                        "Agent {}/{} is quiet; alerted {}",
+                        watchdog.project_id,
+                        watchdog.agent_id,
+                        watchdog.supervisor
+                    );
+                }
+                for watchdog in check.recovered {
+                    tracing::info!(
+                        "Agent {}/{} is active again; notified {}",
+                        watchdog.project_id,
+                        watchdog.agent_id,
+                        watchdog.supervisor
+                    );
+                }
+            }
+            Ok(Err(e)) => tracing::warn!("Failed to check watchdogs: {e}"),
+            Err(e) => tracing::warn!("Watchdog task panicked: {e}"),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Checks that the operator is told, in the database itself, when it is
+//! found corrupted.
+
+use mailbox_mcp::db::{OPERATOR_AGENT, SYSTEM_PROJECT};
+use mailbox_mcp::{Database, DbHealth};
+use std::io::{Seek, SeekFrom, Write};
+
+#[test]
+fn corruption_alert_is_stored() {
+    let dir =
+        std::env::temp_dir().join(format!("mailbox-mcp-test-degraded-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("mailbox.db");
+    drop(Database::open(&path).expect("open database"));
+
+    // Break the page header of a table alerts never touch
+    let conn = rusqlite::Connection::open(&path).expect("open connection");
+    let page_size: u64 = conn
+        .query_row("PRAGMA page_size", [], |row| row.get(0))
+        .expect("read page size");
+    let root_page: u64 = conn
+        .query_row(
+            "SELECT rootpage FROM sqlite_master WHERE name = 'annotations'",
+            [],
+            |row| row.get(0),
+        )
+        .expect("find annotations table");
+    drop(conn);
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .expect("open database file");
+    file.seek(SeekFrom::Start((root_page - 1) * page_size))
+        .expect("seek to page");
+    file.write_all(&[0xFF; 8]).expect("corrupt page");
+    drop(file);
+
+    let db = Database::open(&path).expect("reopen database");
+    assert!(matches!(
+        db.health(),
+        DbHealth::Degraded {
+            corrupted: true,
+            ..
+        }
+    ));
+    drop(db);
+
+    let conn = rusqlite::Connection::open(&path).expect("open connection");
+    let alerts: Vec<String> = conn
+        .prepare("SELECT content FROM messages WHERE project_id = ?1 AND to_agent = ?2")
+        .and_then(|mut stmt| {
+            stmt.query_map([SYSTEM_PROJECT, OPERATOR_AGENT], |row| row.get(0))?
+                .collect()
+        })
+        .expect("read alerts");
+    assert!(
+        alerts
+            .iter()
+            .any(|alert| alert.starts_with("Database entered degraded mode")),
+        "no corruption alert among {alerts:?}"
+    );
+    drop(conn);
+    let _ = std::fs::remove_dir_all(&dir);
+}
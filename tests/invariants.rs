@@ -0,0 +1,192 @@
+//! Exercises the main write paths and checks that they leave the database's
+//! cross-table invariants intact.
+
+use mailbox_mcp::db::{Database, DbError, DeliveryMode, MessageFilter, QueueSettings};
+use mailbox_mcp::NewMessage;
+use std::path::{Path, PathBuf};
+
+/// A database in a fresh temporary directory, deleted on drop.
+struct TempDb {
+    dir: PathBuf,
+    db: Database,
+}
+
+impl TempDb {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("mailbox-mcp-test-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = Database::open(&dir.join("mailbox.db")).expect("open database");
+        Self { dir, db }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join("mailbox.db")
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn send(db: &Database, to_agent: &str, content: &str, tags: Option<&[String]>) -> String {
+    db.send(&NewMessage {
+        project_id: "owner/repo",
+        to_agent,
+        from_agent: "author",
+        content,
+        tags,
+        ..NewMessage::default()
+    })
+    .expect("send")
+}
+
+fn assert_consistent(path: &Path, db: &Database) {
+    assert!(db.check_invariants().expect("check invariants").is_empty());
+    assert!(Database::check_invariants_at(path)
+        .expect("check invariants read-only")
+        .is_empty());
+}
+
+#[test]
+fn message_paths_keep_invariants() {
+    let temp = TempDb::new("messages");
+    let db = &temp.db;
+
+    let attachment = db
+        .store_attachment("owner/repo", b"diff --git", Some("text/x-diff"))
+        .expect("store attachment");
+    let attachments = [attachment.hash];
+    let mut metadata = serde_json::Map::new();
+    metadata.insert("model".to_string(), "small".into());
+    db.send(&NewMessage {
+        project_id: "owner/repo",
+        to_agent: "reviewer",
+        from_agent: "author",
+        content: "PR ready",
+        metadata: Some(&metadata),
+        attachments: Some(&attachments),
+        request_receipt: true,
+        ..NewMessage::default()
+    })
+    .expect("send with metadata and attachment");
+    let tags = ["ci".to_string()];
+    send(db, "reviewer", "build green", Some(&tags));
+
+    let received = db
+        .receive_filtered(
+            "owner/repo",
+            "reviewer",
+            &MessageFilter::default(),
+            None,
+            None,
+        )
+        .expect("receive");
+    assert_eq!(received.len(), 2);
+    let receipts = db
+        .receive_filtered(
+            "owner/repo",
+            "author",
+            &MessageFilter::default(),
+            None,
+            None,
+        )
+        .expect("receive receipt");
+    assert_eq!(receipts.len(), 1);
+
+    db.set_queue_settings(
+        "owner/repo",
+        "worker",
+        QueueSettings {
+            delivery_mode: DeliveryMode::AtLeastOnce,
+            ..QueueSettings::default()
+        },
+    )
+    .expect("set delivery mode");
+    let acked = send(db, "worker", "job 1", None);
+    let nacked = send(db, "worker", "job 2", None);
+    let jobs = db
+        .receive_filtered(
+            "owner/repo",
+            "worker",
+            &MessageFilter::default(),
+            None,
+            None,
+        )
+        .expect("receive jobs");
+    assert_eq!(jobs.len(), 2);
+    assert!(db.ack_message(&acked, None).expect("ack"));
+    assert!(db.nack_message(&nacked, 0, None).expect("nack"));
+
+    assert_consistent(&temp.path(), db);
+}
+
+#[test]
+fn context_paths_keep_invariants() {
+    let temp = TempDb::new("context");
+    let db = &temp.db;
+    let project = Some("owner/repo");
+
+    db.context_watch("owner/repo", "watcher", "plan/", true)
+        .expect("watch");
+    db.context_set(
+        project,
+        "plan/status",
+        "draft",
+        false,
+        None,
+        Some("author"),
+        false,
+    )
+    .expect("set");
+    db.context_set(None, "global", "on", false, Some(60), None, false)
+        .expect("set global");
+    db.context_set_many(
+        None,
+        &[("global", "off"), ("other", "1")],
+        false,
+        None,
+        None,
+        false,
+    )
+    .expect("set many global");
+    assert_eq!(
+        db.context_get(None, "global")
+            .expect("get global")
+            .as_deref(),
+        Some("off")
+    );
+    db.context_append(project, "plan/log", "started", 10, Some("author"))
+        .expect("append");
+    assert!(db
+        .context_delete(project, "plan/status", Some("author"))
+        .expect("delete"));
+    let notices = db
+        .receive_filtered(
+            "owner/repo",
+            "watcher",
+            &MessageFilter::default(),
+            None,
+            None,
+        )
+        .expect("receive notices");
+    assert_eq!(notices.len(), 3);
+
+    assert_consistent(&temp.path(), db);
+}
+
+#[test]
+fn read_only_check_refuses_outdated_schema() {
+    let temp = TempDb::new("outdated");
+    let conn = rusqlite::Connection::open(temp.path()).expect("open connection");
+    conn.pragma_update(None, "user_version", 1)
+        .expect("set schema version");
+    drop(conn);
+
+    match Database::check_invariants_at(&temp.path()) {
+        Err(DbError::SchemaOutdated { found: 1, .. }) => {}
+        other => panic!("expected SchemaOutdated, got {other:?}"),
+    }
+}